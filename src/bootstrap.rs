@@ -0,0 +1,97 @@
+// src/bootstrap.rs
+//! Bootstrap progress, logged the way Tor reports it to controllers
+//! (`NOTICE BOOTSTRAP PROGRESS=NN TAG=... SUMMARY="..."`, control-spec 5.5)
+//! so tooling that watches Tor's bootstrap can watch ours.
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// The bootstrap phases we pass through, with Tor's percentages and tags
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootstrapPhase {
+    Conn,
+    Handshake,
+    OnehopCreate,
+    RequestingStatus,
+    LoadingStatus,
+    LoadingKeys,
+    RequestingDescriptors,
+    CircuitCreate,
+    Done,
+}
+
+impl BootstrapPhase {
+    pub fn progress(self) -> u8 {
+        match self {
+            BootstrapPhase::Conn => 5,
+            BootstrapPhase::Handshake => 10,
+            BootstrapPhase::OnehopCreate => 15,
+            BootstrapPhase::RequestingStatus => 25,
+            BootstrapPhase::LoadingStatus => 40,
+            BootstrapPhase::LoadingKeys => 45,
+            BootstrapPhase::RequestingDescriptors => 50,
+            BootstrapPhase::CircuitCreate => 90,
+            BootstrapPhase::Done => 100,
+        }
+    }
+
+    pub fn tag(self) -> &'static str {
+        match self {
+            BootstrapPhase::Conn => "conn",
+            BootstrapPhase::Handshake => "handshake",
+            BootstrapPhase::OnehopCreate => "onehop_create",
+            BootstrapPhase::RequestingStatus => "requesting_status",
+            BootstrapPhase::LoadingStatus => "loading_status",
+            BootstrapPhase::LoadingKeys => "loading_keys",
+            BootstrapPhase::RequestingDescriptors => "requesting_descriptors",
+            BootstrapPhase::CircuitCreate => "circuit_create",
+            BootstrapPhase::Done => "done",
+        }
+    }
+
+    pub fn summary(self) -> &'static str {
+        match self {
+            BootstrapPhase::Conn => "Connecting to a relay",
+            BootstrapPhase::Handshake => "Handshaking with a relay",
+            BootstrapPhase::OnehopCreate => "Establishing an encrypted directory connection",
+            BootstrapPhase::RequestingStatus => "Asking for networkstatus consensus",
+            BootstrapPhase::LoadingStatus => "Loading networkstatus consensus",
+            BootstrapPhase::LoadingKeys => "Loading authority key certs",
+            BootstrapPhase::RequestingDescriptors => "Asking for relay descriptors",
+            BootstrapPhase::CircuitCreate => "Establishing a Tor circuit",
+            BootstrapPhase::Done => "Done",
+        }
+    }
+}
+
+/// How far bootstrapping has got. Like Tor, progress only moves forward:
+/// reporting a phase at or below the current one logs nothing.
+#[derive(Debug, Default)]
+pub struct Bootstrap {
+    progress: AtomicU8,
+}
+
+impl Bootstrap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Percentage reached so far (0 before anything happened)
+    pub fn progress(&self) -> u8 {
+        self.progress.load(Ordering::SeqCst)
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.progress() >= BootstrapPhase::Done.progress()
+    }
+
+    /// Enter `phase`, logging it if it's progress
+    pub fn report(&self, phase: BootstrapPhase) {
+        if self.progress.fetch_max(phase.progress(), Ordering::SeqCst) < phase.progress() {
+            log::info!(
+                "NOTICE BOOTSTRAP PROGRESS={} TAG={} SUMMARY=\"{}\"",
+                phase.progress(),
+                phase.tag(),
+                phase.summary()
+            );
+        }
+    }
+}
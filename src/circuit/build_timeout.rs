@@ -0,0 +1,125 @@
+// src/circuit/build_timeout.rs
+//! Adaptive circuit build timeout, after Tor's (path-spec 2.4). Build times
+//! roughly follow a Pareto distribution; once enough builds have been seen,
+//! the timeout is put where the fitted distribution has 80% of builds done,
+//! so the slowest builds are abandoned for a new path rather than waited on.
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Timeout used until enough builds have been seen, and the most it adapts to
+pub const DEFAULT_BUILD_TIMEOUT: Duration = Duration::from_secs(60);
+/// The least it adapts to, however fast builds have been
+pub const MIN_BUILD_TIMEOUT: Duration = Duration::from_millis(1500);
+/// Builds needed before the timeout adapts (Tor's CBT_DEFAULT_MIN_CIRCUITS_TO_OBSERVE)
+pub const MIN_BUILD_SAMPLES: usize = 100;
+/// Builds remembered, the oldest giving way (Tor's CBT_NCIRCUITS_TO_OBSERVE)
+const MAX_BUILD_SAMPLES: usize = 1000;
+/// Share of builds that should finish within the timeout
+const BUILD_QUANTILE: f64 = 0.8;
+/// Histogram bins used to find the distribution's mode
+const BIN_WIDTH_MS: u64 = 10;
+/// Xm is the average of this many of the most common bins (Tor's CBT_DEFAULT_NUM_XM_MODES)
+const XM_MODES: usize = 10;
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    millis: u64,
+    /// The build was abandoned at `millis`; it would have taken longer
+    timed_out: bool,
+}
+
+#[derive(Debug)]
+struct State {
+    samples: VecDeque<Sample>,
+    timeout: Duration,
+}
+
+/// Recent build times and the timeout they imply
+#[derive(Debug)]
+pub struct BuildTimes {
+    initial: Duration,
+    state: Mutex<State>,
+}
+
+impl Default for BuildTimes {
+    fn default() -> Self {
+        Self::new(DEFAULT_BUILD_TIMEOUT)
+    }
+}
+
+impl BuildTimes {
+    /// Start out with `initial` as the timeout, and never adapt above it
+    pub fn new(initial: Duration) -> Self {
+        Self {
+            initial,
+            state: Mutex::new(State { samples: VecDeque::new(), timeout: initial }),
+        }
+    }
+
+    /// How long a build may take before it's abandoned
+    pub fn timeout(&self) -> Duration {
+        self.state.lock().unwrap().timeout
+    }
+
+    /// Builds recorded so far, up to the number remembered
+    pub fn sample_count(&self) -> usize {
+        self.state.lock().unwrap().samples.len()
+    }
+
+    /// Record a build that completed in `elapsed`; returns the new timeout
+    pub fn record_build(&self, elapsed: Duration) -> Duration {
+        self.record(Sample { millis: elapsed.as_millis() as u64, timed_out: false })
+    }
+
+    /// Record a build abandoned after `timeout`; returns the new timeout
+    pub fn record_timeout(&self, timeout: Duration) -> Duration {
+        self.record(Sample { millis: timeout.as_millis() as u64, timed_out: true })
+    }
+
+    fn record(&self, sample: Sample) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        if state.samples.len() == MAX_BUILD_SAMPLES {
+            state.samples.pop_front();
+        }
+        state.samples.push_back(sample);
+        if let Some(fitted) = fit_timeout(&state.samples) {
+            state.timeout = fitted.clamp(MIN_BUILD_TIMEOUT, self.initial.max(MIN_BUILD_TIMEOUT));
+        }
+        state.timeout
+    }
+}
+
+/// The BUILD_QUANTILE point of a Pareto distribution fitted to `samples`,
+/// with abandoned builds counted as right-censored; None until there are
+/// enough samples to fit
+fn fit_timeout(samples: &VecDeque<Sample>) -> Option<Duration> {
+    let completed: Vec<u64> = samples.iter().filter(|s| !s.timed_out).map(|s| s.millis).collect();
+    if samples.len() < MIN_BUILD_SAMPLES || completed.is_empty() {
+        return None;
+    }
+
+    // Xm, the distribution's scale, comes from where builds bunch up
+    let mut bins: std::collections::HashMap<u64, usize> = std::collections::HashMap::new();
+    for millis in &completed {
+        *bins.entry(millis / BIN_WIDTH_MS).or_default() += 1;
+    }
+    let mut modes: Vec<(u64, usize)> = bins.into_iter().collect();
+    modes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    modes.truncate(XM_MODES);
+    let mode_builds: usize = modes.iter().map(|(_, count)| count).sum();
+    let xm = modes
+        .iter()
+        .map(|(bin, count)| (bin * BIN_WIDTH_MS + BIN_WIDTH_MS / 2) as f64 * *count as f64)
+        .sum::<f64>()
+        / mode_builds as f64;
+
+    // Maximum likelihood alpha; builds faster than Xm count as Xm
+    let log_sum: f64 = samples.iter().map(|s| (s.millis as f64).max(xm).ln() - xm.ln()).sum();
+    if log_sum <= 0.0 {
+        return None;
+    }
+    let alpha = completed.len() as f64 / log_sum;
+    let millis = xm / (1.0 - BUILD_QUANTILE).powf(1.0 / alpha);
+    millis.is_finite().then(|| Duration::from_millis(millis as u64))
+}
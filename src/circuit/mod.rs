@@ -1,8 +1,73 @@
 // src/circuit/mod.rs
-use crate::crypto::OnionCrypto;
-use crate::directory::DirectoryClient;
-use std::collections::HashMap;
-use tokio::sync::RwLock;
+pub mod build_timeout;
+mod relay;
+pub mod sendme;
+
+use crate::crypto::{ntor_handshake, RelayCrypto};
+use crate::directory::policy::{exit_allows, ExitPolicySummary, ExitTarget};
+use crate::directory::{DirectoryClient, RelayDescriptor};
+use crate::bootstrap::{Bootstrap, BootstrapPhase};
+use crate::metrics::Metrics;
+use crate::network::cells::{
+    parse_resolved, Cell, CellCommand, CellError, Create2Cell, Created2Cell, Extend2Cell, Extended2Cell,
+    LinkSpecifier, RelayCell, ResolvedAddress, HANDSHAKE_TYPE_NTOR, HANDSHAKE_TYPE_NTOR_V3, RELAY_COMMAND_BEGIN,
+    RELAY_COMMAND_CONNECTED, RELAY_COMMAND_DATA, RELAY_COMMAND_END, RELAY_COMMAND_EXTEND2, RELAY_COMMAND_EXTENDED2,
+    RELAY_COMMAND_RESOLVE, RELAY_COMMAND_RESOLVED, RELAY_COMMAND_TRUNCATED, RELAY_PAYLOAD_LEN,
+};
+use crate::network::{has_ipv4_route, Channel, ClientHello, LinkError, RelayIdentity, TlsBackend};
+use build_timeout::BuildTimes;
+use relay::RelayPath;
+pub use relay::{CircuitStream, StreamSender, MAX_RELAY_EARLY_CELLS};
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc, Mutex, Notify, RwLock, Semaphore};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+const DEFAULT_HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+const DEFAULT_HOP_RETRIES: usize = 2;
+/// How long the exit gets to answer a RELAY_RESOLVE
+const RESOLVE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+/// How long the exit gets to connect a RELAY_BEGIN
+const BEGIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+/// How long `echo_test` waits for each echoed cell
+const ECHO_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+/// Idle time between keepalive cells on a built circuit
+const DEFAULT_KEEPALIVE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(300);
+/// Events a subscriber can fall behind by before it misses some
+const CIRCUIT_EVENT_CAPACITY: usize = 256;
+/// Pause before the circuit pool retries after a failed build
+const POOL_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(5);
+/// We open our connections, and on link protocol 4+ the side that opened a
+/// connection sets the top bit of the IDs of circuits it creates (tor-spec 5.1.1)
+const CLIENT_CIRC_ID_FLAG: CircuitId = 0x8000_0000;
+/// CREATE2 handshake types we can complete, most preferred first
+const SUPPORTED_HANDSHAKES: &[u16] = &[HANDSHAKE_TYPE_NTOR];
+
+/// The "Relay" subprotocol version that advertises each handshake type
+fn relay_protocol_version(handshake_type: u16) -> Option<u32> {
+    match handshake_type {
+        HANDSHAKE_TYPE_NTOR => Some(2),
+        HANDSHAKE_TYPE_NTOR_V3 => Some(4),
+        _ => None,
+    }
+}
+
+/// The best handshake both we and the relay support. Relays that don't list
+/// their protocols get ntor, which every current relay speaks.
+fn choose_handshake_type(relay: &RelayDescriptor) -> u16 {
+    SUPPORTED_HANDSHAKES
+        .iter()
+        .copied()
+        .find(|&handshake_type| {
+            relay_protocol_version(handshake_type).is_some_and(|version| relay.supports_protocol("Relay", version))
+        })
+        .unwrap_or(HANDSHAKE_TYPE_NTOR)
+}
 
 #[derive(Debug)]
 pub enum CircuitError {
@@ -10,6 +75,45 @@ pub enum CircuitError {
     Directory(crate::directory::DirectoryError),
     Io(String),
     NoSuitableRelays,
+    HandshakeFailed(String),
+    /// The circuit doesn't exist or isn't Ready
+    NotReady(CircuitId),
+    /// The exit couldn't resolve the hostname
+    ResolveFailed(String),
+    /// The circuit's RELAY_EARLY cells are used up, so it can't be extended again
+    RelayEarlyExhausted(CircuitId),
+    /// The exit closed the stream with RELAY_END and this reason
+    StreamEnded(u8),
+    /// A circuit can only be truncated to at least one hop, and fewer than it has
+    InvalidHopCount(usize),
+    /// The build took longer than the circuit build timeout, shown here
+    BuildTimeout(std::time::Duration),
+    /// The last hop couldn't extend the circuit, and said why in its RELAY_TRUNCATED
+    Truncated(u8),
+    /// Every stream ID on the circuit belongs to an open stream
+    StreamIdsExhausted(CircuitId),
+}
+
+impl From<std::io::Error> for CircuitError {
+    fn from(err: std::io::Error) -> Self {
+        CircuitError::Io(err.to_string())
+    }
+}
+
+impl From<LinkError> for CircuitError {
+    fn from(err: LinkError) -> Self {
+        match err {
+            LinkError::Io(e) => CircuitError::Io(e.to_string()),
+            LinkError::Timeout => CircuitError::HandshakeFailed("timeout".to_string()),
+            other => CircuitError::HandshakeFailed(other.to_string()),
+        }
+    }
+}
+
+impl From<CellError> for CircuitError {
+    fn from(err: CellError) -> Self {
+        CircuitError::HandshakeFailed(err.to_string())
+    }
 }
 
 impl From<crate::crypto::CryptoError> for CircuitError {
@@ -26,13 +130,90 @@ impl From<crate::directory::DirectoryError> for CircuitError {
 
 pub type CircuitId = u32;
 
+/// Streams with equal keys may share a circuit; streams with different keys never do
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum IsolationKey {
+    /// SOCKS username/password, as Tor Browser sets per first-party domain
+    Credentials { username: Vec<u8>, password: Vec<u8> },
+    /// Source port of a client that didn't authenticate
+    ClientPort(u16),
+}
+
+impl std::fmt::Debug for IsolationKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IsolationKey::Credentials { username, .. } => f
+                .debug_struct("Credentials")
+                .field("username", &String::from_utf8_lossy(username))
+                .finish_non_exhaustive(),
+            IsolationKey::ClientPort(port) => f.debug_tuple("ClientPort").field(port).finish(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct RelayHop {
     pub relay_id: String,
+    pub nickname: String,
     pub ip: std::net::SocketAddr,
     pub identity_key: Vec<u8>,
     pub onion_key: Vec<u8>,
-    pub crypto_state: OnionCrypto,
+    /// Ed25519 identity key, if the directory knows it
+    pub ed25519_identity: Option<[u8; 32]>,
+    /// CREATE2 handshake type to use with this relay
+    pub handshake_type: u16,
+    /// Advertised bandwidth from the consensus, in kB/s
+    pub bandwidth: u32,
+    /// Exit policy summary, if the directory had one for this relay
+    pub exit_policy: Option<ExitPolicySummary>,
+    /// IPv6 exit policy summary, if the directory had one for this relay
+    pub exit_policy_v6: Option<ExitPolicySummary>,
+    /// Connection carrying the circuit's cells, for the guard; later hops
+    /// are reached through it
+    pub channel: Option<Arc<Channel>>,
+    /// How far the build has got with this hop
+    pub state: HopState,
+}
+
+impl RelayHop {
+    /// The identities the relay must prove in its link handshake
+    pub fn link_identity(&self) -> RelayIdentity {
+        // A malformed fingerprint can't match any relay's RSA key
+        let rsa = self.identity_key.as_slice().try_into().unwrap_or([0u8; 20]);
+        RelayIdentity { rsa, ed25519: self.ed25519_identity }
+    }
+}
+
+/// Where a hop stands while its circuit is built
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HopState {
+    /// Not contacted yet
+    Pending,
+    /// Connected to the guard, CREATE2 not answered yet. Later hops stay
+    /// Pending until their EXTENDED2 arrives.
+    Connected,
+    /// The handshake completed and the hop's relay crypto is set up
+    KeysEstablished,
+    /// The relay couldn't be reached or failed the handshake
+    Failed,
+}
+
+/// A snapshot of a circuit's state and each of its hops, for debugging builds
+#[derive(Debug, Clone)]
+pub struct CircuitInfo {
+    pub id: CircuitId,
+    pub state: CircuitState,
+    pub hops: Vec<HopInfo>,
+    /// Valid-after time of the consensus the path was chosen from
+    pub consensus_valid_after: std::time::SystemTime,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HopInfo {
+    pub relay_id: String,
+    pub nickname: String,
+    pub address: std::net::SocketAddr,
+    pub state: HopState,
 }
 
 #[derive(Debug)]
@@ -41,69 +222,490 @@ pub struct Circuit {
     pub hops: Vec<RelayHop>,
     pub state: CircuitState,
     pub created_at: std::time::Instant,
+    /// Valid-after time of the consensus the path was chosen from, to tell
+    /// failures on a stale consensus apart
+    pub consensus_valid_after: std::time::SystemTime,
+    /// Built for the client's own use (directory fetches, onion services), so
+    /// its last hop needn't be an exit and it carries no exit traffic
+    pub internal: bool,
+    /// Taken out of use by `new_identity`: no new streams go over it, and it's
+    /// closed once its streams have ended
+    retired: bool,
+    /// Relay cells to and from every hop keyed so far, through the guard.
+    /// The build extends the circuit with it; streams use it once Ready.
+    relay: Option<Arc<RelayPath>>,
+    /// Streams opened on the circuit so far
+    requests: AtomicUsize,
+    /// Stops every task spawned for this circuit; cancelled when the circuit
+    /// is closed or otherwise dropped
+    cancel: CancellationToken,
+    tasks: TaskTracker,
 }
 
-#[derive(Debug)]
+impl Drop for Circuit {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+impl Circuit {
+    /// Whether the exit's policy allows streams to `target` (unknown policies are
+    /// given the benefit of the doubt; the exit refuses with RELAY_END otherwise).
+    /// Internal circuits have no exit, and retired ones take no new streams,
+    /// so they allow nothing.
+    pub fn allows_target(&self, target: Option<ExitTarget>) -> bool {
+        if self.internal || self.retired {
+            return false;
+        }
+        match (target, self.hops.last()) {
+            (Some(target), Some(exit)) => exit_allows(exit.exit_policy.as_ref(), exit.exit_policy_v6.as_ref(), target),
+            _ => true,
+        }
+    }
+
+    /// Ready with no streams open
+    fn is_idle(&self) -> bool {
+        matches!(self.state, CircuitState::Ready) && self.relay.as_ref().is_none_or(|relay| relay.active_streams() == 0)
+    }
+
+    fn relay_nicknames(&self) -> Vec<String> {
+        self.hops.iter().map(|hop| hop.nickname.clone()).collect()
+    }
+
+    fn info(&self) -> CircuitInfo {
+        let hops = self
+            .hops
+            .iter()
+            .map(|hop| HopInfo {
+                relay_id: hop.relay_id.clone(),
+                nickname: hop.nickname.clone(),
+                address: hop.ip,
+                state: hop.state,
+            })
+            .collect();
+        CircuitInfo {
+            id: self.id,
+            state: self.state.clone(),
+            hops,
+            consensus_valid_after: self.consensus_valid_after,
+        }
+    }
+}
+
+/// How busy a Ready circuit is; orders by stream count, then byte rate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CircuitLoad {
+    pub active_streams: usize,
+    /// Bytes per second sent and received over roughly the last 10-20 seconds
+    pub bytes_per_sec: u64,
+}
+
+#[derive(Debug, Clone)]
 pub enum CircuitState {
     Building,
     Ready,
     Closed,
-    Error(String),
+    /// The build failed; the kind says roughly where, the string the details
+    Error(CircuitFailureKind, String),
+}
+
+/// Which part of a circuit build failed, to point troubleshooting the right way
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CircuitFailureKind {
+    /// No relay could be picked for a hop: no usable consensus, or nothing fits the path
+    Directory,
+    /// A relay couldn't be reached, or the link handshake with it failed
+    Connectivity,
+    /// A relay refused the CREATE2 or didn't answer it in time
+    Handshake,
+    /// A relay's CREATED2 failed the ntor check, so it doesn't hold the keys
+    /// the directory lists for it
+    HandshakeAuth,
+    /// The build didn't finish within the circuit build timeout
+    Timeout,
+    /// Anything else, such as the circuit being closed mid-build
+    Other,
+}
+
+impl CircuitFailureKind {
+    pub const ALL: [CircuitFailureKind; 6] = [
+        CircuitFailureKind::Directory,
+        CircuitFailureKind::Connectivity,
+        CircuitFailureKind::Handshake,
+        CircuitFailureKind::HandshakeAuth,
+        CircuitFailureKind::Timeout,
+        CircuitFailureKind::Other,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CircuitFailureKind::Directory => "directory",
+            CircuitFailureKind::Connectivity => "connectivity",
+            CircuitFailureKind::Handshake => "handshake",
+            CircuitFailureKind::HandshakeAuth => "handshake_auth",
+            CircuitFailureKind::Timeout => "timeout",
+            CircuitFailureKind::Other => "other",
+        }
+    }
+}
+
+impl std::fmt::Display for CircuitFailureKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A circuit changing state, as `CircuitManager::subscribe` reports it.
+/// `relays` are hop nicknames, guard first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CircuitEvent {
+    /// Keys were established with another hop; `relays` are the hops built so far
+    Extended { circuit_id: CircuitId, relays: Vec<String> },
+    /// Every hop is built and the circuit is Ready
+    Built { circuit_id: CircuitId, relays: Vec<String> },
+    /// The build failed and the circuit is gone; `relays` is the path it was trying
+    Failed { circuit_id: CircuitId, relays: Vec<String>, kind: CircuitFailureKind, reason: String },
+    /// The circuit was closed
+    Closed { circuit_id: CircuitId, relays: Vec<String> },
+}
+
+impl CircuitEvent {
+    pub fn circuit_id(&self) -> CircuitId {
+        match self {
+            CircuitEvent::Extended { circuit_id, .. }
+            | CircuitEvent::Built { circuit_id, .. }
+            | CircuitEvent::Failed { circuit_id, .. }
+            | CircuitEvent::Closed { circuit_id, .. } => *circuit_id,
+        }
+    }
+
+    pub fn relays(&self) -> &[String] {
+        match self {
+            CircuitEvent::Extended { relays, .. }
+            | CircuitEvent::Built { relays, .. }
+            | CircuitEvent::Failed { relays, .. }
+            | CircuitEvent::Closed { relays, .. } => relays,
+        }
+    }
+
+    /// Number of hops the event covers
+    pub fn hop_count(&self) -> usize {
+        self.relays().len()
+    }
+}
+
+type CircuitMap = Arc<RwLock<HashMap<CircuitId, Circuit>>>;
+
+/// Called with a circuit's id when it becomes Ready
+pub type CircuitReadyHook = Box<dyn Fn(CircuitId) + Send + Sync>;
+
+#[derive(Default)]
+struct ReadyHooks(std::sync::RwLock<Vec<CircuitReadyHook>>);
+
+impl std::fmt::Debug for ReadyHooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ReadyHooks({})", self.0.read().unwrap().len())
+    }
+}
+
+/// Tears down a circuit whose build didn't complete because the
+/// `create_circuit` future was dropped mid-handshake: the circuit leaves the
+/// map and connections no other circuit uses are closed. A build that fails
+/// takes the connections back with `fail` and keeps its errored entry.
+struct PendingCircuit {
+    circuits: CircuitMap,
+    circuit_id: CircuitId,
+    channels: Vec<Arc<Channel>>,
+    armed: bool,
+}
+
+impl PendingCircuit {
+    fn new(circuits: CircuitMap, circuit_id: CircuitId) -> Self {
+        Self { circuits, circuit_id, channels: Vec::new(), armed: true }
+    }
+
+    fn complete(mut self) {
+        self.armed = false;
+    }
+
+    /// Disarm the guard, handing back the connections the circuit was registered on
+    fn fail(mut self) -> Vec<Arc<Channel>> {
+        self.armed = false;
+        std::mem::take(&mut self.channels)
+    }
+}
+
+impl Drop for PendingCircuit {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+        log::debug!("Releasing unfinished circuit {}", self.circuit_id);
+
+        for channel in &self.channels {
+            channel.unregister(self.circuit_id);
+            if channel.circuit_count() == 0 {
+                channel.close();
+            }
+        }
+
+        let circuit_id = self.circuit_id;
+        match self.circuits.try_write() {
+            Ok(mut circuits) => {
+                circuits.remove(&circuit_id);
+            }
+            Err(_) => {
+                // Can't wait for the lock in drop; finish the removal in the background
+                if let Ok(handle) = tokio::runtime::Handle::try_current() {
+                    let circuits = self.circuits.clone();
+                    handle.spawn(async move {
+                        circuits.write().await.remove(&circuit_id);
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// A relay's open connections, and a lock held while another is opened, so
+/// builds through the same relay wait for that one connection attempt
+/// without holding up builds through any other
+#[derive(Debug, Default)]
+struct RelayChannels {
+    open: Vec<Arc<Channel>>,
+    connecting: Arc<Mutex<()>>,
 }
 
 #[derive(Debug)]
 pub struct CircuitManager {
-    circuits: RwLock<HashMap<CircuitId, Circuit>>,
-    next_circuit_id: RwLock<CircuitId>,
+    circuits: CircuitMap,
+    /// Counts up for circuit ids; only its low 31 bits are used
+    next_circuit_id: AtomicU32,
+    /// Open relay connections, keyed by relay id
+    channels: Mutex<HashMap<String, RelayChannels>>,
+    max_circuits_per_guard: Option<usize>,
+    /// TLS used on relay connections
+    tls_backend: TlsBackend,
+    /// What TLS links put in their ClientHello
+    client_hello: ClientHello,
+    /// How long to wait for a hop's CREATED2 before giving up
+    handshake_timeout: std::time::Duration,
+    /// Relays tried in place of one that fails its handshake, per hop
+    hop_retries: usize,
+    /// Recent build times, which set how long a whole build may take
+    build_times: BuildTimes,
+    /// Circuit most recently built for each isolation key
+    isolated: Mutex<HashMap<IsolationKey, CircuitId>>,
+    keepalive_interval: std::time::Duration,
+    /// Parent of every circuit's cancellation token
+    cancel: CancellationToken,
+    /// Ready circuits no stream has used yet, oldest first
+    pool: Mutex<VecDeque<CircuitId>>,
+    /// Wakes the pool builder when a pooled circuit is taken or closed
+    pool_changed: Notify,
+    ready_hooks: ReadyHooks,
+    events: broadcast::Sender<CircuitEvent>,
+    metrics: Arc<Metrics>,
+    bootstrap: Arc<Bootstrap>,
+    /// Bounds how many circuits are handshaking at once (None = unlimited)
+    build_slots: Option<Arc<Semaphore>>,
+}
+
+impl Default for CircuitManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CircuitManager {
     pub fn new() -> Self {
         Self {
-            circuits: RwLock::new(HashMap::new()),
-            next_circuit_id: RwLock::new(1),
+            circuits: Arc::new(RwLock::new(HashMap::new())),
+            next_circuit_id: AtomicU32::new(1),
+            channels: Mutex::new(HashMap::new()),
+            max_circuits_per_guard: None,
+            tls_backend: TlsBackend::default(),
+            client_hello: ClientHello::default(),
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            hop_retries: DEFAULT_HOP_RETRIES,
+            build_times: BuildTimes::default(),
+            isolated: Mutex::new(HashMap::new()),
+            keepalive_interval: DEFAULT_KEEPALIVE_INTERVAL,
+            cancel: CancellationToken::new(),
+            pool: Mutex::new(VecDeque::new()),
+            pool_changed: Notify::new(),
+            ready_hooks: ReadyHooks::default(),
+            events: broadcast::channel(CIRCUIT_EVENT_CAPACITY).0,
+            metrics: Arc::new(Metrics::new()),
+            bootstrap: Arc::new(Bootstrap::new()),
+            build_slots: None,
         }
     }
+
+    /// Cap how many circuits share one guard connection; once a guard's
+    /// connections are all at the cap, a new connection is opened
+    pub fn with_max_circuits_per_guard(mut self, max: Option<usize>) -> Self {
+        self.max_circuits_per_guard = max;
+        self
+    }
+
+    pub fn with_tls_backend(mut self, backend: TlsBackend) -> Self {
+        self.tls_backend = backend;
+        self
+    }
+
+    pub fn with_client_hello(mut self, hello: ClientHello) -> Self {
+        self.client_hello = hello;
+        self
+    }
+
+    pub fn with_handshake_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// How many other relays to try for a hop whose relay can't be reached or
+    /// fails its handshake, before the build fails
+    pub fn with_hop_retries(mut self, retries: usize) -> Self {
+        self.hop_retries = retries;
+        self
+    }
+
+    /// Abandon builds that take longer than `initial` until enough builds
+    /// have been timed to adapt the timeout, which then stays below `initial`
+    pub fn with_build_timeout(mut self, initial: std::time::Duration) -> Self {
+        self.build_times = BuildTimes::new(initial);
+        self.metrics.set_circuit_build_timeout(initial);
+        self
+    }
+
+    /// Count circuits in `metrics` (created, and currently Ready) instead of a
+    /// set of counters of our own
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        metrics.set_circuit_build_timeout(self.build_times.timeout());
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// Report circuit builds to `bootstrap`; the first Ready circuit completes it
+    pub fn with_bootstrap(mut self, bootstrap: Arc<Bootstrap>) -> Self {
+        self.bootstrap = bootstrap;
+        self
+    }
+
+    /// Let at most `max` circuits run their handshakes at once; further builds
+    /// queue until one finishes, rather than all doing their crypto together
+    pub fn with_max_concurrent_builds(mut self, max: Option<usize>) -> Self {
+        self.build_slots = max.map(|max| Arc::new(Semaphore::new(max.max(1))));
+        self
+    }
+
+    /// How often a built circuit sends a keepalive (RELAY_DROP) cell to its exit
+    pub fn with_keepalive_interval(mut self, interval: std::time::Duration) -> Self {
+        self.keepalive_interval = interval;
+        self
+    }
+
+    /// Run `hook` each time a circuit finishes building. Hooks run on the
+    /// building task, so they should return quickly.
+    pub fn on_circuit_ready(&self, hook: CircuitReadyHook) {
+        self.ready_hooks.0.write().unwrap().push(hook);
+    }
+
+    /// Circuit events from now on. A subscriber that falls behind by more
+    /// than a few hundred events gets `RecvError::Lagged` and misses them.
+    pub fn subscribe(&self) -> broadcast::Receiver<CircuitEvent> {
+        self.events.subscribe()
+    }
+
+    fn emit(&self, event: CircuitEvent) {
+        // Nobody may be listening
+        let _ = self.events.send(event);
+    }
+
+    /// Rough throughput of a circuit: the smallest advertised bandwidth among
+    /// its hops. `None` if the circuit doesn't exist.
+    pub async fn estimate_throughput(&self, circuit_id: CircuitId) -> Option<u32> {
+        let circuits = self.circuits.read().await;
+        circuits.get(&circuit_id)?.hops.iter().map(|hop| hop.bandwidth).min()
+    }
+
+    /// Number of circuits currently tracked (building or built); failed
+    /// builds waiting for the reaper aren't counted
+    pub async fn circuit_count(&self) -> usize {
+        let circuits = self.circuits.read().await;
+        circuits.values().filter(|circuit| !matches!(circuit.state, CircuitState::Error(..))).count()
+    }
     
-    /// Create a new circuit with specified number of hops
+    /// Create a new circuit with specified number of hops. An `internal`
+    /// circuit may end on any middle relay rather than an exit.
     pub async fn create_circuit(
         &self,
         num_hops: usize,
+        internal: bool,
         directory: &DirectoryClient
     ) -> Result<CircuitId, CircuitError> {
-        let circuit_id = {
-            let mut next_id = self.next_circuit_id.write().await;
-            let id = *next_id;
-            *next_id += 1;
-            id
-        };
-        
-        log::info!("Creating circuit {} with {} hops", circuit_id, num_hops);
+        self.build_circuit(num_hops, None, internal, directory).await
+    }
+
+    /// Create a circuit whose exit's policy allows connections to `target`
+    pub async fn create_circuit_for_target(
+        &self,
+        num_hops: usize,
+        target: Option<ExitTarget>,
+        directory: &DirectoryClient,
+    ) -> Result<CircuitId, CircuitError> {
+        self.build_circuit(num_hops, target, false, directory).await
+    }
+
+    /// The next circuit id: the low 31 bits count up, skipping zero when they wrap
+    fn allocate_circuit_id(&self) -> CircuitId {
+        loop {
+            let next = self.next_circuit_id.fetch_add(1, Ordering::Relaxed) & !CLIENT_CIRC_ID_FLAG;
+            if next != 0 {
+                return next | CLIENT_CIRC_ID_FLAG;
+            }
+        }
+    }
+
+    async fn build_circuit(
+        &self,
+        num_hops: usize,
+        target: Option<ExitTarget>,
+        internal: bool,
+        directory: &DirectoryClient,
+    ) -> Result<CircuitId, CircuitError> {
+        let circuit_id = self.allocate_circuit_id();
         
-        let mut hops = Vec::with_capacity(num_hops);
+        log::info!("Creating {} circuit {} with {} hops", if internal { "internal" } else { "exit" }, circuit_id, num_hops);
         
-        // Select relays for each hop
-        for hop_num in 0..num_hops {
-            log::debug!("Selecting relay for hop {}", hop_num);
-            let relay = directory.select_relay(hop_num).await?;
-            let crypto = OnionCrypto::new()?;
-            
+        // On IPv6-only hosts, use relays' IPv6 ORPorts where they have one
+        let prefer_ipv6 = !has_ipv4_route();
+
+        // The whole path comes from one consensus, with no two hops sharing a
+        // relay, family or (by default) /16
+        let (path, consensus_valid_after) = match directory.select_dated_path(num_hops, target, internal).await {
+            Ok(selected) => selected,
+            Err(e) => {
+                self.metrics.record_circuit_failure(CircuitFailureKind::Directory);
+                return Err(e.into());
+            }
+        };
+        let mut path = path;
+        let hops = path.iter().map(|relay| relay_hop(relay, prefer_ipv6)).collect();
+        for (hop_num, relay) in path.iter().enumerate() {
             log::info!(
                 "Selected relay for hop {}: {} (Address: {}, Bandwidth: {}, Flags: {:?})",
                 hop_num,
                 relay.nickname,
-                relay.address,
+                relay.or_address(prefer_ipv6),
                 relay.bandwidth,
                 relay.flags
             );
-            
-            hops.push(RelayHop {
-                relay_id: relay.id,
-                ip: relay.address,
-                identity_key: relay.identity_key,
-                onion_key: relay.onion_key,
-                crypto_state: crypto,
-            });
         }
         
         let circuit = Circuit {
@@ -111,35 +713,959 @@ impl CircuitManager {
             hops,
             state: CircuitState::Building,
             created_at: std::time::Instant::now(),
+            consensus_valid_after,
+            internal,
+            retired: false,
+            relay: None,
+            requests: AtomicUsize::new(0),
+            cancel: self.cancel.child_token(),
+            tasks: TaskTracker::new(),
         };
         
-        // Store circuit
+        // Store circuit; from here on it's torn down unless the build completes
+        let cancel = circuit.cancel.clone();
         self.circuits.write().await.insert(circuit_id, circuit);
+        let mut pending = PendingCircuit::new(self.circuits.clone(), circuit_id);
         
-        // Perform circuit handshake with each hop
-        self.perform_handshakes(circuit_id).await?;
+        // Perform circuit handshake with each hop, unless the circuit is closed meanwhile
+        self.bootstrap.report(BootstrapPhase::CircuitCreate);
+        let result = tokio::select! {
+            _ = cancel.cancelled() => Err((
+                CircuitFailureKind::Other,
+                CircuitError::HandshakeFailed("circuit closed".to_string()),
+            )),
+            result = async {
+                let _slot = match &self.build_slots {
+                    // The semaphore is never closed
+                    Some(slots) => Some(slots.acquire().await.expect("build slots closed")),
+                    None => None,
+                };
+                // Only the handshakes count towards the build timeout, not waiting for a slot
+                let timeout = self.build_times.timeout();
+                let started = std::time::Instant::now();
+                let handshakes = self.perform_handshakes(&mut pending, directory, &mut path, target, internal);
+                match tokio::time::timeout(timeout, handshakes).await {
+                    Ok(result) => {
+                        if result.is_ok() {
+                            let timeout = self.build_times.record_build(started.elapsed());
+                            self.metrics.set_circuit_build_timeout(timeout);
+                        }
+                        result
+                    }
+                    Err(_) => {
+                        let adapted = self.build_times.record_timeout(timeout);
+                        self.metrics.set_circuit_build_timeout(adapted);
+                        Err((CircuitFailureKind::Timeout, CircuitError::BuildTimeout(timeout)))
+                    }
+                }
+            } => result,
+        };
+        if let Err((kind, e)) = result {
+            log::error!("Circuit {} handshake failed ({}): {:?}", circuit_id, kind, e);
+            self.metrics.record_circuit_failure(kind);
+            let relays = path.iter().map(|relay| relay.nickname.clone()).collect();
+            // The errored circuit stays in the map, so its state can be looked
+            // up, until the reaper collects it; only its tasks and connections go
+            let failed = self.circuits.write().await.get_mut(&circuit_id).map(|circuit| {
+                circuit.state = CircuitState::Error(kind, format!("{:?}", e));
+                circuit.cancel.cancel();
+                for hop in &mut circuit.hops {
+                    hop.channel = None;
+                }
+                circuit.relay.take()
+            });
+            // Otherwise it was closed meanwhile, and `pending` releases what's left
+            if let Some(relay) = failed {
+                if let Some(relay) = relay {
+                    relay.shutdown().await;
+                }
+                for channel in pending.fail() {
+                    release_channel(circuit_id, &channel).await;
+                }
+            }
+            self.emit(CircuitEvent::Failed { circuit_id, relays, kind, reason: format!("{:?}", e) });
+            return Err(e);
+        }
         
         // Mark circuit as ready
+        let mut built = None;
         if let Some(circuit) = self.circuits.write().await.get_mut(&circuit_id) {
+            if let Some(relay) = &circuit.relay {
+                circuit.tasks.spawn(relay.clone().keepalive(self.keepalive_interval));
+            }
+            // No more tasks for this circuit, so waiting on the tracker ends once they stop
+            circuit.tasks.close();
             circuit.state = CircuitState::Ready;
+            self.metrics.record_circuit_built();
+            self.metrics.active_circuits.fetch_add(1, Ordering::Relaxed);
             log::info!("Circuit {} is ready", circuit_id);
+            self.bootstrap.report(BootstrapPhase::Done);
+            built = Some(circuit.relay_nicknames());
+        }
+        pending.complete();
+        if let Some(relays) = built {
+            self.emit(CircuitEvent::Built { circuit_id, relays });
+        }
+
+        for hook in self.ready_hooks.0.read().unwrap().iter() {
+            hook(circuit_id);
         }
         
         Ok(circuit_id)
     }
+
+    /// Tear a circuit down: send DESTROY to its relays, stop its tasks and close
+    /// connections no other circuit uses. Returns false if there was no such circuit.
+    pub async fn close_circuit(&self, circuit_id: CircuitId) -> bool {
+        let Some(mut circuit) = self.circuits.write().await.remove(&circuit_id) else {
+            return false;
+        };
+        if matches!(circuit.state, CircuitState::Ready) {
+            self.metrics.active_circuits.fetch_sub(1, Ordering::Relaxed);
+        }
+        circuit.state = CircuitState::Closed;
+        circuit.cancel.cancel();
+        if let Some(relay) = circuit.relay.take() {
+            relay.shutdown().await;
+        }
+        log::info!("Closing circuit {}", circuit_id);
+        self.emit(CircuitEvent::Closed { circuit_id, relays: circuit.relay_nicknames() });
+
+        for channel in circuit.hops.iter().filter_map(|hop| hop.channel.as_ref()) {
+            release_channel(circuit_id, channel).await;
+        }
+        self.isolated.lock().await.retain(|_, id| *id != circuit_id);
+        let mut pool = self.pool.lock().await;
+        if let Some(position) = pool.iter().position(|id| *id == circuit_id) {
+            pool.remove(position);
+            self.pool_changed.notify_one();
+        }
+        true
+    }
+
+    /// Start afresh, as Tor's NEWNYM does: every current circuit is retired,
+    /// so no new stream goes over it and it closes once its streams end,
+    /// circuits kept for isolation keys are forgotten, and the pool is
+    /// emptied for the pool builder to refill. Returns how many circuits were
+    /// retired.
+    pub async fn new_identity(&self) -> usize {
+        let (retired, idle) = {
+            let mut circuits = self.circuits.write().await;
+            for circuit in circuits.values_mut() {
+                circuit.retired = true;
+            }
+            let idle: Vec<CircuitId> = circuits.values().filter(|c| c.is_idle()).map(|c| c.id).collect();
+            (circuits.len(), idle)
+        };
+        self.isolated.lock().await.clear();
+        self.pool.lock().await.clear();
+        self.pool_changed.notify_one();
+
+        for circuit_id in &idle {
+            self.close_circuit(*circuit_id).await;
+        }
+        log::info!("New identity: retired {} circuit(s), {} closed right away", retired, idle.len());
+        retired
+    }
+
+    /// Cut a Ready circuit back to its first `num_hops` hops. The new last
+    /// hop is sent a RELAY_TRUNCATE and destroys the rest, streams on the old
+    /// path end along with any cells still queued for them, and the keys of
+    /// the hops beyond are dropped, so relay cells from then on are keyed for
+    /// the new last hop.
+    pub async fn truncate_circuit(&self, circuit_id: CircuitId, num_hops: usize) -> Result<(), CircuitError> {
+        let relay = match self.circuits.read().await.get(&circuit_id) {
+            Some(Circuit { state: CircuitState::Ready, relay: Some(relay), hops, .. }) => {
+                if num_hops == 0 || num_hops >= hops.len() {
+                    return Err(CircuitError::InvalidHopCount(num_hops));
+                }
+                relay.clone()
+            }
+            _ => return Err(CircuitError::NotReady(circuit_id)),
+        };
+        relay.truncate(num_hops, self.handshake_timeout).await?;
+        if let Some(circuit) = self.circuits.write().await.get_mut(&circuit_id) {
+            circuit.hops.truncate(num_hops);
+        }
+        log::info!("Truncated circuit {} to {} hop(s)", circuit_id, num_hops);
+        Ok(())
+    }
+
+    /// Start relaying cells for `circuit` over `channel` to its guard, keyed
+    /// by `guard`. The circuit is extended through it, and each later hop's
+    /// layer added, so once Ready it carries every hop's, guard to exit.
+    fn start_relay_path(
+        &self,
+        circuit: &Circuit,
+        channel: Arc<Channel>,
+        guard: RelayCrypto,
+        inbound: mpsc::UnboundedReceiver<Cell>,
+    ) -> Arc<RelayPath> {
+        let cancel = circuit.cancel.child_token();
+        RelayPath::new(circuit.id, channel, vec![guard], inbound, &circuit.tasks, cancel)
+    }
+
+    /// Keep `size` clean circuits of `num_hops` built in the background, so
+    /// `get_or_create_circuit` can hand one out without waiting for a build.
+    /// Runs until the returned task is aborted.
+    pub fn spawn_circuit_pool(
+        self: &Arc<Self>,
+        directory: Arc<DirectoryClient>,
+        size: usize,
+        num_hops: usize,
+    ) -> tokio::task::JoinHandle<()> {
+        let manager = self.clone();
+        tokio::spawn(async move {
+            loop {
+                if manager.pool.lock().await.len() >= size {
+                    // A permit is stored if the pool changed since we looked
+                    manager.pool_changed.notified().await;
+                    continue;
+                }
+                match manager.create_circuit(num_hops, false, &directory).await {
+                    // Retired while it was being built
+                    Ok(circuit_id) if manager.circuits.read().await.get(&circuit_id).is_none_or(|c| c.retired) => {
+                        manager.close_circuit(circuit_id).await;
+                    }
+                    Ok(circuit_id) => {
+                        log::debug!("Circuit {} added to the pool", circuit_id);
+                        manager.pool.lock().await.push_back(circuit_id);
+                    }
+                    Err(e) => {
+                        log::warn!("Failed to build a pooled circuit: {:?}", e);
+                        tokio::time::sleep(POOL_RETRY_DELAY).await;
+                    }
+                }
+            }
+        })
+    }
+
+    /// Number of clean circuits waiting in the pool
+    pub async fn pooled_circuits(&self) -> usize {
+        self.pool.lock().await.len()
+    }
+
+    /// Take the oldest pooled circuit of `num_hops` that is still Ready and
+    /// whose exit allows `target`
+    async fn take_pooled_circuit(&self, num_hops: usize, target: Option<ExitTarget>) -> Option<CircuitId> {
+        let mut pool = self.pool.lock().await;
+        let circuits = self.circuits.read().await;
+        // Circuits that closed while pooled are dropped from the pool on the way
+        pool.retain(|id| matches!(circuits.get(id), Some(Circuit { state: CircuitState::Ready, .. })));
+        let position = pool
+            .iter()
+            .position(|id| circuits[id].hops.len() == num_hops && circuits[id].allows_target(target))?;
+        self.pool_changed.notify_one();
+        pool.remove(position)
+    }
+
+    /// Close circuits built more than `max_dirtiness` ago, retired circuits
+    /// whose streams have ended, circuits still Building after
+    /// `max_build_time` and circuits whose build failed. Returns how many
+    /// were closed.
+    pub async fn reap_expired(
+        &self,
+        max_dirtiness: std::time::Duration,
+        max_build_time: std::time::Duration,
+    ) -> usize {
+        let expired: Vec<CircuitId> = self
+            .circuits
+            .read()
+            .await
+            .values()
+            .filter(|circuit| {
+                let age = circuit.created_at.elapsed();
+                match circuit.state {
+                    CircuitState::Building => age > max_build_time,
+                    CircuitState::Error(..) => true,
+                    _ => age > max_dirtiness || (circuit.retired && circuit.is_idle()),
+                }
+            })
+            .map(|circuit| circuit.id)
+            .collect();
+
+        let closed = self.close_circuits(expired).await;
+        if closed > 0 {
+            log::info!("Reaped {} expired circuit(s)", closed);
+        }
+        closed
+    }
+
+    /// Close every circuit, whatever its state, built more than `max_age`
+    /// ago. Returns how many were closed.
+    pub async fn close_circuits_older_than(&self, max_age: std::time::Duration) -> usize {
+        let old: Vec<CircuitId> = self
+            .circuits
+            .read()
+            .await
+            .values()
+            .filter(|circuit| circuit.created_at.elapsed() > max_age)
+            .map(|circuit| circuit.id)
+            .collect();
+
+        let closed = self.close_circuits(old).await;
+        if closed > 0 {
+            log::info!("Closed {} circuit(s) older than {:?}", closed, max_age);
+        }
+        closed
+    }
+
+    /// Close every circuit, sending DESTROY to its relays. Returns how many
+    /// were closed.
+    pub async fn close_all_circuits(&self) -> usize {
+        let all: Vec<CircuitId> = self.circuits.read().await.keys().copied().collect();
+        self.close_circuits(all).await
+    }
+
+    /// `close_circuit` each of `circuit_ids`, counting those still open
+    async fn close_circuits(&self, circuit_ids: Vec<CircuitId>) -> usize {
+        let mut closed = 0;
+        for circuit_id in circuit_ids {
+            if self.close_circuit(circuit_id).await {
+                closed += 1;
+            }
+        }
+        closed
+    }
+
+    /// Run `reap_expired` every `interval` in the background
+    pub fn spawn_reaper(
+        self: &Arc<Self>,
+        interval: std::time::Duration,
+        max_dirtiness: std::time::Duration,
+        max_build_time: std::time::Duration,
+    ) -> tokio::task::JoinHandle<()> {
+        let manager = Arc::downgrade(self);
+        let cancel = self.cancel.clone();
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+            loop {
+                tokio::select! {
+                    _ = cancel.cancelled() => break,
+                    _ = ticks.tick() => {}
+                }
+                // Don't keep the manager alive just to reap it
+                let Some(manager) = manager.upgrade() else { break };
+                manager.reap_expired(max_dirtiness, max_build_time).await;
+            }
+        })
+    }
+
+    /// Tracker of the tasks spawned for a circuit (its relay cell demultiplexer
+    /// and keepalive); `wait()` on it completes once they have all stopped
+    pub async fn circuit_tasks(&self, circuit_id: CircuitId) -> Option<TaskTracker> {
+        self.circuits.read().await.get(&circuit_id).map(|circuit| circuit.tasks.clone())
+    }
+
+    /// Resolve `hostname` at the circuit's exit (RELAY_RESOLVE), so the lookup
+    /// never touches the local resolver
+    pub async fn resolve(&self, circuit_id: CircuitId, hostname: &str) -> Result<Vec<IpAddr>, CircuitError> {
+        // RESOLVE borrows a stream ID for the reply but doesn't open a stream;
+        // like one, it isn't sent on a retired circuit and adds to the load
+        let mut stream = self.open_stream(circuit_id).await?;
+        let mut request = hostname.as_bytes().to_vec();
+        request.push(0);
+        stream.send(RELAY_COMMAND_RESOLVE, request).await?;
+        let reply = tokio::time::timeout(RESOLVE_TIMEOUT, stream.recv())
+            .await
+            .map_err(|_| CircuitError::ResolveFailed(format!("{}: timeout", hostname)))?
+            .ok_or_else(|| CircuitError::ResolveFailed(format!("{}: circuit closed", hostname)))?;
+        drop(stream);
+
+        match reply.command {
+            RELAY_COMMAND_RESOLVED => {}
+            RELAY_COMMAND_END => {
+                return Err(CircuitError::ResolveFailed(format!(
+                    "{}: exit ended the request (reason {})",
+                    hostname,
+                    reply.data.first().copied().unwrap_or(0)
+                )));
+            }
+            other => return Err(CellError::UnexpectedCommand(other).into()),
+        }
+
+        let addresses: Vec<IpAddr> = parse_resolved(&reply.data)?
+            .into_iter()
+            .filter_map(|(address, _ttl)| match address {
+                ResolvedAddress::Ip(ip) => Some(ip),
+                _ => None,
+            })
+            .collect();
+        if addresses.is_empty() {
+            return Err(CircuitError::ResolveFailed(hostname.to_string()));
+        }
+        log::debug!("Resolved {} over circuit {}: {:?}", hostname, circuit_id, addresses);
+        Ok(addresses)
+    }
+
+    /// Send `payload` as RELAY_DATA on a new stream and collect the DATA that
+    /// comes back until as many bytes have returned. The cells reach the last
+    /// hop through every hop before it, each peeling its layer on the way out
+    /// and adding it on the way back. Against an exit that echoes DATA (such
+    /// as `MockRelay`) this exercises onion layering, cell framing, flow
+    /// control and stream handling end to end.
+    pub async fn echo_test(&self, circuit_id: CircuitId, payload: &[u8]) -> Result<Vec<u8>, CircuitError> {
+        let mut stream = self.open_stream(circuit_id).await?;
+        for chunk in payload.chunks(RELAY_PAYLOAD_LEN) {
+            stream.send(RELAY_COMMAND_DATA, chunk.to_vec()).await?;
+        }
+
+        let mut echoed = Vec::with_capacity(payload.len());
+        while echoed.len() < payload.len() {
+            let cell = tokio::time::timeout(ECHO_TIMEOUT, stream.recv())
+                .await
+                .map_err(|_| CircuitError::Io(format!("echo timed out after {} bytes", echoed.len())))?
+                .ok_or(CircuitError::NotReady(circuit_id))?;
+            match cell.command {
+                RELAY_COMMAND_DATA => echoed.extend_from_slice(&cell.data),
+                other => log::debug!("Ignoring relay command {} during echo test", other),
+            }
+        }
+        Ok(echoed)
+    }
+
+    /// Open a stream to `host:port` from the circuit's exit (RELAY_BEGIN) and
+    /// wait for it to connect. The exit resolves `host` itself.
+    pub async fn begin_stream(&self, circuit_id: CircuitId, host: &str, port: u16) -> Result<CircuitStream, CircuitError> {
+        let mut stream = self.open_stream(circuit_id).await?;
+        // An IPv6 address goes in brackets, as in the ADDRPORT of tor-spec 6.2
+        let mut request = if host.contains(':') {
+            format!("[{}]:{}", host, port).into_bytes()
+        } else {
+            format!("{}:{}", host, port).into_bytes()
+        };
+        request.push(0);
+        stream.send(RELAY_COMMAND_BEGIN, request).await?;
+
+        let reply = tokio::time::timeout(BEGIN_TIMEOUT, stream.recv())
+            .await
+            .map_err(|_| CircuitError::Io(format!("{}:{}: timed out connecting", host, port)))?
+            .ok_or(CircuitError::NotReady(circuit_id))?;
+        match reply.command {
+            RELAY_COMMAND_CONNECTED => {
+                log::debug!("Stream {} on circuit {} connected to {}:{}", stream.id(), circuit_id, host, port);
+                Ok(stream)
+            }
+            RELAY_COMMAND_END => Err(CircuitError::StreamEnded(reply.data.first().copied().unwrap_or(0))),
+            other => Err(CellError::UnexpectedCommand(other).into()),
+        }
+    }
+
+    /// Allocate a stream on a Ready circuit that hasn't been retired; it
+    /// counts towards the circuit's load until dropped
+    pub async fn open_stream(&self, circuit_id: CircuitId) -> Result<CircuitStream, CircuitError> {
+        match self.circuits.read().await.get(&circuit_id) {
+            Some(circuit @ Circuit { state: CircuitState::Ready, relay: Some(relay), retired: false, .. }) => {
+                let stream = relay.open_stream()?;
+                circuit.requests.fetch_add(1, Ordering::Relaxed);
+                Ok(stream)
+            }
+            _ => Err(CircuitError::NotReady(circuit_id)),
+        }
+    }
+
+    /// How long ago the circuit's build started
+    pub async fn circuit_age(&self, circuit_id: CircuitId) -> Option<std::time::Duration> {
+        self.circuits.read().await.get(&circuit_id).map(|circuit| circuit.created_at.elapsed())
+    }
+
+    /// The circuit's state and, for each hop, its relay and how far the
+    /// build has got with it
+    pub async fn get_circuit_info(&self, circuit_id: CircuitId) -> Option<CircuitInfo> {
+        self.circuits.read().await.get(&circuit_id).map(Circuit::info)
+    }
+
+    /// `get_circuit_info` for every circuit, building or built, by id
+    pub async fn circuit_infos(&self) -> Vec<CircuitInfo> {
+        let mut infos: Vec<CircuitInfo> = self.circuits.read().await.values().map(Circuit::info).collect();
+        infos.sort_by_key(|info| info.id);
+        infos
+    }
+
+    /// How many streams have been opened on the circuit
+    pub async fn circuit_request_count(&self, circuit_id: CircuitId) -> Option<usize> {
+        self.circuits.read().await.get(&circuit_id).map(|circuit| circuit.requests.load(Ordering::Relaxed))
+    }
+
+    /// Current load on a Ready circuit
+    pub async fn circuit_load(&self, circuit_id: CircuitId) -> Option<CircuitLoad> {
+        let relay = self.relay_path(circuit_id).await.ok()?;
+        Some(CircuitLoad { active_streams: relay.active_streams(), bytes_per_sec: relay.bytes_per_sec() })
+    }
+
+    /// The Ready circuit with the fewest active streams, ties broken by the
+    /// lowest recent byte rate
+    pub async fn least_loaded_ready_circuit(&self) -> Option<CircuitId> {
+        self.circuits
+            .read()
+            .await
+            .values()
+            .filter(|circuit| matches!(circuit.state, CircuitState::Ready) && !circuit.retired)
+            .filter_map(|circuit| {
+                let relay = circuit.relay.as_ref()?;
+                let load = CircuitLoad { active_streams: relay.active_streams(), bytes_per_sec: relay.bytes_per_sec() };
+                Some((load, circuit.id))
+            })
+            .min()
+            .map(|(_, circuit_id)| circuit_id)
+    }
+
+    /// The relay path of a Ready circuit. A building circuit's path only
+    /// carries the cells that extend it, until every hop is keyed.
+    async fn relay_path(&self, circuit_id: CircuitId) -> Result<Arc<RelayPath>, CircuitError> {
+        match self.circuits.read().await.get(&circuit_id) {
+            Some(Circuit { state: CircuitState::Ready, relay: Some(relay), .. }) => Ok(relay.clone()),
+            _ => Err(CircuitError::NotReady(circuit_id)),
+        }
+    }
     
-    async fn perform_handshakes(&self, _circuit_id: CircuitId) -> Result<(), CircuitError> {
-        // Mock implementation - in real Tor, this would:
-        // 1. Connect to first relay with CREATE cell
-        // 2. Perform DH handshake
-        // 3. Send EXTEND cells through circuit for each additional hop
-        // 4. Each hop performs DH handshake
-        
-        log::info!("Performing handshakes (mock implementation)");
-        
-        // Simulate some network delay
-        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-        
+    /// Reuse the Ready circuit built for `isolation_key`, or build a new one.
+    /// Only a circuit of `num_hops` hops whose exit allows `target` (if any)
+    /// is reused.
+    /// Concurrent calls with a new key may each build a circuit; the last one
+    /// to finish is reused afterwards.
+    pub async fn get_or_create_circuit(
+        &self,
+        isolation_key: &IsolationKey,
+        target: Option<ExitTarget>,
+        num_hops: usize,
+        directory: &DirectoryClient,
+    ) -> Result<CircuitId, CircuitError> {
+        let existing = self.isolated.lock().await.get(isolation_key).copied();
+        if let Some(circuit_id) = existing {
+            let ready = matches!(
+                self.circuits.read().await.get(&circuit_id),
+                Some(circuit @ Circuit { state: CircuitState::Ready, .. })
+                    if circuit.allows_target(target) && circuit.hops.len() == num_hops
+            );
+            if ready {
+                log::debug!("Reusing circuit {} for {:?}", circuit_id, isolation_key);
+                return Ok(circuit_id);
+            }
+        }
+
+        let circuit_id = self.circuit_for_target(num_hops, target, directory).await?;
+        self.isolated.lock().await.insert(isolation_key.clone(), circuit_id);
+        Ok(circuit_id)
+    }
+
+    /// Take a pooled circuit that can reach `target`, or build one
+    pub async fn circuit_for_target(
+        &self,
+        num_hops: usize,
+        target: Option<ExitTarget>,
+        directory: &DirectoryClient,
+    ) -> Result<CircuitId, CircuitError> {
+        match self.take_pooled_circuit(num_hops, target).await {
+            Some(circuit_id) => {
+                log::debug!("Using pooled circuit {}", circuit_id);
+                Ok(circuit_id)
+            }
+            None => self.create_circuit_for_target(num_hops, target, directory).await,
+        }
+    }
+
+    /// Handshake with each hop in turn: CREATE2 to the guard over its
+    /// connection, then an EXTEND2 through the circuit for each later hop,
+    /// sent as RELAY_EARLY to the hop before it. A hop that can't be reached
+    /// or fails its handshake is reported to the directory so it isn't picked
+    /// again soon, and replaced by another relay for its position, up to
+    /// `hop_retries` times, before the build fails.
+    async fn perform_handshakes(
+        &self,
+        pending: &mut PendingCircuit,
+        directory: &DirectoryClient,
+        path: &mut [RelayDescriptor],
+        target: Option<ExitTarget>,
+        internal: bool,
+    ) -> Result<(), (CircuitFailureKind, CircuitError)> {
+        let circuit_id = pending.circuit_id;
+        let mut hops = match self.circuits.read().await.get(&circuit_id) {
+            Some(circuit) => circuit.hops.clone(),
+            None => {
+                let e = CircuitError::HandshakeFailed(format!("Unknown circuit {}", circuit_id));
+                return Err((CircuitFailureKind::Other, e));
+            }
+        };
+        let prefer_ipv6 = !has_ipv4_route();
+
+        // Reusing an ephemeral key would let hops link their handshakes
+        #[cfg(debug_assertions)]
+        let mut client_keys = std::collections::HashSet::new();
+
+        // Set up by the guard's handshake; every later hop is reached through it
+        let mut relay: Option<Arc<RelayPath>> = None;
+        for hop_num in 0..hops.len() {
+            let mut attempts = 0;
+            let (crypto, _client_public, guard_link) = loop {
+                attempts += 1;
+                let attempt = match &relay {
+                    None => self.handshake_hop(pending, directory, hop_num, &hops[hop_num]).await,
+                    Some(relay) => self.extend_hop(relay, directory, circuit_id, hop_num, &hops[hop_num]).await,
+                };
+                let (kind, e) = match attempt {
+                    Ok(established) => break established,
+                    Err(failure) => failure,
+                };
+                let replacement = if attempts <= self.hop_retries {
+                    directory.select_replacement(path, hop_num, target, internal).await.ok()
+                } else {
+                    None
+                };
+                let Some(replacement) = replacement else {
+                    if attempts == 1 {
+                        return Err((kind, e));
+                    }
+                    let e = CircuitError::HandshakeFailed(format!(
+                        "hop {} failed on {} relays, last with {:?}",
+                        hop_num, attempts, e
+                    ));
+                    return Err((kind, e));
+                };
+                log::warn!(
+                    "Hop {} of circuit {} failed ({}: {:?}), retrying with {}",
+                    hop_num, circuit_id, kind, e, replacement.nickname
+                );
+                // The last hop may have got partway to the failed relay; it
+                // only takes another EXTEND2 once that's undone
+                if let Some(relay) = &relay {
+                    relay
+                        .truncate(hop_num, self.handshake_timeout)
+                        .await
+                        .map_err(|e| (CircuitFailureKind::Handshake, e))?;
+                }
+                hops[hop_num] = relay_hop(&replacement, prefer_ipv6);
+                path[hop_num] = replacement;
+                if let Some(circuit) = self.circuits.write().await.get_mut(&circuit_id) {
+                    circuit.hops[hop_num] = hops[hop_num].clone();
+                }
+            };
+
+            #[cfg(debug_assertions)]
+            assert!(
+                client_keys.insert(*_client_public.as_bytes()),
+                "ntor client key reused for hop {} of circuit {}",
+                hop_num,
+                circuit_id
+            );
+            // Later hops' layers go on the path the guard's handshake set up
+            let guard = match &relay {
+                Some(relay) => {
+                    relay.push_layer(crypto).await;
+                    None
+                }
+                None => Some(crypto),
+            };
+            let mut circuits = self.circuits.write().await;
+            let Some(circuit) = circuits.get_mut(&circuit_id) else {
+                let e = CircuitError::HandshakeFailed(format!("Circuit {} vanished", circuit_id));
+                return Err((CircuitFailureKind::Other, e));
+            };
+            if let (Some(guard), Some((channel, inbound))) = (guard, guard_link) {
+                let guard_path = self.start_relay_path(circuit, channel.clone(), guard, inbound);
+                circuit.hops[hop_num].channel = Some(channel);
+                circuit.relay = Some(guard_path.clone());
+                relay = Some(guard_path);
+            }
+            circuit.hops[hop_num].state = HopState::KeysEstablished;
+            log::debug!("Keys established with hop {}", hop_num);
+            let relays = circuit.hops[..=hop_num].iter().map(|hop| hop.nickname.clone()).collect();
+            drop(circuits);
+            self.emit(CircuitEvent::Extended { circuit_id, relays });
+        }
+
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Connect to the guard `hop` and complete its CREATE2 handshake,
+    /// recording the outcome with the directory. A failed guard's connection
+    /// is released.
+    async fn handshake_hop(
+        &self,
+        pending: &mut PendingCircuit,
+        directory: &DirectoryClient,
+        hop_num: usize,
+        hop: &RelayHop,
+    ) -> Result<EstablishedHop, (CircuitFailureKind, CircuitError)> {
+        let circuit_id = pending.circuit_id;
+        log::info!("Performing handshake type {} with {} ({})", hop.handshake_type, hop.relay_id, hop.ip);
+        let (channel, mut inbound) = match self.attach_channel(circuit_id, hop).await {
+            Ok(attached) => attached,
+            Err(e) => {
+                directory.mark_relay_failed(&hop.relay_id).await;
+                self.set_hop_state(circuit_id, hop_num, HopState::Failed).await;
+                return Err((CircuitFailureKind::Connectivity, e));
+            }
+        };
+        self.set_hop_state(circuit_id, hop_num, HopState::Connected).await;
+        match Self::handshake_with_hop(circuit_id, hop, &channel, &mut inbound, self.handshake_timeout).await {
+            Ok((crypto, client_public)) => {
+                directory.mark_relay_succeeded(&hop.relay_id).await;
+                pending.channels.push(channel.clone());
+                Ok((crypto, client_public, Some((channel, inbound))))
+            }
+            Err(e) => {
+                directory.mark_relay_failed(&hop.relay_id).await;
+                self.set_hop_state(circuit_id, hop_num, HopState::Failed).await;
+                channel.unregister(circuit_id);
+                if channel.circuit_count() == 0 {
+                    channel.close();
+                }
+                // Only the ntor check itself fails with a crypto error
+                let kind = match e {
+                    CircuitError::Crypto(_) => CircuitFailureKind::HandshakeAuth,
+                    _ => CircuitFailureKind::Handshake,
+                };
+                Err((kind, e))
+            }
+        }
+    }
+
+    /// Extend the circuit through `relay` to `hop`, recording the outcome
+    /// with the directory
+    async fn extend_hop(
+        &self,
+        relay: &RelayPath,
+        directory: &DirectoryClient,
+        circuit_id: CircuitId,
+        hop_num: usize,
+        hop: &RelayHop,
+    ) -> Result<EstablishedHop, (CircuitFailureKind, CircuitError)> {
+        log::info!(
+            "Extending circuit {} to {} ({}) with handshake type {}",
+            circuit_id, hop.relay_id, hop.ip, hop.handshake_type
+        );
+        match Self::extend_handshake(relay, hop, self.handshake_timeout).await {
+            Ok((crypto, client_public)) => {
+                directory.mark_relay_succeeded(&hop.relay_id).await;
+                Ok((crypto, client_public, None))
+            }
+            Err(e) => {
+                directory.mark_relay_failed(&hop.relay_id).await;
+                self.set_hop_state(circuit_id, hop_num, HopState::Failed).await;
+                let kind = match e {
+                    CircuitError::Crypto(_) => CircuitFailureKind::HandshakeAuth,
+                    // The last hop couldn't reach the relay
+                    CircuitError::Truncated(_) => CircuitFailureKind::Connectivity,
+                    _ => CircuitFailureKind::Handshake,
+                };
+                Err((kind, e))
+            }
+        }
+    }
+
+    async fn set_hop_state(&self, circuit_id: CircuitId, hop_num: usize, state: HopState) {
+        if let Some(hop) = self.circuits.write().await.get_mut(&circuit_id).and_then(|c| c.hops.get_mut(hop_num)) {
+            hop.state = state;
+        }
+    }
+
+    /// Find (or open) a connection to the guard and register the circuit on it.
+    /// Guard connections are shared by at most `max_circuits_per_guard` circuits.
+    /// The connection map is only locked briefly, never while connecting.
+    async fn attach_channel(
+        &self,
+        circuit_id: CircuitId,
+        hop: &RelayHop,
+    ) -> Result<(Arc<Channel>, mpsc::UnboundedReceiver<Cell>), CircuitError> {
+        let connecting = match self.attach_open_channel(circuit_id, hop).await {
+            Ok(attached) => return Ok(attached),
+            Err(connecting) => connecting,
+        };
+        // Another build may have opened a connection with room while we waited
+        let _connecting = connecting.lock().await;
+        if let Ok(attached) = self.attach_open_channel(circuit_id, hop).await {
+            return Ok(attached);
+        }
+
+        let channel = Channel::connect(
+            &hop.relay_id,
+            &hop.link_identity(),
+            hop.ip,
+            self.tls_backend,
+            &self.client_hello,
+            CONNECT_TIMEOUT,
+            self.handshake_timeout,
+        )
+        .await?;
+        let mut channels = self.channels.lock().await;
+        channels.entry(hop.relay_id.clone()).or_default().open.push(channel.clone());
+        let inbound = channel.register(circuit_id);
+        Ok((channel, inbound))
+    }
+
+    /// Register the circuit on an open connection to the guard that is under
+    /// the cap, or hand back the lock to hold while opening a new one
+    async fn attach_open_channel(
+        &self,
+        circuit_id: CircuitId,
+        hop: &RelayHop,
+    ) -> Result<(Arc<Channel>, mpsc::UnboundedReceiver<Cell>), Arc<Mutex<()>>> {
+        let cap = self.max_circuits_per_guard;
+        let mut channels = self.channels.lock().await;
+        let relay_channels = channels.entry(hop.relay_id.clone()).or_default();
+        relay_channels.open.retain(|c| !c.is_closed());
+
+        let existing = relay_channels.open.iter().find(|c| cap.is_none_or(|max| c.circuit_count() < max));
+        match existing {
+            Some(channel) => {
+                let inbound = channel.register(circuit_id);
+                Ok((channel.clone(), inbound))
+            }
+            None => {
+                if !relay_channels.open.is_empty() {
+                    log::info!(
+                        "All {} connection(s) to {} are at the circuit cap, opening another",
+                        relay_channels.open.len(), hop.relay_id
+                    );
+                }
+                Err(relay_channels.connecting.clone())
+            }
+        }
+    }
+
+    /// Send a CREATE2 to the relay and authenticate its CREATED2 reply
+    async fn handshake_with_hop(
+        circuit_id: CircuitId,
+        hop: &RelayHop,
+        channel: &Channel,
+        inbound: &mut mpsc::UnboundedReceiver<Cell>,
+        timeout: std::time::Duration,
+    ) -> Result<(RelayCrypto, PublicKey), CircuitError> {
+        let (client_secret, create2) = client_handshake(hop)?;
+        let cell = Cell::new(circuit_id, CellCommand::Create2, create2.to_bytes()?);
+        channel.send_cell(&cell).await?;
+
+        let response = tokio::time::timeout(timeout, inbound.recv())
+            .await
+            .map_err(|_| {
+                log::warn!("No CREATED2 from {} within {:?}", hop.ip, timeout);
+                CircuitError::HandshakeFailed("timeout".to_string())
+            })?
+            .ok_or_else(|| {
+                CircuitError::HandshakeFailed(format!("Connection to {} closed during handshake", hop.ip))
+            })?;
+
+        match response.command {
+            CellCommand::Created2 => {}
+            CellCommand::Destroy => {
+                return Err(CircuitError::HandshakeFailed(format!(
+                    "Relay {} destroyed the circuit (reason {})",
+                    hop.relay_id,
+                    response.payload.first().copied().unwrap_or(0)
+                )));
+            }
+            other => return Err(CellError::UnexpectedCommand(other.as_u8()).into()),
+        }
+
+        let created2 = Created2Cell::from_bytes(hop.handshake_type, &response.payload)?;
+        let crypto = finish_handshake(hop, &client_secret, &created2)?;
+        Ok((crypto, PublicKey::from(&client_secret)))
+    }
+
+    /// Send an EXTEND2 for `hop` through `relay` to the circuit's last hop and
+    /// authenticate the CREATED2 its EXTENDED2 carries back
+    async fn extend_handshake(
+        relay: &RelayPath,
+        hop: &RelayHop,
+        timeout: std::time::Duration,
+    ) -> Result<(RelayCrypto, PublicKey), CircuitError> {
+        let (client_secret, create2) = client_handshake(hop)?;
+        let extend2 = Extend2Cell::new(link_specifiers(hop), create2);
+        let request = RelayCell::new(RELAY_COMMAND_EXTEND2, 0, extend2.to_bytes()?);
+        let reply = relay.control_request(request, timeout).await.inspect_err(|_| {
+            log::warn!("No EXTENDED2 from {} within {:?}", hop.ip, timeout);
+        })?;
+
+        match reply.command {
+            RELAY_COMMAND_EXTENDED2 => {}
+            RELAY_COMMAND_TRUNCATED => {
+                let reason = reply.data.first().copied().unwrap_or(0);
+                log::warn!("Couldn't extend to {}: the last hop sent TRUNCATED (reason {})", hop.relay_id, reason);
+                return Err(CircuitError::Truncated(reason));
+            }
+            other => return Err(CellError::UnexpectedCommand(other).into()),
+        }
+
+        let created2 = Extended2Cell::from_bytes(&reply.data)?.created2(hop.handshake_type)?;
+        let crypto = finish_handshake(hop, &client_secret, &created2)?;
+        Ok((crypto, PublicKey::from(&client_secret)))
+    }
+}
+
+/// A fresh ntor key pair for `hop`, and the CREATE2 carrying its public half
+fn client_handshake(hop: &RelayHop) -> Result<(StaticSecret, Create2Cell), CircuitError> {
+    let client_secret = StaticSecret::random_from_rng(rand::rngs::OsRng);
+    match hop.handshake_type {
+        HANDSHAKE_TYPE_NTOR => {
+            let create2 = Create2Cell::new_ntor(&hop.identity_key, &hop.onion_key, &PublicKey::from(&client_secret));
+            Ok((client_secret, create2))
+        }
+        other => Err(CircuitError::HandshakeFailed(format!("Unsupported handshake type {}", other))),
+    }
+}
+
+/// Authenticate `hop`'s CREATED2 and derive its relay crypto
+fn finish_handshake(
+    hop: &RelayHop,
+    client_secret: &StaticSecret,
+    created2: &Created2Cell,
+) -> Result<RelayCrypto, CircuitError> {
+    let keys = ntor_handshake(
+        client_secret,
+        &created2.server_public,
+        &created2.auth,
+        &hop.identity_key,
+        &hop.onion_key,
+    )?;
+    Ok(RelayCrypto::from_ntor_keys(&keys))
+}
+
+/// Where the last hop must connect to reach `hop`, and the identities the
+/// relay there must prove
+fn link_specifiers(hop: &RelayHop) -> Vec<LinkSpecifier> {
+    let address = match hop.ip {
+        std::net::SocketAddr::V4(address) => LinkSpecifier::Ipv4(address),
+        std::net::SocketAddr::V6(address) => LinkSpecifier::Ipv6(address),
+    };
+    let identity = hop.link_identity();
+    let mut specifiers = vec![address, LinkSpecifier::RsaId(identity.rsa)];
+    specifiers.extend(identity.ed25519.map(LinkSpecifier::Ed25519Id));
+    specifiers
+}
+
+/// Tell the relay on `channel` the circuit is gone, and close the connection
+/// once no circuit uses it
+async fn release_channel(circuit_id: CircuitId, channel: &Channel) {
+    // Reason 0 (NONE): clients don't say why they close circuits
+    let destroy = Cell::new(circuit_id, CellCommand::Destroy, vec![0]);
+    if let Err(e) = channel.send_cell(&destroy).await {
+        log::debug!("Couldn't send DESTROY for circuit {} to {}: {}", circuit_id, channel.peer(), e);
+    }
+    channel.unregister(circuit_id);
+    if channel.circuit_count() == 0 {
+        channel.close();
+    }
+}
+
+/// A hop's relay crypto, the client key its handshake used and, for the
+/// guard, its connection and queue of cells for the circuit
+type EstablishedHop = (RelayCrypto, PublicKey, Option<(Arc<Channel>, mpsc::UnboundedReceiver<Cell>)>);
+
+/// The not yet handshaken hop for `relay`
+fn relay_hop(relay: &RelayDescriptor, prefer_ipv6: bool) -> RelayHop {
+    RelayHop {
+        relay_id: relay.id.clone(),
+        nickname: relay.nickname.clone(),
+        ip: relay.or_address(prefer_ipv6),
+        identity_key: relay.identity_key.clone(),
+        onion_key: relay.onion_key.clone(),
+        ed25519_identity: relay.ed25519_identity,
+        handshake_type: choose_handshake_type(relay),
+        bandwidth: relay.bandwidth,
+        exit_policy: relay.exit_policy.clone(),
+        exit_policy_v6: relay.exit_policy_v6.clone(),
+        channel: None,
+        state: HopState::Pending,
+    }
+}
@@ -0,0 +1,509 @@
+// src/circuit/relay.rs
+//! Relay cells on a circuit: onion-encrypting them towards the last hop, a
+//! task that decrypts everything coming back and hands it to its stream,
+//! extending and truncating the circuit, a keepalive for idle circuits,
+//! SENDME flow control, and the load figures used to pick between circuits.
+use super::sendme::{
+    encode_sendme_v1, DeliverWindow, PackageWindow, SendmeDigests, CIRCUIT_WINDOW_INCREMENT, CIRCUIT_WINDOW_START,
+    STREAM_WINDOW_INCREMENT, STREAM_WINDOW_START,
+};
+use super::{CircuitError, CircuitId};
+use crate::crypto::RelayCrypto;
+use crate::network::cells::{
+    Cell, CellCommand, CellError, RelayCell, CELL_LEN, CELL_PAYLOAD_LEN, DESTROY_REASON_PROTOCOL, END_REASON_DONE,
+    RELAY_COMMAND_DATA, RELAY_PAYLOAD_LEN,
+    RELAY_COMMAND_DROP, RELAY_COMMAND_END, RELAY_COMMAND_EXTEND, RELAY_COMMAND_EXTEND2, RELAY_COMMAND_EXTENDED2,
+    RELAY_COMMAND_SENDME, RELAY_COMMAND_TRUNCATE, RELAY_COMMAND_TRUNCATED,
+};
+use crate::network::Channel;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU16, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+/// What the demultiplexer needs to reach an open stream
+#[derive(Debug)]
+struct StreamEntry {
+    queue: mpsc::UnboundedSender<RelayCell>,
+    /// Shared with the stream, which waits on it to send DATA
+    package: Arc<PackageWindow>,
+}
+
+type StreamQueues = Arc<Mutex<HashMap<u16, StreamEntry>>>;
+
+/// RELAY_EARLY cells a circuit may send before relays tear it down (tor-spec 5.6)
+pub const MAX_RELAY_EARLY_CELLS: u8 = 8;
+
+/// Byte rates are measured over the current window plus the one before it
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Bytes moved in the last one or two `RATE_WINDOW`s
+#[derive(Debug)]
+struct ByteRate {
+    window_start: Instant,
+    current: u64,
+    previous: u64,
+}
+
+impl ByteRate {
+    fn new() -> Self {
+        Self { window_start: Instant::now(), current: 0, previous: 0 }
+    }
+
+    fn roll(&mut self) {
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= RATE_WINDOW {
+            // Traffic from more than a window ago no longer counts as recent
+            self.previous = if elapsed < 2 * RATE_WINDOW { self.current } else { 0 };
+            self.current = 0;
+            self.window_start = Instant::now();
+        }
+    }
+
+    fn record(&mut self, bytes: u64) {
+        self.roll();
+        self.current += bytes;
+    }
+
+    fn bytes_per_sec(&mut self) -> u64 {
+        self.roll();
+        let span = RATE_WINDOW + self.window_start.elapsed();
+        ((self.previous + self.current) as f64 / span.as_secs_f64()) as u64
+    }
+}
+
+/// The client's end of a circuit, from the guard's handshake on
+pub(crate) struct RelayPath {
+    circuit_id: CircuitId,
+    channel: Arc<Channel>,
+    /// Crypto state of each hop, nearest first. Held across the send so
+    /// cells reach the wire in the order their keystream was used.
+    layers: Arc<tokio::sync::Mutex<Vec<RelayCrypto>>>,
+    streams: StreamQueues,
+    next_stream_id: AtomicU16,
+    /// Cells sent and received on the circuit
+    traffic: Arc<Mutex<ByteRate>>,
+    /// DATA cells we may still send on the circuit as a whole
+    package: PackageWindow,
+    /// What the exit's circuit SENDMEs must prove it received
+    sendme_digests: Mutex<SendmeDigests>,
+    /// RELAY_EARLY cells left before the circuit may not extend any more
+    relay_early_left: AtomicU8,
+    /// EXTENDED2 and TRUNCATED cells, for whoever is extending or truncating
+    control: mpsc::UnboundedSender<RelayCell>,
+    control_replies: tokio::sync::Mutex<mpsc::UnboundedReceiver<RelayCell>>,
+    /// Fires when the circuit is closed; nothing more may be sent after that
+    cancel: CancellationToken,
+}
+
+impl std::fmt::Debug for RelayPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RelayPath")
+            .field("circuit_id", &self.circuit_id)
+            .field("channel", &self.channel)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RelayPath {
+    /// Take over `inbound`, the channel's queue for this circuit, and start
+    /// demultiplexing the relay cells on it until `cancel` fires
+    pub(crate) fn new(
+        circuit_id: CircuitId,
+        channel: Arc<Channel>,
+        layers: Vec<RelayCrypto>,
+        inbound: mpsc::UnboundedReceiver<Cell>,
+        tasks: &TaskTracker,
+        cancel: CancellationToken,
+    ) -> Arc<Self> {
+        let (control, control_replies) = mpsc::unbounded_channel();
+        let relay = Arc::new(Self {
+            circuit_id,
+            channel,
+            layers: Arc::new(tokio::sync::Mutex::new(layers)),
+            streams: Arc::new(Mutex::new(HashMap::new())),
+            next_stream_id: AtomicU16::new(1),
+            traffic: Arc::new(Mutex::new(ByteRate::new())),
+            package: PackageWindow::new(CIRCUIT_WINDOW_START),
+            sendme_digests: Mutex::new(SendmeDigests::default()),
+            relay_early_left: AtomicU8::new(MAX_RELAY_EARLY_CELLS),
+            control,
+            control_replies: tokio::sync::Mutex::new(control_replies),
+            cancel,
+        });
+        tasks.spawn(relay.clone().demux(inbound));
+        relay
+    }
+
+    /// Allocate a stream ID and start receiving the relay cells addressed to
+    /// it; fails if every ID is taken by an open stream
+    pub(crate) fn open_stream(self: &Arc<Self>) -> Result<CircuitStream, CircuitError> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let package = Arc::new(PackageWindow::new(STREAM_WINDOW_START));
+        let mut streams = self.streams.lock().unwrap();
+        // One pass over every ID. Stream ID 0 is reserved for cells about the whole circuit
+        let stream_id = (0..=u16::MAX)
+            .map(|_| self.next_stream_id.fetch_add(1, Ordering::Relaxed))
+            .find(|id| *id != 0 && !streams.contains_key(id))
+            .ok_or(CircuitError::StreamIdsExhausted(self.circuit_id))?;
+        streams.insert(stream_id, StreamEntry { queue: tx, package: package.clone() });
+        Ok(CircuitStream {
+            sender: StreamSender { relay: self.clone(), stream_id, package },
+            replies: rx,
+            deliver: DeliverWindow::new(STREAM_WINDOW_START, STREAM_WINDOW_INCREMENT),
+        })
+    }
+
+    /// Stop sending on this path and end its streams, dropping cells still
+    /// queued for them
+    pub(crate) fn close(&self) {
+        self.cancel.cancel();
+        self.streams.lock().unwrap().clear();
+    }
+
+    /// `close` the path and wipe every hop's keys, which would otherwise
+    /// live on for as long as a stream holds on to the path
+    pub(crate) async fn shutdown(&self) {
+        self.close();
+        self.layers.lock().await.clear();
+    }
+
+    /// Send `request` (an EXTEND2 or TRUNCATE) to the last hop and wait up to
+    /// `timeout` for its EXTENDED2 or TRUNCATED. Replies to earlier requests
+    /// that came too late are discarded first.
+    pub(crate) async fn control_request(
+        &self,
+        request: RelayCell,
+        timeout: Duration,
+    ) -> Result<RelayCell, CircuitError> {
+        let mut replies = self.control_replies.lock().await;
+        while replies.try_recv().is_ok() {}
+        self.send(request).await?;
+        tokio::time::timeout(timeout, replies.recv())
+            .await
+            .map_err(|_| CircuitError::HandshakeFailed("timeout".to_string()))?
+            .ok_or(CircuitError::NotReady(self.circuit_id))
+    }
+
+    /// Add the layer of the hop the circuit was just extended to
+    pub(crate) async fn push_layer(&self, crypto: RelayCrypto) {
+        self.layers.lock().await.push(crypto);
+    }
+
+    /// Cut the circuit back to its first `num_hops` hops: their keys stay,
+    /// the others' are wiped, streams on the old path end along with any
+    /// cells still queued for them, and the new last hop is sent a
+    /// RELAY_TRUNCATE, whose TRUNCATED is awaited for up to `timeout`
+    pub(crate) async fn truncate(&self, num_hops: usize, timeout: Duration) -> Result<(), CircuitError> {
+        self.layers.lock().await.truncate(num_hops);
+        self.streams.lock().unwrap().clear();
+        let reply = self.control_request(RelayCell::new(RELAY_COMMAND_TRUNCATE, 0, Vec::new()), timeout).await?;
+        match reply.command {
+            RELAY_COMMAND_TRUNCATED => Ok(()),
+            other => Err(CellError::UnexpectedCommand(other).into()),
+        }
+    }
+
+    fn close_stream(&self, stream_id: u16) {
+        self.streams.lock().unwrap().remove(&stream_id);
+    }
+
+    pub(crate) fn active_streams(&self) -> usize {
+        self.streams.lock().unwrap().len()
+    }
+
+    /// Recent traffic on the circuit, both directions
+    pub(crate) fn bytes_per_sec(&self) -> u64 {
+        self.traffic.lock().unwrap().bytes_per_sec()
+    }
+
+    /// DATA cells that may still be sent on the circuit before the exit's next SENDME
+    pub(crate) fn package_window(&self) -> u16 {
+        self.package.remaining()
+    }
+
+    /// Encrypt `cell` for the last hop and send it down the circuit, unless
+    /// the circuit has been closed. Flow control is up to the caller.
+    /// EXTEND and EXTEND2 go out as RELAY_EARLY, which fails once the
+    /// circuit's budget of them is spent.
+    pub(crate) async fn send(&self, cell: RelayCell) -> Result<(), CircuitError> {
+        let mut layers = self.layers.lock().await;
+        if self.cancel.is_cancelled() {
+            return Err(CircuitError::NotReady(self.circuit_id));
+        }
+        let mut body = cell.to_bytes()?;
+        // Relays destroy circuits that extend in a plain RELAY cell or send
+        // too many RELAY_EARLY ones
+        let command = if matches!(cell.command, RELAY_COMMAND_EXTEND | RELAY_COMMAND_EXTEND2) {
+            self.relay_early_left
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| left.checked_sub(1))
+                .map_err(|_| CircuitError::RelayEarlyExhausted(self.circuit_id))?;
+            CellCommand::RelayEarly
+        } else {
+            CellCommand::Relay
+        };
+        let (last, earlier) = layers
+            .split_last_mut()
+            .ok_or_else(|| CircuitError::HandshakeFailed("Circuit has no hops".to_string()))?;
+        last.encrypt_relay_cell(&mut body);
+        if cell.command == RELAY_COMMAND_DATA {
+            self.sendme_digests.lock().unwrap().sent(|| last.forward_digest());
+        }
+        for hop in earlier.iter_mut().rev() {
+            hop.encrypt_forward_layer(&mut body);
+        }
+
+        let cell = Cell::new(self.circuit_id, command, body.to_vec());
+        self.channel.send_cell(&cell).await?;
+        self.traffic.lock().unwrap().record(CELL_LEN as u64);
+        Ok(())
+    }
+
+    /// Send a RELAY_DROP to the exit every `interval` so idle circuits stay
+    /// open, until the path is closed or stops accepting cells
+    pub(crate) async fn keepalive(self: Arc<Self>, interval: Duration) {
+        let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+        loop {
+            tokio::select! {
+                _ = self.cancel.cancelled() => break,
+                _ = ticks.tick() => {
+                    if let Err(e) = self.send(RelayCell::new(RELAY_COMMAND_DROP, 0, Vec::new())).await {
+                        log::debug!("Keepalive on circuit {} failed: {:?}", self.circuit_id, e);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn demux(self: Arc<Self>, mut inbound: mpsc::UnboundedReceiver<Cell>) {
+        let circuit_id = self.circuit_id;
+        let mut deliver = DeliverWindow::new(CIRCUIT_WINDOW_START, CIRCUIT_WINDOW_INCREMENT);
+        loop {
+            let cell = tokio::select! {
+                _ = self.cancel.cancelled() => break,
+                cell = inbound.recv() => match cell {
+                    Some(cell) => cell,
+                    None => break,
+                },
+            };
+            self.traffic.lock().unwrap().record(CELL_LEN as u64);
+            match cell.command {
+                CellCommand::Relay => {}
+                CellCommand::Destroy => {
+                    log::info!(
+                        "Circuit {} destroyed by relay (reason {})",
+                        circuit_id,
+                        cell.payload.first().copied().unwrap_or(0)
+                    );
+                    break;
+                }
+                other => {
+                    log::debug!("Ignoring cell command {} on circuit {}", other, circuit_id);
+                    continue;
+                }
+            }
+
+            let mut body = [0u8; CELL_PAYLOAD_LEN];
+            body.copy_from_slice(&cell.payload[..CELL_PAYLOAD_LEN]);
+            // The running digest right after this cell, which a SENDME for it must carry
+            let recognized = {
+                let mut layers = self.layers.lock().await;
+                layers
+                    .iter_mut()
+                    .find_map(|hop| hop.decrypt_relay_cell(&mut body).then(|| hop.backward_digest()))
+            };
+            let Some(digest) = recognized else {
+                log::warn!("Unrecognized relay cell on circuit {}", circuit_id);
+                continue;
+            };
+
+            let relay_cell = match RelayCell::from_bytes(&body) {
+                Ok(relay_cell) => relay_cell,
+                Err(e) => {
+                    log::warn!("Malformed relay cell on circuit {}: {}", circuit_id, e);
+                    continue;
+                }
+            };
+
+            match relay_cell.command {
+                RELAY_COMMAND_SENDME => {
+                    if !self.sendme_received(&relay_cell) {
+                        // tor-spec 7.4: a SENDME for cells we never sent is a protocol violation
+                        let destroy = Cell::new(circuit_id, CellCommand::Destroy, vec![DESTROY_REASON_PROTOCOL]);
+                        if let Err(e) = self.channel.send_cell(&destroy).await {
+                            log::debug!("Couldn't send DESTROY for circuit {}: {}", circuit_id, e);
+                        }
+                        self.cancel.cancel();
+                        break;
+                    }
+                    continue;
+                }
+                RELAY_COMMAND_EXTENDED2 | RELAY_COMMAND_TRUNCATED if relay_cell.stream_id == 0 => {
+                    // One nobody asked for is discarded by the next request
+                    let _ = self.control.send(relay_cell);
+                    continue;
+                }
+                RELAY_COMMAND_DATA if deliver.deliver() => {
+                    let sendme = RelayCell::new(RELAY_COMMAND_SENDME, 0, encode_sendme_v1(&digest));
+                    if let Err(e) = self.send(sendme).await {
+                        log::debug!("Couldn't send circuit SENDME on circuit {}: {:?}", circuit_id, e);
+                    }
+                }
+                _ => {}
+            }
+
+            let mut streams = self.streams.lock().unwrap();
+            match streams.get(&relay_cell.stream_id) {
+                Some(stream) => {
+                    let stream_id = relay_cell.stream_id;
+                    if stream.queue.send(relay_cell).is_err() {
+                        streams.remove(&stream_id);
+                    }
+                }
+                None => log::debug!(
+                    "Relay cell {} for unknown stream {} on circuit {}",
+                    relay_cell.command, relay_cell.stream_id, circuit_id
+                ),
+            }
+        }
+
+        // Dropping the senders ends every stream on the circuit
+        self.streams.lock().unwrap().clear();
+    }
+
+    /// The exit acknowledged DATA cells: reopen the circuit's window (stream
+    /// 0) or the stream's. False if it acknowledged cells we never sent, or a
+    /// circuit SENDME's digest isn't that of the cell it should acknowledge.
+    fn sendme_received(&self, sendme: &RelayCell) -> bool {
+        if sendme.stream_id == 0 {
+            if !self.sendme_digests.lock().unwrap().acknowledged(&sendme.data) {
+                log::warn!("Circuit SENDME on circuit {} doesn't acknowledge a cell we sent", self.circuit_id);
+                return false;
+            }
+            if !self.package.open(CIRCUIT_WINDOW_INCREMENT) {
+                log::warn!("Circuit SENDME on circuit {} would open its window too far", self.circuit_id);
+                return false;
+            }
+            return true;
+        }
+        match self.streams.lock().unwrap().get(&sendme.stream_id) {
+            Some(stream) if !stream.package.open(STREAM_WINDOW_INCREMENT) => {
+                log::warn!(
+                    "SENDME for stream {} on circuit {} would open its window too far",
+                    sendme.stream_id, self.circuit_id
+                );
+                return false;
+            }
+            Some(_) => {}
+            None => log::debug!("SENDME for unknown stream {} on circuit {}", sendme.stream_id, self.circuit_id),
+        }
+        true
+    }
+}
+
+/// A stream ID allocated on a built circuit, receiving the relay cells the
+/// exit sends for it. Dropping it frees the ID.
+#[derive(Debug)]
+pub struct CircuitStream {
+    sender: StreamSender,
+    replies: mpsc::UnboundedReceiver<RelayCell>,
+    /// DATA cells read off this stream since our last SENDME
+    deliver: DeliverWindow,
+}
+
+/// The sending side of a `CircuitStream`, for sending while another task
+/// reads the stream. Only meaningful while the stream itself is alive.
+#[derive(Debug, Clone)]
+pub struct StreamSender {
+    relay: Arc<RelayPath>,
+    stream_id: u16,
+    /// DATA cells this stream may still send before the exit's next SENDME
+    package: Arc<PackageWindow>,
+}
+
+impl StreamSender {
+    /// Send a relay cell on the stream. RELAY_DATA waits while the stream's
+    /// or the circuit's package window is empty. `data` must fit in one cell.
+    pub async fn send(&self, command: u8, data: Vec<u8>) -> Result<(), CircuitError> {
+        // Checked before taking from the windows, which a failed send wouldn't give back
+        if data.len() > RELAY_PAYLOAD_LEN {
+            return Err(CellError::TooLarge { max: RELAY_PAYLOAD_LEN, actual: data.len() }.into());
+        }
+        if command == RELAY_COMMAND_DATA {
+            self.package.take(self.relay.circuit_id, &self.relay.cancel).await?;
+            self.relay.package.take(self.relay.circuit_id, &self.relay.cancel).await?;
+        }
+        self.relay.send(RelayCell::new(command, self.stream_id, data)).await
+    }
+}
+
+impl CircuitStream {
+    pub fn id(&self) -> u16 {
+        self.sender.stream_id
+    }
+
+    pub fn circuit_id(&self) -> CircuitId {
+        self.sender.relay.circuit_id
+    }
+
+    /// Send a relay cell on this stream. RELAY_DATA waits while the stream's
+    /// or the circuit's package window is empty. `data` must fit in one cell.
+    pub async fn send(&self, command: u8, data: Vec<u8>) -> Result<(), CircuitError> {
+        self.sender.send(command, data).await
+    }
+
+    /// A handle for sending on this stream while it is being read
+    pub fn sender(&self) -> StreamSender {
+        self.sender.clone()
+    }
+
+    /// The next relay cell for this stream; None once the circuit is gone.
+    /// Reading DATA cells is what lets the exit send more, so a stream that
+    /// isn't read eventually stalls.
+    pub async fn recv(&mut self) -> Option<RelayCell> {
+        let cell = self.replies.recv().await?;
+        if cell.command == RELAY_COMMAND_DATA && self.deliver.deliver() {
+            let sendme = RelayCell::new(RELAY_COMMAND_SENDME, self.id(), Vec::new());
+            if let Err(e) = self.sender.relay.send(sendme).await {
+                log::debug!("Couldn't send SENDME for stream {}: {:?}", self.id(), e);
+            }
+        }
+        Some(cell)
+    }
+
+    /// Read DATA until the exit ends the stream, giving up if nothing arrives
+    /// for `idle_timeout`. An END other than DONE is an error.
+    pub async fn read_to_end(&mut self, idle_timeout: Duration) -> Result<Vec<u8>, CircuitError> {
+        let mut data = Vec::new();
+        loop {
+            let cell = tokio::time::timeout(idle_timeout, self.recv())
+                .await
+                .map_err(|_| CircuitError::Io(format!("stream idle after {} bytes", data.len())))?
+                .ok_or(CircuitError::NotReady(self.circuit_id()))?;
+            match cell.command {
+                RELAY_COMMAND_DATA => data.extend_from_slice(&cell.data),
+                RELAY_COMMAND_END => match cell.data.first().copied().unwrap_or(END_REASON_DONE) {
+                    END_REASON_DONE => return Ok(data),
+                    reason => return Err(CircuitError::StreamEnded(reason)),
+                },
+                other => log::debug!("Ignoring relay command {} on stream {}", other, self.id()),
+            }
+        }
+    }
+
+    /// DATA cells that may be sent on this stream right now without waiting
+    /// for a SENDME
+    pub fn package_window(&self) -> u16 {
+        self.sender.package.remaining().min(self.sender.relay.package_window())
+    }
+}
+
+impl Drop for CircuitStream {
+    fn drop(&mut self) {
+        self.sender.relay.close_stream(self.sender.stream_id);
+    }
+}
@@ -0,0 +1,147 @@
+// src/circuit/sendme.rs
+//! SENDME flow control (tor-spec 7.4). Each side may only send so many
+//! RELAY_DATA cells (its package window) before the other side acknowledges
+//! them with a RELAY_SENDME, which it does each time its deliver window has
+//! dropped by an increment. Circuits and streams each have a pair of windows.
+use super::{CircuitError, CircuitId};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+pub const CIRCUIT_WINDOW_START: u16 = 1000;
+pub const CIRCUIT_WINDOW_INCREMENT: u16 = 100;
+pub const STREAM_WINDOW_START: u16 = 500;
+pub const STREAM_WINDOW_INCREMENT: u16 = 50;
+
+/// SENDME versions: 0 has an empty body, 1 carries the digest of the cell
+/// being acknowledged (proposal 289)
+pub const SENDME_VERSION_AUTHENTICATED: u8 = 1;
+const SENDME_DIGEST_LEN: usize = 20;
+
+/// Body of a circuit-level SENDME acknowledging the cell whose running
+/// digest was `digest`: Version(1) | DataLen(2) | Digest(20)
+pub fn encode_sendme_v1(digest: &[u8; SENDME_DIGEST_LEN]) -> Vec<u8> {
+    let mut body = vec![SENDME_VERSION_AUTHENTICATED];
+    body.extend_from_slice(&(SENDME_DIGEST_LEN as u16).to_be_bytes());
+    body.extend_from_slice(digest);
+    body
+}
+
+/// The digest in a version 1 SENDME body; None for version 0 or a malformed body
+pub fn parse_sendme_digest(body: &[u8]) -> Option<[u8; SENDME_DIGEST_LEN]> {
+    match body {
+        [SENDME_VERSION_AUTHENTICATED, len_hi, len_lo, rest @ ..]
+            if u16::from_be_bytes([*len_hi, *len_lo]) as usize == SENDME_DIGEST_LEN =>
+        {
+            rest.get(..SENDME_DIGEST_LEN)?.try_into().ok()
+        }
+        _ => None,
+    }
+}
+
+/// How many more DATA cells we may send; senders wait while it's zero
+#[derive(Debug)]
+pub(crate) struct PackageWindow {
+    start: u16,
+    cells: Mutex<u16>,
+    opened: Notify,
+}
+
+impl PackageWindow {
+    pub(crate) fn new(start: u16) -> Self {
+        Self { start, cells: Mutex::new(start), opened: Notify::new() }
+    }
+
+    /// Use up one cell of the window, waiting for a SENDME if it's empty.
+    /// Fails if `cancel` fires (the circuit closed) first.
+    pub(crate) async fn take(&self, circuit_id: CircuitId, cancel: &CancellationToken) -> Result<(), CircuitError> {
+        loop {
+            let opened = self.opened.notified();
+            tokio::pin!(opened);
+            // Register before looking, so a SENDME landing in between isn't missed
+            opened.as_mut().enable();
+            {
+                let mut cells = self.cells.lock().unwrap();
+                if *cells > 0 {
+                    *cells -= 1;
+                    return Ok(());
+                }
+            }
+            tokio::select! {
+                _ = cancel.cancelled() => return Err(CircuitError::NotReady(circuit_id)),
+                _ = opened => {}
+            }
+        }
+    }
+
+    /// A SENDME arrived: `increment` more cells may be sent. False, leaving
+    /// the window alone, if that would take it past its starting size, as
+    /// only a SENDME for cells we never sent can.
+    pub(crate) fn open(&self, increment: u16) -> bool {
+        let mut cells = self.cells.lock().unwrap();
+        match cells.checked_add(increment).filter(|opened| *opened <= self.start) {
+            Some(opened) => *cells = opened,
+            None => return false,
+        }
+        self.opened.notify_waiters();
+        true
+    }
+
+    pub(crate) fn remaining(&self) -> u16 {
+        *self.cells.lock().unwrap()
+    }
+}
+
+/// The running digests right after each DATA cell we sent that the other
+/// side's next circuit SENDME must carry (every CIRCUIT_WINDOW_INCREMENT'th),
+/// oldest first
+#[derive(Debug, Default)]
+pub(crate) struct SendmeDigests {
+    data_sent: usize,
+    expected: VecDeque<[u8; SENDME_DIGEST_LEN]>,
+}
+
+impl SendmeDigests {
+    /// Count a DATA cell sent on the circuit; `digest` is only taken when a
+    /// SENDME will have to acknowledge it
+    pub(crate) fn sent(&mut self, digest: impl FnOnce() -> [u8; SENDME_DIGEST_LEN]) {
+        self.data_sent += 1;
+        if self.data_sent.is_multiple_of(CIRCUIT_WINDOW_INCREMENT as usize) {
+            self.expected.push_back(digest());
+        }
+    }
+
+    /// Whether the circuit SENDME with `body` acknowledges the oldest
+    /// unacknowledged cell. Version 0 SENDMEs prove nothing and never do.
+    pub(crate) fn acknowledged(&mut self, body: &[u8]) -> bool {
+        let expected = self.expected.pop_front();
+        expected.is_some() && parse_sendme_digest(body) == expected
+    }
+}
+
+/// How many more DATA cells the other side may send us before we owe it a SENDME
+#[derive(Debug)]
+pub(crate) struct DeliverWindow {
+    start: u16,
+    increment: u16,
+    cells: u16,
+}
+
+impl DeliverWindow {
+    pub(crate) fn new(start: u16, increment: u16) -> Self {
+        Self { start, increment, cells: start }
+    }
+
+    /// Count a delivered DATA cell. True if a SENDME is now due, in which
+    /// case the window is already credited with the increment.
+    pub(crate) fn deliver(&mut self) -> bool {
+        // A relay sending past the window is misbehaving; keep counting from zero
+        self.cells = self.cells.saturating_sub(1);
+        if self.cells <= self.start - self.increment {
+            self.cells += self.increment;
+            return true;
+        }
+        false
+    }
+}
@@ -0,0 +1,218 @@
+// src/config.rs
+//! Loading a `TorConfig` from a TOML file and the environment, for
+//! deployments that don't build one in code. A file may set any setting;
+//! the environment only the basic ones.
+use crate::directory::source::ConsensusSource;
+use crate::TorConfig;
+use serde::{Deserialize, Deserializer};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Names the config file for the `tor-client` binary, if not given as its
+/// first argument ("-" reads it from stdin)
+pub const CONFIG_PATH_ENV: &str = "TOR_CLIENT_CONFIG";
+
+/// Environment variables read by `TorConfig::from_env`. The list variables
+/// are comma-separated.
+pub const DATA_DIRECTORY_ENV: &str = "TOR_CLIENT_DATA_DIRECTORY";
+pub const SOCKS_PORT_ENV: &str = "TOR_CLIENT_SOCKS_PORT";
+pub const CONTROL_PORT_ENV: &str = "TOR_CLIENT_CONTROL_PORT";
+pub const DIRECTORY_AUTHORITIES_ENV: &str = "TOR_CLIENT_DIRECTORY_AUTHORITIES";
+pub const ENTRY_GUARDS_ENV: &str = "TOR_CLIENT_ENTRY_GUARDS";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The config file couldn't be read
+    Io(PathBuf, std::io::Error),
+    /// The config file isn't valid TOML, or a setting has the wrong type
+    Parse(String),
+    /// A port setting (named) outside 1-65535
+    InvalidPort(&'static str, String),
+    /// `data_directory` can't be created or written to
+    DataDirectoryNotWritable(PathBuf, std::io::Error),
+    /// No `data_directory` was given
+    MissingDataDirectory,
+    /// The SOCKS and control ports are both this one
+    PortCollision(u16),
+    /// A `directory_authorities` entry that's neither "tor-collector", an
+    /// http(s) URL nor an existing directory
+    InvalidDirectorySource(String),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "Can't read config file {}: {}", path.display(), e),
+            ConfigError::Parse(e) => write!(f, "Invalid config: {}", e),
+            ConfigError::InvalidPort(name, value) => write!(f, "{} must be a port from 1 to 65535, not {}", name, value),
+            ConfigError::DataDirectoryNotWritable(dir, e) => {
+                write!(f, "Data directory {} isn't writable: {}", dir.display(), e)
+            }
+            ConfigError::MissingDataDirectory => write!(f, "data_directory must be set"),
+            ConfigError::PortCollision(port) => write!(f, "socks_port and control_port are both {}", port),
+            ConfigError::InvalidDirectorySource(source) => write!(
+                f,
+                "Directory source {:?} isn't \"tor-collector\", an http(s) URL or an existing directory",
+                source
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// The settings the environment may give; unset ones are left alone
+#[derive(Debug, Default)]
+struct ConfigOverrides {
+    data_directory: Option<String>,
+    socks_port: Option<i64>,
+    control_port: Option<i64>,
+    directory_authorities: Option<Vec<String>>,
+    entry_guards: Option<Vec<String>>,
+}
+
+impl ConfigOverrides {
+    fn from_env() -> Result<Self, ConfigError> {
+        let port = |var: &str, name: &'static str| -> Result<Option<i64>, ConfigError> {
+            match std::env::var(var) {
+                Ok(value) => value.trim().parse().map(Some).map_err(|_| ConfigError::InvalidPort(name, value)),
+                Err(_) => Ok(None),
+            }
+        };
+        let list = |var: &str| {
+            std::env::var(var).ok().map(|value| {
+                value.split(',').map(str::trim).filter(|item| !item.is_empty()).map(String::from).collect()
+            })
+        };
+        Ok(Self {
+            data_directory: std::env::var(DATA_DIRECTORY_ENV).ok(),
+            socks_port: port(SOCKS_PORT_ENV, "socks_port")?,
+            control_port: port(CONTROL_PORT_ENV, "control_port")?,
+            directory_authorities: list(DIRECTORY_AUTHORITIES_ENV),
+            entry_guards: list(ENTRY_GUARDS_ENV),
+        })
+    }
+
+    fn apply(self, config: &mut TorConfig) -> Result<(), ConfigError> {
+        if let Some(dir) = self.data_directory {
+            config.data_directory = dir;
+        }
+        if let Some(port) = self.socks_port {
+            config.socks_port = valid_port("socks_port", port)?;
+        }
+        if let Some(port) = self.control_port {
+            config.control_port = valid_port("control_port", port)?;
+        }
+        if let Some(authorities) = self.directory_authorities {
+            config.directory_authorities = authorities;
+        }
+        if let Some(guards) = self.entry_guards {
+            config.entry_guards = guards;
+        }
+        Ok(())
+    }
+}
+
+/// A duration given as whole seconds (`30`) or with a unit (`"30s"`, `"2h"`)
+pub fn deserialize_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Spelling {
+        Seconds(u64),
+        Text(String),
+    }
+    match Spelling::deserialize(deserializer)? {
+        Spelling::Seconds(secs) => Ok(Duration::from_secs(secs)),
+        Spelling::Text(text) => humantime::parse_duration(&text).map_err(serde::de::Error::custom),
+    }
+}
+
+fn valid_port(name: &'static str, port: i64) -> Result<u16, ConfigError> {
+    u16::try_from(port)
+        .ok()
+        .filter(|&port| port != 0)
+        .ok_or_else(|| ConfigError::InvalidPort(name, port.to_string()))
+}
+
+/// Make sure state can be saved under `dir`, creating it if need be
+fn check_data_directory(dir: &str) -> Result<(), ConfigError> {
+    if dir.is_empty() {
+        return Ok(());
+    }
+    let dir = Path::new(dir);
+    let probe = dir.join(".write-test");
+    std::fs::create_dir_all(dir)
+        .and_then(|_| std::fs::write(&probe, b""))
+        .and_then(|_| std::fs::remove_file(&probe))
+        .map_err(|e| ConfigError::DataDirectoryNotWritable(dir.to_path_buf(), e))
+}
+
+/// Whether `source` is "tor-collector", an http(s) URL with a host (and a
+/// valid port, if any) or an existing local directory
+fn is_valid_directory_source(source: &str) -> bool {
+    let source = source.trim();
+    match ConsensusSource::parse(source) {
+        ConsensusSource::Archive(url) | ConsensusSource::Mirror(url) => {
+            let rest = url.split_once("://").map_or("", |(_, rest)| rest);
+            let authority = rest.split('/').next().unwrap_or("");
+            match authority.rsplit_once(':') {
+                Some((host, port)) => !host.is_empty() && port.parse::<u16>().is_ok(),
+                None => !authority.is_empty(),
+            }
+        }
+        ConsensusSource::Directory(dir) => dir.is_dir(),
+    }
+}
+
+impl TorConfig {
+    /// Check the settings `TorClient::start` can't work without: a writable
+    /// `data_directory` (created if need be), distinct SOCKS and control
+    /// ports, and directory sources it can make sense of
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.data_directory.trim().is_empty() {
+            return Err(ConfigError::MissingDataDirectory);
+        }
+        check_data_directory(&self.data_directory)?;
+        valid_port("socks_port", self.socks_port.into())?;
+        valid_port("control_port", self.control_port.into())?;
+        if self.socks_port == self.control_port {
+            return Err(ConfigError::PortCollision(self.socks_port));
+        }
+        if let Some(source) = self.directory_authorities.iter().find(|s| !is_valid_directory_source(s)) {
+            return Err(ConfigError::InvalidDirectorySource(source.clone()));
+        }
+        Ok(())
+    }
+
+    /// The settings in `text`, a TOML document, with the defaults for any it
+    /// leaves out
+    pub fn from_toml_str(text: &str) -> Result<Self, ConfigError> {
+        let config: Self = toml::from_str(text).map_err(|e| ConfigError::Parse(e.to_string()))?;
+        valid_port("socks_port", config.socks_port.into())?;
+        valid_port("control_port", config.control_port.into())?;
+        Ok(config)
+    }
+
+    /// The settings in the TOML file at `path` (see `from_toml_str`), with
+    /// any from the environment (see `from_env`) on top
+    pub fn from_toml_file(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+        let mut config = Self::from_toml_str(&text).map_err(|e| match e {
+            ConfigError::Parse(e) => ConfigError::Parse(format!("{}: {}", path.display(), e)),
+            other => other,
+        })?;
+        ConfigOverrides::from_env()?.apply(&mut config)?;
+        check_data_directory(&config.data_directory)?;
+        Ok(config)
+    }
+
+    /// The defaults, with any settings given by the TOR_CLIENT_* variables
+    /// on top
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let mut config = Self::default();
+        ConfigOverrides::from_env()?.apply(&mut config)?;
+        check_data_directory(&config.data_directory)?;
+        Ok(config)
+    }
+}
@@ -0,0 +1,156 @@
+// src/control.rs
+//! A small subset of Tor's control protocol (control-spec), enough for
+//! controllers such as stem and nyx to connect and look around:
+//! PROTOCOLINFO, AUTHENTICATE (no authentication, so only bind it to
+//! loopback), GETINFO version / circuit-status, SIGNAL NEWNYM and QUIT.
+use crate::circuit::{CircuitInfo, CircuitManager, CircuitState, HopState};
+use crate::directory::fingerprint::base64_to_hex;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Longest command line accepted before the connection is dropped
+const MAX_LINE_LEN: usize = 4096;
+
+#[derive(Debug)]
+pub struct ControlPort {
+    bind_address: String,
+    circuit_manager: Arc<CircuitManager>,
+}
+
+impl ControlPort {
+    pub fn new(bind_address: String, circuit_manager: Arc<CircuitManager>) -> Self {
+        Self { bind_address, circuit_manager }
+    }
+
+    pub async fn run(&self) -> std::io::Result<()> {
+        let listener = TcpListener::bind(&self.bind_address).await?;
+        self.serve(listener).await
+    }
+
+    /// `run` on a listener that's already bound, in place of `bind_address`
+    pub async fn serve(&self, listener: TcpListener) -> std::io::Result<()> {
+        log::info!("Control port listening on {}", listener.local_addr()?);
+
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) => {
+                    log::info!("New controller connection from {}", addr);
+                    let circuit_manager = self.circuit_manager.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = Self::handle_controller(stream, circuit_manager).await {
+                            log::debug!("Controller {} connection error: {}", addr, e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    log::error!("Failed to accept controller connection: {}", e);
+                }
+            }
+        }
+    }
+
+    async fn handle_controller(stream: TcpStream, circuit_manager: Arc<CircuitManager>) -> std::io::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader).take(MAX_LINE_LEN as u64);
+        let mut authenticated = false;
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+            reader.set_limit(MAX_LINE_LEN as u64);
+            reader.read_line(&mut line).await?;
+            // A closed connection, or a line too long to be a command
+            if !line.ends_with('\n') {
+                return Ok(());
+            }
+            let (keyword, args) = match line.trim_end_matches(['\r', '\n']).split_once(' ') {
+                Some((keyword, args)) => (keyword.to_ascii_uppercase(), args.trim()),
+                None => (line.trim_end_matches(['\r', '\n']).to_ascii_uppercase(), ""),
+            };
+
+            // Until it authenticates, a controller may only ask how to, or leave
+            let reply = match keyword.as_str() {
+                "PROTOCOLINFO" => protocol_info(),
+                "AUTHENTICATE" => {
+                    authenticated = true;
+                    "250 OK\r\n".to_string()
+                }
+                "QUIT" => {
+                    writer.write_all(b"250 closing connection\r\n").await?;
+                    return Ok(());
+                }
+                _ if !authenticated => {
+                    writer.write_all(b"514 Authentication required.\r\n").await?;
+                    return Ok(());
+                }
+                "GETINFO" => get_info(&circuit_manager, args).await,
+                "SIGNAL" => signal(&circuit_manager, args).await,
+                _ => format!("510 Unrecognized command \"{}\"\r\n", keyword),
+            };
+            writer.write_all(reply.as_bytes()).await?;
+        }
+    }
+}
+
+fn protocol_info() -> String {
+    format!(
+        "250-PROTOCOLINFO 1\r\n250-AUTH METHODS=NULL\r\n250-VERSION Tor=\"{}\"\r\n250 OK\r\n",
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+/// Answer every key or none: an unknown key fails the whole request
+async fn get_info(circuit_manager: &CircuitManager, keys: &str) -> String {
+    let mut reply = String::new();
+    for key in keys.split_whitespace() {
+        match key.to_ascii_lowercase().as_str() {
+            "version" => reply.push_str(&format!("250-version={}\r\n", env!("CARGO_PKG_VERSION"))),
+            "circuit-status" => {
+                reply.push_str("250+circuit-status=\r\n");
+                for info in circuit_manager.circuit_infos().await {
+                    reply.push_str(&circuit_status_line(&info));
+                    reply.push_str("\r\n");
+                }
+                reply.push_str(".\r\n");
+            }
+            _ => return format!("552 Unrecognized key \"{}\"\r\n", key),
+        }
+    }
+    reply.push_str("250 OK\r\n");
+    reply
+}
+
+/// "ID STATUS PATH" as circuit-status and CIRC events give it, the path
+/// being the hops built so far as "$FINGERPRINT~nickname"
+fn circuit_status_line(info: &CircuitInfo) -> String {
+    let established = info.hops.iter().filter(|hop| hop.state == HopState::KeysEstablished);
+    let path: Vec<String> = established
+        .map(|hop| match base64_to_hex(&hop.relay_id) {
+            Some(fingerprint) => format!("${}~{}", fingerprint, hop.nickname),
+            None => hop.nickname.clone(),
+        })
+        .collect();
+    let status = match &info.state {
+        CircuitState::Building if path.is_empty() => "LAUNCHED",
+        CircuitState::Building => "EXTENDED",
+        CircuitState::Ready => "BUILT",
+        CircuitState::Closed => "CLOSED",
+        CircuitState::Error(..) => "FAILED",
+    };
+    if path.is_empty() {
+        format!("{} {}", info.id, status)
+    } else {
+        format!("{} {} {}", info.id, status, path.join(","))
+    }
+}
+
+async fn signal(circuit_manager: &CircuitManager, name: &str) -> String {
+    match name.to_ascii_uppercase().as_str() {
+        "NEWNYM" => {
+            circuit_manager.new_identity().await;
+            "250 OK\r\n".to_string()
+        }
+        _ => format!("552 Unrecognized signal code \"{}\"\r\n", name),
+    }
+}
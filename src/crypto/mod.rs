@@ -1,11 +1,22 @@
 // src/crypto/mod.rs
-use ring::{aead, rand};
+use aes::cipher::{KeyIvInit, StreamCipher};
+use crate::security::{constant_time_compare, SecretData};
+use ring::{aead, digest, hkdf, hmac, rand};
 use ring::aead::UnboundKey;
 use ring::rand::SecureRandom;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::{Zeroize, Zeroizing};
+
+pub mod rsa;
+pub mod tor_cert;
 
 #[derive(Debug)]
 pub enum CryptoError {
     RingError(ring::error::Unspecified),
+    NtorError(String),
+    RsaError(String),
+    /// A malformed or unusable Ed25519 certificate
+    CertError(String),
 }
 
 impl From<ring::error::Unspecified> for CryptoError {
@@ -26,11 +37,12 @@ fn generate_nonce(nonce: u64) -> [u8; 12] {
     nonce_bytes
 }
 
-/// Onion encryption state for a circuit
+/// Onion encryption state for a circuit. Only the raw key bytes are kept;
+/// ring's expanded key can't be wiped, so it is rebuilt for each message.
 #[derive(Debug, Clone)]
 pub struct OnionCrypto {
-    forward_key: aead::LessSafeKey,
-    backward_key: aead::LessSafeKey,
+    forward_key: SecretData<32>,
+    backward_key: SecretData<32>,
     forward_nonce: u64,
     backward_nonce: u64,
 }
@@ -40,12 +52,9 @@ impl OnionCrypto {
         let rng = rand::SystemRandom::new();
 
         // Generate initial keys
-        let forward_key = generate_aead_key(&rng)?;
-        let backward_key = generate_aead_key(&rng)?;
-
         Ok(Self {
-            forward_key: aead::LessSafeKey::new(UnboundKey::new(&aead::AES_256_GCM, &forward_key)?),
-            backward_key: aead::LessSafeKey::new(UnboundKey::new(&aead::AES_256_GCM, &backward_key)?),
+            forward_key: SecretData::new(generate_aead_key(&rng)?),
+            backward_key: SecretData::new(generate_aead_key(&rng)?),
             forward_nonce: 0,
             backward_nonce: 0,
         })
@@ -53,32 +62,397 @@ impl OnionCrypto {
 
     /// Encrypt data for forward direction
     pub fn encrypt_forward(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
-        let nonce = generate_nonce(self.forward_nonce);
-        self.forward_nonce += 1;
-
-        let mut in_out = plaintext.to_vec();
-        let tag = self.forward_key.seal_in_place_separate_tag(
-            aead::Nonce::assume_unique_for_key(nonce),
-            aead::Aad::empty(),
-            &mut in_out
-        )?;
-
-        in_out.extend_from_slice(tag.as_ref());
-        Ok(in_out)
+        seal(&self.forward_key, &mut self.forward_nonce, plaintext)
     }
 
     /// Decrypt data from forward direction
     pub fn decrypt_forward(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError> {
-        let nonce = generate_nonce(self.forward_nonce);
-        self.forward_nonce += 1;
+        open(&self.forward_key, &mut self.forward_nonce, ciphertext)
+    }
+
+    /// Encrypt data for backward direction (relay towards client)
+    pub fn encrypt_backward(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        seal(&self.backward_key, &mut self.backward_nonce, plaintext)
+    }
+
+    /// Decrypt data from backward direction, one layer per call
+    pub fn decrypt_backward(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        open(&self.backward_key, &mut self.backward_nonce, ciphertext)
+    }
+}
+
+impl Drop for OnionCrypto {
+    fn drop(&mut self) {
+        self.forward_key.zeroize();
+        self.backward_key.zeroize();
+    }
+}
+
+fn aead_key(key: &SecretData<32>) -> Result<aead::LessSafeKey, CryptoError> {
+    Ok(aead::LessSafeKey::new(UnboundKey::new(&aead::AES_256_GCM, key.expose())?))
+}
+
+/// Encrypt under `key` with the next nonce of `counter`, appending the tag
+fn seal(key: &SecretData<32>, counter: &mut u64, plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let key = aead_key(key)?;
+    let nonce = generate_nonce(*counter);
+    *counter += 1;
+
+    let mut in_out = plaintext.to_vec();
+    let tag = key.seal_in_place_separate_tag(
+        aead::Nonce::assume_unique_for_key(nonce),
+        aead::Aad::empty(),
+        &mut in_out
+    )?;
+
+    in_out.extend_from_slice(tag.as_ref());
+    Ok(in_out)
+}
+
+/// Check and strip the tag of a `seal`ed message
+fn open(key: &SecretData<32>, counter: &mut u64, ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let key = aead_key(key)?;
+    let nonce = generate_nonce(*counter);
+    *counter += 1;
+
+    let mut in_out = ciphertext.to_vec();
+    let plaintext_len = key
+        .open_in_place(aead::Nonce::assume_unique_for_key(nonce), aead::Aad::empty(), &mut in_out)?
+        .len();
+    in_out.truncate(plaintext_len);
+    Ok(in_out)
+}
+
+type Aes128Ctr = ctr::Ctr128BE<aes::Aes128>;
+
+/// Length of a relay cell body (the payload of a fixed-length cell)
+pub const RELAY_CELL_LEN: usize = 509;
 
-        let mut in_out = ciphertext.to_vec();
-        self.forward_key.open_in_place(
-            aead::Nonce::assume_unique_for_key(nonce),
-            aead::Aad::empty(),
-            &mut in_out
-        )?;
+// Relay cell header: command(1) | recognized(2) | stream_id(2) | digest(4) | length(2)
+const RECOGNIZED_RANGE: std::ops::Range<usize> = 1..3;
+const DIGEST_RANGE: std::ops::Range<usize> = 5..9;
+
+/// Relay cell crypto state for a single hop, as used by real Tor relays:
+/// AES-128-CTR over the cell stream plus a running SHA-1 digest per direction.
+/// The AES key schedules and counters are zeroized on drop, and so is the
+/// digest state.
+#[derive(Clone)]
+pub struct RelayCrypto {
+    forward_cipher: Aes128Ctr,
+    backward_cipher: Aes128Ctr,
+    forward_digest: digest::Context,
+    backward_digest: digest::Context,
+}
+
+impl std::fmt::Debug for RelayCrypto {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Never print key material
+        f.debug_struct("RelayCrypto").finish_non_exhaustive()
+    }
+}
+
+impl Drop for RelayCrypto {
+    fn drop(&mut self) {
+        // ring's digest state can't be zeroized in place, but a fresh context
+        // overwrites the chaining value and the pending block
+        self.forward_digest = digest::Context::new(&digest::SHA1_FOR_LEGACY_USE_ONLY);
+        self.backward_digest = digest::Context::new(&digest::SHA1_FOR_LEGACY_USE_ONLY);
+        // Keep the stores from being optimized away as dead
+        std::hint::black_box(&mut self.forward_digest);
+        std::hint::black_box(&mut self.backward_digest);
+    }
+}
+
+impl RelayCrypto {
+    pub fn from_ntor_keys(keys: &NtorKeys) -> Self {
+        Self::new(
+            &keys.forward_digest,
+            &keys.backward_digest,
+            &keys.forward_key,
+            &keys.backward_key,
+        )
+    }
+
+    /// Build the hop state from the ntor KDF output (Df, Db, Kf, Kb)
+    pub fn new(
+        forward_digest_seed: &[u8],
+        backward_digest_seed: &[u8],
+        forward_key: &[u8; 16],
+        backward_key: &[u8; 16],
+    ) -> Self {
+        let mut forward_digest = digest::Context::new(&digest::SHA1_FOR_LEGACY_USE_ONLY);
+        forward_digest.update(forward_digest_seed);
+        let mut backward_digest = digest::Context::new(&digest::SHA1_FOR_LEGACY_USE_ONLY);
+        backward_digest.update(backward_digest_seed);
+
+        // Tor runs the counter from an all-zero IV for the lifetime of the circuit
+        let iv = [0u8; 16];
+        Self {
+            forward_cipher: Aes128Ctr::new(forward_key.into(), &iv.into()),
+            backward_cipher: Aes128Ctr::new(backward_key.into(), &iv.into()),
+            forward_digest,
+            backward_digest,
+        }
+    }
+
+    /// Stamp the running forward digest into a cell addressed to this hop, then encrypt it
+    pub fn encrypt_relay_cell(&mut self, cell: &mut [u8; RELAY_CELL_LEN]) {
+        cell[DIGEST_RANGE].fill(0);
+        self.forward_digest.update(cell);
+        let digest = self.forward_digest.clone().finish();
+        cell[DIGEST_RANGE].copy_from_slice(&digest.as_ref()[..4]);
+
+        self.forward_cipher.apply_keystream(cell);
+    }
+
+    /// Add this hop's layer to a cell addressed to a hop further along the circuit
+    pub fn encrypt_forward_layer(&mut self, cell: &mut [u8; RELAY_CELL_LEN]) {
+        self.forward_cipher.apply_keystream(cell);
+    }
+
+    /// Peel this hop's backward layer. Returns true if the cell originated at
+    /// this hop, in which case the running backward digest is advanced.
+    pub fn decrypt_relay_cell(&mut self, cell: &mut [u8; RELAY_CELL_LEN]) -> bool {
+        self.backward_cipher.apply_keystream(cell);
+
+        if cell[RECOGNIZED_RANGE] != [0, 0] {
+            return false;
+        }
+
+        let mut received = [0u8; 4];
+        received.copy_from_slice(&cell[DIGEST_RANGE]);
+        cell[DIGEST_RANGE].fill(0);
+
+        // Only commit the digest if the cell turns out to be ours
+        let mut digest = self.backward_digest.clone();
+        digest.update(cell);
+        let recognized = digest.clone().finish().as_ref()[..4] == received;
+        if recognized {
+            self.backward_digest = digest;
+        }
+
+        cell[DIGEST_RANGE].copy_from_slice(&received);
+        recognized
+    }
 
-        Ok(in_out)
+    /// Running digest of every cell sent to this hop so far
+    pub fn forward_digest(&self) -> [u8; 20] {
+        let mut digest = [0u8; 20];
+        digest.copy_from_slice(self.forward_digest.clone().finish().as_ref());
+        digest
     }
-}
\ No newline at end of file
+
+    /// Running digest of every cell this hop has sent us so far; authenticated
+    /// SENDMEs echo it back
+    pub fn backward_digest(&self) -> [u8; 20] {
+        let mut digest = [0u8; 20];
+        digest.copy_from_slice(self.backward_digest.clone().finish().as_ref());
+        digest
+    }
+}
+
+
+const NTOR_PROTOID: &[u8] = b"ntor-curve25519-sha256-1";
+const NTOR_T_MAC: &[u8] = b"ntor-curve25519-sha256-1:mac";
+const NTOR_T_KEY: &[u8] = b"ntor-curve25519-sha256-1:key_extract";
+const NTOR_T_VERIFY: &[u8] = b"ntor-curve25519-sha256-1:verify";
+const NTOR_M_EXPAND: &[u8] = b"ntor-curve25519-sha256-1:key_expand";
+const NTOR_SERVER: &[u8] = b"Server";
+
+/// Digest seed length (HASH_LEN) and AES key length (KEY_LEN) from tor-spec
+const NTOR_HASH_LEN: usize = 20;
+const NTOR_KEY_LEN: usize = 16;
+/// Df | Db | Kf | Kb | KH
+const NTOR_KEY_MATERIAL_LEN: usize = 3 * NTOR_HASH_LEN + 2 * NTOR_KEY_LEN;
+
+/// Circuit key material derived from a completed ntor handshake, wiped on drop
+#[derive(Clone)]
+pub struct NtorKeys {
+    /// Df: seeds the running digest of cells sent towards the relay
+    pub forward_digest: [u8; NTOR_HASH_LEN],
+    /// Db: seeds the running digest of cells coming back from the relay
+    pub backward_digest: [u8; NTOR_HASH_LEN],
+    /// Kf: AES-128-CTR key for the forward direction
+    pub forward_key: [u8; NTOR_KEY_LEN],
+    /// Kb: AES-128-CTR key for the backward direction
+    pub backward_key: [u8; NTOR_KEY_LEN],
+    /// KH: only used by hidden service rendezvous
+    pub kh: [u8; NTOR_HASH_LEN],
+}
+
+impl NtorKeys {
+    fn from_key_material(material: &[u8; NTOR_KEY_MATERIAL_LEN]) -> Self {
+        let (forward_digest, rest) = material.split_at(NTOR_HASH_LEN);
+        let (backward_digest, rest) = rest.split_at(NTOR_HASH_LEN);
+        let (forward_key, rest) = rest.split_at(NTOR_KEY_LEN);
+        let (backward_key, kh) = rest.split_at(NTOR_KEY_LEN);
+
+        // The split_at lengths above match the array sizes, so these cannot fail
+        Self {
+            forward_digest: forward_digest.try_into().unwrap(),
+            backward_digest: backward_digest.try_into().unwrap(),
+            forward_key: forward_key.try_into().unwrap(),
+            backward_key: backward_key.try_into().unwrap(),
+            kh: kh.try_into().unwrap(),
+        }
+    }
+}
+
+impl std::fmt::Debug for NtorKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Never print key material
+        f.debug_struct("NtorKeys").finish_non_exhaustive()
+    }
+}
+
+impl Zeroize for NtorKeys {
+    fn zeroize(&mut self) {
+        self.forward_digest.zeroize();
+        self.backward_digest.zeroize();
+        self.forward_key.zeroize();
+        self.backward_key.zeroize();
+        self.kh.zeroize();
+    }
+}
+
+impl Drop for NtorKeys {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+struct KdfLen(usize);
+
+impl hkdf::KeyType for KdfLen {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+/// KDF-RFC5869: HKDF-SHA256 with t_key as salt and m_expand as info
+fn ntor_kdf(secret_input: &[u8]) -> Result<NtorKeys, CryptoError> {
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, NTOR_T_KEY).extract(secret_input);
+    let mut material = Zeroizing::new([0u8; NTOR_KEY_MATERIAL_LEN]);
+    prk.expand(&[NTOR_M_EXPAND], KdfLen(material.len()))?
+        .fill(material.as_mut())?;
+    Ok(NtorKeys::from_key_material(&material))
+}
+
+/// H(x, t) from the ntor spec: HMAC-SHA256 keyed with the tweak t
+fn ntor_hmac(tweak: &[u8], message: &[u8]) -> hmac::Tag {
+    hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, tweak), message)
+}
+
+/// secret_input = EXP(Y,x) | EXP(B,x) | ID | B | X | Y | PROTOID
+/// (the relay computes the same value as EXP(X,y) | EXP(X,b))
+fn ntor_secret_input(
+    shared_xy: &[u8; 32],
+    shared_xb: &[u8; 32],
+    relay_identity: &[u8],
+    onion_key: &PublicKey,
+    client_public: &PublicKey,
+    relay_public: &PublicKey,
+) -> Zeroizing<Vec<u8>> {
+    // Sized up front so no copy of the shared secrets is left behind by a reallocation
+    let mut secret_input = Zeroizing::new(Vec::with_capacity(32 * 5 + relay_identity.len() + NTOR_PROTOID.len()));
+    secret_input.extend_from_slice(shared_xy);
+    secret_input.extend_from_slice(shared_xb);
+    secret_input.extend_from_slice(relay_identity);
+    secret_input.extend_from_slice(onion_key.as_bytes());
+    secret_input.extend_from_slice(client_public.as_bytes());
+    secret_input.extend_from_slice(relay_public.as_bytes());
+    secret_input.extend_from_slice(NTOR_PROTOID);
+    secret_input
+}
+
+/// AUTH = H(verify | ID | B | Y | X | PROTOID | "Server", t_mac), verify = H(secret_input, t_verify)
+fn ntor_auth(
+    secret_input: &[u8],
+    relay_identity: &[u8],
+    onion_key: &PublicKey,
+    client_public: &PublicKey,
+    relay_public: &PublicKey,
+) -> hmac::Tag {
+    let verify = ntor_hmac(NTOR_T_VERIFY, secret_input);
+    let mut auth_input = Vec::with_capacity(32 * 4 + relay_identity.len() + NTOR_PROTOID.len() + NTOR_SERVER.len());
+    auth_input.extend_from_slice(verify.as_ref());
+    auth_input.extend_from_slice(relay_identity);
+    auth_input.extend_from_slice(onion_key.as_bytes());
+    auth_input.extend_from_slice(relay_public.as_bytes());
+    auth_input.extend_from_slice(client_public.as_bytes());
+    auth_input.extend_from_slice(NTOR_PROTOID);
+    auth_input.extend_from_slice(NTOR_SERVER);
+    ntor_hmac(NTOR_T_MAC, &auth_input)
+}
+
+/// Client side of the ntor handshake: authenticate the relay's reply (Y, AUTH)
+/// and derive circuit keys from our ephemeral secret x and the relay's
+/// identity/onion keys (ID, B)
+pub fn ntor_handshake(
+    client_secret: &StaticSecret,
+    relay_public: &PublicKey,
+    relay_auth: &[u8],
+    relay_identity: &[u8],
+    relay_onion_key: &[u8],
+) -> Result<NtorKeys, CryptoError> {
+    let onion_key: [u8; 32] = relay_onion_key
+        .try_into()
+        .map_err(|_| CryptoError::NtorError("onion key must be 32 bytes".to_string()))?;
+    let onion_key = PublicKey::from(onion_key);
+    let client_public = PublicKey::from(client_secret);
+
+    // A low-order Y or B (the all-zero point among them) makes the shared
+    // secret all zeros, which anyone can compute
+    if relay_public.as_bytes() == &[0u8; 32] {
+        return Err(CryptoError::NtorError("relay public key is the all-zero point".to_string()));
+    }
+    let shared_xy = client_secret.diffie_hellman(relay_public);
+    let shared_xb = client_secret.diffie_hellman(&onion_key);
+    if !shared_xy.was_contributory() || !shared_xb.was_contributory() {
+        return Err(CryptoError::NtorError("all-zero shared secret (low-order relay key)".to_string()));
+    }
+
+    let secret_input = ntor_secret_input(
+        shared_xy.as_bytes(),
+        shared_xb.as_bytes(),
+        relay_identity,
+        &onion_key,
+        &client_public,
+        relay_public,
+    );
+
+    let auth = ntor_auth(&secret_input, relay_identity, &onion_key, &client_public, relay_public);
+    if !constant_time_compare(auth.as_ref(), relay_auth) {
+        return Err(CryptoError::NtorError("relay AUTH does not match".to_string()));
+    }
+
+    ntor_kdf(&secret_input)
+}
+
+/// Relay side of the ntor handshake, given the relay's ephemeral secret y and
+/// onion secret b. Returns (Y, AUTH, keys); used by the mock relay and tests.
+pub fn ntor_server_handshake(
+    server_secret: &StaticSecret,
+    onion_secret: &StaticSecret,
+    relay_identity: &[u8],
+    client_public: &PublicKey,
+) -> Result<(PublicKey, [u8; 32], NtorKeys), CryptoError> {
+    let relay_public = PublicKey::from(server_secret);
+    let onion_key = PublicKey::from(onion_secret);
+
+    let secret_input = ntor_secret_input(
+        server_secret.diffie_hellman(client_public).as_bytes(),
+        onion_secret.diffie_hellman(client_public).as_bytes(),
+        relay_identity,
+        &onion_key,
+        client_public,
+        &relay_public,
+    );
+
+    let mut auth = [0u8; 32];
+    auth.copy_from_slice(
+        ntor_auth(&secret_input, relay_identity, &onion_key, client_public, &relay_public).as_ref(),
+    );
+
+    Ok((relay_public, auth, ntor_kdf(&secret_input)?))
+}
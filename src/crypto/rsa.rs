@@ -0,0 +1,172 @@
+// src/crypto/rsa.rs
+//! Verify-only RSA for Tor directory documents and relays' legacy RSA
+//! identities. Authorities sign the bare document digest with PKCS#1 v1.5
+//! type-1 padding and no DigestInfo, as relays do their RSA->Ed25519
+//! cross-certificates. ring's RSA verifier doesn't accept that, so the
+//! `rsa` crate's unprefixed PKCS#1 v1.5 verifier checks them.
+use super::CryptoError;
+use ::rsa::traits::PublicKeyParts;
+use ::rsa::{BigUint, Pkcs1v15Sign};
+use base64::{engine::general_purpose, Engine as _};
+use ring::digest;
+
+/// OBJECT IDENTIFIER 1.2.840.113549.1.1.1 (rsaEncryption), DER-encoded
+const RSA_ENCRYPTION_OID: &[u8] = &[0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+
+/// Tor only uses RSA keys with this public exponent (crypto_pk_public_exponent_ok)
+const RSA_EXPONENT: &[u8] = &[0x01, 0x00, 0x01];
+
+/// The largest modulus accepted, so a hostile document can't make us do
+/// arbitrarily expensive exponentiations
+const RSA_MAX_BITS: usize = 4096;
+
+/// An RSA public key as found in Tor documents ("RSA PUBLIC KEY" PEM, PKCS#1 DER)
+#[derive(Clone, PartialEq, Eq)]
+pub struct RsaPublicKey {
+    der: Vec<u8>,
+    key: ::rsa::RsaPublicKey,
+}
+
+impl std::fmt::Debug for RsaPublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RsaPublicKey")
+            .field("bits", &self.key.n().bits())
+            .field("digest", &self.digest_hex())
+            .finish()
+    }
+}
+
+impl RsaPublicKey {
+    /// Parse a PKCS#1 `RSAPublicKey ::= SEQUENCE { modulus INTEGER, publicExponent INTEGER }`.
+    /// Moduli over 4096 bits and exponents other than 65537 are refused.
+    pub fn from_der(der: &[u8]) -> Result<Self, CryptoError> {
+        let invalid = || CryptoError::RsaError("malformed RSA public key".to_string());
+
+        let (tag, body, rest) = der_element(der).ok_or_else(invalid)?;
+        if tag != 0x30 || !rest.is_empty() {
+            return Err(invalid());
+        }
+        let (tag, modulus, body) = der_element(body).ok_or_else(invalid)?;
+        if tag != 0x02 {
+            return Err(invalid());
+        }
+        let (tag, exponent, body) = der_element(body).ok_or_else(invalid)?;
+        if tag != 0x02 || !body.is_empty() {
+            return Err(invalid());
+        }
+
+        let strip = |v: &[u8]| v.iter().skip_while(|&&b| b == 0).copied().collect::<Vec<u8>>();
+        let (modulus, exponent) = (strip(modulus), strip(exponent));
+        if modulus.is_empty() || exponent.is_empty() {
+            return Err(invalid());
+        }
+        if modulus.len() * 8 > RSA_MAX_BITS {
+            return Err(CryptoError::RsaError(format!("RSA modulus longer than {} bits", RSA_MAX_BITS)));
+        }
+        if exponent != RSA_EXPONENT {
+            return Err(CryptoError::RsaError("RSA public exponent isn't 65537".to_string()));
+        }
+        let key = ::rsa::RsaPublicKey::new(BigUint::from_bytes_be(&modulus), BigUint::from_bytes_be(&exponent))
+            .map_err(|e| CryptoError::RsaError(e.to_string()))?;
+        Ok(Self { der: der.to_vec(), key })
+    }
+
+    /// The subject key of a DER X.509 certificate, such as the RSA identity
+    /// certificate in a relay's CERTS cell. The certificate's own signature
+    /// isn't checked.
+    pub fn from_x509(der: &[u8]) -> Result<Self, CryptoError> {
+        let invalid = || CryptoError::RsaError("malformed X.509 certificate".to_string());
+
+        let (tag, certificate, _) = der_element(der).ok_or_else(invalid)?;
+        if tag != 0x30 {
+            return Err(invalid());
+        }
+        let (tag, mut tbs, _) = der_element(certificate).ok_or_else(invalid)?;
+        if tag != 0x30 {
+            return Err(invalid());
+        }
+        // Skip the optional [0] version, then serial, signature, issuer,
+        // validity and subject
+        if tbs.first() == Some(&0xa0) {
+            tbs = der_element(tbs).ok_or_else(invalid)?.2;
+        }
+        for _ in 0..5 {
+            tbs = der_element(tbs).ok_or_else(invalid)?.2;
+        }
+
+        // SubjectPublicKeyInfo ::= SEQUENCE { algorithm, subjectPublicKey BIT STRING }
+        let (tag, spki, _) = der_element(tbs).ok_or_else(invalid)?;
+        if tag != 0x30 {
+            return Err(invalid());
+        }
+        let (tag, algorithm, spki) = der_element(spki).ok_or_else(invalid)?;
+        if tag != 0x30 || !algorithm.starts_with(RSA_ENCRYPTION_OID) {
+            return Err(CryptoError::RsaError("certificate key isn't RSA".to_string()));
+        }
+        match der_element(spki).ok_or_else(invalid)? {
+            (0x03, [0, key @ ..], _) => Self::from_der(key),
+            _ => Err(invalid()),
+        }
+    }
+
+    /// Parse the first "-----BEGIN RSA PUBLIC KEY-----" block in `text`
+    pub fn from_pem(text: &str) -> Result<Self, CryptoError> {
+        let der = pem_decode(text, "RSA PUBLIC KEY")
+            .ok_or_else(|| CryptoError::RsaError("no RSA PUBLIC KEY block".to_string()))?;
+        Self::from_der(&der)
+    }
+
+    pub fn der(&self) -> &[u8] {
+        &self.der
+    }
+
+    /// SHA-1 of the DER encoding: Tor's key fingerprint, e.g. a relay's RSA identity
+    pub fn digest(&self) -> [u8; 20] {
+        let mut fingerprint = [0u8; 20];
+        fingerprint.copy_from_slice(digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &self.der).as_ref());
+        fingerprint
+    }
+
+    /// Upper-case hex SHA-1 of the DER encoding: Tor's key fingerprint
+    pub fn digest_hex(&self) -> String {
+        hex::encode_upper(self.digest())
+    }
+
+    /// Check a signature over a pre-computed digest, Tor style
+    /// (EMSA-PKCS1-v1_5 type 1 padding around the raw digest)
+    pub fn verify_digest(&self, expected_digest: &[u8], signature: &[u8]) -> bool {
+        self.key.verify(Pkcs1v15Sign::new_unprefixed(), expected_digest, signature).is_ok()
+    }
+}
+
+/// Decode the first PEM block labelled `label` in `text`
+pub fn pem_decode(text: &str, label: &str) -> Option<Vec<u8>> {
+    let begin = format!("-----BEGIN {}-----", label);
+    let end = format!("-----END {}-----", label);
+    let start = text.find(&begin)? + begin.len();
+    let stop = start + text[start..].find(&end)?;
+    let body: String = text[start..stop].split_whitespace().collect();
+    general_purpose::STANDARD.decode(body).ok()
+}
+
+/// Split one DER TLV off the front of `input`: (tag, contents, remainder)
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    let (&first, mut input) = input.split_first()?;
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || input.len() < count {
+            return None;
+        }
+        let (len_bytes, rest) = input.split_at(count);
+        input = rest;
+        len_bytes.iter().fold(0usize, |acc, &b| (acc << 8) | b as usize)
+    };
+    if input.len() < len {
+        return None;
+    }
+    let (contents, rest) = input.split_at(len);
+    Some((tag, contents, rest))
+}
@@ -0,0 +1,192 @@
+// src/crypto/tor_cert.rs
+//! Tor's Ed25519 certificates (cert-spec 2.1), as found in CERTS cells:
+//! VERSION(1) | CERT_TYPE(1) | EXPIRATION(4, hours) | KEY_TYPE(1) |
+//! CERTIFIED_KEY(32) | N_EXT(1) | EXTENSIONS | SIGNATURE(64)
+//!
+//! Also the RSA->Ed25519 cross-certificate (cert-spec 2.3) that ties a
+//! relay's Ed25519 identity to its legacy RSA one.
+use super::rsa::RsaPublicKey;
+use super::CryptoError;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Ed25519 identity key certifying the relay's signing key
+pub const CERT_TYPE_IDENTITY_V_SIGNING: u8 = 4;
+/// Signing key certifying the SHA-256 digest of the relay's TLS certificate
+pub const CERT_TYPE_SIGNING_V_TLS_CERT: u8 = 5;
+
+/// Self-signed X.509 certificate for the relay's RSA identity key
+pub const CERT_TYPE_RSA_IDENTITY: u8 = 2;
+/// RSA identity key cross-certifying the Ed25519 identity key (`RsaCrossCert`)
+pub const CERT_TYPE_RSA_ED25519_CROSSCERT: u8 = 7;
+
+/// CERTIFIED_KEY is an Ed25519 public key
+pub const CERT_KEY_TYPE_ED25519: u8 = 1;
+/// CERTIFIED_KEY is the SHA-256 digest of an X.509 certificate
+pub const CERT_KEY_TYPE_SHA256_OF_X509: u8 = 3;
+
+/// Extension carrying the Ed25519 key that signed the certificate
+const EXT_SIGNED_WITH_ED25519_KEY: u8 = 4;
+/// Extension flag: the certificate is invalid if the extension isn't understood
+const EXT_FLAG_AFFECTS_VALIDATION: u8 = 1;
+
+const HEADER_LEN: usize = 1 + 1 + 4 + 1 + 32 + 1;
+const SIGNATURE_LEN: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ed25519Cert {
+    pub cert_type: u8,
+    /// Hours since the epoch
+    pub expiration_hours: u32,
+    pub key_type: u8,
+    pub certified_key: [u8; 32],
+    /// The key that signed the certificate, if it's included
+    pub signing_key: Option<[u8; 32]>,
+    /// Everything the signature covers
+    signed: Vec<u8>,
+    signature: [u8; SIGNATURE_LEN],
+}
+
+impl Ed25519Cert {
+    pub fn parse(bytes: &[u8]) -> Result<Self, CryptoError> {
+        let invalid = |reason: &str| CryptoError::CertError(reason.to_string());
+        if bytes.len() < HEADER_LEN + SIGNATURE_LEN {
+            return Err(invalid("certificate too short"));
+        }
+        if bytes[0] != 1 {
+            return Err(invalid("unknown certificate version"));
+        }
+        let cert_type = bytes[1];
+        let expiration_hours = u32::from_be_bytes([bytes[2], bytes[3], bytes[4], bytes[5]]);
+        let key_type = bytes[6];
+        let mut certified_key = [0u8; 32];
+        certified_key.copy_from_slice(&bytes[7..39]);
+        let n_extensions = bytes[39];
+
+        let body_end = bytes.len() - SIGNATURE_LEN;
+        let mut rest = &bytes[HEADER_LEN..body_end];
+        let mut signing_key = None;
+        for _ in 0..n_extensions {
+            if rest.len() < 4 {
+                return Err(invalid("truncated extension"));
+            }
+            let len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+            let (ext_type, flags) = (rest[2], rest[3]);
+            let data = rest.get(4..4 + len).ok_or_else(|| invalid("truncated extension"))?;
+            match ext_type {
+                EXT_SIGNED_WITH_ED25519_KEY if len == 32 => {
+                    let mut key = [0u8; 32];
+                    key.copy_from_slice(data);
+                    signing_key = Some(key);
+                }
+                _ if flags & EXT_FLAG_AFFECTS_VALIDATION != 0 => {
+                    return Err(invalid("unknown extension that affects validation"));
+                }
+                _ => {}
+            }
+            rest = &rest[4 + len..];
+        }
+        if !rest.is_empty() {
+            return Err(invalid("trailing bytes before signature"));
+        }
+
+        let mut signature = [0u8; SIGNATURE_LEN];
+        signature.copy_from_slice(&bytes[body_end..]);
+        Ok(Self {
+            cert_type,
+            expiration_hours,
+            key_type,
+            certified_key,
+            signing_key,
+            signed: bytes[..body_end].to_vec(),
+            signature,
+        })
+    }
+
+    pub fn expires_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.expiration_hours as u64 * 3600)
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        now >= self.expires_at()
+    }
+
+    /// Whether `key` made the signature
+    pub fn verify(&self, key: &[u8; 32]) -> bool {
+        let Ok(key) = VerifyingKey::from_bytes(key) else {
+            return false;
+        };
+        key.verify(&self.signed, &Signature::from_bytes(&self.signature)).is_ok()
+    }
+
+    /// Issue a certificate for `certified_key`, signed by `signer` and naming
+    /// it in a signed-with-ed25519-key extension
+    pub fn sign(
+        cert_type: u8,
+        key_type: u8,
+        certified_key: &[u8; 32],
+        expires_at: SystemTime,
+        signer: &SigningKey,
+    ) -> Vec<u8> {
+        let hours = expires_at
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| (since.as_secs() / 3600) as u32);
+        let mut cert = vec![1, cert_type];
+        cert.extend_from_slice(&hours.to_be_bytes());
+        cert.push(key_type);
+        cert.extend_from_slice(certified_key);
+        cert.push(1);
+        cert.extend_from_slice(&32u16.to_be_bytes());
+        cert.extend_from_slice(&[EXT_SIGNED_WITH_ED25519_KEY, 0]);
+        cert.extend_from_slice(signer.verifying_key().as_bytes());
+        let signature = signer.sign(&cert);
+        cert.extend_from_slice(&signature.to_bytes());
+        cert
+    }
+}
+
+/// What an RSA->Ed25519 cross-certificate's signature covers, before its body
+const CROSSCERT_PREFIX: &[u8] = b"Tor TLS RSA/Ed25519 cross-certificate";
+
+/// ED25519_KEY(32) | EXPIRATION(4, hours) | SIGLEN(1) | SIGNATURE, signed by
+/// the relay's RSA identity key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RsaCrossCert {
+    pub ed25519_key: [u8; 32],
+    /// Hours since the epoch
+    pub expiration_hours: u32,
+    signature: Vec<u8>,
+}
+
+impl RsaCrossCert {
+    pub fn parse(bytes: &[u8]) -> Result<Self, CryptoError> {
+        let invalid = |reason: &str| CryptoError::CertError(reason.to_string());
+        if bytes.len() < 32 + 4 + 1 {
+            return Err(invalid("cross-certificate too short"));
+        }
+        let mut ed25519_key = [0u8; 32];
+        ed25519_key.copy_from_slice(&bytes[..32]);
+        let expiration_hours = u32::from_be_bytes([bytes[32], bytes[33], bytes[34], bytes[35]]);
+        let signature = &bytes[37..];
+        if signature.len() != bytes[36] as usize {
+            return Err(invalid("cross-certificate signature length doesn't match"));
+        }
+        Ok(Self { ed25519_key, expiration_hours, signature: signature.to_vec() })
+    }
+
+    pub fn expires_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.expiration_hours as u64 * 3600)
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        now >= self.expires_at()
+    }
+
+    /// Whether `key` signed SHA256(prefix | ED25519_KEY | EXPIRATION)
+    pub fn verify(&self, key: &RsaPublicKey) -> bool {
+        let mut signed = CROSSCERT_PREFIX.to_vec();
+        signed.extend_from_slice(&self.ed25519_key);
+        signed.extend_from_slice(&self.expiration_hours.to_be_bytes());
+        key.verify_digest(ring::digest::digest(&ring::digest::SHA256, &signed).as_ref(), &self.signature)
+    }
+}
@@ -0,0 +1,116 @@
+// src/directory/authority.rs
+//! Directory authorities and their key certificates, used to check who
+//! signed a consensus.
+use crate::crypto::rsa::{pem_decode, RsaPublicKey};
+use ring::digest;
+use std::time::SystemTime;
+
+/// A v3 directory authority trusted to sign the consensus
+#[derive(Debug, Clone, Copy)]
+pub struct DirectoryAuthority {
+    pub nickname: &'static str,
+    /// Hex SHA-1 fingerprint of the authority's v3 identity key
+    pub v3ident: &'static str,
+    /// Address of the authority's DirPort, serving its key certificates
+    pub dir_address: &'static str,
+}
+
+/// The authorities shipped with Tor (as listed in the consensus "dir-source" lines)
+pub const DIRECTORY_AUTHORITIES: &[DirectoryAuthority] = &[
+    DirectoryAuthority { nickname: "dannenberg", v3ident: "0232AF901C31A04EE9848595AF9BB7620D4C5B2E", dir_address: "193.23.244.244:80" },
+    DirectoryAuthority { nickname: "longclaw", v3ident: "23D15D965BC35114467363C165C4F724B64B4F66", dir_address: "199.58.81.140:80" },
+    DirectoryAuthority { nickname: "bastet", v3ident: "27102BC123E7AF1D4741AE047E160C91ADC76B21", dir_address: "204.13.164.118:80" },
+    DirectoryAuthority { nickname: "tor26", v3ident: "2F3DF9CA0E5D36F2685A2DA67184EB8DCB8CBA8C", dir_address: "217.196.147.77:80" },
+    DirectoryAuthority { nickname: "maatuska", v3ident: "49015F787433103580E3B66A1707A00E60F2D15B", dir_address: "171.25.193.9:443" },
+    DirectoryAuthority { nickname: "faravahar", v3ident: "70849B868D606BAECFB6128C5E3D782029AA394F", dir_address: "216.218.219.41:80" },
+    DirectoryAuthority { nickname: "dizum", v3ident: "E8A9C45EDE6D711294FADF8E7951F4DE6CA56B58", dir_address: "45.66.35.11:80" },
+    DirectoryAuthority { nickname: "gabelmoo", v3ident: "ED03BB616EB2F60BEC80151114BB25CEF515B226", dir_address: "131.188.40.189:80" },
+    DirectoryAuthority { nickname: "moria1", v3ident: "F533C81CEF0BC0267857C99B2F471ADF249FA232", dir_address: "128.31.0.39:9231" },
+];
+
+/// A verified "dir-key-certificate-3": an authority's identity key vouching
+/// for the medium-term signing key it signs consensuses with
+#[derive(Debug, Clone)]
+pub struct AuthorityCertificate {
+    /// Hex fingerprint of the identity key (the authority's v3ident)
+    pub fingerprint: String,
+    pub signing_key: RsaPublicKey,
+    pub expires: SystemTime,
+}
+
+impl AuthorityCertificate {
+    /// Hex SHA-1 of the signing key, as referenced by "directory-signature" lines
+    pub fn signing_key_digest(&self) -> String {
+        self.signing_key.digest_hex()
+    }
+
+    pub fn is_expired(&self) -> bool {
+        SystemTime::now() >= self.expires
+    }
+}
+
+/// Parse the key certificates in `text` from the `trusted` authorities (hex
+/// v3idents), keeping only those whose certification signature checks out
+/// against their own identity key. Anyone else's are skipped before their
+/// keys are even parsed.
+pub fn parse_authority_certificates(text: &str, trusted: &[String]) -> Vec<AuthorityCertificate> {
+    const START: &str = "dir-key-certificate-version";
+
+    let mut certificates = Vec::new();
+    let mut offsets: Vec<usize> = text.match_indices(START).map(|(i, _)| i).collect();
+    offsets.push(text.len());
+
+    for window in offsets.windows(2) {
+        let text = &text[window[0]..window[1]];
+        let Some(fingerprint) = certificate_fingerprint(text) else {
+            log::warn!("Discarding authority key certificate without a fingerprint");
+            continue;
+        };
+        if !trusted.contains(&fingerprint) {
+            log::debug!("Skipping key certificate of untrusted authority {}", fingerprint);
+            continue;
+        }
+        match parse_certificate(text, fingerprint) {
+            Some(cert) => certificates.push(cert),
+            None => log::warn!("Discarding invalid authority key certificate"),
+        }
+    }
+    certificates
+}
+
+/// The upper-case hex identity fingerprint a certificate claims to be for
+fn certificate_fingerprint(text: &str) -> Option<String> {
+    let fingerprint = text.lines().find_map(|line| line.strip_prefix("fingerprint "))?;
+    Some(fingerprint.trim().to_uppercase())
+}
+
+fn parse_certificate(text: &str, fingerprint: String) -> Option<AuthorityCertificate> {
+    let keyword_block = |keyword: &str| {
+        let start = text.find(&format!("\n{}\n", keyword))?;
+        Some(&text[start + keyword.len() + 2..])
+    };
+
+    let expires = text
+        .lines()
+        .find_map(|line| line.strip_prefix("dir-key-expires "))
+        .and_then(|value| chrono::NaiveDateTime::parse_from_str(value.trim(), "%Y-%m-%d %H:%M:%S").ok())?
+        .and_utc()
+        .into();
+
+    let identity_key = RsaPublicKey::from_pem(keyword_block("dir-identity-key")?).ok()?;
+    let signing_key = RsaPublicKey::from_pem(keyword_block("dir-signing-key")?).ok()?;
+    if identity_key.digest_hex() != fingerprint {
+        return None;
+    }
+
+    // Signed from the start of the certificate through "dir-key-certification\n"
+    const CERTIFICATION: &str = "\ndir-key-certification\n";
+    let signed_len = text.find(CERTIFICATION)? + CERTIFICATION.len();
+    let signature = pem_decode(&text[signed_len..], "SIGNATURE")?;
+    let signed_digest = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &text.as_bytes()[..signed_len]);
+    if !identity_key.verify_digest(signed_digest.as_ref(), &signature) {
+        return None;
+    }
+
+    Some(AuthorityCertificate { fingerprint, signing_key, expires })
+}
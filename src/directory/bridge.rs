@@ -0,0 +1,60 @@
+// src/directory/bridge.rs
+//! Bridges: unlisted relays used as the first hop when the consensus guards
+//! can't be reached. They aren't in the consensus, so everything needed to
+//! handshake with one comes from its configuration line.
+use super::{decode_unpadded_base64, DirectoryError, RelayDescriptor, RelayFlag};
+use base64::{engine::general_purpose, Engine as _};
+
+/// Parse a bridge line: `IP:ORPort FINGERPRINT NTOR-ONION-KEY [ED25519-ID]`,
+/// with the fingerprint in hex and the ntor onion key and optional Ed25519
+/// identity in base64 as they appear in the bridge's descriptor. A leading
+/// "Bridge" keyword is accepted, as in torrc.
+pub fn parse_bridge_line(line: &str) -> Result<RelayDescriptor, DirectoryError> {
+    let invalid = |reason: &str| DirectoryError::ParseError(format!("Bridge line \"{}\": {}", line, reason));
+
+    let mut parts = line.split_whitespace().peekable();
+    if parts.peek() == Some(&"Bridge") {
+        parts.next();
+    }
+    let address = parts
+        .next()
+        .ok_or_else(|| invalid("missing address"))?
+        .parse()
+        .map_err(|_| invalid("invalid address"))?;
+    let identity_key = hex::decode(parts.next().ok_or_else(|| invalid("missing fingerprint"))?)
+        .ok()
+        .filter(|id| id.len() == 20)
+        .ok_or_else(|| invalid("fingerprint must be 40 hex digits"))?;
+    let onion_key = decode_unpadded_base64(parts.next().ok_or_else(|| invalid("missing ntor onion key"))?)
+        .ok()
+        .filter(|key| key.len() == 32)
+        .ok_or_else(|| invalid("ntor onion key must be 32 bytes of base64"))?;
+    let ed25519_identity = parts
+        .next()
+        .map(|id| {
+            decode_unpadded_base64(id)
+                .ok()
+                .and_then(|id| <[u8; 32]>::try_from(id).ok())
+                .ok_or_else(|| invalid("Ed25519 identity must be 32 bytes of base64"))
+        })
+        .transpose()?;
+
+    Ok(RelayDescriptor {
+        id: general_purpose::STANDARD_NO_PAD.encode(&identity_key),
+        nickname: "Bridge".to_string(),
+        address,
+        identity_key,
+        onion_key,
+        ed25519_identity,
+        // Unknown; bridges are picked uniformly
+        bandwidth: 0,
+        flags: vec![RelayFlag::Running, RelayFlag::Valid],
+        platform: None,
+        microdesc_digest: None,
+        exit_policy: None,
+        exit_policy_v6: None,
+        ipv6_address: None,
+        protocols: None,
+        family: Vec::new(),
+    })
+}
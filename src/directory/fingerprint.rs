@@ -0,0 +1,29 @@
+// src/directory/fingerprint.rs
+//! Relay identity fingerprints in their two spellings: the unpadded base64
+//! of consensus "r" lines (our relay ids), and the uppercase hex that
+//! torrc options, controllers and Relay Search use
+use super::decode_unpadded_base64;
+use base64::{engine::general_purpose, Engine as _};
+
+/// RSA identity digests are SHA-1 sized
+const FINGERPRINT_LEN: usize = 20;
+
+/// "AAgYiZwp6HDQSMHR8lyrau/kF10" -> "000818899C29E870D048C1D1F25CAB6AEFE4175D"
+pub fn base64_to_hex(id: &str) -> Option<String> {
+    let identity = decode_unpadded_base64(id).ok()?;
+    (identity.len() == FINGERPRINT_LEN).then(|| hex::encode_upper(identity))
+}
+
+/// The reverse of `base64_to_hex`. Accepts either case, a leading '$' and
+/// Tor's space-separated groups of four.
+pub fn hex_to_base64(fingerprint: &str) -> Option<String> {
+    let digits: String = fingerprint.trim().trim_start_matches('$').split_whitespace().collect();
+    let identity = hex::decode(digits).ok()?;
+    (identity.len() == FINGERPRINT_LEN).then(|| general_purpose::STANDARD_NO_PAD.encode(identity))
+}
+
+/// Whether a configured relay (`$`-prefixed or bare hex fingerprint, base64
+/// id, or nickname) names the relay with this id and nickname
+pub fn names_relay(configured: &str, id: &str, nickname: &str) -> bool {
+    configured == id || configured == nickname || hex_to_base64(configured).is_some_and(|configured| configured == id)
+}
@@ -0,0 +1,72 @@
+// src/directory/guards.rs
+//! Entry guards: the small set of relays we use as the first hop, kept under
+//! `data_directory` so the client enters the network the same way across restarts.
+use super::{NetworkConsensus, RelayDescriptor, RelayFlag};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::SystemTime;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GuardEntry {
+    pub relay_id: String,
+    pub nickname: String,
+    /// When the relay first became one of our guards
+    pub added_at: SystemTime,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GuardSet {
+    pub guards: Vec<GuardEntry>,
+}
+
+impl GuardSet {
+    /// Read a guard set written by `save`; None if missing or unreadable
+    pub fn load(path: &Path) -> Option<Self> {
+        let data = std::fs::read(path).ok()?;
+        match serde_json::from_slice(&data) {
+            Ok(guards) => Some(guards),
+            Err(e) => {
+                log::warn!("Ignoring unreadable guard file {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        // Write then rename so a crash never leaves a truncated file behind
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec(self)?)?;
+        std::fs::rename(&tmp, path)
+    }
+
+    pub fn contains(&self, relay_id: &str) -> bool {
+        self.guards.iter().any(|guard| guard.relay_id == relay_id)
+    }
+
+    /// Drop the guards `consensus` no longer lists, or lists without the
+    /// Running flag, and return them. The others keep their place.
+    pub fn reconcile(&mut self, consensus: &NetworkConsensus) -> Vec<GuardEntry> {
+        let (kept, down) = std::mem::take(&mut self.guards).into_iter().partition(|guard| {
+            consensus
+                .relays
+                .get(&guard.relay_id)
+                .is_some_and(|relay| relay.flags.contains(&RelayFlag::Running))
+        });
+        self.guards = kept;
+        down
+    }
+
+    /// Add `relay` to the set, unless it's already in it
+    pub fn record(&mut self, relay: &RelayDescriptor) {
+        if !self.contains(&relay.id) {
+            self.guards.push(GuardEntry {
+                relay_id: relay.id.clone(),
+                nickname: relay.nickname.clone(),
+                added_at: SystemTime::now(),
+            });
+        }
+    }
+}
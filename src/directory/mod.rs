@@ -1,20 +1,111 @@
 // src/directory/mod.rs
+pub mod authority;
+pub mod bridge;
+pub mod fingerprint;
+pub mod guards;
+pub mod policy;
+pub mod probe;
+pub mod rate_limit;
+pub mod source;
+
+use crate::bootstrap::{Bootstrap, BootstrapPhase};
+use authority::{parse_authority_certificates, AuthorityCertificate, DIRECTORY_AUTHORITIES};
+use ring::digest;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::{Duration, SystemTime};
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, RwLock};
 use rand::Rng;
 use chrono::{Utc, Timelike, Datelike};
 use base64::{Engine as _, engine::general_purpose};
+use guards::{GuardEntry, GuardSet};
+use policy::{exit_allows, ExitPolicySummary, ExitTarget};
+use probe::HopReachability;
+use rate_limit::TokenBucket;
+use source::ConsensusSource;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ConsensusSignature {
     pub algorithm: String,
     pub identity: String,
+    /// Hex digest of the signing key, matched against authority certificates
+    #[serde(default)]
+    pub signing_key_digest: String,
     pub signature: Vec<u8>,
 }
 
+/// Parse the "directory-signature [algorithm] identity signing-key-digest"
+/// entries from a consensus footer
+pub fn parse_consensus_signatures(text: &str) -> Vec<ConsensusSignature> {
+    let mut signatures = Vec::new();
+    for (start, _) in text.match_indices("directory-signature ") {
+        let rest = &text[start..];
+        let header = rest.lines().next().unwrap_or_default();
+        let parts: Vec<&str> = header.split_whitespace().skip(1).collect();
+        // The algorithm is omitted for sha1 signatures
+        let (algorithm, identity, signing_key_digest) = match parts.as_slice() {
+            [identity, digest] => ("sha1", *identity, *digest),
+            [algorithm, identity, digest] => (*algorithm, *identity, *digest),
+            _ => continue,
+        };
+        let Some(signature) = crate::crypto::rsa::pem_decode(rest, "SIGNATURE") else {
+            continue;
+        };
+        signatures.push(ConsensusSignature {
+            algorithm: algorithm.to_string(),
+            identity: identity.to_uppercase(),
+            signing_key_digest: signing_key_digest.to_uppercase(),
+            signature,
+        });
+    }
+    signatures
+}
+
+/// Thresholds the authorities used when assigning flags, from a
+/// "flag-thresholds" line. Bandwidths are in bytes/s, times in seconds.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FlagThresholds {
+    pub stable_uptime: Option<u64>,
+    pub stable_mtbf: Option<u64>,
+    pub enough_mtbf: Option<bool>,
+    pub fast_speed: Option<u64>,
+    /// Weighted fractional uptime required for Guard, as a percentage
+    pub guard_wfu: Option<f64>,
+    pub guard_tk: Option<u64>,
+    pub guard_bw_inc_exits: Option<u64>,
+    pub guard_bw_exc_exits: Option<u64>,
+    pub ignoring_advertised_bws: Option<bool>,
+}
+
+/// Parse the key=value pairs of a "flag-thresholds" line (with or without the
+/// keyword). Unknown keys and malformed values are skipped.
+pub fn parse_flag_thresholds(line: &str) -> FlagThresholds {
+    let line = line.trim();
+    let line = line.strip_prefix("flag-thresholds").unwrap_or(line);
+    let mut thresholds = FlagThresholds::default();
+
+    for (key, value) in line.split_whitespace().filter_map(|kv| kv.split_once('=')) {
+        let number = || value.parse::<u64>().ok();
+        let flag = || value.parse::<u8>().ok().map(|v| v != 0);
+        match key {
+            "stable-uptime" => thresholds.stable_uptime = number(),
+            "stable-mtbf" => thresholds.stable_mtbf = number(),
+            "enough-mtbf" => thresholds.enough_mtbf = flag(),
+            "fast-speed" => thresholds.fast_speed = number(),
+            "guard-wfu" => thresholds.guard_wfu = value.trim_end_matches('%').parse().ok(),
+            "guard-tk" => thresholds.guard_tk = number(),
+            "guard-bw-inc-exits" => thresholds.guard_bw_inc_exits = number(),
+            "guard-bw-exc-exits" => thresholds.guard_bw_exc_exits = number(),
+            "ignoring-advertised-bws" => thresholds.ignoring_advertised_bws = flag(),
+            _ => {}
+        }
+    }
+    thresholds
+}
+
 #[derive(Debug)]
 pub enum DirectoryError {
     NoSuitableRelays,
@@ -43,8 +134,305 @@ pub struct RelayDescriptor {
     pub address: SocketAddr,
     pub identity_key: Vec<u8>,
     pub onion_key: Vec<u8>,
+    /// Ed25519 identity key from the microdescriptor's "id ed25519" line
+    #[serde(default)]
+    pub ed25519_identity: Option<[u8; 32]>,
     pub bandwidth: u32,
     pub flags: Vec<RelayFlag>,
+    /// Software version from the consensus "v" line, e.g. "Tor 0.4.8.10"
+    #[serde(default)]
+    pub platform: Option<String>,
+    /// Base64 SHA-256 digest of the relay's microdescriptor (consensus "m" line)
+    #[serde(default)]
+    pub microdesc_digest: Option<String>,
+    /// Exit policy summary ("p" line), from the consensus or the microdescriptor
+    #[serde(default)]
+    pub exit_policy: Option<ExitPolicySummary>,
+    /// IPv6 exit policy summary ("p6" line); absent if the relay doesn't exit to IPv6
+    #[serde(default)]
+    pub exit_policy_v6: Option<ExitPolicySummary>,
+    /// IPv6 ORPort from the consensus "a" line, if the relay has one
+    #[serde(default)]
+    pub ipv6_address: Option<SocketAddr>,
+    /// Subprotocol versions from the "pr" line, e.g. "Link=1-5 Relay=1-4"
+    #[serde(default)]
+    pub protocols: Option<String>,
+    /// Relays run by the same operator, from the microdescriptor's "family"
+    /// line: "$"-prefixed hex fingerprints (maybe with "~nickname") or nicknames
+    #[serde(default)]
+    pub family: Vec<String>,
+}
+
+impl RelayDescriptor {
+    /// Whether we know the relay's curve25519 ntor onion key, i.e. can handshake with it
+    pub fn has_ntor_onion_key(&self) -> bool {
+        self.onion_key.len() == 32
+    }
+
+    /// Numeric Tor version parsed from `platform`, if known
+    pub fn tor_version(&self) -> Option<Vec<u32>> {
+        self.platform.as_deref().and_then(parse_tor_version)
+    }
+
+    /// Whether the relay's "pr" line lists `version` of subprotocol `name`.
+    /// False if we don't know its protocols.
+    pub fn supports_protocol(&self, name: &str, version: u32) -> bool {
+        self.protocols
+            .as_deref()
+            .is_some_and(|protocols| protocol_versions_include(protocols, name, version))
+    }
+
+    /// Whether the relay's exit policy allows streams to `target`
+    pub fn allows_exit_to(&self, target: ExitTarget) -> bool {
+        exit_allows(self.exit_policy.as_ref(), self.exit_policy_v6.as_ref(), target)
+    }
+
+    /// The ORPort to connect to: the IPv6 one if `prefer_ipv6` and the relay
+    /// has one, otherwise the IPv4 one from the "r" line
+    pub fn or_address(&self, prefer_ipv6: bool) -> SocketAddr {
+        match self.ipv6_address {
+            Some(ipv6) if prefer_ipv6 => ipv6,
+            _ => self.address,
+        }
+    }
+
+    /// Whether the two relays are in one family: either lists the other, or
+    /// both list a common member. A relay is in its own family.
+    pub fn in_family_with(&self, other: &RelayDescriptor) -> bool {
+        let lists = |relay: &RelayDescriptor, member: &RelayDescriptor| {
+            relay
+                .family
+                .iter()
+                .any(|entry| fingerprint::names_relay(family_entry_name(entry), &member.id, &member.nickname))
+        };
+        self.id == other.id
+            || lists(self, other)
+            || lists(other, self)
+            || self.family.iter().any(|a| other.family.iter().any(|b| family_members_match(a, b)))
+    }
+
+    /// Whether the relays' IPv4 ORPorts share a /16, or their IPv6 ones a /32,
+    /// which Tor treats as probably under one operator's control
+    pub fn shares_subnet_with(&self, other: &RelayDescriptor) -> bool {
+        let same_subnet = |a: IpAddr, b: IpAddr| match (a, b) {
+            (IpAddr::V4(a), IpAddr::V4(b)) => a.octets()[..2] == b.octets()[..2],
+            (IpAddr::V6(a), IpAddr::V6(b)) => a.segments()[..2] == b.segments()[..2],
+            _ => false,
+        };
+        same_subnet(self.address.ip(), other.address.ip())
+            || matches!((self.ipv6_address, other.ipv6_address), (Some(a), Some(b)) if same_subnet(a.ip(), b.ip()))
+    }
+}
+
+/// A "family" entry without the nickname some fingerprints carry ("$HEX~nick")
+fn family_entry_name(entry: &str) -> &str {
+    entry.split(['~', '=']).next().unwrap_or(entry)
+}
+
+/// Whether two "family" entries name the same relay, however each is spelled
+fn family_members_match(a: &str, b: &str) -> bool {
+    let (a, b) = (family_entry_name(a), family_entry_name(b));
+    match (fingerprint::hex_to_base64(a), fingerprint::hex_to_base64(b)) {
+        (Some(a), Some(b)) => a == b,
+        (None, None) => a.eq_ignore_ascii_case(b),
+        _ => false,
+    }
+}
+
+fn collector_url(base: &str, timestamp: &chrono::DateTime<Utc>, suffix: &str) -> String {
+    format!("{}/{}", base, collector_name(timestamp, suffix))
+}
+
+/// Collector's name for the document of type `suffix` published at `timestamp`'s hour
+fn collector_name(timestamp: &chrono::DateTime<Utc>, suffix: &str) -> String {
+    format!(
+        "{:04}-{:02}-{:02}-{:02}-00-00-{}",
+        timestamp.year(),
+        timestamp.month(),
+        timestamp.day(),
+        timestamp.hour(),
+        suffix
+    )
+}
+
+/// Parse a consensus header timestamp ("2024-01-02 15:00:00", always UTC)
+fn parse_consensus_time(value: &str) -> Result<SystemTime, DirectoryError> {
+    chrono::NaiveDateTime::parse_from_str(value.trim(), "%Y-%m-%d %H:%M:%S")
+        .map(|t| t.and_utc().into())
+        .map_err(|e| DirectoryError::ParseError(format!("Invalid consensus time {:?}: {}", value, e)))
+}
+
+/// The position (0 guard, 1 middle, 2 exit) hop `hop_num` of a `num_hops`
+/// circuit is picked for: circuits longer than three hops get extra middles,
+/// and shorter ones, like internal circuits, have no exit
+pub fn path_position(hop_num: usize, num_hops: usize, internal: bool) -> usize {
+    match hop_num {
+        0 => 0,
+        n if n + 1 == num_hops && num_hops >= 3 && !internal => 2,
+        _ => 1,
+    }
+}
+
+/// A relay's block in the consensus runs until the next "r" line or the footer
+fn is_relay_block_end(line: &str) -> bool {
+    let line = line.trim();
+    line.starts_with("r ") || line == "directory-footer"
+}
+
+/// Read a cached consensus, returning it with the time it was written if it
+/// hasn't expired yet
+fn load_cached_consensus(path: &Path) -> Option<(NetworkConsensus, SystemTime)> {
+    let data = std::fs::read(path).ok()?;
+    let consensus: NetworkConsensus = match serde_json::from_slice(&data) {
+        Ok(consensus) => consensus,
+        Err(e) => {
+            log::warn!("Ignoring unreadable cached consensus {}: {}", path.display(), e);
+            return None;
+        }
+    };
+    if SystemTime::now() >= consensus.valid_until {
+        log::info!("Cached consensus in {} has expired", path.display());
+        return None;
+    }
+    let fetched_at = std::fs::metadata(path).and_then(|m| m.modified()).unwrap_or(consensus.valid_after);
+    Some((consensus, fetched_at))
+}
+
+fn save_cached_consensus(path: &Path, consensus: &NetworkConsensus) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    // Write then rename so a crash never leaves a truncated cache behind
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_vec(consensus)?)?;
+    std::fs::rename(&tmp, path)
+}
+
+/// Decode Tor's unpadded base64 (standard or URL-safe alphabet)
+fn decode_unpadded_base64(value: &str) -> Result<Vec<u8>, base64::DecodeError> {
+    let mut padded = value.trim().replace('-', "+").replace('_', "/");
+    while !padded.len().is_multiple_of(4) {
+        padded.push('=');
+    }
+    general_purpose::STANDARD.decode(&padded)
+}
+
+/// The parts of a microdescriptor we use
+#[derive(Debug, Clone, PartialEq)]
+pub struct Microdescriptor {
+    pub ntor_onion_key: [u8; 32],
+    pub ed25519_identity: Option<[u8; 32]>,
+    pub exit_policy: Option<ExitPolicySummary>,
+    pub exit_policy_v6: Option<ExitPolicySummary>,
+    pub family: Vec<String>,
+}
+
+/// Split a microdescriptor document and map each descriptor's digest (unpadded
+/// base64 SHA-256 of its text, as used in consensus "m" lines) to its contents
+pub fn parse_microdescriptors(text: &str) -> HashMap<String, Microdescriptor> {
+    struct Current {
+        text: String,
+        ntor_key: Option<[u8; 32]>,
+        ed25519_identity: Option<[u8; 32]>,
+        exit_policy: Option<ExitPolicySummary>,
+        exit_policy_v6: Option<ExitPolicySummary>,
+        family: Vec<String>,
+    }
+
+    fn finish(current: &mut Current, out: &mut HashMap<String, Microdescriptor>) {
+        if let Some(key) = current.ntor_key.take() {
+            let digest = ring::digest::digest(&ring::digest::SHA256, current.text.as_bytes());
+            let digest = general_purpose::STANDARD_NO_PAD.encode(digest.as_ref());
+            out.insert(
+                digest,
+                Microdescriptor {
+                    ntor_onion_key: key,
+                    ed25519_identity: current.ed25519_identity.take(),
+                    exit_policy: current.exit_policy.take(),
+                    exit_policy_v6: current.exit_policy_v6.take(),
+                    family: std::mem::take(&mut current.family),
+                },
+            );
+        }
+        current.ed25519_identity = None;
+        current.exit_policy = None;
+        current.exit_policy_v6 = None;
+        current.family.clear();
+        current.text.clear();
+    }
+
+    let mut microdescs = HashMap::new();
+    let mut current = Current {
+        text: String::new(),
+        ntor_key: None,
+        ed25519_identity: None,
+        exit_policy: None,
+        exit_policy_v6: None,
+        family: Vec::new(),
+    };
+
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim_end();
+        // A descriptor starts at its (legacy) "onion-key", or at "ntor-onion-key" when
+        // the relay no longer publishes a TAP key; annotations sit between descriptors
+        let starts_new = trimmed == "onion-key"
+            || (trimmed.starts_with("ntor-onion-key ") && current.ntor_key.is_some());
+        if trimmed.starts_with('@') || starts_new {
+            finish(&mut current, &mut microdescs);
+            if trimmed.starts_with('@') {
+                continue;
+            }
+        }
+
+        if let Some(key) = trimmed.strip_prefix("ntor-onion-key ") {
+            match decode_unpadded_base64(key).ok().and_then(|k| <[u8; 32]>::try_from(k).ok()) {
+                Some(key) => current.ntor_key = Some(key),
+                None => log::warn!("Invalid ntor-onion-key in microdescriptor: {}", key),
+            }
+        } else if let Some(key) = trimmed.strip_prefix("id ed25519 ") {
+            match decode_unpadded_base64(key).ok().and_then(|k| <[u8; 32]>::try_from(k).ok()) {
+                Some(key) => current.ed25519_identity = Some(key),
+                None => log::warn!("Invalid ed25519 id in microdescriptor: {}", key),
+            }
+        } else if let Some(summary) = trimmed.strip_prefix("p ") {
+            current.exit_policy = ExitPolicySummary::parse(summary);
+        } else if let Some(summary) = trimmed.strip_prefix("p6 ") {
+            current.exit_policy_v6 = ExitPolicySummary::parse(summary);
+        } else if let Some(members) = trimmed.strip_prefix("family ") {
+            current.family = members.split_whitespace().map(str::to_string).collect();
+        }
+        if !current.text.is_empty() || !trimmed.is_empty() {
+            current.text.push_str(line);
+        }
+    }
+    finish(&mut current, &mut microdescs);
+
+    microdescs
+}
+
+/// Parse "Tor 0.4.8.10", "0.4.9.1-alpha" or "0.4.7.0" into numeric components
+pub fn parse_tor_version(version: &str) -> Option<Vec<u32>> {
+    let version = version.trim();
+    let version = version.strip_prefix("Tor ").unwrap_or(version);
+    // Drop status tags and anything after the dotted numbers ("-alpha", " (git-...)")
+    let numeric = version.split(|c: char| c == '-' || c.is_whitespace()).next()?;
+    numeric.split('.').map(|part| part.parse().ok()).collect()
+}
+
+/// Whether a protocol list like "Link=1-5 Relay=1-2,4" includes `name` at `version`
+pub fn protocol_versions_include(protocols: &str, name: &str, version: u32) -> bool {
+    protocols
+        .split_whitespace()
+        .filter_map(|entry| entry.split_once('='))
+        .filter(|(proto, _)| *proto == name)
+        .flat_map(|(_, versions)| versions.split(','))
+        .any(|range| match range.split_once('-') {
+            Some((low, high)) => matches!(
+                (low.parse::<u32>(), high.parse::<u32>()),
+                (Ok(low), Ok(high)) if (low..=high).contains(&version)
+            ),
+            None => range.parse() == Ok(version),
+        })
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -63,84 +451,649 @@ pub enum RelayFlag {
     Unknown(String),
 }
 
+impl std::fmt::Display for RelayFlag {
+    /// The flag as spelled on a consensus "s" line
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RelayFlag::Unknown(flag) => f.write_str(flag),
+            known => write!(f, "{:?}", known),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NetworkConsensus {
     pub valid_after: SystemTime,
+    /// A newer consensus should be available after this point
+    pub fresh_until: SystemTime,
+    /// The consensus must not be used after this point
     pub valid_until: SystemTime,
     pub relays: HashMap<String, RelayDescriptor>,
     pub signatures: Vec<ConsensusSignature>,
+    #[serde(default)]
+    pub flag_thresholds: Option<FlagThresholds>,
+    /// Position weights from the "bandwidth-weights" footer line (Wgg, Wee, ...),
+    /// out of `BANDWIDTH_WEIGHT_SCALE`
+    #[serde(default)]
+    pub bandwidth_weights: HashMap<String, i64>,
 }
 
-const TOR_COLLECTOR_BASE: &str = "https://collector.torproject.org/recent/relay-descriptors/consensuses";
+/// Denominator of the consensus bandwidth weights
+pub const BANDWIDTH_WEIGHT_SCALE: i64 = 10000;
+
+impl NetworkConsensus {
+    /// Flag assignment criteria published with the document, if any
+    pub fn flag_thresholds(&self) -> Option<&FlagThresholds> {
+        self.flag_thresholds.as_ref()
+    }
+
+    /// A bandwidth weight such as "Wee", defaulting to an unweighted 1.0
+    /// (`BANDWIDTH_WEIGHT_SCALE`) when the consensus doesn't give one
+    pub fn bandwidth_weight(&self, name: &str) -> i64 {
+        self.bandwidth_weights.get(name).copied().unwrap_or(BANDWIDTH_WEIGHT_SCALE)
+    }
+
+    /// The weight for `relay` at path position `hop` (0 guard, 2 exit, else
+    /// middle), e.g. Wmg for a Guard relay used as a middle or Wed for a
+    /// Guard+Exit relay used as an exit
+    pub fn position_weight(&self, relay: &RelayDescriptor, hop: usize) -> i64 {
+        let position = match hop {
+            0 => 'g',
+            2 => 'e',
+            _ => 'm',
+        };
+        let is_exit = relay.flags.contains(&RelayFlag::Exit) && !relay.flags.contains(&RelayFlag::BadExit);
+        let class = match (relay.flags.contains(&RelayFlag::Guard), is_exit) {
+            (true, true) => 'd',
+            (true, false) => 'g',
+            (false, true) => 'e',
+            (false, false) => 'm',
+        };
+        self.bandwidth_weight(&format!("W{}{}", position, class))
+    }
+}
 
 #[derive(Debug)]
 pub struct DirectoryClient {
     consensus: RwLock<Option<NetworkConsensus>>,
     last_update: RwLock<SystemTime>,
     use_real_consensus: bool,
+    /// Where real consensuses are fetched from, in order of preference
+    sources: Vec<ConsensusSource>,
+    /// The Collector that "tor-collector" sources and microdescriptors come from
+    collector: String,
+    min_relay_version: Option<Vec<u32>>,
+    /// v3 identity fingerprints whose signatures count towards a majority
+    trusted_authorities: Vec<String>,
+    authority_certs: RwLock<Vec<AuthorityCertificate>>,
+    /// Relays that recently failed to connect or handshake, by relay id
+    relay_failures: RwLock<HashMap<String, RelayFailure>>,
+    failure_cooldown: Duration,
+    /// Where the last verified consensus is cached between runs
+    cache_path: Option<PathBuf>,
+    /// Middles without an explicit Middle flag must be Stable as well as Fast
+    require_stable_middle: bool,
+    /// Configured bridges; when non-empty they replace consensus guards as hop 0
+    bridges: Vec<RelayDescriptor>,
+    /// Refetch a consensus we've held this long, even if it's still valid
+    max_consensus_age: Duration,
+    /// Where `save_state` writes the guard set and consensus
+    data_directory: Option<PathBuf>,
+    /// Our entry guards: the only relays used as the first hop
+    guards: RwLock<GuardSet>,
+    /// Configured guards (relay ids or nicknames) that replace the sampled set
+    entry_guards: Vec<String>,
+    /// How many guards to sample
+    num_guards: usize,
+    /// Sampled guards are replaced once they've been ours this long
+    guard_lifetime: Duration,
+    /// Paces consensus fetches so repeated refreshes don't hammer the collector
+    fetch_limiter: Mutex<TokenBucket>,
+    /// Where fetching directory information is reported as bootstrap progress
+    bootstrap: Arc<Bootstrap>,
+    /// Sort relays into per-position buckets once per consensus, rather than
+    /// filtering the whole consensus for every hop
+    bucket_relays: bool,
+    /// Buckets for the current consensus; cleared whenever it changes
+    buckets: RwLock<Option<Arc<RelayBuckets>>>,
+    /// Keep relays sharing a /16 out of the same path
+    distinct_subnets: bool,
+    /// Our own addresses; relays listening on one are never selected
+    local_addresses: Vec<IpAddr>,
+    /// Relays (fingerprints, ids or nicknames) the next selections return, in order
+    forced_selections: Mutex<VecDeque<String>>,
+    /// Share of consensus fetches made to fail on purpose, for resilience testing
+    fetch_failure_rate: f64,
+    /// Tries at a consensus fetch before a refresh gives up
+    fetch_attempts: usize,
+    /// Pause after the first failed try; doubles with each one after
+    fetch_retry_delay: Duration,
+}
+
+/// Ids of the relays eligible for each path position in one consensus
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RelayBuckets {
+    pub guards: Vec<String>,
+    pub middles: Vec<String>,
+    pub exits: Vec<String>,
+}
+
+impl RelayBuckets {
+    /// The bucket for path position `hop` (0 guard, 1 middle, 2 exit)
+    pub fn for_hop(&self, hop: usize) -> Option<&[String]> {
+        match hop {
+            0 => Some(&self.guards),
+            1 => Some(&self.middles),
+            2 => Some(&self.exits),
+            _ => None,
+        }
+    }
+}
+
+const CONSENSUS_CACHE_FILE: &str = "cached-consensus.json";
+const GUARDS_FILE: &str = "guards.json";
+
+/// How long a relay is passed over after failing; doubles with each
+/// consecutive failure, up to `MAX_FAILURE_BACKOFF` times
+const DEFAULT_FAILURE_COOLDOWN: Duration = Duration::from_secs(60);
+const MAX_FAILURE_BACKOFF: u32 = 16;
+
+const DEFAULT_MAX_CONSENSUS_AGE: Duration = Duration::from_secs(3600);
+
+/// A real fetch already falls back across sources and hours, so by default
+/// a failed one isn't retried
+const DEFAULT_FETCH_ATTEMPTS: usize = 1;
+const DEFAULT_FETCH_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Consensus fetches allowed back to back, and how fast that allowance comes back
+const DEFAULT_FETCH_BURST: u32 = 2;
+const DEFAULT_FETCH_INTERVAL: Duration = Duration::from_secs(60);
+
+const DEFAULT_NUM_GUARDS: usize = 3;
+const DEFAULT_GUARD_LIFETIME: Duration = Duration::from_secs(60 * 24 * 3600);
+
+#[derive(Debug, Clone, Copy)]
+struct RelayFailure {
+    consecutive: u32,
+    retry_after: std::time::Instant,
+}
+
+fn default_trusted_authorities() -> Vec<String> {
+    DIRECTORY_AUTHORITIES.iter().map(|a| a.v3ident.to_string()).collect()
 }
 
 impl DirectoryClient {
-    pub fn new(_authorities: Vec<String>) -> Self {
-        log::info!("DirectoryClient initialized with Tor Collector API");
+    fn with_source(use_real_consensus: bool, consensus: Option<NetworkConsensus>) -> Self {
+        let last_update = if consensus.is_some() { SystemTime::now() } else { SystemTime::UNIX_EPOCH };
         Self {
-            consensus: RwLock::new(None),
-            last_update: RwLock::new(SystemTime::UNIX_EPOCH),
-            use_real_consensus: true,
+            consensus: RwLock::new(consensus),
+            last_update: RwLock::new(last_update),
+            use_real_consensus,
+            sources: vec![ConsensusSource::Archive(source::TOR_COLLECTOR_BASE.to_string())],
+            collector: source::TOR_COLLECTOR.to_string(),
+            min_relay_version: None,
+            trusted_authorities: default_trusted_authorities(),
+            authority_certs: RwLock::new(Vec::new()),
+            relay_failures: RwLock::new(HashMap::new()),
+            failure_cooldown: DEFAULT_FAILURE_COOLDOWN,
+            cache_path: None,
+            require_stable_middle: true,
+            bridges: Vec::new(),
+            max_consensus_age: DEFAULT_MAX_CONSENSUS_AGE,
+            data_directory: None,
+            guards: RwLock::new(GuardSet::default()),
+            entry_guards: Vec::new(),
+            num_guards: DEFAULT_NUM_GUARDS,
+            guard_lifetime: DEFAULT_GUARD_LIFETIME,
+            fetch_limiter: Mutex::new(TokenBucket::new(DEFAULT_FETCH_BURST, DEFAULT_FETCH_INTERVAL)),
+            bootstrap: Arc::new(Bootstrap::new()),
+            bucket_relays: false,
+            buckets: RwLock::new(None),
+            // Mock networks put every relay in one subnet
+            distinct_subnets: use_real_consensus,
+            local_addresses: Vec::new(),
+            forced_selections: Mutex::new(VecDeque::new()),
+            fetch_failure_rate: 0.0,
+            fetch_attempts: DEFAULT_FETCH_ATTEMPTS,
+            fetch_retry_delay: DEFAULT_FETCH_RETRY_DELAY,
+        }
+    }
+
+    /// Client fetching real consensuses from `sources`, tried in order for each
+    /// hour: Collector-style archive URLs, DirPort mirrors ("http://host:port")
+    /// and local directories (see `ConsensusSource::parse`). Collector if empty.
+    pub fn new(sources: Vec<String>) -> Self {
+        let mut client = Self::with_source(true, None);
+        if !sources.is_empty() {
+            client.sources = sources.iter().map(|s| ConsensusSource::parse(s)).collect();
+        }
+        log::info!(
+            "DirectoryClient initialized with consensus sources: {}",
+            client.sources.iter().map(|s| s.to_string()).collect::<Vec<_>>().join(", ")
+        );
+        client
+    }
+
+    /// Where real consensuses are fetched from, in the order they're tried
+    pub fn consensus_sources(&self) -> &[ConsensusSource] {
+        &self.sources
+    }
+
+    /// Fetch from the Collector at `url` ("https://host", e.g. a mirror or a
+    /// test server) instead of collector.torproject.org: both Collector
+    /// consensus sources and microdescriptors. None keeps the default.
+    pub fn with_collector(mut self, url: Option<&str>) -> Self {
+        let Some(url) = url else {
+            return self;
+        };
+        let collector = url.trim().trim_end_matches('/').to_string();
+        let default = ConsensusSource::Archive(source::TOR_COLLECTOR_BASE.to_string());
+        for source in self.sources.iter_mut().filter(|s| **s == default) {
+            *source = ConsensusSource::Archive(source::collector_consensus_base(&collector));
         }
+        log::info!("Using Collector {}", collector);
+        self.collector = collector;
+        self
     }
 
     pub fn new_mock() -> Self {
         log::info!("DirectoryClient initialized with mock data");
-        Self {
-            consensus: RwLock::new(None),
-            last_update: RwLock::new(SystemTime::UNIX_EPOCH),
-            use_real_consensus: false,
+        Self::with_source(false, None)
+    }
+
+    /// Mock client serving a fixed, caller-supplied consensus
+    pub fn from_consensus(consensus: NetworkConsensus) -> Self {
+        log::info!("DirectoryClient initialized with {} supplied relays", consensus.relays.len());
+        Self::with_source(false, Some(consensus))
+    }
+
+    /// Exclude relays reporting a Tor version older than `version` (e.g. "0.4.7.0").
+    /// Relays that don't advertise a version are still considered.
+    pub fn with_min_relay_version(mut self, version: Option<&str>) -> Result<Self, DirectoryError> {
+        self.min_relay_version = match version {
+            Some(v) => Some(parse_tor_version(v).ok_or_else(|| {
+                DirectoryError::ParseError(format!("Invalid minimum relay version: {}", v))
+            })?),
+            None => None,
+        };
+        Ok(self)
+    }
+
+    /// Replace the set of authorities whose signatures are accepted
+    pub fn with_trusted_authorities(mut self, v3idents: Vec<String>) -> Self {
+        self.trusted_authorities = v3idents.into_iter().map(|id| id.to_uppercase()).collect();
+        self
+    }
+
+    /// Add the valid key certificates of trusted authorities found in
+    /// `text`. Returns how many were loaded.
+    pub async fn load_authority_certificates(&self, text: &str) -> usize {
+        let parsed = parse_authority_certificates(text, &self.trusted_authorities);
+        let count = parsed.len();
+        let mut certs = self.authority_certs.write().await;
+        for cert in parsed {
+            certs.retain(|c| c.fingerprint != cert.fingerprint || c.signing_key != cert.signing_key);
+            certs.push(cert);
         }
+        count
+    }
+
+    async fn fetch_authority_certificates(&self) -> Result<usize, DirectoryError> {
+        let mut last_error = DirectoryError::RequestFailed("No authority reachable".to_string());
+        for authority in DIRECTORY_AUTHORITIES {
+            let url = format!("http://{}/tor/keys/all", authority.dir_address);
+            log::info!("Fetching authority key certificates from {}", authority.nickname);
+            match self.download(&url).await {
+                Ok(text) => {
+                    let loaded = self.load_authority_certificates(&text).await;
+                    if loaded > 0 {
+                        return Ok(loaded);
+                    }
+                    last_error = DirectoryError::InvalidConsensus("No valid key certificates".to_string());
+                }
+                Err(e) => {
+                    log::warn!("✗ Failed to fetch certificates from {}: {}", authority.nickname, e);
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Check that a majority of the trusted authorities signed the consensus
+    /// document `text`, using the loaded key certificates
+    pub async fn verify_signatures(&self, text: &str) -> Result<(), DirectoryError> {
+        const SIGNATURE_KEYWORD: &str = "\ndirectory-signature ";
+
+        // Signed from "network-status-version" through the first "directory-signature "
+        let start = text.find("network-status-version").ok_or_else(|| {
+            DirectoryError::InvalidConsensus("Missing network-status-version".to_string())
+        })?;
+        let end = text.find(SIGNATURE_KEYWORD).ok_or_else(|| {
+            DirectoryError::InvalidConsensus("Consensus is not signed".to_string())
+        })? + SIGNATURE_KEYWORD.len();
+        let signed = text.as_bytes().get(start..end).ok_or_else(|| {
+            DirectoryError::InvalidConsensus("Malformed signature section".to_string())
+        })?;
+        let sha1 = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, signed);
+        let sha256 = digest::digest(&digest::SHA256, signed);
+
+        let certs = self.authority_certs.read().await;
+        let mut signers = HashSet::new();
+        for signature in parse_consensus_signatures(text) {
+            if !self.trusted_authorities.contains(&signature.identity) {
+                continue;
+            }
+            let document_digest = match signature.algorithm.as_str() {
+                "sha1" => sha1.as_ref(),
+                "sha256" => sha256.as_ref(),
+                _ => continue,
+            };
+            let verified = certs.iter().any(|cert| {
+                cert.fingerprint == signature.identity
+                    && cert.signing_key_digest() == signature.signing_key_digest
+                    && !cert.is_expired()
+                    && cert.signing_key.verify_digest(document_digest, &signature.signature)
+            });
+            if verified {
+                signers.insert(signature.identity);
+            } else {
+                log::debug!("Could not verify consensus signature from {}", signature.identity);
+            }
+        }
+
+        let needed = self.trusted_authorities.len() / 2 + 1;
+        log::info!("Consensus signed by {}/{} trusted authorities", signers.len(), self.trusted_authorities.len());
+        if signers.len() < needed {
+            return Err(DirectoryError::InvalidConsensus(format!(
+                "Only {} valid authority signatures, need {}",
+                signers.len(),
+                needed
+            )));
+        }
+        Ok(())
+    }
+
+    /// Keep state under `data_directory` (ignored if empty), starting from the
+    /// guard set saved there and from the cached consensus if it's still valid.
+    /// Only real consensuses are cached as they're fetched.
+    pub fn with_data_directory(mut self, data_directory: &str) -> Self {
+        if data_directory.is_empty() {
+            return self;
+        }
+        let guards_path = Path::new(data_directory).join(GUARDS_FILE);
+        if let Some(guards) = GuardSet::load(&guards_path) {
+            log::info!("Loaded {} guard(s) from {}", guards.guards.len(), guards_path.display());
+            self.guards = RwLock::new(guards);
+        }
+        self.data_directory = Some(PathBuf::from(data_directory));
+        if !self.use_real_consensus {
+            return self;
+        }
+        let path = Path::new(data_directory).join(CONSENSUS_CACHE_FILE);
+        if let Some((consensus, fetched_at)) = load_cached_consensus(&path) {
+            log::info!("Loaded cached consensus with {} relays from {}", consensus.relays.len(), path.display());
+            self.consensus = RwLock::new(Some(consensus));
+            self.last_update = RwLock::new(fetched_at);
+            self.buckets = RwLock::new(None);
+        }
+        self.cache_path = Some(path);
+        self
+    }
+
+    /// The consensus currently in use, if one has been loaded
+    pub async fn current_consensus(&self) -> Option<NetworkConsensus> {
+        self.consensus.read().await.clone()
+    }
+
+    /// A multi-line, human-readable summary of the relay (or bridge) with
+    /// fingerprint `fingerprint`, for diagnostics. None if we don't know it.
+    pub async fn describe_relay(&self, fingerprint: &str) -> Option<String> {
+        let consensus = self.consensus.read().await;
+        let relay = consensus
+            .as_ref()
+            .and_then(|c| c.relays.get(fingerprint))
+            .or_else(|| self.bridges.iter().find(|b| b.id == fingerprint))?;
+
+        let flags: Vec<String> = relay.flags.iter().map(|f| f.to_string()).collect();
+        let mut lines = vec![
+            format!("Nickname: {}", relay.nickname),
+            format!("Fingerprint: {}", relay.id),
+            format!("Address: {}", relay.address),
+        ];
+        if let Some(ipv6) = relay.ipv6_address {
+            lines.push(format!("IPv6 address: {}", ipv6));
+        }
+        lines.push(format!("Flags: {}", flags.join(" ")));
+        lines.push(format!("Bandwidth: {} KB/s", relay.bandwidth));
+        if let Some(platform) = &relay.platform {
+            lines.push(format!("Platform: {}", platform));
+        }
+        let policy = relay.exit_policy.as_ref().map_or("unknown".to_string(), |p| p.to_string());
+        lines.push(format!("Exit policy: {}", policy));
+        if let Some(policy_v6) = &relay.exit_policy_v6 {
+            lines.push(format!("IPv6 exit policy: {}", policy_v6));
+        }
+        Some(lines.join("\n"))
+    }
+
+    /// Our current entry guards
+    pub async fn guards(&self) -> GuardSet {
+        self.guards.read().await.clone()
+    }
+
+    /// Write the guard set and the current consensus to `data_directory`, so
+    /// the next start keeps its guards and needn't refetch. No-op without one.
+    pub async fn save_state(&self) -> std::io::Result<()> {
+        let Some(data_directory) = &self.data_directory else {
+            return Ok(());
+        };
+        self.guards.read().await.save(&data_directory.join(GUARDS_FILE))?;
+        if let Some(consensus) = self.consensus.read().await.as_ref() {
+            save_cached_consensus(&data_directory.join(CONSENSUS_CACHE_FILE), consensus)?;
+        }
+        Ok(())
+    }
+
+    /// Whether middle hops must carry the Stable flag (the default). Turning
+    /// this off admits Fast-only relays to the middle position.
+    pub fn with_require_stable_middle(mut self, required: bool) -> Self {
+        self.require_stable_middle = required;
+        self
+    }
+
+    /// Sort each consensus' relays into guard, middle and exit buckets the
+    /// first time it's used, so picking a hop only looks at its bucket. Worth
+    /// it on full-size consensuses; off by default.
+    pub fn with_relay_buckets(mut self, enabled: bool) -> Self {
+        self.bucket_relays = enabled;
+        self
+    }
+
+    /// Whether a path may use two relays in the same /16. Tor forbids it (its
+    /// EnforceDistinctSubnets); turn it off for test networks on one host.
+    /// On by default for real consensuses. Family members are always kept apart.
+    pub fn with_distinct_subnets(mut self, enabled: bool) -> Self {
+        self.distinct_subnets = enabled;
+        self
+    }
+
+    /// This machine's own addresses. A relay at one of them would be us (or
+    /// our network) and is never selected, for any position.
+    pub fn with_local_addresses(mut self, addresses: Vec<IpAddr>) -> Self {
+        self.local_addresses = addresses;
+        self
+    }
+
+    /// Test hook: make the next selections, one per relay picked for a hop,
+    /// return these relays (fingerprints, ids or nicknames) in order, whatever
+    /// their weight. A forced relay that doesn't fit the path so far, or isn't
+    /// in the consensus, is passed over and the hop is selected as usual.
+    pub async fn force_next_selection(&self, fingerprints: Vec<String>) {
+        self.forced_selections.lock().await.extend(fingerprints);
+    }
+
+    /// Use these bridges (see `bridge::parse_bridge_line`) as first hops instead
+    /// of consensus guards. The rest of the path still comes from the consensus.
+    pub fn with_bridges(mut self, bridges: Vec<RelayDescriptor>) -> Self {
+        self.bridges = bridges;
+        self
+    }
+
+    /// How long a consensus is used before it's refreshed (it's also refreshed
+    /// once its valid-until passes, whichever comes first)
+    pub fn with_max_consensus_age(mut self, max_age: Duration) -> Self {
+        self.max_consensus_age = max_age;
+        self
+    }
+
+    /// Always enter through these relays (hex or base64 fingerprints, or
+    /// nicknames) instead of a
+    /// sampled guard set
+    pub fn with_entry_guards(mut self, entry_guards: Vec<String>) -> Self {
+        self.entry_guards = entry_guards;
+        self
+    }
+
+    /// Sample `num_guards` guards, each kept for `lifetime` before it's rotated out
+    pub fn with_guard_params(mut self, num_guards: usize, lifetime: Duration) -> Self {
+        self.num_guards = num_guards.max(1);
+        self.guard_lifetime = lifetime;
+        self
+    }
+
+    /// Allow `burst` consensus fetches back to back, then one per `interval`
+    pub fn with_fetch_rate_limit(mut self, burst: u32, interval: Duration) -> Self {
+        self.fetch_limiter = Mutex::new(TokenBucket::new(burst, interval));
+        self
+    }
+
+    /// Make this share (0.0 to 1.0) of consensus fetches fail as if the
+    /// directory were unreachable, to exercise retries and fallbacks in
+    /// resilience tests. Works with mock consensuses as well as real ones.
+    pub fn with_fetch_failure_rate(mut self, rate: f64) -> Self {
+        self.fetch_failure_rate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Try a consensus fetch up to `attempts` times, pausing `delay` after the
+    /// first failure and twice as long after each one after (up to
+    /// `MAX_FAILURE_BACKOFF` times `delay`)
+    pub fn with_fetch_retries(mut self, attempts: usize, delay: Duration) -> Self {
+        self.fetch_attempts = attempts.max(1);
+        self.fetch_retry_delay = delay;
+        self
+    }
+
+    /// Report consensus fetches to `bootstrap`, shared with the circuit manager
+    pub fn with_bootstrap(mut self, bootstrap: Arc<Bootstrap>) -> Self {
+        self.bootstrap = bootstrap;
+        self
+    }
+
+    pub fn is_bridge_mode(&self) -> bool {
+        !self.bridges.is_empty()
+    }
+
+    /// Base cooldown before a failed relay is preferred again
+    pub fn with_relay_failure_cooldown(mut self, cooldown: Duration) -> Self {
+        self.failure_cooldown = cooldown;
+        self
     }
 
-    async fn is_consensus_fresh(&self) -> bool {
+    /// Record that a relay couldn't be reached or refused the handshake, so
+    /// `select_relay` passes over it for a while
+    pub async fn mark_relay_failed(&self, relay_id: &str) {
+        let mut failures = self.relay_failures.write().await;
+        let consecutive = failures.get(relay_id).map_or(0, |f| f.consecutive) + 1;
+        let backoff = (1u32 << (consecutive - 1).min(31)).min(MAX_FAILURE_BACKOFF);
+        let cooldown = self.failure_cooldown * backoff;
+        log::info!("Relay {} failed ({} in a row), avoiding it for {:?}", relay_id, consecutive, cooldown);
+        failures.insert(relay_id.to_string(), RelayFailure {
+            consecutive,
+            retry_after: std::time::Instant::now() + cooldown,
+        });
+    }
+
+    /// Forget a relay's failures after it worked
+    pub async fn mark_relay_succeeded(&self, relay_id: &str) {
+        self.relay_failures.write().await.remove(relay_id);
+    }
+
+    /// Whether the current consensus can still be used without refetching:
+    /// held for less than the max consensus age and not past its valid-until
+    pub async fn is_consensus_fresh(&self) -> bool {
+        let valid_until = match self.consensus.read().await.as_ref() {
+            Some(consensus) => consensus.valid_until,
+            None => return false,
+        };
         let last_update = *self.last_update.read().await;
-        let age = SystemTime::now()
+        let now = SystemTime::now();
+        let age = now
             .duration_since(last_update)
             .unwrap_or(Duration::from_secs(u64::MAX));
-        age < Duration::from_secs(3600)
+        age < self.max_consensus_age && now < valid_until
     }
 
     async fn fetch_latest_consensus(&self) -> Result<NetworkConsensus, DirectoryError> {
-        log::info!("Fetching latest consensus from Tor Collector");
+        log::info!("Fetching latest consensus from {} source(s)", self.sources.len());
         
         let now = Utc::now();
         
-        // Try current hour and previous (up to 48 for ~2-day coverage)
+        // Try current hour and previous (up to 48 for ~2-day coverage), each
+        // hour from every source in turn
         for hour_offset in 0..48u32 {
             let timestamp = now - chrono::Duration::hours(hour_offset as i64);
-            let url = format!(
-                "{}/{:04}-{:02}-{:02}-{:02}-00-00-consensus",
-                TOR_COLLECTOR_BASE,
-                timestamp.year(),
-                timestamp.month(),
-                timestamp.day(),
-                timestamp.hour()
-            );
-            
-            log::info!("Trying: {}", url);
-            
-            match self.download_and_parse(&url).await {
-                Ok(consensus) => {
-                    log::info!("✓ Successfully fetched consensus (offset: {} hours)", hour_offset);
-                    return Ok(consensus);
-                }
-                Err(e) => {
-                    log::warn!("✗ Failed offset {}: {}", hour_offset, e);
+            let name = collector_name(&timestamp, "consensus-microdesc");
+            for source in &self.sources {
+                let Some(location) = source.location(&name, hour_offset == 0) else {
+                    continue;
+                };
+                log::info!("Trying: {}", location);
+
+                match self.download_and_parse(source, &location).await {
+                    Ok(consensus) => {
+                        log::info!("✓ Successfully fetched consensus from {} (offset: {} hours)", source, hour_offset);
+                        return Ok(consensus);
+                    }
+                    Err(e) => {
+                        log::warn!("✗ Failed {} offset {}: {}", source, hour_offset, e);
+                    }
                 }
             }
         }
         
-        Err(DirectoryError::RequestFailed("Could not fetch from any recent hour".to_string()))
+        Err(DirectoryError::RequestFailed("Could not fetch from any source for any recent hour".to_string()))
+    }
+
+    async fn download_and_parse(
+        &self,
+        source: &ConsensusSource,
+        location: &str,
+    ) -> Result<NetworkConsensus, DirectoryError> {
+        let text = if source.is_remote() {
+            self.download(location).await?
+        } else {
+            tokio::fs::read_to_string(location)
+                .await
+                .map_err(|e| DirectoryError::RequestFailed(format!("{}: {}", location, e)))?
+        };
+        self.bootstrap.report(BootstrapPhase::LoadingStatus);
+
+        // Debug: Count raw r lines
+        let r_count = text.lines().filter(|l| l.trim().starts_with("r ")).count();
+        log::info!("Raw r line count in download: {}", r_count);
+
+        self.bootstrap.report(BootstrapPhase::LoadingKeys);
+        if self.authority_certs.read().await.iter().all(|c| c.is_expired()) {
+            self.fetch_authority_certificates().await?;
+        }
+        self.verify_signatures(&text).await?;
+
+        self.parse_consensus(&text).await
     }
 
-    async fn download_and_parse(&self, url: &str) -> Result<NetworkConsensus, DirectoryError> {
+    async fn download(&self, url: &str) -> Result<String, DirectoryError> {
         let client = reqwest::Client::builder()
             .timeout(Duration::from_secs(120))  // Increased for large file
             .build()
@@ -157,12 +1110,68 @@ impl DirectoryClient {
             .map_err(|e| DirectoryError::RequestFailed(format!("Read: {}", e)))?;
         
         log::info!("Downloaded {} bytes", text.len());
+        Ok(text)
+    }
 
-        // Debug: Count raw r lines
-        let r_count = text.lines().filter(|l| l.trim().starts_with("r ")).count();
-        log::info!("Raw r line count in download: {}", r_count);
-        
-        self.parse_consensus(&text).await
+    /// Download the microdescriptors referenced by the cached consensus' "m" lines
+    /// and fill in each relay's ntor onion key. Returns how many relays were updated.
+    pub async fn fetch_microdescriptors(&self) -> Result<usize, DirectoryError> {
+        let valid_after = match self.consensus.read().await.as_ref() {
+            Some(consensus) => consensus.valid_after,
+            None => return Err(DirectoryError::InvalidConsensus("No consensus loaded".to_string())),
+        };
+
+        // Clients use the microdesc-flavored consensus: its "m" lines reference
+        // the microdescriptors that carry each relay's ntor onion key
+        log::info!("Fetching microdescriptors from {}", self.collector);
+        let published: chrono::DateTime<Utc> = valid_after.into();
+
+        // Microdescriptors are published alongside the consensus; allow for a few hours of lag
+        let mut last_error = DirectoryError::RequestFailed("No microdescriptor document found".to_string());
+        for hour_offset in 0..4i64 {
+            let timestamp = published - chrono::Duration::hours(hour_offset);
+            let url = collector_url(&source::collector_microdesc_base(&self.collector), &timestamp, "micro");
+            log::info!("Trying: {}", url);
+
+            match self.download(&url).await {
+                Ok(text) => return Ok(self.apply_microdescriptors(&text).await),
+                Err(e) => {
+                    log::warn!("✗ Failed microdescriptors offset {}: {}", hour_offset, e);
+                    last_error = e;
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+
+    async fn apply_microdescriptors(&self, text: &str) -> usize {
+        let microdescs = parse_microdescriptors(text);
+        log::info!("Parsed {} microdescriptors", microdescs.len());
+
+        let mut updated = 0;
+        // Relays without onion keys were left out of the buckets
+        *self.buckets.write().await = None;
+        if let Some(consensus) = self.consensus.write().await.as_mut() {
+            for relay in consensus.relays.values_mut() {
+                let microdesc = relay.microdesc_digest.as_ref().and_then(|d| microdescs.get(d));
+                if let Some(microdesc) = microdesc {
+                    relay.onion_key = microdesc.ntor_onion_key.to_vec();
+                    relay.ed25519_identity = microdesc.ed25519_identity;
+                    // The microdesc consensus carries no "p" lines
+                    if relay.exit_policy.is_none() {
+                        relay.exit_policy = microdesc.exit_policy.clone();
+                    }
+                    if relay.exit_policy_v6.is_none() {
+                        relay.exit_policy_v6 = microdesc.exit_policy_v6.clone();
+                    }
+                    relay.family = microdesc.family.clone();
+                    updated += 1;
+                }
+            }
+            log::info!("Onion keys known for {}/{} relays", updated, consensus.relays.len());
+        }
+        updated
     }
 
     async fn create_mock_consensus(&self) -> Result<NetworkConsensus, DirectoryError> {
@@ -192,40 +1201,74 @@ impl DirectoryClient {
                 address: addr,
                 identity_key: vec![0u8; 20],  // Dummy
                 onion_key: vec![0u8; 32],  // Dummy
+                ed25519_identity: None,
                 bandwidth: bw,
                 flags,
+                platform: None,
+                microdesc_digest: None,
+                exit_policy: None,
+                exit_policy_v6: None,
+                ipv6_address: None,
+                protocols: None,
+                family: Vec::new(),
             });
         }
 
         log::info!("Created mock consensus with {} relays", relays.len());
 
+        let now = SystemTime::now();
         Ok(NetworkConsensus {
-            valid_after: SystemTime::now(),
-            valid_until: SystemTime::now() + Duration::from_secs(3600),
+            valid_after: now,
+            fresh_until: now + Duration::from_secs(3600),
+            valid_until: now + Duration::from_secs(3 * 3600),
             relays,
             signatures: vec![],
+            flag_thresholds: None,
+            bandwidth_weights: HashMap::new(),
         })
     }
 
-    async fn parse_consensus(&self, text: &str) -> Result<NetworkConsensus, DirectoryError> {
+    pub async fn parse_consensus(&self, text: &str) -> Result<NetworkConsensus, DirectoryError> {
         let mut relays = HashMap::new();
+        // Relay entries left out because they were malformed or unusable
+        let mut skipped = 0usize;
         let lines: Vec<&str> = text.lines().collect();
         let mut i = 0usize;
+        let mut valid_after = None;
+        let mut fresh_until = None;
+        let mut valid_until = None;
+        let mut flag_thresholds = None;
+        let mut bandwidth_weights = HashMap::new();
 
         while i < lines.len() {
             let line = lines[i].trim();
-            if line.starts_with("r ") {
+            if let Some(rest) = line.strip_prefix("valid-after ") {
+                valid_after = Some(parse_consensus_time(rest)?);
+            } else if let Some(rest) = line.strip_prefix("fresh-until ") {
+                fresh_until = Some(parse_consensus_time(rest)?);
+            } else if let Some(rest) = line.strip_prefix("valid-until ") {
+                valid_until = Some(parse_consensus_time(rest)?);
+            } else if line.starts_with("flag-thresholds ") {
+                flag_thresholds = Some(parse_flag_thresholds(line));
+            } else if let Some(weights) = line.strip_prefix("bandwidth-weights ") {
+                bandwidth_weights = weights
+                    .split_whitespace()
+                    .filter_map(|kv| kv.split_once('='))
+                    .filter_map(|(k, v)| Some((k.to_string(), v.parse().ok()?)))
+                    .collect();
+            } else if line.starts_with("r ") {
                 match self.parse_relay(&lines, &mut i) {
                     Ok(relay) => {
                         relays.insert(relay.id.clone(), relay);
                     }
                     Err(e) => {
                         log::warn!("Failed to parse relay at line {}: {}", i, e);
+                        skipped += 1;
                     }
                 }
                 // Skip to next potential r (handles s/w/p/v lines in block)
                 i += 1;
-                while i < lines.len() && !lines[i].trim().starts_with("r ") {
+                while i < lines.len() && !is_relay_block_end(lines[i]) {
                     i += 1;
                 }
                 continue;
@@ -234,49 +1277,59 @@ impl DirectoryClient {
         }
 
         log::info!("Parsed {} relays", relays.len());
+        if skipped > 0 {
+            log::warn!("Skipped {} unusable relay entries", skipped);
+        }
 
         if relays.is_empty() {
             return Err(DirectoryError::InvalidConsensus("No relays found".to_string()));
         }
 
+        let missing = |field: &str| DirectoryError::InvalidConsensus(format!("Missing {} header", field));
         Ok(NetworkConsensus {
-            valid_after: SystemTime::now(),
-            valid_until: SystemTime::now() + Duration::from_secs(3600),
+            valid_after: valid_after.ok_or_else(|| missing("valid-after"))?,
+            fresh_until: fresh_until.ok_or_else(|| missing("fresh-until"))?,
+            valid_until: valid_until.ok_or_else(|| missing("valid-until"))?,
             relays,
-            signatures: vec![],
+            signatures: parse_consensus_signatures(text),
+            flag_thresholds,
+            bandwidth_weights,
         })
     }
 
     fn parse_relay(&self, lines: &[&str], i: &mut usize) -> Result<RelayDescriptor, DirectoryError> {
-        let parts: Vec<&str> = lines[*i].trim().split_whitespace().collect();
-        if parts.len() != 9 || parts[0] != "r" {
+        let mut parts: Vec<&str> = lines[*i].split_whitespace().collect();
+        // The microdesc flavor omits the descriptor digest: 8 parts instead of 9
+        // (both count the space inside the timestamp)
+        if parts.len() == 9 && parts[0] == "r" {
+            parts.remove(3);  // Base64 descriptor digest, unused
+        }
+        if parts.len() != 8 || parts[0] != "r" {
             return Err(DirectoryError::ParseError(format!(
-                "Invalid r line (expected 9 parts due to space in timestamp, got {}): {}",
+                "Invalid r line (expected 8 or 9 parts due to space in timestamp, got {}): {}",
                 parts.len(), lines[*i]
             )));
         }
 
         let nickname = parts[1].to_string();
         let identity_full = parts[2];  // Unpadded base64
-        let _digest = parts[3];  // Base64 digest, unused
-        let _published_date = parts[4];  // "YYYY-MM-DD", unused
-        let _published_time = parts[5];  // "HH:MM:SS", unused
-        let ip = parts[6];  // IPv4
-        let or_port: u16 = parts[7].parse()
+        let _published_date = parts[3];  // "YYYY-MM-DD", unused
+        let _published_time = parts[4];  // "HH:MM:SS", unused
+        let ip = parts[5];  // IPv4
+        let or_port: u16 = parts[6].parse()
             .map_err(|_| DirectoryError::ParseError("Invalid OR port".to_string()))?;
-        let _dir_port: u16 = parts[8].parse()
+        if or_port == 0 {
+            // Nothing listens on port 0, so the relay could never be reached
+            return Err(DirectoryError::ParseError(format!("{} has OR port 0", parts[1])));
+        }
+        let _dir_port: u16 = parts[7].parse()
             .map_err(|_| DirectoryError::ParseError("Invalid Dir port".to_string()))?;
 
         let address = format!("{}:{}", ip, or_port).parse()
             .map_err(|e| DirectoryError::ParseError(format!("Invalid address: {}", e)))?;
 
-        // Decode identity: Unpadded base64 → replace chars, add padding, decode to 20 bytes
-        let mut identity_padded = identity_full.replace('-', "+").replace('_', "/");
-        while identity_padded.len() % 4 != 0 {
-            identity_padded.push('=');
-        }
-        let identity_key = general_purpose::STANDARD
-            .decode(&identity_padded)
+        // Decode identity: Unpadded base64 → 20 bytes
+        let identity_key = decode_unpadded_base64(identity_full)
             .map_err(|e| DirectoryError::ParseError(format!("Invalid identity base64: {}", e)))?;
         if identity_key.len() != 20 {
             return Err(DirectoryError::ParseError(format!(
@@ -284,30 +1337,45 @@ impl DirectoryClient {
             )));
         }
 
-        let onion_key = identity_key.clone();  // Placeholder; fetch NTor key from microdesc later
+        // The ntor onion key lives in the microdescriptor; filled in by fetch_microdescriptors
+        let onion_key = Vec::new();
 
-        // Parse flags: Next line usually "s "
+        // Walk the rest of this relay's block (s/v/pr/w/p/p6 lines) up to the next "r " line
         let mut flags = vec![RelayFlag::Running, RelayFlag::Valid];
+        let mut bandwidth = 1000000u32;
+        let mut platform = None;
+        let mut microdesc_digest = None;
+        let mut exit_policy = None;
+        let mut exit_policy_v6 = None;
+        let mut ipv6_address = None;
+        let mut protocols = None;
         let mut j = *i + 1;
-        if j < lines.len() {
-            let next_line = lines[j].trim();
-            if next_line.starts_with("s ") {
-                flags = self.parse_flags(next_line);
-                j += 1;
+        while j < lines.len() {
+            let line = lines[j].trim();
+            if is_relay_block_end(line) {
+                break;
             }
-        }
-
-        // Parse bandwidth: First "w Bandwidth=..." in next 5 lines
-        let mut bandwidth = 1000000u32;
-        for offset in 0..5 {
-            let check_j = j + offset;
-            if check_j < lines.len() {
-                let w_line = lines[check_j].trim();
-                if let Some(bw) = self.parse_bandwidth(w_line) {
-                    bandwidth = bw;
-                    break;
+            if line.starts_with("s ") {
+                flags = self.parse_flags(line);
+            } else if let Some(version) = line.strip_prefix("v ") {
+                platform = Some(version.to_string());
+            } else if let Some(digest) = line.strip_prefix("m ") {
+                microdesc_digest = Some(digest.trim().to_string());
+            } else if let Some(summary) = line.strip_prefix("p ") {
+                exit_policy = ExitPolicySummary::parse(summary);
+            } else if let Some(summary) = line.strip_prefix("p6 ") {
+                exit_policy_v6 = ExitPolicySummary::parse(summary);
+            } else if let Some(list) = line.strip_prefix("pr ") {
+                protocols = Some(list.trim().to_string());
+            } else if let Some(or_address) = line.strip_prefix("a ") {
+                // "a [2001:db8::1]:9001"; keep the first IPv6 ORPort
+                if let Ok(addr @ SocketAddr::V6(_)) = or_address.trim().parse() {
+                    ipv6_address.get_or_insert(addr);
                 }
+            } else if let Some(bw) = self.parse_bandwidth(line) {
+                bandwidth = bw;
             }
+            j += 1;
         }
 
         *i = j - 1;  // For outer loop advance
@@ -318,8 +1386,17 @@ impl DirectoryClient {
             address,
             identity_key,
             onion_key,
+            // Only known once the relay's microdescriptor is fetched
+            ed25519_identity: None,
             bandwidth,
             flags,
+            platform,
+            microdesc_digest,
+            exit_policy,
+            exit_policy_v6,
+            ipv6_address,
+            protocols,
+            family: Vec::new(),
         })
     }
 
@@ -342,12 +1419,31 @@ impl DirectoryClient {
     }
 
     fn parse_bandwidth(&self, line: &str) -> Option<u32> {
+        if !line.starts_with("w ") {
+            return None;
+        }
         line.split_whitespace().skip(1).find_map(|part| {
             part.strip_prefix("Bandwidth=").and_then(|bw_str| bw_str.parse().ok())
         })
     }
 
+    fn is_version_allowed(&self, relay: &RelayDescriptor) -> bool {
+        match (&self.min_relay_version, relay.tor_version()) {
+            (Some(min), Some(version)) => version >= *min,
+            _ => true,
+        }
+    }
+
+    /// Whether `relay` listens on one of our own addresses
+    fn is_local(&self, relay: &RelayDescriptor) -> bool {
+        let mut addresses = std::iter::once(relay.address).chain(relay.ipv6_address);
+        addresses.any(|address| self.local_addresses.contains(&address.ip()))
+    }
+
     fn is_relay_suitable(&self, relay: &RelayDescriptor, hop: usize) -> bool {
+        if !relay.has_ntor_onion_key() || !self.is_version_allowed(relay) || self.is_local(relay) {
+            return false;
+        }
         if !relay.flags.contains(&RelayFlag::Running) || !relay.flags.contains(&RelayFlag::Valid) {
             return false;
         }
@@ -361,9 +1457,9 @@ impl DirectoryClient {
                 if relay.flags.contains(&RelayFlag::Middle) {
                     return relay.flags.contains(&RelayFlag::Fast);
                 }
-                // Otherwise, middle relay = Fast + Stable, not Guard, not Exit
+                // Otherwise, middle relay = Fast (+ Stable unless relaxed), not Guard, not Exit
                 relay.flags.contains(&RelayFlag::Fast) 
-                    && relay.flags.contains(&RelayFlag::Stable)
+                    && (!self.require_stable_middle || relay.flags.contains(&RelayFlag::Stable))
                     && !relay.flags.contains(&RelayFlag::Guard)
                     && !relay.flags.contains(&RelayFlag::Exit)
             },
@@ -372,12 +1468,26 @@ impl DirectoryClient {
         }
     }
 
-    fn select_weighted(&self, relays: Vec<&RelayDescriptor>) -> Result<RelayDescriptor, DirectoryError> {
+    /// Weight by bandwidth times the consensus weight for the relay at position `hop`
+    fn select_weighted(
+        &self,
+        consensus: &NetworkConsensus,
+        relays: Vec<&RelayDescriptor>,
+        hop: usize,
+    ) -> Result<RelayDescriptor, DirectoryError> {
+        self.select_weighted_by(relays, |r| r.bandwidth as u64 * consensus.position_weight(r, hop).max(0) as u64)
+    }
+
+    fn select_weighted_by(
+        &self,
+        relays: Vec<&RelayDescriptor>,
+        weight: impl Fn(&RelayDescriptor) -> u64,
+    ) -> Result<RelayDescriptor, DirectoryError> {
         if relays.is_empty() {
             return Err(DirectoryError::NoSuitableRelays);
         }
         
-        let total: u64 = relays.iter().map(|r| r.bandwidth as u64).sum();
+        let total: u64 = relays.iter().map(|r| weight(r)).sum();
         if total == 0 {
             let mut rng = rand::thread_rng();
             return Ok(relays[rng.gen_range(0..relays.len())].clone());
@@ -387,57 +1497,504 @@ impl DirectoryClient {
         let mut sel = rng.gen_range(0..total);
         
         for relay in &relays {
-            if sel < relay.bandwidth as u64 {
+            let w = weight(relay);
+            if sel < w {
                 return Ok((*relay).clone());
             }
-            sel -= relay.bandwidth as u64;
+            sel -= w;
         }
         
         Ok(relays[0].clone())
     }
     
     pub async fn fetch_consensus(&self) -> Result<NetworkConsensus, DirectoryError> {
-        if self.is_consensus_fresh().await {
+        self.refresh_consensus(false).await
+    }
+
+    /// Fetch a new consensus, unless the current one is still fresh and
+    /// `force` isn't set. Fetches are rate limited; a refresh over the limit
+    /// waits for its turn.
+    pub async fn refresh_consensus(&self, force: bool) -> Result<NetworkConsensus, DirectoryError> {
+        if !force && self.is_consensus_fresh().await {
             if let Some(c) = self.consensus.read().await.as_ref() {
                 log::debug!("Using cached consensus");
                 return Ok(c.clone());
             }
         }
+
+        self.wait_for_fetch_slot().await;
+        // Directory documents come from the collector over HTTPS rather than
+        // a one-hop circuit, so opening that connection covers the first phases
+        for phase in [
+            BootstrapPhase::Conn,
+            BootstrapPhase::Handshake,
+            BootstrapPhase::OnehopCreate,
+            BootstrapPhase::RequestingStatus,
+        ] {
+            self.bootstrap.report(phase);
+        }
+        let consensus = self.fetch_with_retries().await?;
+        // Already reported while verifying a real consensus
+        self.bootstrap.report(BootstrapPhase::LoadingStatus);
+        self.bootstrap.report(BootstrapPhase::LoadingKeys);
         
-        let consensus = if self.use_real_consensus {
-            self.fetch_latest_consensus().await?
-        } else {
-            self.create_mock_consensus().await?
-        };
-        
-        *self.consensus.write().await = Some(consensus.clone());
+        *self.consensus.write().await = Some(consensus);
         *self.last_update.write().await = SystemTime::now();
+        *self.buckets.write().await = None;
+
+        // Relays are only usable for circuits once their ntor onion keys are known
+        self.bootstrap.report(BootstrapPhase::RequestingDescriptors);
+        if self.use_real_consensus {
+            if let Err(e) = self.fetch_microdescriptors().await {
+                log::warn!("Failed to fetch microdescriptors: {}", e);
+            }
+        }
         
+        let consensus = self.consensus.read().await.clone()
+            .ok_or_else(|| DirectoryError::InvalidConsensus("Consensus disappeared".to_string()))?;
+        self.reconcile_guards(&consensus).await;
+        if let Some(path) = &self.cache_path {
+            if let Err(e) = save_cached_consensus(path, &consensus) {
+                log::warn!("Failed to cache consensus to {}: {}", path.display(), e);
+            }
+        }
         Ok(consensus)
     }
     
-    pub async fn select_relay(&self, hop: usize) -> Result<RelayDescriptor, DirectoryError> {
+    /// Fetch a consensus (or make up the mock one), retrying with backoff
+    /// within the `fetch_attempts` budget
+    async fn fetch_with_retries(&self) -> Result<NetworkConsensus, DirectoryError> {
+        let mut attempt = 1;
+        loop {
+            let result = if rand::random::<f64>() < self.fetch_failure_rate {
+                Err(DirectoryError::RequestFailed("Simulated directory failure".to_string()))
+            } else if self.use_real_consensus {
+                self.fetch_latest_consensus().await
+            } else {
+                self.create_mock_consensus().await
+            };
+            match result {
+                Err(e) if attempt < self.fetch_attempts => {
+                    let backoff = (1u32 << (attempt - 1).min(31)).min(MAX_FAILURE_BACKOFF);
+                    let delay = self.fetch_retry_delay * backoff;
+                    log::warn!(
+                        "Consensus fetch failed ({}), try {} of {}; retrying in {:?}",
+                        e, attempt, self.fetch_attempts, delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn wait_for_fetch_slot(&self) {
+        let mut limiter = self.fetch_limiter.lock().await;
+        while let Err(wait) = limiter.try_acquire() {
+            log::info!("Consensus fetches are rate limited; waiting {:?} before the next one", wait);
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Probe up to `candidates_per_hop` relays suitable for each of the three
+    /// hops (the highest-bandwidth ones; bridges for hop 0 in bridge mode)
+    /// with a TCP connection, `concurrency` probes at a time across all
+    /// hops, and report how many answered per hop
+    pub async fn probe_all_hops_concurrent(
+        &self,
+        candidates_per_hop: usize,
+        concurrency: usize,
+        timeout: Duration,
+    ) -> Result<Vec<HopReachability>, DirectoryError> {
         let consensus = self.fetch_consensus().await?;
-        
-        let suitable: Vec<&RelayDescriptor> = consensus.relays.values()
-            .filter(|r| self.is_relay_suitable(r, hop))
+        let mut hops = Vec::new();
+        let mut addresses = Vec::new();
+        for hop in 0..3 {
+            let mut candidates: Vec<&RelayDescriptor> = if hop == 0 && self.is_bridge_mode() {
+                self.bridges.iter().collect()
+            } else {
+                consensus.relays.values().filter(|r| self.is_relay_suitable(r, hop)).collect()
+            };
+            candidates.sort_by(|a, b| b.bandwidth.cmp(&a.bandwidth).then_with(|| a.id.cmp(&b.id)));
+            candidates.truncate(candidates_per_hop);
+            hops.extend(std::iter::repeat_n(hop, candidates.len()));
+            addresses.extend(candidates.iter().map(|r| r.address));
+        }
+
+        let results = probe::probe_addresses(&addresses, concurrency, timeout).await;
+        let report = (0..3)
+            .map(|hop| {
+                let probed = hops.iter().filter(|&&h| h == hop).count();
+                let reachable = hops.iter().zip(&results).filter(|&(&h, &ok)| h == hop && ok).count();
+                log::info!("Hop {}: {}/{} candidate relays reachable", hop, reachable, probed);
+                HopReachability { hop, probed, reachable }
+            })
             .collect();
+        Ok(report)
+    }
+
+    /// Drop relays that recently failed, unless nothing else is left
+    async fn without_failed<'a>(&self, relays: Vec<&'a RelayDescriptor>) -> Vec<&'a RelayDescriptor> {
+        let (usable, cooling) = self.partition_failed(relays).await;
+        if usable.is_empty() { cooling } else { usable }
+    }
+
+    /// Split relays into (usable, cooling down after a recent failure)
+    async fn partition_failed<'a>(
+        &self,
+        relays: Vec<&'a RelayDescriptor>,
+    ) -> (Vec<&'a RelayDescriptor>, Vec<&'a RelayDescriptor>) {
+        let now = std::time::Instant::now();
+        let failures = self.relay_failures.read().await;
+        relays
+            .into_iter()
+            .partition(|r| failures.get(&r.id).is_none_or(|f| f.retry_after <= now))
+    }
+
+    /// The relays in `consensus` suitable for position `hop`, from the
+    /// position's bucket when relays are bucketed
+    async fn suitable_relays<'a>(&self, consensus: &'a NetworkConsensus, hop: usize) -> Vec<&'a RelayDescriptor> {
+        if self.bucket_relays && hop <= 2 {
+            let buckets = self.buckets_for(consensus).await;
+            if let Some(bucket) = buckets.for_hop(hop) {
+                return bucket.iter().filter_map(|id| consensus.relays.get(id)).collect();
+            }
+        }
+        consensus.relays.values().filter(|r| self.is_relay_suitable(r, hop)).collect()
+    }
+
+    /// The buckets for the current consensus, sorting `consensus` into them
+    /// if that hasn't happened since it was loaded
+    async fn buckets_for(&self, consensus: &NetworkConsensus) -> Arc<RelayBuckets> {
+        if let Some(buckets) = self.buckets.read().await.as_ref() {
+            return buckets.clone();
+        }
+        let mut buckets = RelayBuckets::default();
+        for relay in consensus.relays.values() {
+            for (hop, bucket) in [(0, &mut buckets.guards), (1, &mut buckets.middles), (2, &mut buckets.exits)] {
+                if self.is_relay_suitable(relay, hop) {
+                    bucket.push(relay.id.clone());
+                }
+            }
+        }
+        log::debug!(
+            "Bucketed relays: {} guards, {} middles, {} exits",
+            buckets.guards.len(), buckets.middles.len(), buckets.exits.len()
+        );
+        let buckets = Arc::new(buckets);
+        *self.buckets.write().await = Some(buckets.clone());
+        buckets
+    }
+
+    /// The current consensus' relay buckets; None unless bucketing is on and
+    /// a consensus is loaded
+    pub async fn relay_buckets(&self) -> Option<RelayBuckets> {
+        if !self.bucket_relays {
+            return None;
+        }
+        let consensus = self.consensus.read().await.clone()?;
+        Some((*self.buckets_for(&consensus).await).clone())
+    }
+
+    /// The relays that can join `path`: none of its hops is in their family
+    /// or, when distinct subnets are enforced, their /16
+    fn fitting_path<'a>(&self, relays: Vec<&'a RelayDescriptor>, path: &[RelayDescriptor]) -> Vec<&'a RelayDescriptor> {
+        relays
+            .into_iter()
+            .filter(|r| {
+                !path.iter().any(|hop| hop.in_family_with(r) || (self.distinct_subnets && hop.shares_subnet_with(r)))
+            })
+            .collect()
+    }
+
+    fn fallback_relays<'a>(&self, consensus: &'a NetworkConsensus) -> Vec<&'a RelayDescriptor> {
+        consensus.relays.values()
+            .filter(|r| r.flags.contains(&RelayFlag::Running) && !r.flags.contains(&RelayFlag::BadExit))
+            .filter(|r| r.has_ntor_onion_key() && self.is_version_allowed(r) && !self.is_local(r))
+            .collect()
+    }
+
+    pub async fn select_relay(&self, hop: usize) -> Result<RelayDescriptor, DirectoryError> {
+        self.select_relay_for_path(hop, &[]).await
+    }
+
+    /// Pick a relay for position `hop` of a circuit whose earlier hops are
+    /// `path`, skipping relays in their family or (if enforced) their subnet
+    pub async fn select_relay_for_path(
+        &self,
+        hop: usize,
+        path: &[RelayDescriptor],
+    ) -> Result<RelayDescriptor, DirectoryError> {
+        if hop == 0 && self.is_bridge_mode() {
+            return self.select_bridge().await;
+        }
+        let consensus = self.fetch_consensus().await?;
+        self.select_from(&consensus, hop, None, path).await
+    }
+
+    /// Pick a whole `num_hops` path from one consensus: a guard (or bridge),
+    /// middles, and for three or more hops an exit allowing `target` (if
+    /// given), unless the circuit is `internal` and ends on a middle. No two
+    /// hops are the same relay or in one family, nor (if enforced) in one subnet.
+    pub async fn select_path(
+        &self,
+        num_hops: usize,
+        target: Option<ExitTarget>,
+        internal: bool,
+    ) -> Result<Vec<RelayDescriptor>, DirectoryError> {
+        self.select_dated_path(num_hops, target, internal).await.map(|(path, _)| path)
+    }
+
+    /// `select_path`, along with the valid-after time of the consensus the
+    /// path was chosen from
+    pub async fn select_dated_path(
+        &self,
+        num_hops: usize,
+        target: Option<ExitTarget>,
+        internal: bool,
+    ) -> Result<(Vec<RelayDescriptor>, SystemTime), DirectoryError> {
+        let consensus = self.fetch_consensus().await?;
+        let mut path = Vec::with_capacity(num_hops);
+        for hop_num in 0..num_hops {
+            let position = path_position(hop_num, num_hops, internal);
+            let relay = self.select_from(&consensus, position, target.filter(|_| position == 2), &path).await?;
+            log::debug!("Selected {} for hop {} of {}", relay.nickname, hop_num, num_hops);
+            path.push(relay);
+        }
+        Ok((path, consensus.valid_after))
+    }
+
+    /// Pick another relay for hop `hop_num` of `path` after the one there
+    /// failed, fitting the rest of the path as `select_path` would
+    pub async fn select_replacement(
+        &self,
+        path: &[RelayDescriptor],
+        hop_num: usize,
+        target: Option<ExitTarget>,
+        internal: bool,
+    ) -> Result<RelayDescriptor, DirectoryError> {
+        let consensus = self.fetch_consensus().await?;
+        let position = path_position(hop_num, path.len(), internal);
+        let others: Vec<RelayDescriptor> =
+            path.iter().enumerate().filter(|(i, _)| *i != hop_num).map(|(_, relay)| relay.clone()).collect();
+        let relay = self.select_from(&consensus, position, target.filter(|_| position == 2), &others).await?;
+        // Fallbacks (and a guard set that's all down) may offer the failed relay again
+        if path.get(hop_num).is_some_and(|failed| failed.id == relay.id) {
+            return Err(DirectoryError::NoSuitableRelays);
+        }
+        Ok(relay)
+    }
+
+    /// Pick a relay from `consensus` for position `hop` that can follow `path`
+    async fn select_from(
+        &self,
+        consensus: &NetworkConsensus,
+        hop: usize,
+        target: Option<ExitTarget>,
+        path: &[RelayDescriptor],
+    ) -> Result<RelayDescriptor, DirectoryError> {
+        let forced = self.forced_selections.lock().await.pop_front();
+        if let Some(forced) = forced {
+            let relay = consensus.relays.values().find(|r| fingerprint::names_relay(&forced, &r.id, &r.nickname));
+            match relay {
+                Some(relay) if !self.fitting_path(vec![relay], path).is_empty() => {
+                    log::debug!("Forced selection of {} for hop {}", relay.nickname, hop);
+                    return Ok(relay.clone());
+                }
+                Some(relay) => log::debug!("Forced relay {} doesn't fit the path, selecting another", relay.nickname),
+                None => log::warn!("Forced relay {} isn't in the consensus", forced),
+            }
+        }
+        if hop == 0 && self.is_bridge_mode() {
+            return self.select_bridge().await;
+        }
+        if hop == 0 {
+            return self.select_guard(consensus).await;
+        }
+        if hop == 2 {
+            return self.select_exit_from(consensus, target, path).await;
+        }
+
+        let suitable = self.fitting_path(self.suitable_relays(consensus, hop).await, path);
         
         log::debug!("Found {} suitable relays for hop {}", suitable.len(), hop);
+        let suitable = self.without_failed(suitable).await;
         
         if suitable.is_empty() {
-            let fallback: Vec<&RelayDescriptor> = consensus.relays.values()
-                .filter(|r| r.flags.contains(&RelayFlag::Running) && !r.flags.contains(&RelayFlag::BadExit))
-                .collect();
-            
+            let fallback = self.fitting_path(self.fallback_relays(consensus), path);
             if fallback.is_empty() {
                 return Err(DirectoryError::NoSuitableRelays);
             }
             
             log::warn!("Using fallback for hop {}", hop);
-            return self.select_weighted(fallback);
+            return self.select_weighted(consensus, fallback, hop);
         }
         
-        self.select_weighted(suitable)
+        self.select_weighted(consensus, suitable, hop)
+    }
+
+    /// Pick the first hop from our guards. The set is sampled on first use and
+    /// saved; guards leave it when they expire or drop out of the consensus,
+    /// and another is only taken on when every guard is down.
+    async fn select_guard(&self, consensus: &NetworkConsensus) -> Result<RelayDescriptor, DirectoryError> {
+        let candidates = self.suitable_relays(consensus, 0).await;
+
+        if !self.entry_guards.is_empty() {
+            let pinned = candidates
+                .into_iter()
+                .filter(|r| self.entry_guards.iter().any(|g| fingerprint::names_relay(g, &r.id, &r.nickname)))
+                .collect();
+            let pinned = self.without_failed(pinned).await;
+            return self.select_weighted_by(pinned, |r| r.bandwidth as u64);
+        }
+        if candidates.is_empty() {
+            let fallback = self.fallback_relays(consensus);
+            log::warn!("No guard-capable relays, using fallback for hop 0");
+            return self.select_weighted(consensus, fallback, 0);
+        }
+
+        let mut guards = self.guards.write().await;
+        let before = guards.clone();
+        let now = SystemTime::now();
+        guards.guards.retain(|guard| {
+            let expired = now.duration_since(guard.added_at).is_ok_and(|age| age >= self.guard_lifetime);
+            !expired && candidates.iter().any(|r| r.id == guard.relay_id)
+        });
+        self.fill_guards(&mut guards, consensus, &candidates);
+
+        let ours = candidates.iter().copied().filter(|r| guards.contains(&r.id)).collect();
+        let (live, down) = self.partition_failed(ours).await;
+        let selected = if !live.is_empty() {
+            self.select_weighted_by(live, |r| r.bandwidth as u64)
+        } else {
+            // Every guard is down: take on another, if any is up
+            let unused = candidates.iter().copied().filter(|r| !guards.contains(&r.id)).collect();
+            let (unused, _) = self.partition_failed(unused).await;
+            match self.select_weighted(consensus, unused, 0) {
+                Ok(guard) => {
+                    log::warn!("All {} guards are down, adding {}", guards.guards.len(), guard.nickname);
+                    guards.record(&guard);
+                    Ok(guard)
+                }
+                Err(_) => self.select_weighted_by(down, |r| r.bandwidth as u64),
+            }
+        };
+
+        if *guards != before {
+            self.save_guards(&guards);
+        }
+        selected
+    }
+
+    /// Sample guards from `candidates` until there are `num_guards` (or no more candidates)
+    fn fill_guards(&self, guards: &mut GuardSet, consensus: &NetworkConsensus, candidates: &[&RelayDescriptor]) {
+        while guards.guards.len() < self.num_guards {
+            let unused: Vec<&RelayDescriptor> =
+                candidates.iter().copied().filter(|r| !guards.contains(&r.id)).collect();
+            let Ok(guard) = self.select_weighted(consensus, unused, 0) else {
+                break;
+            };
+            guards.record(&guard);
+        }
+    }
+
+    fn save_guards(&self, guards: &GuardSet) {
+        if let Some(data_directory) = &self.data_directory {
+            let path = data_directory.join(GUARDS_FILE);
+            if let Err(e) = guards.save(&path) {
+                log::warn!("Failed to save guards to {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Bring our guards in line with a new consensus: guards it no longer
+    /// lists, or lists as not Running, are dropped and replaced, and the rest
+    /// are kept. Returns the dropped guards.
+    pub async fn reconcile_guards(&self, consensus: &NetworkConsensus) -> Vec<GuardEntry> {
+        let mut guards = self.guards.write().await;
+        let down = guards.reconcile(consensus);
+        if down.is_empty() {
+            return down;
+        }
+        for guard in &down {
+            log::info!("Guard {} ({}) is gone or not Running, replacing it", guard.nickname, guard.relay_id);
+        }
+        let candidates: Vec<&RelayDescriptor> =
+            consensus.relays.values().filter(|r| self.is_relay_suitable(r, 0)).collect();
+        self.fill_guards(&mut guards, consensus, &candidates);
+        self.save_guards(&guards);
+        down
+    }
+
+    /// Pick one of the configured bridges uniformly; no consensus needed
+    async fn select_bridge(&self) -> Result<RelayDescriptor, DirectoryError> {
+        let bridges = self.without_failed(self.bridges.iter().collect()).await;
+        log::debug!("Selecting among {} bridges", bridges.len());
+        self.select_weighted_by(bridges, |_| 1)
+    }
+
+    /// Pick an exit whose exit policy accepts IPv4 connections to `port`
+    pub async fn select_exit_for_port(&self, port: u16) -> Result<RelayDescriptor, DirectoryError> {
+        self.select_exit(Some(ExitTarget::ipv4(port)), &[]).await
+    }
+
+    /// Pick an exit whose exit policy for `target`'s address family accepts its port
+    pub async fn select_exit_for_target(&self, target: ExitTarget) -> Result<RelayDescriptor, DirectoryError> {
+        self.select_exit(Some(target), &[]).await
+    }
+
+
+    /// Pick an exit whose policy allows IPv4 connections to `port` (if given),
+    /// weighted by bandwidth times the consensus exit-position weight
+    pub async fn select_exit_relay(&self, port: Option<u16>) -> Result<RelayDescriptor, DirectoryError> {
+        self.select_exit(port.map(ExitTarget::ipv4), &[]).await
+    }
+
+    async fn select_exit(
+        &self,
+        target: Option<ExitTarget>,
+        path: &[RelayDescriptor],
+    ) -> Result<RelayDescriptor, DirectoryError> {
+        let consensus = self.fetch_consensus().await?;
+        self.select_exit_from(&consensus, target, path).await
+    }
+
+    async fn select_exit_from(
+        &self,
+        consensus: &NetworkConsensus,
+        target: Option<ExitTarget>,
+        path: &[RelayDescriptor],
+    ) -> Result<RelayDescriptor, DirectoryError> {
+        let exits: Vec<&RelayDescriptor> = self.fitting_path(self.suitable_relays(consensus, 2).await, path)
+            .into_iter()
+            .filter(|r| match (target, &r.exit_policy) {
+                (Some(target), _) => r.allows_exit_to(target),
+                (None, Some(policy)) => policy.allows_any_port(),
+                // Unknown policy: let the exit decide
+                (None, None) => true,
+            })
+            .collect();
+
+        log::debug!("Found {} suitable exits for {:?}", exits.len(), target);
+        let exits = self.without_failed(exits).await;
+
+        if exits.is_empty() {
+            // A specific port needs an exit that allows it; don't fall back.
+            // Otherwise any Exit-flagged relay will do, but never a non-exit.
+            let fallback = if target.is_none() {
+                let exits = self.fallback_relays(consensus).into_iter().filter(|r| r.flags.contains(&RelayFlag::Exit));
+                self.fitting_path(exits.collect(), path)
+            } else {
+                Vec::new()
+            };
+            if fallback.is_empty() {
+                return Err(DirectoryError::NoSuitableRelays);
+            }
+            log::warn!("Using fallback for exit hop");
+            return self.select_weighted(consensus, fallback, 2);
+        }
+
+        self.select_weighted(consensus, exits, 2)
     }
 }
\ No newline at end of file
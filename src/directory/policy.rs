@@ -0,0 +1,108 @@
+// src/directory/policy.rs
+//! Exit policy summaries, as found in consensus and microdescriptor "p"
+//! (IPv4) and "p6" (IPv6) lines
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+/// "accept 80,443,1000-2000" or "reject 1-65535": the ports a relay will
+/// (or won't) exit to on most addresses
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExitPolicySummary {
+    pub accept: bool,
+    /// Inclusive port ranges the keyword applies to
+    pub ports: Vec<(u16, u16)>,
+}
+
+impl ExitPolicySummary {
+    /// Parse the part after "p ", e.g. "accept 80,443"
+    pub fn parse(summary: &str) -> Option<Self> {
+        let mut parts = summary.split_whitespace();
+        let accept = match parts.next()? {
+            "accept" => true,
+            "reject" => false,
+            _ => return None,
+        };
+        let ports = parts
+            .next()?
+            .split(',')
+            .map(|range| match range.split_once('-') {
+                Some((low, high)) => Some((low.parse().ok()?, high.parse().ok()?)),
+                None => range.parse().ok().map(|port| (port, port)),
+            })
+            .collect::<Option<Vec<(u16, u16)>>>()?;
+        Some(Self { accept, ports })
+    }
+
+    pub fn allows_port(&self, port: u16) -> bool {
+        let listed = self.ports.iter().any(|&(low, high)| (low..=high).contains(&port));
+        listed == self.accept
+    }
+
+    /// Whether the relay exits to any port at all
+    pub fn allows_any_port(&self) -> bool {
+        if self.accept {
+            !self.ports.is_empty()
+        } else {
+            self.ports != [(1, 65535)]
+        }
+    }
+}
+
+impl std::fmt::Display for ExitPolicySummary {
+    /// The summary as it appears in a "p" line, e.g. "accept 80,443,1000-2000"
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(if self.accept { "accept " } else { "reject " })?;
+        for (i, &(low, high)) in self.ports.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            if low == high {
+                write!(f, "{}", low)?;
+            } else {
+                write!(f, "{}-{}", low, high)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where a stream is headed, as far as the exit's policy is concerned
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ExitTarget {
+    pub port: u16,
+    /// The destination is an IPv6 address, so the "p6" summary applies
+    pub ipv6: bool,
+}
+
+impl ExitTarget {
+    pub fn ipv4(port: u16) -> Self {
+        Self { port, ipv6: false }
+    }
+
+    pub fn ipv6(port: u16) -> Self {
+        Self { port, ipv6: true }
+    }
+
+    /// The target for `host:port`. Hostnames are resolved by the exit, which
+    /// connects over IPv4 unless told otherwise, so only IPv6 literals count as IPv6.
+    pub fn for_host(host: &str, port: u16) -> Self {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        Self { port, ipv6: matches!(host.parse::<IpAddr>(), Ok(IpAddr::V6(_))) }
+    }
+}
+
+/// Whether an exit with these summaries allows `target`. An unknown IPv4
+/// policy gets the benefit of the doubt (the exit refuses with RELAY_END
+/// otherwise). Relays leave out "p6" when they don't exit to IPv6 at all, so
+/// a missing IPv6 summary only counts as unknown when the IPv4 one is too.
+pub fn exit_allows(
+    ipv4: Option<&ExitPolicySummary>,
+    ipv6: Option<&ExitPolicySummary>,
+    target: ExitTarget,
+) -> bool {
+    match (target.ipv6, ipv4, ipv6) {
+        (false, Some(policy), _) | (true, _, Some(policy)) => policy.allows_port(target.port),
+        (false, None, _) => true,
+        (true, known, None) => known.is_none(),
+    }
+}
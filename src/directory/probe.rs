@@ -0,0 +1,41 @@
+// src/directory/probe.rs
+//! TCP reachability probes of candidate relays, for diagnosing which hops
+//! of a circuit we can't get to
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+/// How many of one hop's candidate relays accepted a connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HopReachability {
+    pub hop: usize,
+    pub probed: usize,
+    pub reachable: usize,
+}
+
+/// Try a TCP connection to each address, at most `concurrency` at a time,
+/// giving each `timeout`. Results are in the order of `addresses`.
+pub async fn probe_addresses(addresses: &[SocketAddr], concurrency: usize, timeout: Duration) -> Vec<bool> {
+    let permits = Arc::new(Semaphore::new(concurrency.max(1)));
+    let mut probes = JoinSet::new();
+    for (index, &address) in addresses.iter().enumerate() {
+        let permits = permits.clone();
+        probes.spawn(async move {
+            let _permit = permits.acquire_owned().await;
+            let reachable = matches!(tokio::time::timeout(timeout, TcpStream::connect(address)).await, Ok(Ok(_)));
+            log::debug!("Probe of {}: {}", address, if reachable { "reachable" } else { "unreachable" });
+            (index, reachable)
+        });
+    }
+
+    let mut results = vec![false; addresses.len()];
+    while let Some(probe) = probes.join_next().await {
+        if let Ok((index, reachable)) = probe {
+            results[index] = reachable;
+        }
+    }
+    results
+}
@@ -0,0 +1,44 @@
+// src/directory/rate_limit.rs
+//! A token bucket capping how often we hit the collector and directory mirrors
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: u32,
+    /// Time for one token to come back
+    refill_interval: Duration,
+    tokens: u32,
+    /// When the next token comes back; meaningless while the bucket is full
+    next_refill: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket allowing bursts of `capacity`, then one request per `refill_interval`
+    pub fn new(capacity: u32, refill_interval: Duration) -> Self {
+        let capacity = capacity.max(1);
+        Self { capacity, refill_interval, tokens: capacity, next_refill: Instant::now() }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        while self.tokens < self.capacity && self.next_refill <= now {
+            self.tokens += 1;
+            self.next_refill += self.refill_interval;
+        }
+    }
+
+    /// Take a token now, or say how long until one is available
+    pub fn try_acquire(&mut self) -> Result<(), Duration> {
+        let now = Instant::now();
+        self.refill(now);
+        if self.tokens == 0 {
+            return Err(self.next_refill.saturating_duration_since(now));
+        }
+        // A full bucket wasn't refilling; the first token comes back a full interval from now
+        if self.tokens == self.capacity {
+            self.next_refill = now + self.refill_interval;
+        }
+        self.tokens -= 1;
+        Ok(())
+    }
+}
@@ -0,0 +1,93 @@
+// src/directory/source.rs
+//! Where consensus documents come from. Sources are tried in order for each
+//! hour the client looks back, so a Collector outage falls through to a
+//! mirror or a local copy instead of leaving the client without a consensus.
+use std::path::PathBuf;
+
+/// Collector itself; a mirror or test server can stand in for it
+pub const TOR_COLLECTOR: &str = "https://collector.torproject.org";
+
+/// Collector's archive of recent microdesc-flavored consensuses
+pub const TOR_COLLECTOR_BASE: &str =
+    "https://collector.torproject.org/recent/relay-descriptors/microdescs/consensus-microdesc";
+
+/// Where under a Collector its consensus and microdescriptor archives are
+const COLLECTOR_CONSENSUS_PATH: &str = "/recent/relay-descriptors/microdescs/consensus-microdesc";
+const COLLECTOR_MICRODESC_PATH: &str = "/recent/relay-descriptors/microdescs/micro";
+
+/// Environment variable naming a Collector to use instead of `TOR_COLLECTOR`
+pub const COLLECTOR_URL_ENV: &str = "TOR_CLIENT_COLLECTOR_URL";
+
+/// Where a directory cache serves the current microdesc consensus (dir-spec 4.3)
+const DIRPORT_CONSENSUS_PATH: &str = "/tor/status-vote/current/consensus-microdesc";
+
+/// Accepted as a name for Collector, as older configurations list it
+const COLLECTOR_ALIAS: &str = "tor-collector";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConsensusSource {
+    /// A Collector-style archive: one document per hour under the base URL,
+    /// named "YYYY-MM-DD-HH-00-00-consensus-microdesc"
+    Archive(String),
+    /// A relay or authority DirPort ("http://host:port"), which only serves
+    /// the current consensus
+    Mirror(String),
+    /// A local directory holding documents named like an archive's
+    Directory(PathBuf),
+}
+
+/// The consensus archive of the Collector at `collector` ("https://host")
+pub fn collector_consensus_base(collector: &str) -> String {
+    format!("{}{}", collector.trim_end_matches('/'), COLLECTOR_CONSENSUS_PATH)
+}
+
+/// The microdescriptor archive of the Collector at `collector`
+pub fn collector_microdesc_base(collector: &str) -> String {
+    format!("{}{}", collector.trim_end_matches('/'), COLLECTOR_MICRODESC_PATH)
+}
+
+impl ConsensusSource {
+    /// "tor-collector" is Collector, an http(s) URL with a path an archive, one
+    /// without a path a DirPort mirror, and anything else a local directory
+    pub fn parse(source: &str) -> Self {
+        let source = source.trim();
+        if source == COLLECTOR_ALIAS {
+            return ConsensusSource::Archive(TOR_COLLECTOR_BASE.to_string());
+        }
+        let Some(rest) = source.strip_prefix("http://").or_else(|| source.strip_prefix("https://")) else {
+            return ConsensusSource::Directory(PathBuf::from(source));
+        };
+        let source = source.trim_end_matches('/').to_string();
+        if rest.trim_end_matches('/').contains('/') {
+            ConsensusSource::Archive(source)
+        } else {
+            ConsensusSource::Mirror(source)
+        }
+    }
+
+    /// Where this source keeps the consensus published at `name` (an
+    /// archive-style file name); None if it only has the current one and
+    /// `current` isn't set
+    pub fn location(&self, name: &str, current: bool) -> Option<String> {
+        match self {
+            ConsensusSource::Archive(base) => Some(format!("{}/{}", base, name)),
+            ConsensusSource::Mirror(base) => current.then(|| format!("{}{}", base, DIRPORT_CONSENSUS_PATH)),
+            ConsensusSource::Directory(dir) => Some(dir.join(name).to_string_lossy().into_owned()),
+        }
+    }
+
+    /// Whether `location` must be downloaded rather than read from disk
+    pub fn is_remote(&self) -> bool {
+        !matches!(self, ConsensusSource::Directory(_))
+    }
+}
+
+impl std::fmt::Display for ConsensusSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConsensusSource::Archive(base) => write!(f, "archive {}", base),
+            ConsensusSource::Mirror(base) => write!(f, "mirror {}", base),
+            ConsensusSource::Directory(dir) => write!(f, "directory {}", dir.display()),
+        }
+    }
+}
@@ -0,0 +1,103 @@
+// src/http.rs
+//! Just enough HTTP/1.1 for `TorClient::http_get`: parsing http:// URLs,
+//! writing a GET request and pulling the body out of the response.
+
+/// An http:// URL, split into what the exit and the request need
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpUrl {
+    pub host: String,
+    pub port: u16,
+    /// Path and query, always starting with '/'
+    pub path: String,
+}
+
+impl HttpUrl {
+    /// Parse `http://host[:port][/path]`; the port defaults to 80. Other
+    /// schemes (https included) aren't supported.
+    pub fn parse(url: &str) -> Option<Self> {
+        let rest = url.strip_prefix("http://")?;
+        let (authority, path) = match rest.find(['/', '?', '#']) {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let path = path.split('#').next().unwrap_or_default();
+        let path = if path.starts_with('/') { path.to_string() } else { format!("/{}", path) };
+
+        // Bracketed IPv6 literals contain colons of their own
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (host, port.parse().ok()?),
+            _ => (authority, 80),
+        };
+        if host.is_empty() || host.contains('@') {
+            return None;
+        }
+        let host = host.trim_start_matches('[').trim_end_matches(']').to_string();
+        Some(Self { host, port, path })
+    }
+
+    /// The Host header value: the port is left out when it's the default
+    fn host_header(&self) -> String {
+        let host = if self.host.contains(':') { format!("[{}]", self.host) } else { self.host.clone() };
+        match self.port {
+            80 => host,
+            port => format!("{}:{}", host, port),
+        }
+    }
+
+    /// A minimal GET; the server closes the connection once it has answered
+    pub fn get_request(&self) -> Vec<u8> {
+        format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nAccept-Encoding: identity\r\n\r\n",
+            self.path,
+            self.host_header()
+        )
+        .into_bytes()
+    }
+}
+
+/// The body of a complete HTTP/1.x response, de-chunked if need be
+pub fn response_body(response: &[u8]) -> Result<String, String> {
+    let header_end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or("response has no end of headers")?;
+    let head = String::from_utf8_lossy(&response[..header_end]);
+    let mut lines = head.split("\r\n");
+
+    let status_line = lines.next().unwrap_or_default();
+    if !status_line.starts_with("HTTP/1.") {
+        return Err(format!("not an HTTP response: {:?}", status_line));
+    }
+    let status = status_line.split_whitespace().nth(1).unwrap_or_default();
+    if !status.starts_with('2') {
+        return Err(format!("HTTP status {}", status_line));
+    }
+
+    let chunked = lines.filter_map(|line| line.split_once(':')).any(|(name, value)| {
+        name.trim().eq_ignore_ascii_case("transfer-encoding") && value.to_ascii_lowercase().contains("chunked")
+    });
+    let body = &response[header_end + 4..];
+    let body = if chunked { dechunk(body)? } else { body.to_vec() };
+    String::from_utf8(body).map_err(|_| "response body is not UTF-8".to_string())
+}
+
+/// Undo `Transfer-Encoding: chunked`
+fn dechunk(mut body: &[u8]) -> Result<Vec<u8>, String> {
+    let mut decoded = Vec::new();
+    loop {
+        let line_end = body.windows(2).position(|w| w == b"\r\n").ok_or("truncated chunk size")?;
+        let size_line = String::from_utf8_lossy(&body[..line_end]);
+        // Chunk extensions follow a ';'
+        let size = size_line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| format!("bad chunk size {:?}", size))?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Ok(decoded);
+        }
+        if body.len() < size + 2 {
+            return Err("truncated chunk".to_string());
+        }
+        decoded.extend_from_slice(&body[..size]);
+        body = &body[size + 2..];
+    }
+}
@@ -1,18 +1,107 @@
 // src/lib.rs
 
+use serde::Deserialize;
 use std::sync::Arc;
 
-pub use circuit::{CircuitId, CircuitManager, CircuitError};
+pub use circuit::{
+    CircuitError, CircuitEvent, CircuitFailureKind, CircuitId, CircuitInfo, CircuitLoad, CircuitManager,
+    CircuitReadyHook, CircuitStream, HopInfo, HopState, IsolationKey,
+};
 pub use directory::{DirectoryClient, DirectoryError};
+pub use network::{ClientHello, SniStrategy, TlsBackend};
 // pub use proxy::ProxyServer;
 
-#[derive(Debug, Clone)]
+/// Settings for a `TorClient`. Deserializable (see `from_toml_file`), with
+/// missing keys left at their defaults and durations given in seconds or as
+/// "90s", "10m" and the like.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct TorConfig {
+    /// Where state such as the cached consensus is kept; created if missing.
+    /// Required: `TorClient::start` refuses an empty one.
     pub data_directory: String,
     pub socks_port: u16,
+    /// Interface the SOCKS proxy listens on. Loopback by default; anything
+    /// else offers the proxy to the network and is logged as a warning.
+    pub socks_bind_address: std::net::IpAddr,
+    /// Port for controllers (stem, nyx) on 127.0.0.1. It takes no
+    /// authentication, so it's never bound to other addresses.
     pub control_port: u16,
+    /// Where to fetch the consensus from, in order: Collector-style archive URLs,
+    /// DirPort mirrors or local directories. Empty uses the built-in mock directory.
     pub directory_authorities: Vec<String>,
+    /// Collector to use instead of collector.torproject.org ("https://host"),
+    /// e.g. a mirror or a test server. Falls back to the
+    /// TOR_CLIENT_COLLECTOR_URL environment variable, then the real one.
+    pub collector_base_url: Option<String>,
+    /// Relay fingerprints (hex or base64) or nicknames to always use as the first hop; when empty a
+    /// small guard set is sampled and saved under `data_directory`
     pub entry_guards: Vec<String>,
+    /// How many guards to sample
+    pub num_entry_guards: usize,
+    /// Sampled guards are rotated out after this long
+    #[serde(deserialize_with = "config::deserialize_duration")]
+    pub guard_lifetime: std::time::Duration,
+    /// Bypass Tor entirely and connect SOCKS clients straight to their target.
+    /// Only meant for testing the proxy without a working circuit path.
+    pub direct_connect_insecure: bool,
+    /// Skip relays running a Tor version older than this (e.g. "0.4.7.0")
+    pub min_relay_version: Option<String>,
+    /// Maximum circuits multiplexed over one guard connection (None = unlimited)
+    pub max_circuits_per_guard: Option<usize>,
+    /// TLS implementation for relay connections
+    pub tls_backend: TlsBackend,
+    /// Server name sent in the link ClientHello ("random" gives a made-up
+    /// www-style name per connection, as Tor does)
+    pub tls_sni: SniStrategy,
+    /// Protocols offered with ALPN on links (empty = no ALPN extension)
+    pub tls_alpn: Vec<String>,
+    /// Require the Stable flag for middle relays; disable to also use Fast-only relays
+    pub require_stable_middle: bool,
+    /// Sort relays into guard/middle/exit buckets once per consensus instead of
+    /// filtering every relay for each hop
+    pub bucket_relays: bool,
+    /// Never put two relays from the same /16 in one circuit, as Tor does.
+    /// Turn off for test networks running every relay on one host.
+    pub enforce_distinct_subnets: bool,
+    /// This machine's own addresses (e.g. its public IP); relays at any of
+    /// them are never used, as a hop through ourselves gains nothing
+    pub local_addresses: Vec<std::net::IpAddr>,
+    /// How long to wait for a relay to answer a circuit handshake
+    #[serde(deserialize_with = "config::deserialize_duration")]
+    pub handshake_read_timeout: std::time::Duration,
+    /// Other relays tried for a hop whose relay can't be reached or fails
+    /// its handshake, before the circuit build fails
+    pub hop_retries: usize,
+    /// Overall limit on an `http_get`: building the circuit, opening the
+    /// stream, sending the request and reading the whole response
+    #[serde(deserialize_with = "config::deserialize_duration")]
+    pub http_timeout: std::time::Duration,
+    /// Refetch the consensus once it's been in use this long, even if still valid
+    #[serde(deserialize_with = "config::deserialize_duration")]
+    pub max_consensus_age: std::time::Duration,
+    /// How often expired circuits are looked for
+    #[serde(deserialize_with = "config::deserialize_duration")]
+    pub circuit_reap_interval: std::time::Duration,
+    /// Circuits older than this are closed, so new streams get fresh circuits
+    #[serde(deserialize_with = "config::deserialize_duration")]
+    pub max_circuit_dirtiness: std::time::Duration,
+    /// Circuits still being built after this long are abandoned; the build
+    /// timeout starts here and adapts downwards as builds are timed
+    #[serde(deserialize_with = "config::deserialize_duration")]
+    pub max_circuit_build_time: std::time::Duration,
+    /// Ready, unused circuits kept on hand so new SOCKS clients don't wait for a
+    /// build. They have the usual three hops, so `sensitive_hosts` still wait.
+    pub circuit_pool_size: usize,
+    /// Hosts (or ".example.com" for a whole domain) whose SOCKS streams get
+    /// `sensitive_circuit_hops`-hop circuits instead of the usual three
+    pub sensitive_hosts: Vec<String>,
+    pub sensitive_circuit_hops: usize,
+    /// Circuits allowed to handshake at once; more builds wait their turn (None = unlimited)
+    pub max_concurrent_builds: Option<usize>,
+    /// Bridge lines ("IP:ORPort FINGERPRINT NTOR-ONION-KEY [ED25519-ID]"); when
+    /// set, circuits enter the network through these instead of consensus guards
+    pub bridges: Vec<String>,
     // pub exit_policy: ExitPolicy,
 }
 
@@ -21,9 +110,35 @@ impl Default for TorConfig {
         Self {
             data_directory: "".to_string(),
             socks_port: 9050,
+            socks_bind_address: std::net::Ipv4Addr::LOCALHOST.into(),
             control_port: 9051,
             directory_authorities: vec![],
+            collector_base_url: None,
             entry_guards: vec![],
+            num_entry_guards: 3,
+            guard_lifetime: std::time::Duration::from_secs(60 * 24 * 3600),
+            direct_connect_insecure: false,
+            min_relay_version: None,
+            max_circuits_per_guard: None,
+            tls_backend: TlsBackend::NativeTls,
+            tls_sni: SniStrategy::Random,
+            tls_alpn: vec![],
+            require_stable_middle: true,
+            bucket_relays: false,
+            enforce_distinct_subnets: true,
+            local_addresses: vec![],
+            handshake_read_timeout: std::time::Duration::from_secs(10),
+            hop_retries: 2,
+            http_timeout: std::time::Duration::from_secs(120),
+            bridges: vec![],
+            max_consensus_age: std::time::Duration::from_secs(3600),
+            circuit_reap_interval: std::time::Duration::from_secs(30),
+            max_circuit_dirtiness: std::time::Duration::from_secs(600),
+            max_circuit_build_time: std::time::Duration::from_secs(60),
+            circuit_pool_size: 2,
+            max_concurrent_builds: None,
+            sensitive_hosts: vec![],
+            sensitive_circuit_hops: 4,
         }
     }
 }
@@ -31,70 +146,257 @@ impl Default for TorConfig {
 impl TorConfig {
     pub fn test_config() -> Self {
         Self {
-            data_directory: "".to_string(),
+            data_directory: std::env::temp_dir()
+                .join(format!("tor-client-test-{}", rand::random::<u64>()))
+                .to_string_lossy()
+                .into_owned(),
             socks_port: 9050,
+            socks_bind_address: std::net::Ipv4Addr::LOCALHOST.into(),
             control_port: 9051,
             directory_authorities: vec![],
+            collector_base_url: None,
             entry_guards: vec![],
+            num_entry_guards: 3,
+            guard_lifetime: std::time::Duration::from_secs(60 * 24 * 3600),
+            direct_connect_insecure: false,
+            min_relay_version: None,
+            max_circuits_per_guard: None,
+            tls_backend: TlsBackend::NativeTls,
+            tls_sni: SniStrategy::Random,
+            tls_alpn: vec![],
+            require_stable_middle: true,
+            bucket_relays: false,
+            enforce_distinct_subnets: false,
+            local_addresses: vec![],
+            handshake_read_timeout: std::time::Duration::from_secs(10),
+            hop_retries: 2,
+            http_timeout: std::time::Duration::from_secs(120),
+            bridges: vec![],
+            max_consensus_age: std::time::Duration::from_secs(3600),
+            circuit_reap_interval: std::time::Duration::from_secs(30),
+            max_circuit_dirtiness: std::time::Duration::from_secs(600),
+            max_circuit_build_time: std::time::Duration::from_secs(60),
+            circuit_pool_size: 2,
+            max_concurrent_builds: None,
+            sensitive_hosts: vec![],
+            sensitive_circuit_hops: 4,
         }
     }
 }
 
 
-use crate::proxy::socks5::Socks5Proxy;
+use crate::bootstrap::Bootstrap;
+use crate::control::ControlPort;
+use crate::metrics::Metrics;
+use crate::proxy::socks5::{CircuitLength, Socks5Proxy, DEFAULT_CIRCUIT_HOPS};
+
+const METRICS_SNAPSHOT_FILE: &str = "metrics.json";
+/// `http_get` gives up on a server that goes quiet this long mid-response
+const HTTP_IDLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
 
 pub struct TorClient {
     circuit_manager: Arc<CircuitManager>,
     directory_client: Arc<DirectoryClient>,
     pub socks5_proxy: Socks5Proxy,
+    pub control_port: ControlPort,
+    metrics: Arc<Metrics>,
+    bootstrap: Arc<Bootstrap>,
+    /// Reaper and circuit pool, stopped on shutdown
+    background_tasks: Vec<tokio::task::JoinHandle<()>>,
+    /// Where state is saved on shutdown (None = nothing persisted)
+    data_directory: Option<std::path::PathBuf>,
+    http_timeout: std::time::Duration,
 }
 
 impl TorClient {
     pub async fn start(config: TorConfig) -> Result<Self, TorError> {
-        let circuit_manager = Arc::new(CircuitManager::new());
+        config.validate().map_err(|e| TorError::Config(e.to_string()))?;
+        let metrics = Arc::new(Metrics::new());
+        let bootstrap = Arc::new(Bootstrap::new());
+        let circuit_manager = Arc::new(
+            CircuitManager::new()
+                .with_max_circuits_per_guard(config.max_circuits_per_guard)
+                .with_tls_backend(config.tls_backend)
+                .with_client_hello(ClientHello { sni: config.tls_sni.clone(), alpn: config.tls_alpn.clone() })
+                .with_handshake_timeout(config.handshake_read_timeout)
+                .with_hop_retries(config.hop_retries)
+                .with_max_concurrent_builds(config.max_concurrent_builds)
+                .with_build_timeout(config.max_circuit_build_time)
+                .with_metrics(metrics.clone())
+                .with_bootstrap(bootstrap.clone()),
+        );
+        let mut background_tasks = vec![circuit_manager.spawn_reaper(
+            config.circuit_reap_interval,
+            config.max_circuit_dirtiness,
+            config.max_circuit_build_time,
+        )];
         
         // Create directory client with real authorities or mock for testing
         let directory_client = if config.directory_authorities.is_empty() {
             log::warn!("No directory authorities configured, using mock directory");
-            Arc::new(DirectoryClient::new_mock())
+            DirectoryClient::new_mock()
         } else {
             log::info!("Using real directory authorities");
-            Arc::new(DirectoryClient::new(config.directory_authorities))
+            let collector = config
+                .collector_base_url
+                .or_else(|| std::env::var(directory::source::COLLECTOR_URL_ENV).ok());
+            DirectoryClient::new(config.directory_authorities).with_collector(collector.as_deref())
         };
+        let bridges = config
+            .bridges
+            .iter()
+            .map(|line| directory::bridge::parse_bridge_line(line))
+            .collect::<Result<Vec<_>, _>>()?;
+        if !bridges.is_empty() {
+            log::info!("Using {} bridge(s) as entry points", bridges.len());
+        }
+        let directory_client = Arc::new(
+            directory_client
+                .with_bridges(bridges)
+                .with_entry_guards(config.entry_guards)
+                .with_guard_params(config.num_entry_guards, config.guard_lifetime)
+                .with_data_directory(&config.data_directory)
+                .with_require_stable_middle(config.require_stable_middle)
+                .with_relay_buckets(config.bucket_relays)
+                .with_distinct_subnets(config.enforce_distinct_subnets)
+                .with_local_addresses(config.local_addresses)
+                .with_max_consensus_age(config.max_consensus_age)
+                .with_bootstrap(bootstrap.clone())
+                .with_min_relay_version(config.min_relay_version.as_deref())?,
+        );
         
+        if config.circuit_pool_size > 0 && !config.direct_connect_insecure {
+            background_tasks.push(circuit_manager.spawn_circuit_pool(
+                directory_client.clone(),
+                config.circuit_pool_size,
+                DEFAULT_CIRCUIT_HOPS,
+            ));
+        }
+
+        if config.direct_connect_insecure {
+            log::warn!("⚠ direct_connect_insecure is enabled: SOCKS traffic will NOT go through Tor");
+        }
+
+        let socks_address = std::net::SocketAddr::new(config.socks_bind_address, config.socks_port);
+        if !config.socks_bind_address.is_loopback() {
+            log::warn!(
+                "⚠ SOCKS proxy bound to {}, not loopback: anyone who can reach it can use it as an open proxy",
+                socks_address
+            );
+        }
         let socks5_proxy = Socks5Proxy::new(
-            format!("0.0.0.0:{}", config.socks_port),
+            socks_address.to_string(),
             circuit_manager.clone(),
             directory_client.clone(),
-        );
+            config.direct_connect_insecure,
+        )
+        .with_metrics(metrics.clone())
+        .with_circuit_length(CircuitLength::new(config.sensitive_hosts, config.sensitive_circuit_hops));
+
+        let control_port = ControlPort::new(format!("127.0.0.1:{}", config.control_port), circuit_manager.clone());
+
+        let http_timeout = config.http_timeout;
+        let data_directory = Some(config.data_directory)
+            .filter(|dir| !dir.is_empty())
+            .map(std::path::PathBuf::from);
 
         Ok(Self {
             circuit_manager,
             directory_client,
             socks5_proxy,
+            control_port,
+            metrics,
+            bootstrap,
+            background_tasks,
+            data_directory,
+            http_timeout,
         })
     }
 
+    pub fn directory(&self) -> &Arc<DirectoryClient> {
+        &self.directory_client
+    }
+
+    /// Counters shared by the circuit manager and the SOCKS proxy; log
+    /// `report()` or export `to_json()` for a snapshot
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// Bootstrap progress: fetching the consensus, then building the first circuit
+    pub fn bootstrap(&self) -> &Arc<Bootstrap> {
+        &self.bootstrap
+    }
+
+    /// Circuit events (extended, built, failed, closed) from now on, for
+    /// applications that follow circuits as they come and go
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<CircuitEvent> {
+        self.circuit_manager.subscribe()
+    }
+
     pub async fn create_circuit(&self, num_hops: usize) -> Result<CircuitId, TorError> {
         self.circuit_manager
-            .create_circuit(num_hops, &self.directory_client)
+            .create_circuit(num_hops, false, &self.directory_client)
             .await
             .map_err(TorError::Circuit)
     }
 
+    /// New streams from now on go over new circuits, unlinkable to earlier
+    /// ones (Tor's NEWNYM); see `CircuitManager::new_identity`
+    pub async fn new_identity(&self) {
+        self.circuit_manager.new_identity().await;
+    }
+
+    /// Fetch an http:// URL through a circuit whose exit allows its port,
+    /// returning the response body. Gives up with `TorError::Timeout` once
+    /// `http_timeout` has passed, however far the request got.
     pub async fn http_get(&self, url: &str) -> Result<String, TorError> {
-        // Create a circuit first
-        let _circuit_id = self.create_circuit(3).await?;
-        
-        // TODO: Route HTTP request through the circuit
-        // For now, this is a placeholder
-        log::warn!("HTTP GET not fully implemented, URL: {}", url);
-        Err(TorError::NotImplemented("HTTP GET through Tor circuit".to_string()))
+        tokio::time::timeout(self.http_timeout, self.fetch(url))
+            .await
+            .map_err(|_| TorError::Timeout(self.http_timeout))?
     }
 
+    async fn fetch(&self, url: &str) -> Result<String, TorError> {
+        let parsed = http::HttpUrl::parse(url).ok_or_else(|| TorError::Http(format!("unsupported URL {}", url)))?;
+        let target = directory::policy::ExitTarget::for_host(&parsed.host, parsed.port);
+        let circuit_id = self
+            .circuit_manager
+            .circuit_for_target(3, Some(target), &self.directory_client)
+            .await?;
+
+        let mut stream = self.circuit_manager.begin_stream(circuit_id, &parsed.host, parsed.port).await?;
+        for chunk in parsed.get_request().chunks(network::cells::RELAY_PAYLOAD_LEN) {
+            stream.send(network::cells::RELAY_COMMAND_DATA, chunk.to_vec()).await?;
+        }
+        let response = stream.read_to_end(HTTP_IDLE_TIMEOUT).await?;
+        log::debug!("GET {} returned {} bytes over circuit {}", url, response.len(), circuit_id);
+        http::response_body(&response).map_err(TorError::Http)
+    }
+
+    /// Stop background work and save the guard set, consensus and a metrics
+    /// snapshot to `data_directory`, so the next start is fast and keeps its guards
     pub async fn shutdown(self) {
         log::info!("Shutting down TorClient");
-        // TODO: Cleanup circuits, close connections
+        for task in &self.background_tasks {
+            task.abort();
+        }
+        self.socks5_proxy.shutdown();
+        let closed = self.circuit_manager.close_all_circuits().await;
+        log::info!("Closed {} circuit(s)", closed);
+
+        let Some(data_directory) = &self.data_directory else {
+            return;
+        };
+        if let Err(e) = self.directory_client.save_state().await {
+            log::warn!("Failed to save directory state to {}: {}", data_directory.display(), e);
+        }
+        let snapshot = data_directory.join(METRICS_SNAPSHOT_FILE);
+        let written = serde_json::to_vec(&self.metrics.to_json())
+            .map_err(std::io::Error::from)
+            .and_then(|json| std::fs::write(&snapshot, json));
+        if let Err(e) = written {
+            log::warn!("Failed to write metrics snapshot {}: {}", snapshot.display(), e);
+        }
     }
 }
 
@@ -103,6 +405,12 @@ pub enum TorError {
     Circuit(CircuitError),
     Directory(DirectoryError),
     Proxy(crate::proxy::socks5::ProxyError),
+    /// An unsupported URL or a response that isn't a successful HTTP one
+    Http(String),
+    /// The operation didn't finish within this limit
+    Timeout(std::time::Duration),
+    /// The configuration was rejected by `TorConfig::validate`
+    Config(String),
     NotImplemented(String),
 }
 
@@ -111,7 +419,10 @@ impl std::fmt::Display for TorError {
         match self {
             TorError::Circuit(e) => write!(f, "Circuit error: {:?}", e),
             TorError::Directory(e) => write!(f, "Directory error: {}", e),
-            TorError::Proxy(e) => write!(f, "Proxy error: {:?}", e),
+            TorError::Proxy(e) => write!(f, "Proxy error: {}", e),
+            TorError::Http(e) => write!(f, "HTTP error: {}", e),
+            TorError::Timeout(limit) => write!(f, "Timed out after {:?}", limit),
+            TorError::Config(e) => write!(f, "Invalid configuration: {}", e),
             TorError::NotImplemented(s) => write!(f, "Not implemented: {}", s),
         }
     }
@@ -131,9 +442,15 @@ impl From<DirectoryError> for TorError {
     }
 }
 
+pub mod bootstrap;
 pub mod circuit;
+pub mod config;
+pub mod control;
 pub mod crypto;
 pub mod directory;
+pub mod hs;
+pub mod http;
 pub mod proxy;
 pub mod security;
-pub mod metrics;
\ No newline at end of file
+pub mod metrics;
+pub mod network;
\ No newline at end of file
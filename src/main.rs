@@ -1,5 +1,7 @@
 // src/main.rs
-use tor_client::{TorClient, TorConfig};
+use std::io::Read;
+use tor_client::config::CONFIG_PATH_ENV;
+use tor_client::{SniStrategy, TlsBackend, TorClient, TorConfig};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -7,34 +9,95 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     
     log::info!("🚀 Starting Tor client...");
     
-    let config = TorConfig {
-        data_directory: dirs::home_dir()
-            .unwrap_or_else(|| std::path::PathBuf::from("/tmp"))
-            .join(".tor-client")
-            .to_string_lossy()
-            .to_string(),
-        socks_port: 9050,
-        control_port: 9051,
-        directory_authorities: vec!["tor-collector".to_string()], // Not used, for compatibility
-        entry_guards: vec![],
+    // A config file named on the command line or in TOR_CLIENT_CONFIG ("-"
+    // for stdin) replaces the settings below
+    let config = match std::env::args().nth(1).or_else(|| std::env::var(CONFIG_PATH_ENV).ok()) {
+        Some(path) if path == "-" => {
+            let mut text = String::new();
+            std::io::stdin().read_to_string(&mut text)?;
+            TorConfig::from_toml_str(&text)?
+        }
+        Some(path) => {
+            log::info!("📄 Loading config from {}", path);
+            TorConfig::from_toml_file(&path)?
+        }
+        None => default_config(),
     };
+    let control_port = config.control_port;
     
     log::info!("📡 Using Tor Collector: https://collector.torproject.org");
     
     let tor_client = TorClient::start(config).await?;
     
     log::info!("✓ Tor client started");
-    log::info!("🔌 SOCKS5 proxy listening on 127.0.0.1:9050");
+    log::info!("🔌 SOCKS5 proxy listening on {}", tor_client.socks5_proxy.bind_address());
+    log::info!("🎛 Control port listening on 127.0.0.1:{}", control_port);
     
-    tokio::spawn(async move {
-        if let Err(e) = tor_client.socks5_proxy.run().await {
-            log::error!("❌ SOCKS5 proxy error: {:?}", e);
-        }
-    });
-
     log::info!("Press Ctrl+C to shutdown");
-    tokio::signal::ctrl_c().await?;
-    log::info!("👋 Shutting down...");
+    {
+        let proxy = tor_client.socks5_proxy.run();
+        tokio::pin!(proxy);
+        let proxy_result = tokio::select! {
+            result = &mut proxy => result,
+            result = tor_client.control_port.run() => {
+                if let Err(e) = result {
+                    log::error!("❌ Control port error: {}", e);
+                }
+                Ok(())
+            }
+            result = tokio::signal::ctrl_c() => {
+                result?;
+                log::info!("👋 Shutting down...");
+                // Let clients being served finish before circuits are torn down
+                tor_client.socks5_proxy.shutdown();
+                proxy.await
+            }
+        };
+        if let Err(e) = proxy_result {
+            log::error!("❌ SOCKS5 proxy error: {}", e);
+        }
+    }
+    tor_client.shutdown().await;
     
     Ok(())
-}
\ No newline at end of file
+}
+
+fn default_config() -> TorConfig {
+    TorConfig {
+        data_directory: dirs::home_dir()
+            .unwrap_or_else(|| std::path::PathBuf::from("/tmp"))
+            .join(".tor-client")
+            .to_string_lossy()
+            .to_string(),
+        socks_port: 9050,
+        socks_bind_address: std::net::Ipv4Addr::LOCALHOST.into(),
+        control_port: 9051,
+        directory_authorities: vec!["tor-collector".to_string()], // Not used, for compatibility
+        collector_base_url: None,
+        entry_guards: vec![],
+        num_entry_guards: 3,
+        guard_lifetime: std::time::Duration::from_secs(60 * 24 * 3600),
+        direct_connect_insecure: false,
+        min_relay_version: None,
+        max_circuits_per_guard: None,
+        tls_backend: TlsBackend::NativeTls,
+        tls_sni: SniStrategy::Random,
+        tls_alpn: vec![],
+        require_stable_middle: true,
+        bucket_relays: false,
+        enforce_distinct_subnets: true,
+        local_addresses: vec![],
+        handshake_read_timeout: std::time::Duration::from_secs(10),
+        hop_retries: 2,
+        http_timeout: std::time::Duration::from_secs(120),
+        bridges: vec![],
+        max_consensus_age: std::time::Duration::from_secs(3600),
+        circuit_reap_interval: std::time::Duration::from_secs(30),
+        max_circuit_dirtiness: std::time::Duration::from_secs(600),
+        max_circuit_build_time: std::time::Duration::from_secs(60),
+        circuit_pool_size: 2,
+        max_concurrent_builds: None,
+        sensitive_hosts: vec![],
+        sensitive_circuit_hops: 4,
+    }
+}
@@ -1,12 +1,33 @@
 
 // src/metrics.rs
+use crate::circuit::build_timeout::DEFAULT_BUILD_TIMEOUT;
+use crate::circuit::CircuitFailureKind;
+use std::collections::VecDeque;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
 
+/// How many of the latest circuit builds `build_success_rate` covers
+pub const BUILD_OUTCOME_WINDOW: usize = 100;
+
+#[derive(Debug)]
 pub struct Metrics {
     pub circuits_created: AtomicU64,
     pub bytes_sent: AtomicU64,
     pub bytes_received: AtomicU64,
     pub active_circuits: AtomicU64,
+    /// Failed circuit builds, indexed like `CircuitFailureKind::ALL`
+    circuit_failures: [AtomicU64; CircuitFailureKind::ALL.len()],
+    /// Current circuit build timeout, in milliseconds
+    circuit_build_timeout_ms: AtomicU64,
+    /// Whether each of the last `BUILD_OUTCOME_WINDOW` builds succeeded, oldest first
+    recent_builds: Mutex<VecDeque<bool>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Metrics {
@@ -16,16 +37,84 @@ impl Metrics {
             bytes_sent: AtomicU64::new(0),
             bytes_received: AtomicU64::new(0),
             active_circuits: AtomicU64::new(0),
+            circuit_failures: Default::default(),
+            circuit_build_timeout_ms: AtomicU64::new(DEFAULT_BUILD_TIMEOUT.as_millis() as u64),
+            recent_builds: Mutex::new(VecDeque::with_capacity(BUILD_OUTCOME_WINDOW)),
+        }
+    }
+
+    pub fn record_circuit_built(&self) {
+        self.circuits_created.fetch_add(1, Ordering::Relaxed);
+        self.record_build_outcome(true);
+    }
+
+    pub fn record_circuit_failure(&self, kind: CircuitFailureKind) {
+        self.circuit_failures[kind as usize].fetch_add(1, Ordering::Relaxed);
+        self.record_build_outcome(false);
+    }
+
+    fn record_build_outcome(&self, succeeded: bool) {
+        let mut recent = self.recent_builds.lock().unwrap();
+        if recent.len() == BUILD_OUTCOME_WINDOW {
+            recent.pop_front();
         }
+        recent.push_back(succeeded);
+    }
+
+    /// Share of the last `BUILD_OUTCOME_WINDOW` circuit builds that succeeded,
+    /// from 0.0 to 1.0; 1.0 before any build has finished
+    pub fn build_success_rate(&self) -> f64 {
+        let recent = self.recent_builds.lock().unwrap();
+        if recent.is_empty() {
+            return 1.0;
+        }
+        recent.iter().filter(|&&succeeded| succeeded).count() as f64 / recent.len() as f64
+    }
+
+    /// Circuit builds that have failed this way so far
+    pub fn circuit_failures(&self, kind: CircuitFailureKind) -> u64 {
+        self.circuit_failures[kind as usize].load(Ordering::Relaxed)
     }
     
+    pub fn set_circuit_build_timeout(&self, timeout: Duration) {
+        self.circuit_build_timeout_ms.store(timeout.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// How long a circuit build may currently take before it's abandoned
+    pub fn circuit_build_timeout(&self) -> Duration {
+        Duration::from_millis(self.circuit_build_timeout_ms.load(Ordering::Relaxed))
+    }
+
     pub fn report(&self) -> String {
         format!(
-            "circuits_created: {}, bytes_sent: {}, bytes_received: {}, active_circuits: {}",
+            "circuits_created: {}, bytes_sent: {}, bytes_received: {}, active_circuits: {}, build_success_rate: {:.2}, circuit_build_timeout_ms: {}, circuit_failures: {}",
             self.circuits_created.load(Ordering::Relaxed),
             self.bytes_sent.load(Ordering::Relaxed),
             self.bytes_received.load(Ordering::Relaxed),
             self.active_circuits.load(Ordering::Relaxed),
+            self.build_success_rate(),
+            self.circuit_build_timeout_ms.load(Ordering::Relaxed),
+            CircuitFailureKind::ALL
+                .iter()
+                .map(|&kind| format!("{}={}", kind, self.circuit_failures(kind)))
+                .collect::<Vec<_>>()
+                .join(" "),
         )
     }
+
+    /// Snapshot of all counters as JSON, for embedders feeding their own telemetry
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "circuits_created": self.circuits_created.load(Ordering::Relaxed),
+            "bytes_sent": self.bytes_sent.load(Ordering::Relaxed),
+            "bytes_received": self.bytes_received.load(Ordering::Relaxed),
+            "active_circuits": self.active_circuits.load(Ordering::Relaxed),
+            "build_success_rate": self.build_success_rate(),
+            "circuit_build_timeout_ms": self.circuit_build_timeout_ms.load(Ordering::Relaxed),
+            "circuit_failures": CircuitFailureKind::ALL
+                .iter()
+                .map(|&kind| (kind.as_str().to_string(), self.circuit_failures(kind).into()))
+                .collect::<serde_json::Map<String, serde_json::Value>>(),
+        })
+    }
 }
@@ -0,0 +1,587 @@
+// src/network/cells.rs
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+use x25519_dalek::PublicKey;
+
+/// Fixed-length cell size for link protocol v4+: CircID(4) | Command(1) | Payload(509)
+pub const CELL_LEN: usize = 514;
+pub const CELL_PAYLOAD_LEN: usize = 509;
+
+// Cell commands on the wire (tor-spec 3); `CellCommand` is the typed form
+pub const CELL_COMMAND_PADDING: u8 = 0;
+pub const CELL_COMMAND_CREATE: u8 = 1;
+pub const CELL_COMMAND_CREATED: u8 = 2;
+pub const CELL_COMMAND_RELAY: u8 = 3;
+pub const CELL_COMMAND_DESTROY: u8 = 4;
+pub const CELL_COMMAND_CREATE_FAST: u8 = 5;
+pub const CELL_COMMAND_CREATED_FAST: u8 = 6;
+pub const CELL_COMMAND_VERSIONS: u8 = 7;
+pub const CELL_COMMAND_NETINFO: u8 = 8;
+pub const CELL_COMMAND_RELAY_EARLY: u8 = 9;
+pub const CELL_COMMAND_CREATE2: u8 = 10;
+pub const CELL_COMMAND_CREATED2: u8 = 11;
+pub const CELL_COMMAND_PADDING_NEGOTIATE: u8 = 12;
+pub const CELL_COMMAND_VPADDING: u8 = 128;
+pub const CELL_COMMAND_CERTS: u8 = 129;
+pub const CELL_COMMAND_AUTH_CHALLENGE: u8 = 130;
+pub const CELL_COMMAND_AUTHENTICATE: u8 = 131;
+pub const CELL_COMMAND_AUTHORIZE: u8 = 132;
+
+pub const HANDSHAKE_TYPE_NTOR: u16 = 2;
+pub const HANDSHAKE_TYPE_NTOR_V3: u16 = 3;
+
+// Relay cell commands (tor-spec 6.1)
+pub const RELAY_COMMAND_BEGIN: u8 = 1;
+pub const RELAY_COMMAND_DATA: u8 = 2;
+pub const RELAY_COMMAND_END: u8 = 3;
+pub const RELAY_COMMAND_CONNECTED: u8 = 4;
+pub const RELAY_COMMAND_SENDME: u8 = 5;
+pub const RELAY_COMMAND_EXTEND: u8 = 6;
+pub const RELAY_COMMAND_EXTENDED: u8 = 7;
+pub const RELAY_COMMAND_TRUNCATE: u8 = 8;
+pub const RELAY_COMMAND_TRUNCATED: u8 = 9;
+pub const RELAY_COMMAND_DROP: u8 = 10;
+pub const RELAY_COMMAND_RESOLVE: u8 = 11;
+pub const RELAY_COMMAND_RESOLVED: u8 = 12;
+pub const RELAY_COMMAND_BEGIN_DIR: u8 = 13;
+pub const RELAY_COMMAND_EXTEND2: u8 = 14;
+pub const RELAY_COMMAND_EXTENDED2: u8 = 15;
+
+/// Relay cell header: command(1) | recognized(2) | stream_id(2) | digest(4) | length(2)
+pub const RELAY_HEADER_LEN: usize = 11;
+/// Most data a single relay cell can carry
+pub const RELAY_PAYLOAD_LEN: usize = CELL_PAYLOAD_LEN - RELAY_HEADER_LEN;
+
+// RELAY_RESOLVED answer types (tor-spec 6.4)
+pub const RESOLVED_TYPE_HOSTNAME: u8 = 0x00;
+pub const RESOLVED_TYPE_IPV4: u8 = 0x04;
+pub const RESOLVED_TYPE_IPV6: u8 = 0x06;
+pub const RESOLVED_TYPE_ERROR_TRANSIENT: u8 = 0xF0;
+pub const RESOLVED_TYPE_ERROR_NONTRANSIENT: u8 = 0xF1;
+
+// RELAY_END reasons (tor-spec 6.3)
+pub const END_REASON_MISC: u8 = 1;
+pub const END_REASON_RESOLVEFAILED: u8 = 2;
+pub const END_REASON_CONNECTREFUSED: u8 = 3;
+pub const END_REASON_EXITPOLICY: u8 = 4;
+pub const END_REASON_DESTROY: u8 = 5;
+pub const END_REASON_DONE: u8 = 6;
+pub const END_REASON_TIMEOUT: u8 = 7;
+pub const END_REASON_NOROUTE: u8 = 8;
+pub const END_REASON_HIBERNATING: u8 = 9;
+pub const END_REASON_INTERNAL: u8 = 10;
+pub const END_REASON_RESOURCELIMIT: u8 = 11;
+pub const END_REASON_CONNRESET: u8 = 12;
+pub const END_REASON_TORPROTOCOL: u8 = 13;
+pub const END_REASON_NOTDIRECTORY: u8 = 14;
+
+// DESTROY and RELAY_TRUNCATED reasons (tor-spec 5.4)
+pub const DESTROY_REASON_NONE: u8 = 0;
+pub const DESTROY_REASON_PROTOCOL: u8 = 1;
+pub const DESTROY_REASON_REQUESTED: u8 = 3;
+pub const DESTROY_REASON_CONNECTFAILED: u8 = 6;
+pub const DESTROY_REASON_TIMEOUT: u8 = 10;
+
+// EXTEND2 link specifier types (tor-spec 5.1.2)
+pub const LINK_SPECIFIER_IPV4: u8 = 0;
+pub const LINK_SPECIFIER_IPV6: u8 = 1;
+pub const LINK_SPECIFIER_RSA_ID: u8 = 2;
+pub const LINK_SPECIFIER_ED25519_ID: u8 = 3;
+
+/// ntor client handshake data: ID(20) | B(32) | X(32)
+pub const NTOR_ONIONSKIN_LEN: usize = 84;
+/// ntor server reply: Y(32) | AUTH(32)
+pub const NTOR_REPLY_LEN: usize = 64;
+
+#[derive(Debug)]
+pub enum CellError {
+    Truncated { expected: usize, actual: usize },
+    UnexpectedCommand(u8),
+    InvalidHandshake(String),
+    /// The contents don't fit in a cell payload
+    TooLarge { max: usize, actual: usize },
+}
+
+impl std::fmt::Display for CellError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CellError::Truncated { expected, actual } => {
+                write!(f, "Truncated cell: expected {} bytes, got {}", expected, actual)
+            }
+            CellError::UnexpectedCommand(cmd) => write!(f, "Unexpected cell command: {}", cmd),
+            CellError::InvalidHandshake(e) => write!(f, "Invalid handshake: {}", e),
+            CellError::TooLarge { max, actual } => {
+                write!(f, "Cell contents too large: {} bytes (at most {})", actual, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CellError {}
+
+/// A cell command we know, fixed- or variable-length
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CellCommand {
+    Padding,
+    Create,
+    Created,
+    Relay,
+    Destroy,
+    CreateFast,
+    CreatedFast,
+    Versions,
+    Netinfo,
+    RelayEarly,
+    Create2,
+    Created2,
+    PaddingNegotiate,
+    VPadding,
+    Certs,
+    AuthChallenge,
+    Authenticate,
+    Authorize,
+}
+
+impl CellCommand {
+    /// The command for a wire byte; None for commands we don't know
+    pub fn from_u8(command: u8) -> Option<Self> {
+        Some(match command {
+            CELL_COMMAND_PADDING => CellCommand::Padding,
+            CELL_COMMAND_CREATE => CellCommand::Create,
+            CELL_COMMAND_CREATED => CellCommand::Created,
+            CELL_COMMAND_RELAY => CellCommand::Relay,
+            CELL_COMMAND_DESTROY => CellCommand::Destroy,
+            CELL_COMMAND_CREATE_FAST => CellCommand::CreateFast,
+            CELL_COMMAND_CREATED_FAST => CellCommand::CreatedFast,
+            CELL_COMMAND_VERSIONS => CellCommand::Versions,
+            CELL_COMMAND_NETINFO => CellCommand::Netinfo,
+            CELL_COMMAND_RELAY_EARLY => CellCommand::RelayEarly,
+            CELL_COMMAND_CREATE2 => CellCommand::Create2,
+            CELL_COMMAND_CREATED2 => CellCommand::Created2,
+            CELL_COMMAND_PADDING_NEGOTIATE => CellCommand::PaddingNegotiate,
+            CELL_COMMAND_VPADDING => CellCommand::VPadding,
+            CELL_COMMAND_CERTS => CellCommand::Certs,
+            CELL_COMMAND_AUTH_CHALLENGE => CellCommand::AuthChallenge,
+            CELL_COMMAND_AUTHENTICATE => CellCommand::Authenticate,
+            CELL_COMMAND_AUTHORIZE => CellCommand::Authorize,
+            _ => return None,
+        })
+    }
+
+    pub fn as_u8(self) -> u8 {
+        match self {
+            CellCommand::Padding => CELL_COMMAND_PADDING,
+            CellCommand::Create => CELL_COMMAND_CREATE,
+            CellCommand::Created => CELL_COMMAND_CREATED,
+            CellCommand::Relay => CELL_COMMAND_RELAY,
+            CellCommand::Destroy => CELL_COMMAND_DESTROY,
+            CellCommand::CreateFast => CELL_COMMAND_CREATE_FAST,
+            CellCommand::CreatedFast => CELL_COMMAND_CREATED_FAST,
+            CellCommand::Versions => CELL_COMMAND_VERSIONS,
+            CellCommand::Netinfo => CELL_COMMAND_NETINFO,
+            CellCommand::RelayEarly => CELL_COMMAND_RELAY_EARLY,
+            CellCommand::Create2 => CELL_COMMAND_CREATE2,
+            CellCommand::Created2 => CELL_COMMAND_CREATED2,
+            CellCommand::PaddingNegotiate => CELL_COMMAND_PADDING_NEGOTIATE,
+            CellCommand::VPadding => CELL_COMMAND_VPADDING,
+            CellCommand::Certs => CELL_COMMAND_CERTS,
+            CellCommand::AuthChallenge => CELL_COMMAND_AUTH_CHALLENGE,
+            CellCommand::Authenticate => CELL_COMMAND_AUTHENTICATE,
+            CellCommand::Authorize => CELL_COMMAND_AUTHORIZE,
+        }
+    }
+
+    pub fn is_variable_length(self) -> bool {
+        is_variable_length(self.as_u8())
+    }
+}
+
+/// VERSIONS and commands 128 and up are variable-length: CircID | Command | Length(2) | Payload
+pub fn is_variable_length(command: u8) -> bool {
+    command == CELL_COMMAND_VERSIONS || command >= 128
+}
+
+impl std::fmt::Display for CellCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} ({})", self, self.as_u8())
+    }
+}
+
+/// A cell with its command typed; `link::CellCodec` frames it on the wire
+#[derive(Debug, Clone)]
+pub struct Cell {
+    pub circ_id: u32,
+    pub command: CellCommand,
+    pub payload: Vec<u8>,
+}
+
+impl Cell {
+    pub fn new(circ_id: u32, command: CellCommand, payload: Vec<u8>) -> Self {
+        Self { circ_id, command, payload }
+    }
+}
+
+/// CREATE2 payload: HTYPE(2) | HLEN(2) | HDATA
+#[derive(Debug, Clone)]
+pub struct Create2Cell {
+    pub handshake_type: u16,
+    pub handshake_data: Vec<u8>,
+}
+
+impl Create2Cell {
+    pub fn new(handshake_type: u16, handshake_data: Vec<u8>) -> Self {
+        Self { handshake_type, handshake_data }
+    }
+
+    /// Build an ntor onionskin for the relay with identity ID and onion key B
+    pub fn new_ntor(relay_identity: &[u8], relay_onion_key: &[u8], client_public: &PublicKey) -> Self {
+        let mut handshake_data = Vec::with_capacity(NTOR_ONIONSKIN_LEN);
+        handshake_data.extend_from_slice(relay_identity);
+        handshake_data.extend_from_slice(relay_onion_key);
+        handshake_data.extend_from_slice(client_public.as_bytes());
+        Self::new(HANDSHAKE_TYPE_NTOR, handshake_data)
+    }
+
+    pub fn from_bytes(payload: &[u8]) -> Result<Self, CellError> {
+        if payload.len() < 4 {
+            return Err(CellError::Truncated { expected: 4, actual: payload.len() });
+        }
+        let handshake_type = u16::from_be_bytes([payload[0], payload[1]]);
+        let hlen = u16::from_be_bytes([payload[2], payload[3]]) as usize;
+        if payload.len() < 4 + hlen {
+            return Err(CellError::Truncated { expected: 4 + hlen, actual: payload.len() });
+        }
+        Ok(Self::new(handshake_type, payload[4..4 + hlen].to_vec()))
+    }
+
+    /// Serialize the payload, refusing handshake data that wouldn't fit in a cell
+    pub fn to_bytes(&self) -> Result<Vec<u8>, CellError> {
+        let len = 4 + self.handshake_data.len();
+        if len > CELL_PAYLOAD_LEN {
+            return Err(CellError::TooLarge { max: CELL_PAYLOAD_LEN - 4, actual: self.handshake_data.len() });
+        }
+        let mut payload = Vec::with_capacity(len);
+        payload.extend_from_slice(&self.handshake_type.to_be_bytes());
+        payload.extend_from_slice(&(self.handshake_data.len() as u16).to_be_bytes());
+        payload.extend_from_slice(&self.handshake_data);
+        Ok(payload)
+    }
+}
+
+/// CREATED2 payload for ntor: HLEN(2) | Y(32) | AUTH(32)
+#[derive(Debug, Clone)]
+pub struct Created2Cell {
+    pub server_public: PublicKey,
+    pub auth: [u8; 32],
+}
+
+impl Created2Cell {
+    /// Parse the reply to a CREATE2 of type `handshake_type`; CREATED2 doesn't
+    /// repeat the type, so the caller says which handshake it started
+    pub fn from_bytes(handshake_type: u16, payload: &[u8]) -> Result<Self, CellError> {
+        if handshake_type != HANDSHAKE_TYPE_NTOR {
+            return Err(CellError::InvalidHandshake(format!(
+                "Unsupported handshake type {}", handshake_type
+            )));
+        }
+        if payload.len() < 2 {
+            return Err(CellError::Truncated { expected: 2, actual: payload.len() });
+        }
+        let hlen = u16::from_be_bytes([payload[0], payload[1]]) as usize;
+        if hlen != NTOR_REPLY_LEN {
+            return Err(CellError::InvalidHandshake(format!(
+                "CREATED2 HLEN is {} (expected {})", hlen, NTOR_REPLY_LEN
+            )));
+        }
+        if payload.len() < 2 + hlen {
+            return Err(CellError::Truncated { expected: 2 + hlen, actual: payload.len() });
+        }
+
+        let mut server_public = [0u8; 32];
+        server_public.copy_from_slice(&payload[2..34]);
+        let mut auth = [0u8; 32];
+        auth.copy_from_slice(&payload[34..66]);
+
+        Ok(Self {
+            server_public: PublicKey::from(server_public),
+            auth,
+        })
+    }
+}
+
+/// Where the next hop of an EXTEND2 is and who it should be
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkSpecifier {
+    /// IPv4 address and ORPort
+    Ipv4(SocketAddrV4),
+    /// IPv6 address and ORPort
+    Ipv6(SocketAddrV6),
+    /// SHA-1 of the relay's RSA identity key
+    RsaId([u8; 20]),
+    /// The relay's ed25519 identity key
+    Ed25519Id([u8; 32]),
+    /// A type we don't know, kept as-is
+    Unrecognized { ls_type: u8, data: Vec<u8> },
+}
+
+impl LinkSpecifier {
+    pub fn ls_type(&self) -> u8 {
+        match self {
+            LinkSpecifier::Ipv4(_) => LINK_SPECIFIER_IPV4,
+            LinkSpecifier::Ipv6(_) => LINK_SPECIFIER_IPV6,
+            LinkSpecifier::RsaId(_) => LINK_SPECIFIER_RSA_ID,
+            LinkSpecifier::Ed25519Id(_) => LINK_SPECIFIER_ED25519_ID,
+            LinkSpecifier::Unrecognized { ls_type, .. } => *ls_type,
+        }
+    }
+
+    fn value(&self) -> Vec<u8> {
+        match self {
+            LinkSpecifier::Ipv4(addr) => [&addr.ip().octets()[..], &addr.port().to_be_bytes()].concat(),
+            LinkSpecifier::Ipv6(addr) => [&addr.ip().octets()[..], &addr.port().to_be_bytes()].concat(),
+            LinkSpecifier::RsaId(id) => id.to_vec(),
+            LinkSpecifier::Ed25519Id(id) => id.to_vec(),
+            LinkSpecifier::Unrecognized { data, .. } => data.clone(),
+        }
+    }
+
+    /// Parse one LSTYPE(1) | LSLEN(1) | LSPEC, returning it and the bytes consumed
+    fn parse(bytes: &[u8]) -> Result<(Self, usize), CellError> {
+        if bytes.len() < 2 {
+            return Err(CellError::Truncated { expected: 2, actual: bytes.len() });
+        }
+        let (ls_type, len) = (bytes[0], bytes[1] as usize);
+        if bytes.len() < 2 + len {
+            return Err(CellError::Truncated { expected: 2 + len, actual: bytes.len() });
+        }
+        let value = &bytes[2..2 + len];
+
+        let spec = match (ls_type, len) {
+            (LINK_SPECIFIER_IPV4, 6) => {
+                let ip = Ipv4Addr::new(value[0], value[1], value[2], value[3]);
+                LinkSpecifier::Ipv4(SocketAddrV4::new(ip, u16::from_be_bytes([value[4], value[5]])))
+            }
+            (LINK_SPECIFIER_IPV6, 18) => {
+                let octets: [u8; 16] = value[..16].try_into().unwrap_or_default();
+                let port = u16::from_be_bytes([value[16], value[17]]);
+                LinkSpecifier::Ipv6(SocketAddrV6::new(Ipv6Addr::from(octets), port, 0, 0))
+            }
+            (LINK_SPECIFIER_RSA_ID, 20) => LinkSpecifier::RsaId(value.try_into().unwrap_or_default()),
+            (LINK_SPECIFIER_ED25519_ID, 32) => LinkSpecifier::Ed25519Id(value.try_into().unwrap_or_default()),
+            (LINK_SPECIFIER_IPV4..=LINK_SPECIFIER_ED25519_ID, _) => {
+                return Err(CellError::InvalidHandshake(format!(
+                    "Link specifier type {} with length {}", ls_type, len
+                )));
+            }
+            _ => LinkSpecifier::Unrecognized { ls_type, data: value.to_vec() },
+        };
+        Ok((spec, 2 + len))
+    }
+}
+
+/// EXTEND2 body: NSPEC(1) | NSPEC link specifiers | HTYPE(2) | HLEN(2) | HDATA.
+/// Everything after the specifiers is a CREATE2 payload for the next hop.
+#[derive(Debug, Clone)]
+pub struct Extend2Cell {
+    pub link_specifiers: Vec<LinkSpecifier>,
+    pub create2: Create2Cell,
+}
+
+impl Extend2Cell {
+    pub fn new(link_specifiers: Vec<LinkSpecifier>, create2: Create2Cell) -> Self {
+        Self { link_specifiers, create2 }
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, CellError> {
+        if data.is_empty() {
+            return Err(CellError::Truncated { expected: 1, actual: 0 });
+        }
+        let mut link_specifiers = Vec::with_capacity(data[0] as usize);
+        let mut rest = &data[1..];
+        for _ in 0..data[0] {
+            let (spec, used) = LinkSpecifier::parse(rest)?;
+            link_specifiers.push(spec);
+            rest = &rest[used..];
+        }
+        Ok(Self::new(link_specifiers, Create2Cell::from_bytes(rest)?))
+    }
+
+    /// Serialize the relay cell body, refusing anything that wouldn't fit in one
+    pub fn to_bytes(&self) -> Result<Vec<u8>, CellError> {
+        if self.link_specifiers.len() > u8::MAX as usize {
+            return Err(CellError::TooLarge { max: u8::MAX as usize, actual: self.link_specifiers.len() });
+        }
+        let mut data = vec![self.link_specifiers.len() as u8];
+        for spec in &self.link_specifiers {
+            let value = spec.value();
+            if value.len() > u8::MAX as usize {
+                return Err(CellError::TooLarge { max: u8::MAX as usize, actual: value.len() });
+            }
+            data.push(spec.ls_type());
+            data.push(value.len() as u8);
+            data.extend_from_slice(&value);
+        }
+        data.extend_from_slice(&self.create2.to_bytes()?);
+        if data.len() > RELAY_PAYLOAD_LEN {
+            return Err(CellError::TooLarge { max: RELAY_PAYLOAD_LEN, actual: data.len() });
+        }
+        Ok(data)
+    }
+}
+
+/// EXTENDED2 body: HLEN(2) | HDATA, the next hop's CREATED2 payload passed back
+#[derive(Debug, Clone, PartialEq)]
+pub struct Extended2Cell {
+    pub handshake_data: Vec<u8>,
+}
+
+impl Extended2Cell {
+    pub fn new(handshake_data: Vec<u8>) -> Self {
+        Self { handshake_data }
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, CellError> {
+        if data.len() < 2 {
+            return Err(CellError::Truncated { expected: 2, actual: data.len() });
+        }
+        let hlen = u16::from_be_bytes([data[0], data[1]]) as usize;
+        if data.len() < 2 + hlen {
+            return Err(CellError::Truncated { expected: 2 + hlen, actual: data.len() });
+        }
+        Ok(Self::new(data[2..2 + hlen].to_vec()))
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, CellError> {
+        if 2 + self.handshake_data.len() > RELAY_PAYLOAD_LEN {
+            return Err(CellError::TooLarge { max: RELAY_PAYLOAD_LEN - 2, actual: self.handshake_data.len() });
+        }
+        let mut data = Vec::with_capacity(2 + self.handshake_data.len());
+        data.extend_from_slice(&(self.handshake_data.len() as u16).to_be_bytes());
+        data.extend_from_slice(&self.handshake_data);
+        Ok(data)
+    }
+
+    /// The reply as a CREATED2 to the handshake of type `handshake_type`
+    pub fn created2(&self, handshake_type: u16) -> Result<Created2Cell, CellError> {
+        Created2Cell::from_bytes(handshake_type, &self.to_bytes()?)
+    }
+}
+
+/// A decrypted relay cell body. The recognized and digest fields are left to
+/// the relay crypto, which fills them in on the way out and checks them on the way in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RelayCell {
+    pub command: u8,
+    pub stream_id: u16,
+    pub data: Vec<u8>,
+}
+
+impl RelayCell {
+    pub fn new(command: u8, stream_id: u16, data: Vec<u8>) -> Self {
+        Self { command, stream_id, data }
+    }
+
+    /// Serialize into a cell payload with zeroed recognized/digest fields.
+    /// Fails if `data` doesn't fit in one cell's `RELAY_PAYLOAD_LEN`.
+    pub fn to_bytes(&self) -> Result<[u8; CELL_PAYLOAD_LEN], CellError> {
+        let len = self.data.len();
+        if len > RELAY_PAYLOAD_LEN {
+            return Err(CellError::TooLarge { max: RELAY_PAYLOAD_LEN, actual: len });
+        }
+        let mut body = [0u8; CELL_PAYLOAD_LEN];
+        body[0] = self.command;
+        body[3..5].copy_from_slice(&self.stream_id.to_be_bytes());
+        body[9..11].copy_from_slice(&(len as u16).to_be_bytes());
+        body[RELAY_HEADER_LEN..RELAY_HEADER_LEN + len].copy_from_slice(&self.data);
+        Ok(body)
+    }
+
+    pub fn from_bytes(body: &[u8]) -> Result<Self, CellError> {
+        if body.len() < RELAY_HEADER_LEN {
+            return Err(CellError::Truncated { expected: RELAY_HEADER_LEN, actual: body.len() });
+        }
+        let len = u16::from_be_bytes([body[9], body[10]]) as usize;
+        if len > RELAY_PAYLOAD_LEN || body.len() < RELAY_HEADER_LEN + len {
+            return Err(CellError::Truncated { expected: RELAY_HEADER_LEN + len, actual: body.len() });
+        }
+        Ok(Self {
+            command: body[0],
+            stream_id: u16::from_be_bytes([body[3], body[4]]),
+            data: body[RELAY_HEADER_LEN..RELAY_HEADER_LEN + len].to_vec(),
+        })
+    }
+}
+
+/// One answer from a RELAY_RESOLVED cell
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResolvedAddress {
+    Ip(IpAddr),
+    Hostname(String),
+    /// The exit couldn't resolve the name; `transient` errors may succeed on retry
+    Error { transient: bool },
+}
+
+/// Parse a RELAY_RESOLVED body: a list of TYPE(1) | LEN(1) | VALUE | TTL(4)
+pub fn parse_resolved(data: &[u8]) -> Result<Vec<(ResolvedAddress, u32)>, CellError> {
+    let mut answers = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        if rest.len() < 2 {
+            return Err(CellError::Truncated { expected: 2, actual: rest.len() });
+        }
+        let (answer_type, len) = (rest[0], rest[1] as usize);
+        if rest.len() < 2 + len + 4 {
+            return Err(CellError::Truncated { expected: 2 + len + 4, actual: rest.len() });
+        }
+        let value = &rest[2..2 + len];
+        let ttl = u32::from_be_bytes([rest[2 + len], rest[3 + len], rest[4 + len], rest[5 + len]]);
+
+        let address = match (answer_type, len) {
+            (RESOLVED_TYPE_IPV4, 4) => {
+                ResolvedAddress::Ip(IpAddr::V4(Ipv4Addr::new(value[0], value[1], value[2], value[3])))
+            }
+            (RESOLVED_TYPE_IPV6, 16) => {
+                let octets: [u8; 16] = value.try_into().unwrap_or_default();
+                ResolvedAddress::Ip(IpAddr::V6(Ipv6Addr::from(octets)))
+            }
+            (RESOLVED_TYPE_HOSTNAME, _) => ResolvedAddress::Hostname(String::from_utf8_lossy(value).into_owned()),
+            (RESOLVED_TYPE_ERROR_TRANSIENT, _) => ResolvedAddress::Error { transient: true },
+            (RESOLVED_TYPE_ERROR_NONTRANSIENT, _) => ResolvedAddress::Error { transient: false },
+            (other, _) => {
+                return Err(CellError::InvalidHandshake(format!(
+                    "RESOLVED answer type {} with length {}", other, len
+                )));
+            }
+        };
+        answers.push((address, ttl));
+        rest = &rest[2 + len + 4..];
+    }
+    Ok(answers)
+}
+
+/// Build a RELAY_RESOLVED body from answers
+pub fn encode_resolved(answers: &[(ResolvedAddress, u32)]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for (address, ttl) in answers {
+        match address {
+            ResolvedAddress::Ip(IpAddr::V4(ip)) => {
+                data.extend_from_slice(&[RESOLVED_TYPE_IPV4, 4]);
+                data.extend_from_slice(&ip.octets());
+            }
+            ResolvedAddress::Ip(IpAddr::V6(ip)) => {
+                data.extend_from_slice(&[RESOLVED_TYPE_IPV6, 16]);
+                data.extend_from_slice(&ip.octets());
+            }
+            ResolvedAddress::Hostname(name) => {
+                let name = &name.as_bytes()[..name.len().min(255)];
+                data.extend_from_slice(&[RESOLVED_TYPE_HOSTNAME, name.len() as u8]);
+                data.extend_from_slice(name);
+            }
+            ResolvedAddress::Error { transient } => {
+                let answer_type = if *transient { RESOLVED_TYPE_ERROR_TRANSIENT } else { RESOLVED_TYPE_ERROR_NONTRANSIENT };
+                data.extend_from_slice(&[answer_type, 0]);
+            }
+        }
+        data.extend_from_slice(&ttl.to_be_bytes());
+    }
+    data
+}
@@ -0,0 +1,197 @@
+// src/network/channel.rs
+use crate::network::cells::{Cell, CellCommand};
+use crate::network::link::{self, LinkError, LinkInfo, RelayIdentity};
+use crate::network::tls::{self, ClientHello, RelayStream, TlsBackend};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncWriteExt, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+type CircuitQueues = Arc<Mutex<HashMap<u32, mpsc::UnboundedSender<Cell>>>>;
+
+/// A connection to a relay's ORPort, multiplexing any number of circuits.
+/// Incoming cells are demultiplexed by circuit ID onto per-circuit queues.
+pub struct Channel {
+    relay_id: String,
+    peer: SocketAddr,
+    link: LinkInfo,
+    /// Taken on `close`, which drops our half of the socket
+    writer: tokio::sync::Mutex<Option<WriteHalf<RelayStream>>>,
+    circuits: CircuitQueues,
+    closed: Arc<AtomicBool>,
+    reader_task: tokio::task::JoinHandle<()>,
+}
+
+impl std::fmt::Debug for Channel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Channel")
+            .field("relay_id", &self.relay_id)
+            .field("peer", &self.peer)
+            .field("link_version", &self.link.version)
+            .field("circuits", &self.circuit_count())
+            .field("closed", &self.is_closed())
+            .finish()
+    }
+}
+
+impl Channel {
+    /// Connect to the relay's ORPort, set up TLS with `tls` (sending the
+    /// ClientHello `hello` describes) and run the link handshake, in which the
+    /// relay must prove to be `identity`; each of the two must finish within
+    /// `handshake_timeout`
+    pub async fn connect(
+        relay_id: &str,
+        identity: &RelayIdentity,
+        peer: SocketAddr,
+        tls: TlsBackend,
+        hello: &ClientHello,
+        connect_timeout: Duration,
+        handshake_timeout: Duration,
+    ) -> Result<Arc<Self>, LinkError> {
+        let stream = tokio::time::timeout(connect_timeout, TcpStream::connect(peer))
+            .await
+            .map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::TimedOut, format!("Timeout connecting to {}", peer))
+            })??;
+        // The TLS handshake counts as part of the link handshake
+        let mut stream = tokio::time::timeout(handshake_timeout, tls::connect(stream, tls, hello))
+            .await
+            .map_err(|_| LinkError::Timeout)??;
+        let tls_cert = stream.peer_certificate()?;
+        let link = link::negotiate(&mut stream, peer.ip(), identity, tls_cert.as_deref(), handshake_timeout).await?;
+        log::debug!("Opened channel to {} ({}), link protocol {}", relay_id, peer, link.version);
+
+        let (mut reader, writer) = tokio::io::split(stream);
+        let circuits: CircuitQueues = Arc::new(Mutex::new(HashMap::new()));
+        let closed = Arc::new(AtomicBool::new(false));
+
+        let reader_task = {
+            let circuits = circuits.clone();
+            let closed = closed.clone();
+            let codec = link.codec();
+            tokio::spawn(async move {
+                loop {
+                    let cell = match codec.read_cell(&mut reader).await {
+                        Ok(cell) => cell,
+                        Err(e) => {
+                            log::debug!("Channel to {} closed: {}", peer, e);
+                            break;
+                        }
+                    };
+                    if cell.command == CellCommand::Padding || cell.command == CellCommand::VPadding {
+                        continue;
+                    }
+
+                    let circ_id = cell.circ_id;
+                    let mut queues = circuits.lock().unwrap();
+                    match queues.get(&circ_id) {
+                        Some(queue) => {
+                            if queue.send(cell).is_err() {
+                                // Circuit went away without unregistering
+                                queues.remove(&circ_id);
+                            }
+                        }
+                        None => log::debug!("Cell for unknown circuit {} from {}", circ_id, peer),
+                    }
+                }
+
+                closed.store(true, Ordering::SeqCst);
+                // Dropping the senders wakes every circuit waiting on this channel
+                circuits.lock().unwrap().clear();
+            })
+        };
+
+        Ok(Arc::new(Self {
+            relay_id: relay_id.to_string(),
+            peer,
+            link,
+            writer: tokio::sync::Mutex::new(Some(writer)),
+            circuits,
+            closed,
+            reader_task,
+        }))
+    }
+
+    pub fn relay_id(&self) -> &str {
+        &self.relay_id
+    }
+
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// What the relay told us during the link handshake
+    pub fn link_info(&self) -> &LinkInfo {
+        &self.link
+    }
+
+    /// Start receiving cells addressed to `circ_id`. On a closed channel the
+    /// returned queue is already finished.
+    pub fn register(&self, circ_id: u32) -> mpsc::UnboundedReceiver<Cell> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut circuits = self.circuits.lock().unwrap();
+        if !self.is_closed() {
+            circuits.insert(circ_id, tx);
+        }
+        rx
+    }
+
+    pub fn unregister(&self, circ_id: u32) {
+        self.circuits.lock().unwrap().remove(&circ_id);
+    }
+
+    /// Number of circuits currently multiplexed over this channel
+    pub fn circuit_count(&self) -> usize {
+        self.circuits.lock().unwrap().len()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Shut the connection down, ending every circuit still registered on it
+    pub fn close(&self) {
+        {
+            let mut circuits = self.circuits.lock().unwrap();
+            self.closed.store(true, Ordering::SeqCst);
+            circuits.clear();
+        }
+        self.reader_task.abort();
+        // If a send is in flight the writer goes away when the channel is dropped
+        if let Ok(mut writer) = self.writer.try_lock() {
+            writer.take();
+        }
+        log::debug!("Closed channel to {} ({})", self.relay_id, self.peer);
+    }
+
+    pub async fn send_cell(&self, cell: &Cell) -> std::io::Result<()> {
+        let mut writer = self.writer.lock().await;
+        let writer = writer.as_mut().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotConnected, format!("Channel to {} is closed", self.peer))
+        })?;
+        // Cells are framed for the link protocol the handshake settled on
+        self.link.codec().write_cell(writer, cell).await.map_err(|e| match e {
+            LinkError::Io(e) => e,
+            other => std::io::Error::new(std::io::ErrorKind::InvalidInput, other.to_string()),
+        })?;
+        writer.flush().await
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        self.reader_task.abort();
+    }
+}
+
+/// Whether this host can reach IPv4 addresses at all. Connecting a UDP socket
+/// only looks up a route, so nothing is sent.
+pub fn has_ipv4_route() -> bool {
+    std::net::UdpSocket::bind(("0.0.0.0", 0))
+        .and_then(|socket| socket.connect(("192.0.2.1", 9)))
+        .is_ok()
+}
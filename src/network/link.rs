@@ -0,0 +1,478 @@
+// src/network/link.rs
+//! The link handshake that opens every relay connection (tor-spec 4): we send
+//! VERSIONS, the relay answers with VERSIONS, CERTS, AUTH_CHALLENGE and
+//! NETINFO, and we finish with a NETINFO of our own. Only then will a relay
+//! accept CREATE2 cells.
+//!
+//! The CERTS cell is what authenticates the relay: its Ed25519 identity key
+//! certifies a signing key, which in turn certifies the TLS certificate the
+//! connection was made with. That identity, or the RSA identity that
+//! cross-certifies it, must be the one the directory lists for the relay.
+use crate::crypto::rsa::RsaPublicKey;
+use crate::crypto::tor_cert::{
+    Ed25519Cert, RsaCrossCert, CERT_KEY_TYPE_ED25519, CERT_KEY_TYPE_SHA256_OF_X509, CERT_TYPE_IDENTITY_V_SIGNING,
+    CERT_TYPE_RSA_ED25519_CROSSCERT, CERT_TYPE_RSA_IDENTITY, CERT_TYPE_SIGNING_V_TLS_CERT,
+};
+use crate::network::cells::{is_variable_length, Cell, CellCommand, CELL_PAYLOAD_LEN};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Link protocol versions we speak; all use 4-byte circuit IDs
+pub const LINK_PROTOCOL_VERSIONS: &[u16] = &[4, 5];
+
+/// VERSIONS goes out before the circuit ID width is agreed, so it always uses 2 bytes
+const VERSIONS_CIRC_ID_LEN: usize = 2;
+/// Widest circuit ID any link protocol uses
+const CIRC_ID_LEN: usize = 4;
+
+/// Width of circuit IDs in cell headers: 2 bytes before link protocol 4, 4 from then on
+pub fn circ_id_len(version: u16) -> usize {
+    if version < 4 {
+        2
+    } else {
+        4
+    }
+}
+
+/// NETINFO address types
+const NETINFO_ADDR_IPV4: u8 = 4;
+const NETINFO_ADDR_IPV6: u8 = 6;
+
+/// Who the relay we meant to reach is, as the directory lists it: CERTS must
+/// prove the same identities
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RelayIdentity {
+    /// SHA-1 of the relay's RSA identity key (its fingerprint)
+    pub rsa: [u8; 20],
+    /// The relay's Ed25519 identity key, where the directory knows it
+    pub ed25519: Option<[u8; 32]>,
+}
+
+/// What the relay told us during the link handshake
+#[derive(Debug, Clone)]
+pub struct LinkInfo {
+    /// Negotiated link protocol version
+    pub version: u16,
+    /// Types of the certificates in the relay's CERTS cell
+    pub cert_types: Vec<u8>,
+    /// The relay's Ed25519 identity key, as certified in CERTS
+    pub ed25519_identity: [u8; 32],
+    /// Our address as the relay sees it
+    pub our_address: Option<IpAddr>,
+    /// Addresses the relay says it has
+    pub relay_addresses: Vec<IpAddr>,
+    /// The relay's clock when it sent NETINFO
+    pub relay_time: SystemTime,
+}
+
+impl LinkInfo {
+    /// Width of circuit IDs on this link
+    pub fn circ_id_len(&self) -> usize {
+        circ_id_len(self.version)
+    }
+
+    /// How cells are framed on this link
+    pub fn codec(&self) -> CellCodec {
+        CellCodec::for_version(self.version)
+    }
+}
+
+#[derive(Debug)]
+pub enum LinkError {
+    Io(std::io::Error),
+    /// The relay didn't finish the handshake in time
+    Timeout,
+    /// The relay offered none of `LINK_PROTOCOL_VERSIONS`; these are its versions
+    NoCommonVersion(Vec<u16>),
+    Protocol(String),
+}
+
+impl std::fmt::Display for LinkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LinkError::Io(e) => write!(f, "I/O error: {}", e),
+            LinkError::Timeout => write!(f, "Link handshake timed out"),
+            LinkError::NoCommonVersion(theirs) => {
+                write!(f, "No common link protocol version (relay offers {:?})", theirs)
+            }
+            LinkError::Protocol(e) => write!(f, "Link protocol error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for LinkError {}
+
+impl From<std::io::Error> for LinkError {
+    fn from(err: std::io::Error) -> Self {
+        LinkError::Io(err)
+    }
+}
+
+/// Run the client side of the link handshake with the relay at `peer`, which
+/// must prove to be `expected`. `tls_cert` is the DER certificate the relay
+/// presented, which CERTS must vouch for; None on a cleartext link, where
+/// only the identity chain is checked.
+pub async fn negotiate<S>(
+    stream: &mut S,
+    peer: IpAddr,
+    expected: &RelayIdentity,
+    tls_cert: Option<&[u8]>,
+    timeout: Duration,
+) -> Result<LinkInfo, LinkError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    tokio::time::timeout(timeout, handshake(stream, peer, expected, tls_cert))
+        .await
+        .map_err(|_| LinkError::Timeout)?
+}
+
+async fn handshake<S>(
+    stream: &mut S,
+    peer: IpAddr,
+    expected: &RelayIdentity,
+    tls_cert: Option<&[u8]>,
+) -> Result<LinkInfo, LinkError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let versions = Cell::new(0, CellCommand::Versions, encode_versions(LINK_PROTOCOL_VERSIONS));
+    CellCodec::versions().write_cell(stream, &versions).await?;
+
+    let reply = CellCodec::versions().read_cell(stream).await?;
+    if reply.command != CellCommand::Versions {
+        return Err(LinkError::Protocol(format!("expected VERSIONS, got command {}", reply.command)));
+    }
+    let theirs = parse_versions(&reply.payload);
+    let version = LINK_PROTOCOL_VERSIONS
+        .iter()
+        .copied()
+        .filter(|v| theirs.contains(v))
+        .max()
+        .ok_or(LinkError::NoCommonVersion(theirs))?;
+
+    let codec = CellCodec::for_version(version);
+    let mut certs = None;
+    let netinfo = loop {
+        let cell = codec.read_cell(stream).await?;
+        match cell.command {
+            CellCommand::Certs => certs = Some(parse_certs(&cell.payload)?),
+            // Only needed to authenticate ourselves as a relay
+            CellCommand::AuthChallenge => {}
+            CellCommand::Padding | CellCommand::VPadding => {}
+            CellCommand::Netinfo => break parse_netinfo(&cell.payload)?,
+            other => {
+                return Err(LinkError::Protocol(format!("unexpected command {} during link handshake", other)));
+            }
+        }
+    };
+    let certs = certs.ok_or_else(|| LinkError::Protocol("NETINFO before CERTS".to_string()))?;
+    let ed25519_identity = verify_certs(&certs, tls_cert, expected, SystemTime::now())?;
+
+    // Clients send a zero timestamp so they can't be fingerprinted by their clock
+    let reply = Cell::new(0, CellCommand::Netinfo, encode_netinfo(0, peer, &[]));
+    codec.write_cell(stream, &reply).await?;
+
+    log::debug!("Link protocol {} negotiated with {}", version, peer);
+    Ok(LinkInfo {
+        version,
+        cert_types: certs.iter().map(|(cert_type, _)| *cert_type).collect(),
+        ed25519_identity,
+        our_address: netinfo.other_address,
+        relay_addresses: netinfo.my_addresses,
+        relay_time: UNIX_EPOCH + Duration::from_secs(netinfo.timestamp as u64),
+    })
+}
+
+/// Frames cells on a relay connection: reads exactly one fixed- or
+/// variable-length cell at a time, however the bytes arrive, and writes
+/// cells with the circuit ID width the link protocol uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CellCodec {
+    circ_id_len: usize,
+}
+
+impl CellCodec {
+    /// The codec for link protocol `version`
+    pub fn for_version(version: u16) -> Self {
+        Self { circ_id_len: circ_id_len(version) }
+    }
+
+    /// The codec VERSIONS cells use, before a version has been agreed
+    pub fn versions() -> Self {
+        Self { circ_id_len: VERSIONS_CIRC_ID_LEN }
+    }
+
+    pub fn circ_id_len(&self) -> usize {
+        self.circ_id_len
+    }
+
+    /// Read one cell. Cells with commands we don't know are skipped, as
+    /// tor-spec 3 asks.
+    pub async fn read_cell<R>(&self, reader: &mut R) -> Result<Cell, LinkError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let circ_id_len = self.circ_id_len;
+        loop {
+            let mut header = [0u8; CIRC_ID_LEN + 1];
+            reader.read_exact(&mut header[..circ_id_len + 1]).await?;
+            let circ_id = header[..circ_id_len].iter().fold(0u32, |id, &b| (id << 8) | b as u32);
+            let command = header[circ_id_len];
+
+            let len = if is_variable_length(command) {
+                let mut len = [0u8; 2];
+                reader.read_exact(&mut len).await?;
+                u16::from_be_bytes(len) as usize
+            } else {
+                CELL_PAYLOAD_LEN
+            };
+            let mut payload = vec![0u8; len];
+            reader.read_exact(&mut payload).await?;
+            match CellCommand::from_u8(command) {
+                Some(command) => return Ok(Cell::new(circ_id, command, payload)),
+                None => log::debug!("Skipping cell with unknown command {}", command),
+            }
+        }
+    }
+
+    /// Write `cell`, as a variable-length cell if its command calls for one
+    pub async fn write_cell<W>(&self, writer: &mut W, cell: &Cell) -> Result<(), LinkError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        writer.write_all(&self.encode(cell)?).await?;
+        Ok(())
+    }
+
+    /// `cell` as it goes on the wire
+    pub fn encode(&self, cell: &Cell) -> Result<Vec<u8>, LinkError> {
+        let circ_id_len = self.circ_id_len;
+        if circ_id_len < CIRC_ID_LEN && cell.circ_id >> (8 * circ_id_len) != 0 {
+            return Err(LinkError::Protocol(format!(
+                "circuit ID {:#x} doesn't fit in {} bytes", cell.circ_id, circ_id_len
+            )));
+        }
+        let mut bytes = cell.circ_id.to_be_bytes()[CIRC_ID_LEN - circ_id_len..].to_vec();
+        bytes.push(cell.command.as_u8());
+        if cell.command.is_variable_length() {
+            let len = u16::try_from(cell.payload.len())
+                .map_err(|_| LinkError::Protocol(format!("{}-byte cell payload is too long", cell.payload.len())))?;
+            bytes.extend_from_slice(&len.to_be_bytes());
+            bytes.extend_from_slice(&cell.payload);
+        } else {
+            bytes.extend_from_slice(&cell.payload);
+            bytes.resize(circ_id_len + 1 + CELL_PAYLOAD_LEN, 0);
+        }
+        Ok(bytes)
+    }
+}
+
+pub fn encode_versions(versions: &[u16]) -> Vec<u8> {
+    versions.iter().flat_map(|v| v.to_be_bytes()).collect()
+}
+
+pub fn parse_versions(payload: &[u8]) -> Vec<u16> {
+    payload.chunks_exact(2).map(|v| u16::from_be_bytes([v[0], v[1]])).collect()
+}
+
+/// The (type, certificate) pairs in a CERTS payload: N(1) | N * (Type(1) | Len(2) | Cert)
+pub fn parse_certs(payload: &[u8]) -> Result<Vec<(u8, Vec<u8>)>, LinkError> {
+    let truncated = || LinkError::Protocol("truncated CERTS cell".to_string());
+    let (&count, mut rest) = payload.split_first().ok_or_else(truncated)?;
+    let mut certs = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        if rest.len() < 3 {
+            return Err(truncated());
+        }
+        let len = u16::from_be_bytes([rest[1], rest[2]]) as usize;
+        if rest.len() < 3 + len {
+            return Err(truncated());
+        }
+        certs.push((rest[0], rest[3..3 + len].to_vec()));
+        rest = &rest[3 + len..];
+    }
+    Ok(certs)
+}
+
+/// Check the relay's Ed25519 certificate chain (tor-spec 4.2): an
+/// IDENTITY_V_SIGNING cert signed by the identity key it names, and a
+/// SIGNING_V_TLS_CERT cert signed by that signing key. If `tls_cert` is
+/// given, the latter must certify its SHA-256 digest.
+///
+/// The identity must then be `expected`'s: its Ed25519 key where that's
+/// known, and its RSA fingerprint wherever CERTS carries the RSA identity
+/// certificate and the cross-certificate binding it to the Ed25519 key. A
+/// relay that proves neither is refused. Returns the identity key.
+pub fn verify_certs(
+    certs: &[(u8, Vec<u8>)],
+    tls_cert: Option<&[u8]>,
+    expected: &RelayIdentity,
+    now: SystemTime,
+) -> Result<[u8; 32], LinkError> {
+    let only = |cert_type: u8| -> Result<Option<&[u8]>, LinkError> {
+        let mut matching = certs.iter().filter(|(t, _)| *t == cert_type);
+        let first = matching.next().map(|(_, body)| body.as_slice());
+        if matching.next().is_some() {
+            return Err(LinkError::Protocol(format!("CERTS has more than one type {} certificate", cert_type)));
+        }
+        Ok(first)
+    };
+    let find = |cert_type: u8| -> Result<Ed25519Cert, LinkError> {
+        let body = only(cert_type)?
+            .ok_or_else(|| LinkError::Protocol(format!("CERTS has no type {} certificate", cert_type)))?;
+        let cert = Ed25519Cert::parse(body)
+            .map_err(|e| LinkError::Protocol(format!("type {} certificate: {:?}", cert_type, e)))?;
+        if cert.is_expired(now) {
+            return Err(LinkError::Protocol(format!("type {} certificate has expired", cert_type)));
+        }
+        Ok(cert)
+    };
+
+    let identity_cert = find(CERT_TYPE_IDENTITY_V_SIGNING)?;
+    let identity = identity_cert
+        .signing_key
+        .ok_or_else(|| LinkError::Protocol("identity certificate doesn't name the identity key".to_string()))?;
+    if identity_cert.key_type != CERT_KEY_TYPE_ED25519 || !identity_cert.verify(&identity) {
+        return Err(LinkError::Protocol("bad signature on identity certificate".to_string()));
+    }
+
+    let tls_link_cert = find(CERT_TYPE_SIGNING_V_TLS_CERT)?;
+    if tls_link_cert.key_type != CERT_KEY_TYPE_SHA256_OF_X509 || !tls_link_cert.verify(&identity_cert.certified_key) {
+        return Err(LinkError::Protocol("bad signature on TLS link certificate".to_string()));
+    }
+    if let Some(tls_cert) = tls_cert {
+        let digest = ring::digest::digest(&ring::digest::SHA256, tls_cert);
+        if tls_link_cert.certified_key[..] != *digest.as_ref() {
+            return Err(LinkError::Protocol("TLS certificate isn't the one CERTS vouches for".to_string()));
+        }
+    }
+
+    let ed25519_matches = match expected.ed25519 {
+        Some(ed25519) if ed25519 != identity => {
+            return Err(LinkError::Protocol(format!(
+                "relay's Ed25519 identity {} isn't the expected {}",
+                hex::encode(identity),
+                hex::encode(ed25519)
+            )));
+        }
+        Some(_) => true,
+        None => false,
+    };
+    let rsa_matches = match (only(CERT_TYPE_RSA_IDENTITY)?, only(CERT_TYPE_RSA_ED25519_CROSSCERT)?) {
+        (Some(rsa_cert), Some(crosscert)) => {
+            verify_rsa_identity(rsa_cert, crosscert, &identity, &expected.rsa, now)?;
+            true
+        }
+        _ => false,
+    };
+    if !ed25519_matches && !rsa_matches {
+        return Err(LinkError::Protocol(
+            "relay proved neither its Ed25519 nor its RSA identity".to_string(),
+        ));
+    }
+    Ok(identity)
+}
+
+/// Check that the RSA identity certificate is for the key with fingerprint
+/// `expected`, and that the key cross-certifies the Ed25519 `identity`
+fn verify_rsa_identity(
+    rsa_cert: &[u8],
+    crosscert: &[u8],
+    identity: &[u8; 32],
+    expected: &[u8; 20],
+    now: SystemTime,
+) -> Result<(), LinkError> {
+    let rsa_key = RsaPublicKey::from_x509(rsa_cert)
+        .map_err(|e| LinkError::Protocol(format!("RSA identity certificate: {:?}", e)))?;
+    if rsa_key.digest() != *expected {
+        return Err(LinkError::Protocol(format!(
+            "relay's RSA identity {} isn't the expected {}",
+            rsa_key.digest_hex(),
+            hex::encode_upper(expected)
+        )));
+    }
+    let crosscert = RsaCrossCert::parse(crosscert)
+        .map_err(|e| LinkError::Protocol(format!("RSA cross-certificate: {:?}", e)))?;
+    if crosscert.is_expired(now) {
+        return Err(LinkError::Protocol("RSA cross-certificate has expired".to_string()));
+    }
+    if crosscert.ed25519_key != *identity || !crosscert.verify(&rsa_key) {
+        return Err(LinkError::Protocol("RSA identity doesn't cross-certify the Ed25519 identity".to_string()));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Netinfo {
+    pub timestamp: u32,
+    /// The sender's view of the receiver's address
+    pub other_address: Option<IpAddr>,
+    pub my_addresses: Vec<IpAddr>,
+}
+
+/// NETINFO payload: Time(4) | OtherAddr | NMyAddr(1) | MyAddrs, with each
+/// address as Type(1) | Len(1) | Value
+pub fn encode_netinfo(timestamp: u32, other_address: IpAddr, my_addresses: &[IpAddr]) -> Vec<u8> {
+    let mut payload = timestamp.to_be_bytes().to_vec();
+    encode_netinfo_address(&mut payload, other_address);
+    payload.push(my_addresses.len() as u8);
+    for &address in my_addresses {
+        encode_netinfo_address(&mut payload, address);
+    }
+    payload
+}
+
+fn encode_netinfo_address(payload: &mut Vec<u8>, address: IpAddr) {
+    match address {
+        IpAddr::V4(ip) => {
+            payload.extend_from_slice(&[NETINFO_ADDR_IPV4, 4]);
+            payload.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            payload.extend_from_slice(&[NETINFO_ADDR_IPV6, 16]);
+            payload.extend_from_slice(&ip.octets());
+        }
+    }
+}
+
+pub fn parse_netinfo(payload: &[u8]) -> Result<Netinfo, LinkError> {
+    if payload.len() < 4 {
+        return Err(truncated_netinfo());
+    }
+    let timestamp = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+    let mut rest = &payload[4..];
+
+    let other_address = take_netinfo_address(&mut rest)?;
+    let (&count, remaining) = rest.split_first().ok_or_else(truncated_netinfo)?;
+    rest = remaining;
+    let mut my_addresses = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        my_addresses.extend(take_netinfo_address(&mut rest)?);
+    }
+    Ok(Netinfo { timestamp, other_address, my_addresses })
+}
+
+fn truncated_netinfo() -> LinkError {
+    LinkError::Protocol("truncated NETINFO cell".to_string())
+}
+
+/// Read one address off the front of `rest`; unknown address types are skipped
+fn take_netinfo_address(rest: &mut &[u8]) -> Result<Option<IpAddr>, LinkError> {
+    if rest.len() < 2 {
+        return Err(truncated_netinfo());
+    }
+    let (kind, len) = (rest[0], rest[1] as usize);
+    let value = rest.get(2..2 + len).ok_or_else(truncated_netinfo)?;
+    *rest = &rest[2 + len..];
+    Ok(match (kind, value) {
+        (NETINFO_ADDR_IPV4, &[a, b, c, d]) => Some(IpAddr::V4(Ipv4Addr::new(a, b, c, d))),
+        (NETINFO_ADDR_IPV6, value) if value.len() == 16 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(value);
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        _ => None,
+    })
+}
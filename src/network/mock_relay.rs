@@ -0,0 +1,908 @@
+// src/network/mock_relay.rs
+//! A minimal in-process relay speaking just enough of the OR protocol to
+//! complete ntor handshakes, extend circuits to other relays and answer relay
+//! cells, for exercising the circuit layer without the real network. As an
+//! exit it echoes every RELAY_DATA cell back on the same stream.
+use crate::crypto::tor_cert::{
+    Ed25519Cert, CERT_KEY_TYPE_ED25519, CERT_KEY_TYPE_SHA256_OF_X509, CERT_TYPE_IDENTITY_V_SIGNING,
+    CERT_TYPE_SIGNING_V_TLS_CERT,
+};
+use crate::circuit::MAX_RELAY_EARLY_CELLS;
+use crate::circuit::sendme::{
+    encode_sendme_v1, parse_sendme_digest, CIRCUIT_WINDOW_INCREMENT, STREAM_WINDOW_INCREMENT,
+};
+use crate::crypto::{ntor_server_handshake, RelayCrypto};
+use crate::directory::{RelayDescriptor, RelayFlag};
+use crate::network::cells::{
+    encode_resolved, Cell, CellCommand, Create2Cell, Extend2Cell, Extended2Cell, LinkSpecifier, RelayCell,
+    ResolvedAddress, CELL_PAYLOAD_LEN, DESTROY_REASON_CONNECTFAILED, DESTROY_REASON_NONE, DESTROY_REASON_PROTOCOL,
+    DESTROY_REASON_REQUESTED, DESTROY_REASON_TIMEOUT, END_REASON_DONE, HANDSHAKE_TYPE_NTOR, NTOR_ONIONSKIN_LEN,
+    RELAY_COMMAND_BEGIN, RELAY_COMMAND_CONNECTED, RELAY_COMMAND_DATA, RELAY_COMMAND_END, RELAY_COMMAND_EXTEND,
+    RELAY_COMMAND_EXTEND2, RELAY_COMMAND_EXTENDED2, RELAY_COMMAND_RESOLVE, RELAY_COMMAND_RESOLVED,
+    RELAY_COMMAND_SENDME, RELAY_COMMAND_TRUNCATE, RELAY_COMMAND_TRUNCATED, RELAY_PAYLOAD_LEN,
+};
+use crate::network::channel::Channel;
+use crate::network::link::{self, CellCodec, LinkError, RelayIdentity, LINK_PROTOCOL_VERSIONS};
+use crate::network::tls::{ClientHello, TlsBackend};
+use base64::{engine::general_purpose, Engine as _};
+use ed25519_dalek::SigningKey;
+use rand::RngCore;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Self-signed certificate and key every mock relay serves TLS with. Like the
+/// rest of this module they are only compiled in with the `mock-relay`
+/// feature, so the key never ends up in a build of the client itself.
+const TLS_CERT_PEM: &[u8] = include_bytes!("mock_relay/tls_cert.pem");
+const TLS_KEY_PEM: &[u8] = include_bytes!("mock_relay/tls_key.pem");
+
+/// How long extending a circuit may take to connect, and then to get a CREATED2
+const EXTEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default)]
+struct MockRelayStats {
+    connections: AtomicUsize,
+    handshakes: AtomicUsize,
+    /// Stream-level SENDMEs received from clients
+    stream_sendmes: AtomicUsize,
+    /// Circuit-level SENDMEs whose digest matched a cell we sent
+    circuit_sendmes: AtomicUsize,
+    /// Don't acknowledge the DATA cells clients send
+    withhold_sendmes: AtomicBool,
+    /// Send CREATED2 replies whose AUTH doesn't match
+    corrupt_auth: AtomicBool,
+    /// Follow each CONNECTED with a SENDME for DATA the client never sent
+    over_credit: AtomicBool,
+    /// Send circuit SENDMEs with a digest that acknowledges nothing
+    forge_sendme_digests: AtomicBool,
+    /// RELAY_EARLY cells received on all circuits
+    relay_early_cells: AtomicUsize,
+    /// RELAY_DATA cells echoed back on all circuits
+    data_cells: AtomicUsize,
+    /// Relay cells passed on to the next hop, with our layer peeled, on all circuits
+    forwarded_cells: AtomicUsize,
+    /// Client ntor public keys (X) of every CREATE2 answered, in order
+    client_keys: Mutex<Vec<[u8; 32]>>,
+    /// When each CREATED2 was sent, in order
+    handshake_times: Mutex<Vec<std::time::Instant>>,
+}
+
+/// Relay-side state of one circuit
+struct MockCircuit {
+    crypto: RelayCrypto,
+    /// DATA cells sent and received on the whole circuit
+    data_sent: usize,
+    data_received: usize,
+    /// DATA cells received per stream
+    stream_data_received: HashMap<u16, usize>,
+    /// Digests the client's circuit SENDMEs must carry, oldest first
+    expected_sendmes: std::collections::VecDeque<[u8; 20]>,
+    relay_early_received: u8,
+    /// Streams begun to a site, with the response still to send
+    site_streams: HashMap<u16, Vec<u8>>,
+    /// Where the circuit was extended to
+    next: Option<NextHop>,
+    /// The ID on the next hop's connection of an EXTEND2 still under way
+    extending: Option<u32>,
+}
+
+impl MockCircuit {
+    fn new(crypto: RelayCrypto) -> Self {
+        Self {
+            crypto,
+            data_sent: 0,
+            data_received: 0,
+            stream_data_received: HashMap::new(),
+            expected_sendmes: Default::default(),
+            relay_early_received: 0,
+            site_streams: HashMap::new(),
+            next: None,
+            extending: None,
+        }
+    }
+
+    /// Encrypt a relay cell of ours for the client, as circuit `circ_id`
+    fn reply(&mut self, circ_id: u32, reply: RelayCell) -> Cell {
+        let is_data = reply.command == RELAY_COMMAND_DATA;
+        // The mock only builds replies that fit, chunking site data by RELAY_PAYLOAD_LEN
+        let mut body = reply.to_bytes().expect("mock relay reply larger than a cell");
+        self.crypto.encrypt_relay_cell(&mut body);
+        if is_data {
+            self.data_sent += 1;
+            if self.data_sent.is_multiple_of(CIRCUIT_WINDOW_INCREMENT as usize) {
+                self.expected_sendmes.push_back(self.crypto.forward_digest());
+            }
+        }
+        Cell::new(circ_id, CellCommand::Relay, body.to_vec())
+    }
+}
+
+/// The rest of a circuit past this relay: the connection it was extended
+/// over and its ID there. Dropping it destroys the circuit onwards.
+struct NextHop {
+    channel: Arc<Channel>,
+    circ_id: u32,
+}
+
+impl Drop for NextHop {
+    fn drop(&mut self) {
+        self.channel.unregister(self.circ_id);
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            let (channel, circ_id) = (self.channel.clone(), self.circ_id);
+            handle.spawn(async move {
+                let destroy = Cell::new(circ_id, CellCommand::Destroy, vec![DESTROY_REASON_NONE]);
+                let _ = channel.send_cell(&destroy).await;
+            });
+        }
+    }
+}
+
+/// What a connection's serving loop acts on, in the order it happened
+enum RelayEvent {
+    /// A cell from the client
+    FromClient(Cell),
+    /// The client closed the connection
+    ClientClosed,
+    /// An EXTEND2 for `circ_id` was carried out as `next_circ_id` on the next
+    /// hop's connection: the EXTENDED2 body to send back, or the reason it failed
+    Extended { circ_id: u32, next_circ_id: u32, result: Result<(NextHop, Vec<u8>), u8> },
+    /// A cell the next hop of `circ_id` sent back
+    FromNextHop { circ_id: u32, cell: Cell },
+}
+
+/// What to do with a relay cell from the client
+enum RelayAnswer {
+    /// Send these cells back
+    Replies(Vec<Cell>),
+    /// Not ours: pass the body, with our layer peeled, to the next hop
+    Forward(Vec<u8>),
+    /// Extend the circuit
+    Extend(Extend2Cell),
+}
+
+/// The relay's connections to other relays, which every circuit it extends
+/// shares
+struct Onward {
+    /// TLS for outgoing connections, the same as the relay serves
+    tls: TlsBackend,
+    channels: tokio::sync::Mutex<HashMap<SocketAddr, Arc<Channel>>>,
+    next_circ_id: AtomicU32,
+}
+
+impl Onward {
+    fn new(tls: TlsBackend) -> Self {
+        Self { tls, channels: tokio::sync::Mutex::new(HashMap::new()), next_circ_id: AtomicU32::new(1) }
+    }
+
+    /// A circuit ID for our connections, with the bit of the side that opened them set
+    fn allocate_circ_id(&self) -> u32 {
+        (self.next_circ_id.fetch_add(1, Ordering::Relaxed) & 0x7fff_ffff) | 0x8000_0000
+    }
+
+    /// The open connection to the relay at `address`, or a new one on which it
+    /// must prove to be `identity`
+    async fn channel(&self, address: SocketAddr, identity: &RelayIdentity) -> Result<Arc<Channel>, LinkError> {
+        let relay_id = general_purpose::STANDARD_NO_PAD.encode(identity.rsa);
+        let mut channels = self.channels.lock().await;
+        if let Some(channel) = channels.get(&address) {
+            if !channel.is_closed() && channel.relay_id() == relay_id {
+                return Ok(channel.clone());
+            }
+        }
+        let hello = ClientHello::default();
+        let channel =
+            Channel::connect(&relay_id, identity, address, self.tls, &hello, EXTEND_TIMEOUT, EXTEND_TIMEOUT).await?;
+        channels.insert(address, channel.clone());
+        Ok(channel)
+    }
+
+    /// Carry out an EXTEND2 for circuit `circ_id` as `next_circ_id` on the
+    /// connection to the relay it names, report the outcome to `events`, and
+    /// then pass back whatever the next hop sends until the circuit is gone
+    async fn extend(
+        self: Arc<Self>,
+        circ_id: u32,
+        next_circ_id: u32,
+        extend2: Extend2Cell,
+        events: mpsc::UnboundedSender<RelayEvent>,
+    ) {
+        let mut address = None;
+        let mut identity = RelayIdentity { rsa: [0; 20], ed25519: None };
+        for spec in &extend2.link_specifiers {
+            match spec {
+                LinkSpecifier::Ipv4(addr) => address = address.or(Some(SocketAddr::V4(*addr))),
+                LinkSpecifier::Ipv6(addr) => address = address.or(Some(SocketAddr::V6(*addr))),
+                LinkSpecifier::RsaId(id) => identity.rsa = *id,
+                LinkSpecifier::Ed25519Id(id) => identity.ed25519 = Some(*id),
+                LinkSpecifier::Unrecognized { .. } => {}
+            }
+        }
+        let created = match address {
+            Some(address) => self.create(address, &identity, next_circ_id, &extend2.create2).await,
+            None => Err(DESTROY_REASON_PROTOCOL),
+        };
+        let mut inbound = match created {
+            Ok((channel, inbound, extended2)) => {
+                let next = NextHop { channel, circ_id: next_circ_id };
+                let result = Ok((next, extended2));
+                if events.send(RelayEvent::Extended { circ_id, next_circ_id, result }).is_err() {
+                    return;
+                }
+                inbound
+            }
+            Err(reason) => {
+                let _ = events.send(RelayEvent::Extended { circ_id, next_circ_id, result: Err(reason) });
+                return;
+            }
+        };
+        while let Some(cell) = inbound.recv().await {
+            if events.send(RelayEvent::FromNextHop { circ_id, cell }).is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Send `create2` to the relay at `address` as circuit `next_circ_id` and
+    /// turn its CREATED2 into an EXTENDED2 body, or give the reason it failed
+    async fn create(
+        &self,
+        address: SocketAddr,
+        identity: &RelayIdentity,
+        next_circ_id: u32,
+        create2: &Create2Cell,
+    ) -> Result<(Arc<Channel>, mpsc::UnboundedReceiver<Cell>, Vec<u8>), u8> {
+        let channel = self.channel(address, identity).await.map_err(|e| {
+            log::debug!("Mock relay couldn't extend to {}: {}", address, e);
+            DESTROY_REASON_CONNECTFAILED
+        })?;
+        let mut inbound = channel.register(next_circ_id);
+        let created = async {
+            let payload = create2.to_bytes().map_err(|_| DESTROY_REASON_PROTOCOL)?;
+            let create2 = Cell::new(next_circ_id, CellCommand::Create2, payload);
+            channel.send_cell(&create2).await.map_err(|_| DESTROY_REASON_CONNECTFAILED)?;
+            let reply = tokio::time::timeout(EXTEND_TIMEOUT, inbound.recv())
+                .await
+                .map_err(|_| DESTROY_REASON_TIMEOUT)?
+                .ok_or(DESTROY_REASON_CONNECTFAILED)?;
+            match reply.command {
+                // EXTENDED2 carries the CREATED2 payload as it is
+                CellCommand::Created2 => Extended2Cell::from_bytes(&reply.payload)
+                    .and_then(|extended2| extended2.to_bytes())
+                    .map_err(|_| DESTROY_REASON_PROTOCOL),
+                CellCommand::Destroy => Err(reply.payload.first().copied().unwrap_or(DESTROY_REASON_NONE)),
+                _ => Err(DESTROY_REASON_PROTOCOL),
+            }
+        }
+        .await;
+        match created {
+            Ok(extended2) => Ok((channel, inbound, extended2)),
+            Err(reason) => {
+                channel.unregister(next_circ_id);
+                Err(reason)
+            }
+        }
+    }
+}
+
+struct MockRelayKeys {
+    identity: [u8; 20],
+    onion_secret: StaticSecret,
+    /// CERTS payload vouching for the TLS certificate
+    certs: Vec<u8>,
+}
+
+/// What the relay knows of the outside world, as an exit
+#[derive(Default)]
+struct HostTable {
+    /// Names answered to RELAY_RESOLVE
+    addresses: HashMap<String, IpAddr>,
+    /// Canned responses by "host:port": a stream begun to one gets the
+    /// response to its first DATA cell, then RELAY_END
+    sites: HashMap<String, Vec<u8>>,
+}
+
+type Hosts = Arc<Mutex<HostTable>>;
+
+pub struct MockRelay {
+    address: SocketAddr,
+    identity: [u8; 20],
+    ed25519_identity: [u8; 32],
+    onion_key: PublicKey,
+    stats: Arc<MockRelayStats>,
+    hosts: Hosts,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl MockRelay {
+    /// Start a mock relay on an ephemeral loopback port
+    pub async fn spawn() -> std::io::Result<Self> {
+        Self::spawn_on("127.0.0.1").await
+    }
+
+    /// Start a mock relay on a specific local IP (any 127.x.y.z works on Linux),
+    /// e.g. to place relays in different /16 subnets
+    pub async fn spawn_on(ip: &str) -> std::io::Result<Self> {
+        Self::start(ip, true).await
+    }
+
+    /// Start a mock relay that speaks the link protocol without TLS, for
+    /// clients using `TlsBackend::None`
+    pub async fn spawn_cleartext() -> std::io::Result<Self> {
+        Self::start("127.0.0.1", false).await
+    }
+
+    async fn start(ip: &str, tls: bool) -> std::io::Result<Self> {
+        let listener = TcpListener::bind((ip, 0)).await?;
+        let address = listener.local_addr()?;
+
+        let mut identity = [0u8; 20];
+        rand::thread_rng().fill_bytes(&mut identity);
+        let onion_secret = StaticSecret::random_from_rng(rand::rngs::OsRng);
+        let onion_key = PublicKey::from(&onion_secret);
+
+        let tls_cert = native_tls::Certificate::from_pem(TLS_CERT_PEM)
+            .and_then(|cert| cert.to_der())
+            .map_err(std::io::Error::other)?;
+        let ed25519_key = SigningKey::from_bytes(&rand::random());
+        let certs = Self::certs(&ed25519_key, &tls_cert);
+        let acceptor = if tls {
+            let identity = native_tls::Identity::from_pkcs8(TLS_CERT_PEM, TLS_KEY_PEM).map_err(std::io::Error::other)?;
+            let acceptor = native_tls::TlsAcceptor::new(identity).map_err(std::io::Error::other)?;
+            Some(tokio_native_tls::TlsAcceptor::from(acceptor))
+        } else {
+            None
+        };
+
+        let stats = Arc::new(MockRelayStats::default());
+        let hosts: Hosts = Arc::new(Mutex::new(HostTable::default()));
+        let keys = Arc::new(MockRelayKeys { identity, onion_secret, certs });
+        let onward = Arc::new(Onward::new(if tls { TlsBackend::NativeTls } else { TlsBackend::None }));
+
+        let task = {
+            let stats = stats.clone();
+            let hosts = hosts.clone();
+            tokio::spawn(async move {
+                while let Ok((stream, peer)) = listener.accept().await {
+                    stats.connections.fetch_add(1, Ordering::SeqCst);
+                    let (keys, stats, hosts, onward) = (keys.clone(), stats.clone(), hosts.clone(), onward.clone());
+                    let Ok(local) = stream.local_addr() else { continue };
+                    match acceptor.clone() {
+                        Some(acceptor) => {
+                            tokio::spawn(async move {
+                                match acceptor.accept(stream).await {
+                                    Ok(stream) => Self::serve(stream, peer, local, keys, stats, hosts, onward).await,
+                                    Err(e) => log::debug!("Mock relay TLS handshake failed: {}", e),
+                                }
+                            });
+                        }
+                        None => {
+                            tokio::spawn(Self::serve(stream, peer, local, keys, stats, hosts, onward));
+                        }
+                    }
+                }
+            })
+        };
+
+        log::debug!("Mock relay listening on {}", address);
+        Ok(Self {
+            address,
+            identity,
+            ed25519_identity: ed25519_key.verifying_key().to_bytes(),
+            onion_key,
+            stats,
+            hosts,
+            task,
+        })
+    }
+
+    /// CERTS payload with the relay's identity->signing and signing->TLS
+    /// certificates, under a fresh signing key
+    fn certs(identity: &SigningKey, tls_cert: &[u8]) -> Vec<u8> {
+        let signing_key = SigningKey::from_bytes(&rand::random());
+        let expires = SystemTime::now() + Duration::from_secs(7 * 24 * 3600);
+        let tls_digest = ring::digest::digest(&ring::digest::SHA256, tls_cert);
+        let mut tls_digest_key = [0u8; 32];
+        tls_digest_key.copy_from_slice(tls_digest.as_ref());
+
+        let certs = [
+            (
+                CERT_TYPE_IDENTITY_V_SIGNING,
+                Ed25519Cert::sign(
+                    CERT_TYPE_IDENTITY_V_SIGNING,
+                    CERT_KEY_TYPE_ED25519,
+                    signing_key.verifying_key().as_bytes(),
+                    expires,
+                    identity,
+                ),
+            ),
+            (
+                CERT_TYPE_SIGNING_V_TLS_CERT,
+                Ed25519Cert::sign(
+                    CERT_TYPE_SIGNING_V_TLS_CERT,
+                    CERT_KEY_TYPE_SHA256_OF_X509,
+                    &tls_digest_key,
+                    expires,
+                    &signing_key,
+                ),
+            ),
+        ];
+        let mut payload = vec![certs.len() as u8];
+        for (cert_type, cert) in certs {
+            payload.push(cert_type);
+            payload.extend_from_slice(&(cert.len() as u16).to_be_bytes());
+            payload.extend_from_slice(&cert);
+        }
+        payload
+    }
+
+    /// Answer RELAY_RESOLVE requests for `hostname` with `ip`; other names fail to resolve
+    pub fn add_host(&self, hostname: &str, ip: IpAddr) {
+        self.hosts.lock().unwrap().addresses.insert(hostname.to_string(), ip);
+    }
+
+    /// Serve `response` to streams begun to `host:port`, instead of echoing
+    /// their DATA back
+    pub fn add_site(&self, host: &str, port: u16, response: &[u8]) {
+        self.hosts.lock().unwrap().sites.insert(format!("{}:{}", host, port), response.to_vec());
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Relay fingerprint in the consensus' unpadded base64 form
+    pub fn id(&self) -> String {
+        general_purpose::STANDARD_NO_PAD.encode(self.identity)
+    }
+
+    /// The relay's Ed25519 identity key, as its CERTS cell certifies it
+    pub fn ed25519_identity(&self) -> [u8; 32] {
+        self.ed25519_identity
+    }
+
+    /// The identities this relay proves in its link handshake
+    pub fn relay_identity(&self) -> RelayIdentity {
+        RelayIdentity { rsa: self.identity, ed25519: Some(self.ed25519_identity) }
+    }
+
+    /// Consensus entry pointing at this relay, with its real ntor onion key
+    pub fn descriptor(&self, nickname: &str, flags: Vec<RelayFlag>, bandwidth: u32) -> RelayDescriptor {
+        RelayDescriptor {
+            id: self.id(),
+            nickname: nickname.to_string(),
+            address: self.address,
+            identity_key: self.identity.to_vec(),
+            onion_key: self.onion_key.as_bytes().to_vec(),
+            ed25519_identity: Some(self.ed25519_identity),
+            bandwidth,
+            flags,
+            platform: None,
+            microdesc_digest: None,
+            exit_policy: None,
+            exit_policy_v6: None,
+            ipv6_address: None,
+            protocols: None,
+            family: Vec::new(),
+        }
+    }
+
+    /// TCP connections accepted so far
+    pub fn connections(&self) -> usize {
+        self.stats.connections.load(Ordering::SeqCst)
+    }
+
+    /// CREATE2 handshakes answered so far
+    pub fn handshakes(&self) -> usize {
+        self.stats.handshakes.load(Ordering::SeqCst)
+    }
+
+    /// When each CREATE2 answered so far was answered
+    pub fn handshake_times(&self) -> Vec<std::time::Instant> {
+        self.stats.handshake_times.lock().unwrap().clone()
+    }
+
+    /// Stop acknowledging DATA cells with SENDMEs, so clients run out of window
+    pub fn withhold_sendmes(&self) {
+        self.stats.withhold_sendmes.store(true, Ordering::SeqCst);
+    }
+
+    /// Answer CREATE2s with a wrong AUTH, as a relay without the listed keys would
+    pub fn corrupt_auth(&self) {
+        self.stats.corrupt_auth.store(true, Ordering::SeqCst);
+    }
+
+    /// Credit every stream begun with a SENDME before it has sent anything,
+    /// opening its window past where it started
+    pub fn over_credit(&self) {
+        self.stats.over_credit.store(true, Ordering::SeqCst);
+    }
+
+    /// Acknowledge DATA with circuit SENDMEs whose digest is wrong, as a
+    /// relay that didn't read the cells would have to
+    pub fn forge_sendme_digests(&self) {
+        self.stats.forge_sendme_digests.store(true, Ordering::SeqCst);
+    }
+
+    /// Stream-level SENDMEs received so far
+    pub fn stream_sendmes(&self) -> usize {
+        self.stats.stream_sendmes.load(Ordering::SeqCst)
+    }
+
+    /// The client public key (X) of every CREATE2 answered so far
+    pub fn client_keys(&self) -> Vec<[u8; 32]> {
+        self.stats.client_keys.lock().unwrap().clone()
+    }
+
+    /// RELAY_EARLY cells received so far
+    pub fn relay_early_cells(&self) -> usize {
+        self.stats.relay_early_cells.load(Ordering::SeqCst)
+    }
+
+    /// RELAY_DATA cells echoed back so far
+    pub fn data_cells(&self) -> usize {
+        self.stats.data_cells.load(Ordering::SeqCst)
+    }
+
+    /// Relay cells passed on to a next hop so far
+    pub fn forwarded_cells(&self) -> usize {
+        self.stats.forwarded_cells.load(Ordering::SeqCst)
+    }
+
+    /// Circuit-level SENDMEs received so far that carried the right digest
+    pub fn circuit_sendmes(&self) -> usize {
+        self.stats.circuit_sendmes.load(Ordering::SeqCst)
+    }
+
+    async fn serve<S>(
+        mut stream: S,
+        peer: SocketAddr,
+        local: SocketAddr,
+        keys: Arc<MockRelayKeys>,
+        stats: Arc<MockRelayStats>,
+        hosts: Hosts,
+        onward: Arc<Onward>,
+    ) where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        if let Err(e) = Self::link_handshake(&mut stream, peer.ip(), local.ip(), &keys.certs).await {
+            log::debug!("Mock relay link handshake failed: {}", e);
+            return;
+        }
+
+        // Variable-length cells (VPADDING) can turn up between fixed-length ones
+        let codec = CellCodec::for_version(*LINK_PROTOCOL_VERSIONS.last().unwrap());
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        // The client's cells and what next hops send back, in one queue
+        let (events_tx, mut events) = mpsc::unbounded_channel();
+        let reader_task = {
+            let events = events_tx.clone();
+            tokio::spawn(async move {
+                while let Ok(cell) = codec.read_cell(&mut reader).await {
+                    if events.send(RelayEvent::FromClient(cell)).is_err() {
+                        return;
+                    }
+                }
+                let _ = events.send(RelayEvent::ClientClosed);
+            })
+        };
+
+        // Every circuit created on this connection
+        let mut circuits: HashMap<u32, MockCircuit> = HashMap::new();
+        while let Some(event) = events.recv().await {
+            let replies = match event {
+                RelayEvent::FromClient(cell) => match cell.command {
+                    CellCommand::Create2 => match Self::answer_create2(&cell, &keys, &stats) {
+                        Ok((reply, crypto)) => {
+                            circuits.insert(cell.circ_id, MockCircuit::new(crypto));
+                            vec![reply]
+                        }
+                        Err(destroy) => destroy.into_iter().collect(),
+                    },
+                    CellCommand::Relay | CellCommand::RelayEarly => {
+                        let Some(circuit) = circuits.get_mut(&cell.circ_id) else {
+                            continue;
+                        };
+                        match Self::answer_relay(&cell, circuit, &stats, &hosts) {
+                            RelayAnswer::Replies(replies) => {
+                                if replies.iter().any(|reply| reply.command == CellCommand::Destroy) {
+                                    circuits.remove(&cell.circ_id);
+                                }
+                                replies
+                            }
+                            RelayAnswer::Forward(body) => {
+                                match &circuit.next {
+                                    Some(next) => {
+                                        stats.forwarded_cells.fetch_add(1, Ordering::SeqCst);
+                                        // RELAY_EARLY stays RELAY_EARLY, so later hops can enforce the budget too
+                                        let forwarded = Cell::new(next.circ_id, cell.command, body);
+                                        if let Err(e) = next.channel.send_cell(&forwarded).await {
+                                            log::debug!("Mock relay couldn't forward a relay cell: {}", e);
+                                        }
+                                    }
+                                    None => log::debug!("Mock relay got an unrecognized relay cell"),
+                                }
+                                Vec::new()
+                            }
+                            RelayAnswer::Extend(extend2) => {
+                                let next_circ_id = onward.allocate_circ_id();
+                                circuit.extending = Some(next_circ_id);
+                                let events = events_tx.clone();
+                                tokio::spawn(onward.clone().extend(cell.circ_id, next_circ_id, extend2, events));
+                                Vec::new()
+                            }
+                        }
+                    }
+                    CellCommand::Destroy => {
+                        // Dropping the circuit destroys the rest of it too
+                        circuits.remove(&cell.circ_id);
+                        Vec::new()
+                    }
+                    other => {
+                        log::debug!("Mock relay ignoring cell command {}", other);
+                        Vec::new()
+                    }
+                },
+                RelayEvent::ClientClosed => break,
+                RelayEvent::Extended { circ_id, next_circ_id, result } => match circuits.get_mut(&circ_id) {
+                    Some(circuit) if circuit.extending == Some(next_circ_id) => {
+                        circuit.extending = None;
+                        let reply = match result {
+                            Ok((next, extended2)) => {
+                                circuit.next = Some(next);
+                                RelayCell::new(RELAY_COMMAND_EXTENDED2, 0, extended2)
+                            }
+                            Err(reason) => RelayCell::new(RELAY_COMMAND_TRUNCATED, 0, vec![reason]),
+                        };
+                        vec![circuit.reply(circ_id, reply)]
+                    }
+                    // Destroyed or truncated meanwhile: dropping the result tears the extension down
+                    _ => Vec::new(),
+                },
+                RelayEvent::FromNextHop { circ_id, cell } => match circuits.get_mut(&circ_id) {
+                    Some(circuit) if circuit.next.as_ref().is_some_and(|next| next.circ_id == cell.circ_id) => {
+                        match cell.command {
+                            CellCommand::Relay | CellCommand::RelayEarly => {
+                                // Add our layer on the way back to the client
+                                let mut body = [0u8; CELL_PAYLOAD_LEN];
+                                body.copy_from_slice(&cell.payload[..CELL_PAYLOAD_LEN]);
+                                circuit.crypto.encrypt_forward_layer(&mut body);
+                                vec![Cell::new(circ_id, CellCommand::Relay, body.to_vec())]
+                            }
+                            CellCommand::Destroy => {
+                                circuit.next = None;
+                                let reason = cell.payload.first().copied().unwrap_or(DESTROY_REASON_NONE);
+                                vec![circuit.reply(circ_id, RelayCell::new(RELAY_COMMAND_TRUNCATED, 0, vec![reason]))]
+                            }
+                            _ => Vec::new(),
+                        }
+                    }
+                    _ => Vec::new(),
+                },
+            };
+
+            for reply in &replies {
+                if reply.command == CellCommand::Created2 {
+                    stats.handshakes.fetch_add(1, Ordering::SeqCst);
+                    stats.handshake_times.lock().unwrap().push(std::time::Instant::now());
+                }
+                if codec.write_cell(&mut writer, reply).await.is_err() {
+                    reader_task.abort();
+                    return;
+                }
+            }
+            if !replies.is_empty() && writer.flush().await.is_err() {
+                break;
+            }
+        }
+        reader_task.abort();
+    }
+
+    /// The relay side of the link handshake: answer VERSIONS with VERSIONS,
+    /// CERTS, AUTH_CHALLENGE and NETINFO, then take the client's NETINFO
+    async fn link_handshake<S>(stream: &mut S, peer: IpAddr, local: IpAddr, certs: &[u8]) -> Result<(), LinkError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let codec = CellCodec::for_version(*LINK_PROTOCOL_VERSIONS.last().unwrap());
+        let versions = CellCodec::versions().read_cell(stream).await?;
+        if versions.command != CellCommand::Versions {
+            return Err(LinkError::Protocol(format!("expected VERSIONS, got command {}", versions.command)));
+        }
+        let reply = Cell::new(0, CellCommand::Versions, link::encode_versions(LINK_PROTOCOL_VERSIONS));
+        CellCodec::versions().write_cell(stream, &reply).await?;
+
+        codec.write_cell(stream, &Cell::new(0, CellCommand::Certs, certs.to_vec())).await?;
+        // Challenge(32) | NMethods(2) | Methods: RSA-SHA256-TLSSecret and Ed25519-SHA256-RFC5705
+        let mut challenge = vec![0u8; 32];
+        rand::thread_rng().fill_bytes(&mut challenge);
+        challenge.extend_from_slice(&[0, 2, 0, 1, 0, 3]);
+        codec.write_cell(stream, &Cell::new(0, CellCommand::AuthChallenge, challenge)).await?;
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs() as u32);
+        let netinfo = link::encode_netinfo(now, peer, &[local]);
+        codec.write_cell(stream, &Cell::new(0, CellCommand::Netinfo, netinfo)).await?;
+        stream.flush().await?;
+
+        let netinfo = codec.read_cell(stream).await?;
+        if netinfo.command != CellCommand::Netinfo {
+            return Err(LinkError::Protocol(format!("expected NETINFO, got command {}", netinfo.command)));
+        }
+        Ok(())
+    }
+
+    /// Answer a CREATE2 with a CREATED2 and the relay's crypto for the new
+    /// circuit, or with the reply to send instead (a DESTROY, or nothing)
+    fn answer_create2(
+        cell: &Cell,
+        keys: &MockRelayKeys,
+        stats: &MockRelayStats,
+    ) -> Result<(Cell, RelayCrypto), Option<Cell>> {
+        let destroy = Some(Cell::new(cell.circ_id, CellCommand::Destroy, vec![DESTROY_REASON_PROTOCOL]));
+
+        let create2 = Create2Cell::from_bytes(&cell.payload).map_err(|_| destroy.clone())?;
+        if create2.handshake_type != HANDSHAKE_TYPE_NTOR || create2.handshake_data.len() != NTOR_ONIONSKIN_LEN {
+            return Err(destroy);
+        }
+
+        // ID(20) | B(32) | X(32)
+        let onionskin = &create2.handshake_data;
+        if onionskin[..20] != keys.identity
+            || onionskin[20..52] != *PublicKey::from(&keys.onion_secret).as_bytes()
+        {
+            return Err(destroy);
+        }
+        let mut client_public = [0u8; 32];
+        client_public.copy_from_slice(&onionskin[52..84]);
+        stats.client_keys.lock().unwrap().push(client_public);
+
+        let server_secret = StaticSecret::random_from_rng(rand::rngs::OsRng);
+        let (server_public, auth, ntor_keys) = ntor_server_handshake(
+            &server_secret,
+            &keys.onion_secret,
+            &keys.identity,
+            &PublicKey::from(client_public),
+        )
+        .map_err(|_| None)?;
+
+        let mut reply = Vec::with_capacity(2 + 64);
+        reply.extend_from_slice(&64u16.to_be_bytes());
+        reply.extend_from_slice(server_public.as_bytes());
+        reply.extend_from_slice(&auth);
+        if stats.corrupt_auth.load(Ordering::SeqCst) {
+            reply[2 + 32] ^= 0xff;
+        }
+
+        // The relay encrypts with the client's backward keys and decrypts with its forward keys
+        let crypto = RelayCrypto::new(
+            &ntor_keys.backward_digest,
+            &ntor_keys.forward_digest,
+            &ntor_keys.backward_key,
+            &ntor_keys.forward_key,
+        );
+        Ok((Cell::new(cell.circ_id, CellCommand::Created2, reply), crypto))
+    }
+
+    /// Answer a relay cell: extend and truncate the circuit, resolve names, open streams, serve
+    /// sites, echo other DATA back and acknowledge it with SENDMEs, and check the client's
+    /// SENDMEs. Cells for later hops are handed back to be forwarded.
+    fn answer_relay(cell: &Cell, circuit: &mut MockCircuit, stats: &MockRelayStats, hosts: &Hosts) -> RelayAnswer {
+        // Like real relays, kill circuits that overspend RELAY_EARLY or extend without it
+        let destroy = Cell::new(cell.circ_id, CellCommand::Destroy, vec![DESTROY_REASON_PROTOCOL]);
+        let destroy = RelayAnswer::Replies(vec![destroy]);
+        let early = cell.command == CellCommand::RelayEarly;
+        if early {
+            stats.relay_early_cells.fetch_add(1, Ordering::SeqCst);
+            circuit.relay_early_received += 1;
+            if circuit.relay_early_received > MAX_RELAY_EARLY_CELLS {
+                return destroy;
+            }
+        }
+
+        let mut body = [0u8; CELL_PAYLOAD_LEN];
+        body.copy_from_slice(&cell.payload[..CELL_PAYLOAD_LEN]);
+        if !circuit.crypto.decrypt_relay_cell(&mut body) {
+            return RelayAnswer::Forward(body.to_vec());
+        }
+        let Ok(request) = RelayCell::from_bytes(&body) else {
+            return RelayAnswer::Replies(Vec::new());
+        };
+        if matches!(request.command, RELAY_COMMAND_EXTEND | RELAY_COMMAND_EXTEND2) && !early {
+            return destroy;
+        }
+
+        let mut replies = Vec::new();
+        match request.command {
+            RELAY_COMMAND_EXTEND2 => {
+                // A circuit is extended once; extending it again needs a TRUNCATE first
+                if circuit.next.is_some() || circuit.extending.is_some() {
+                    return destroy;
+                }
+                match Extend2Cell::from_bytes(&request.data) {
+                    Ok(extend2) => return RelayAnswer::Extend(extend2),
+                    Err(_) => replies.push(RelayCell::new(RELAY_COMMAND_TRUNCATED, 0, vec![DESTROY_REASON_PROTOCOL])),
+                }
+            }
+            RELAY_COMMAND_TRUNCATE => {
+                circuit.next = None;
+                circuit.extending = None;
+                replies.push(RelayCell::new(RELAY_COMMAND_TRUNCATED, 0, vec![DESTROY_REASON_REQUESTED]));
+            }
+            RELAY_COMMAND_RESOLVE => {
+                let hostname = request.data.split(|&b| b == 0).next().unwrap_or_default();
+                let hostname = String::from_utf8_lossy(hostname);
+                let answer = match hosts.lock().unwrap().addresses.get(hostname.as_ref()) {
+                    Some(ip) => (ResolvedAddress::Ip(*ip), 60),
+                    None => (ResolvedAddress::Error { transient: false }, 0),
+                };
+                replies.push(RelayCell::new(RELAY_COMMAND_RESOLVED, request.stream_id, encode_resolved(&[answer])));
+            }
+            RELAY_COMMAND_BEGIN => {
+                let target = request.data.split(|&b| b == 0).next().unwrap_or_default();
+                if let Some(response) = hosts.lock().unwrap().sites.get(String::from_utf8_lossy(target).as_ref()) {
+                    circuit.site_streams.insert(request.stream_id, response.clone());
+                }
+                replies.push(RelayCell::new(RELAY_COMMAND_CONNECTED, request.stream_id, Vec::new()));
+                if stats.over_credit.load(Ordering::SeqCst) {
+                    replies.push(RelayCell::new(RELAY_COMMAND_SENDME, request.stream_id, Vec::new()));
+                }
+            }
+            RELAY_COMMAND_DATA if circuit.site_streams.contains_key(&request.stream_id) => {
+                let response = circuit.site_streams.remove(&request.stream_id).unwrap_or_default();
+                for chunk in response.chunks(RELAY_PAYLOAD_LEN) {
+                    replies.push(RelayCell::new(RELAY_COMMAND_DATA, request.stream_id, chunk.to_vec()));
+                }
+                replies.push(RelayCell::new(RELAY_COMMAND_END, request.stream_id, vec![END_REASON_DONE]));
+            }
+            RELAY_COMMAND_DATA => {
+                stats.data_cells.fetch_add(1, Ordering::SeqCst);
+                circuit.data_received += 1;
+                let stream_received = circuit.stream_data_received.entry(request.stream_id).or_default();
+                *stream_received += 1;
+                let stream_received = *stream_received;
+                replies.push(RelayCell::new(RELAY_COMMAND_DATA, request.stream_id, request.data));
+                if !stats.withhold_sendmes.load(Ordering::SeqCst) {
+                    if stream_received.is_multiple_of(STREAM_WINDOW_INCREMENT as usize) {
+                        replies.push(RelayCell::new(RELAY_COMMAND_SENDME, request.stream_id, Vec::new()));
+                    }
+                    if circuit.data_received.is_multiple_of(CIRCUIT_WINDOW_INCREMENT as usize) {
+                        // The running digest of what the client sent, up to and including this cell
+                        let mut digest = circuit.crypto.backward_digest();
+                        if stats.forge_sendme_digests.load(Ordering::SeqCst) {
+                            digest[0] ^= 0xff;
+                        }
+                        replies.push(RelayCell::new(RELAY_COMMAND_SENDME, 0, encode_sendme_v1(&digest)));
+                    }
+                }
+            }
+            RELAY_COMMAND_SENDME if request.stream_id == 0 => {
+                let expected = circuit.expected_sendmes.pop_front();
+                if expected.is_some() && parse_sendme_digest(&request.data) == expected {
+                    stats.circuit_sendmes.fetch_add(1, Ordering::SeqCst);
+                } else {
+                    log::debug!("Mock relay got a circuit SENDME with the wrong digest");
+                }
+            }
+            RELAY_COMMAND_SENDME => {
+                stats.stream_sendmes.fetch_add(1, Ordering::SeqCst);
+            }
+            other => log::debug!("Mock relay ignoring relay command {}", other),
+        }
+
+        RelayAnswer::Replies(replies.into_iter().map(|reply| circuit.reply(cell.circ_id, reply)).collect())
+    }
+}
+
+impl Drop for MockRelay {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
@@ -0,0 +1,12 @@
+// src/network/mod.rs
+pub mod cells;
+pub mod channel;
+pub mod link;
+#[cfg(feature = "mock-relay")]
+pub mod mock_relay;
+pub mod tls;
+
+pub use cells::{Cell, CellError, Create2Cell, Created2Cell, RelayCell};
+pub use channel::{has_ipv4_route, Channel};
+pub use link::{CellCodec, LinkError, LinkInfo, RelayIdentity};
+pub use tls::{ClientHello, SniStrategy, TlsBackend};
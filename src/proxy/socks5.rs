@@ -1,17 +1,114 @@
 // src/proxy/socks5.rs
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
+use std::net::{IpAddr, Ipv4Addr};
 use std::sync::Arc;
-use crate::circuit::CircuitManager;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use crate::circuit::{CircuitError, CircuitManager, CircuitStream, IsolationKey};
+use crate::directory::policy::ExitTarget;
+use crate::hs::{self, HsError, OnionAddress};
+use crate::metrics::Metrics;
+use crate::network::cells::{
+    END_REASON_CONNECTREFUSED, END_REASON_CONNRESET, END_REASON_DONE, END_REASON_EXITPOLICY,
+    END_REASON_NOROUTE, END_REASON_RESOLVEFAILED, END_REASON_TIMEOUT, RELAY_COMMAND_DATA, RELAY_COMMAND_END,
+    RELAY_PAYLOAD_LEN,
+};
+
+// SOCKS5 commands: CONNECT and BIND from RFC 1928, RESOLVE is Tor's extension
+pub const COMMAND_CONNECT: u8 = 0x01;
+pub const COMMAND_BIND: u8 = 0x02;
+pub const COMMAND_RESOLVE: u8 = 0xF0;
+
+// SOCKS4 reply codes; SOCKS4 has no finer-grained failure than "rejected"
+pub const SOCKS4_REQUEST_GRANTED: u8 = 90;
+pub const SOCKS4_REQUEST_REJECTED: u8 = 91;
+
+// SOCKS5 reply codes (RFC 1928 section 6)
+pub const REPLY_SUCCEEDED: u8 = 0x00;
+pub const REPLY_GENERAL_FAILURE: u8 = 0x01;
+pub const REPLY_NOT_ALLOWED: u8 = 0x02;
+pub const REPLY_NETWORK_UNREACHABLE: u8 = 0x03;
+pub const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+pub const REPLY_CONNECTION_REFUSED: u8 = 0x05;
+pub const REPLY_TTL_EXPIRED: u8 = 0x06;
+pub const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+pub const REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+/// SOCKS5 reply for a stream the exit closed with RELAY_END `reason`,
+/// following Tor's own mapping
+pub fn reply_for_end_reason(reason: u8) -> u8 {
+    match reason {
+        END_REASON_DONE => REPLY_SUCCEEDED,
+        END_REASON_RESOLVEFAILED => REPLY_HOST_UNREACHABLE,
+        END_REASON_CONNECTREFUSED | END_REASON_CONNRESET => REPLY_CONNECTION_REFUSED,
+        END_REASON_EXITPOLICY => REPLY_NOT_ALLOWED,
+        END_REASON_TIMEOUT => REPLY_TTL_EXPIRED,
+        END_REASON_NOROUTE => REPLY_NETWORK_UNREACHABLE,
+        _ => REPLY_GENERAL_FAILURE,
+    }
+}
+
+/// SOCKS5 reply for a circuit we couldn't build
+fn reply_for_circuit_error(err: &CircuitError) -> u8 {
+    match err {
+        // Couldn't get into the Tor network at all
+        CircuitError::NoSuitableRelays | CircuitError::Directory(_) | CircuitError::Io(_) => {
+            REPLY_NETWORK_UNREACHABLE
+        }
+        CircuitError::HandshakeFailed(reason) if reason == "timeout" => REPLY_TTL_EXPIRED,
+        CircuitError::ResolveFailed(_) => REPLY_HOST_UNREACHABLE,
+        CircuitError::StreamEnded(reason) => reply_for_end_reason(*reason),
+        _ => REPLY_GENERAL_FAILURE,
+    }
+}
+
+/// SOCKS5 reply for a failed direct connection
+fn reply_for_io_error(err: &std::io::Error) -> u8 {
+    use std::io::ErrorKind;
+    match err.kind() {
+        ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset => REPLY_CONNECTION_REFUSED,
+        ErrorKind::TimedOut => REPLY_TTL_EXPIRED,
+        ErrorKind::NetworkUnreachable => REPLY_NETWORK_UNREACHABLE,
+        // Includes failed DNS lookups
+        _ => REPLY_HOST_UNREACHABLE,
+    }
+}
 
 #[derive(Debug)]
 pub enum ProxyError {
     InvalidVersion(u8),
     Io(std::io::Error),
     Circuit(crate::circuit::CircuitError),
-    Unsupported(String),
+    UnsupportedCommand(u8),
+    UnsupportedAddressType(u8),
+    /// A .onion host that isn't a valid v3 address
+    Onion(HsError),
+    /// Something else is already listening on the proxy's address
+    BindFailed { address: String, source: std::io::Error },
+}
+
+impl std::fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProxyError::InvalidVersion(version) => write!(f, "Unsupported SOCKS version {}", version),
+            ProxyError::Io(e) => write!(f, "I/O error: {}", e),
+            ProxyError::Circuit(e) => write!(f, "Circuit error: {:?}", e),
+            ProxyError::UnsupportedCommand(command) => write!(f, "Unsupported SOCKS command {}", command),
+            ProxyError::UnsupportedAddressType(atyp) => write!(f, "Unsupported address type {}", atyp),
+            ProxyError::Onion(e) => write!(f, "{}", e),
+            ProxyError::BindFailed { address, source } => write!(
+                f,
+                "Can't listen on {}: the port is already in use, perhaps by Tor or another tor-client ({})",
+                address, source
+            ),
+        }
+    }
 }
 
+impl std::error::Error for ProxyError {}
+
 impl From<std::io::Error> for ProxyError {
     fn from(err: std::io::Error) -> Self {
         ProxyError::Io(err)
@@ -24,11 +121,60 @@ impl From<crate::circuit::CircuitError> for ProxyError {
     }
 }
 
+/// Circuits are three hops, except to hosts marked sensitive
+#[derive(Debug, Clone)]
+pub struct CircuitLength {
+    /// Hostnames, or ".example.com" for a domain and its subdomains
+    sensitive_hosts: Vec<String>,
+    sensitive_hops: usize,
+}
+
+impl CircuitLength {
+    pub fn new(sensitive_hosts: Vec<String>, sensitive_hops: usize) -> Self {
+        let sensitive_hosts = sensitive_hosts.into_iter().map(|host| host.to_ascii_lowercase()).collect();
+        Self { sensitive_hosts, sensitive_hops }
+    }
+
+    /// How many hops a circuit for streams to `host` gets
+    pub fn hops_for(&self, host: &str) -> usize {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let sensitive = self.sensitive_hosts.iter().any(|rule| match rule.strip_prefix('.') {
+            Some(domain) => host == domain || host.ends_with(rule.as_str()),
+            None => host == *rule,
+        });
+        if sensitive { self.sensitive_hops } else { DEFAULT_CIRCUIT_HOPS }
+    }
+}
+
+impl Default for CircuitLength {
+    fn default() -> Self {
+        Self::new(Vec::new(), DEFAULT_CIRCUIT_HOPS + 1)
+    }
+}
+
+/// Hops in the circuits for streams to hosts that aren't sensitive, and so
+/// in the circuits kept in the pool
+pub const DEFAULT_CIRCUIT_HOPS: usize = 3;
+
+/// How long a BIND waits for the far end to connect
+const BIND_ACCEPT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How long `run` waits, once shut down, for clients it's still serving
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[derive(Debug)]
 pub struct Socks5Proxy {
     bind_address: String,
     circuit_manager: Arc<CircuitManager>,
     directory_client: Arc<crate::directory::DirectoryClient>,
+    direct_connect_insecure: bool,
+    metrics: Arc<Metrics>,
+    circuit_length: Arc<CircuitLength>,
+    /// Cancelled by `shutdown`
+    shutdown: CancellationToken,
+    /// A task per client being served
+    clients: TaskTracker,
+    drain_timeout: Duration,
 }
 
 impl Socks5Proxy {
@@ -36,28 +182,79 @@ impl Socks5Proxy {
         bind_address: String,
         circuit_manager: Arc<CircuitManager>,
         directory_client: Arc<crate::directory::DirectoryClient>,
+        direct_connect_insecure: bool,
     ) -> Self {
         Self {
             bind_address,
             circuit_manager,
             directory_client,
+            direct_connect_insecure,
+            metrics: Arc::new(Metrics::new()),
+            circuit_length: Arc::new(CircuitLength::default()),
+            shutdown: CancellationToken::new(),
+            clients: TaskTracker::new(),
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
         }
     }
 
+    /// Give clients up to `timeout` to finish once the proxy is shut down
+    pub fn with_drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Build longer circuits for streams to the hosts `circuit_length` marks sensitive
+    pub fn with_circuit_length(mut self, circuit_length: CircuitLength) -> Self {
+        self.circuit_length = Arc::new(circuit_length);
+        self
+    }
+
+    /// Count relayed bytes in `metrics` instead of a set of counters of our own
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// The address `run` binds to
+    pub fn bind_address(&self) -> &str {
+        &self.bind_address
+    }
+
+    /// Make `run` stop accepting clients, wait for those it's serving (up to
+    /// the drain timeout), close every circuit and return
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
+
     pub async fn run(&self) -> Result<(), ProxyError> {
-        let listener = TcpListener::bind(&self.bind_address).await?;
-        log::info!("SOCKS5 proxy listening on {}", self.bind_address);
+        let listener = TcpListener::bind(&self.bind_address).await.map_err(|e| match e.kind() {
+            std::io::ErrorKind::AddrInUse => ProxyError::BindFailed { address: self.bind_address.clone(), source: e },
+            _ => ProxyError::Io(e),
+        })?;
+        self.serve(listener).await
+    }
+
+    /// `run` on a listener that's already bound, in place of `bind_address`
+    pub async fn serve(&self, listener: TcpListener) -> Result<(), ProxyError> {
+        log::info!("SOCKS5 proxy listening on {}", listener.local_addr()?);
 
         loop {
-            match listener.accept().await {
+            let accepted = tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                accepted = listener.accept() => accepted,
+            };
+            match accepted {
                 Ok((stream, addr)) => {
                     log::info!("New connection from {}", addr);
                     let circuit_manager = self.circuit_manager.clone();
                     let directory_client = self.directory_client.clone();
+                    let direct_connect_insecure = self.direct_connect_insecure;
+                    let metrics = self.metrics.clone();
+                    let circuit_length = self.circuit_length.clone();
                     
-                    tokio::spawn(async move {
+                    self.clients.spawn(async move {
                         log::debug!("Spawned handler for {}", addr);
-                        match Self::handle_client(stream, circuit_manager, directory_client).await {
+                        match Self::handle_client(stream, addr.port(), circuit_manager, directory_client, direct_connect_insecure, metrics, circuit_length).await {
                             Ok(_) => log::info!("Client {} handled successfully", addr),
                             Err(e) => log::error!("Client {} handling error: {:?}", addr, e),
                         }
@@ -68,126 +265,409 @@ impl Socks5Proxy {
                 }
             }
         }
+
+        drop(listener);
+        self.clients.close();
+        log::info!("SOCKS5 proxy shutting down, waiting for {} client(s)", self.clients.len());
+        if tokio::time::timeout(self.drain_timeout, self.clients.wait()).await.is_err() {
+            log::warn!("{} client(s) still open after {:?}; closing their circuits", self.clients.len(), self.drain_timeout);
+        }
+        self.circuit_manager.close_all_circuits().await;
+        Ok(())
     }
 
     async fn handle_client(
         mut stream: TcpStream,
+        client_port: u16,
         circuit_manager: Arc<CircuitManager>,
         directory_client: Arc<crate::directory::DirectoryClient>,
+        direct_connect_insecure: bool,
+        metrics: Arc<Metrics>,
+        circuit_length: Arc<CircuitLength>,
     ) -> Result<(), ProxyError> {
         log::debug!("Starting client handler");
+
+        // Older tools speak SOCKS4/4a, which has no handshake before the request
+        let mut version = [0u8; 1];
+        stream.peek(&mut version).await?;
+        if version[0] == 0x04 {
+            let (request, userid) = match Self::parse_socks4_request(&mut stream).await {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    if matches!(e, ProxyError::UnsupportedCommand(_) | ProxyError::Onion(_)) {
+                        Self::send_socks4_reply(&mut stream, SOCKS4_REQUEST_REJECTED).await?;
+                    }
+                    return Err(e);
+                }
+            };
+            log::info!("SOCKS4 request: {}", request.target());
+            // The userid is SOCKS4's only means of asking for isolation
+            let isolation_key = if userid.is_empty() {
+                IsolationKey::ClientPort(client_port)
+            } else {
+                IsolationKey::Credentials { username: userid, password: Vec::new() }
+            };
+            let hops = circuit_length.hops_for(&request.host);
+            return Self::connect(stream, &request, &isolation_key, hops, &circuit_manager, &directory_client, direct_connect_insecure, metrics).await;
+        }
         
         // SOCKS5 handshake
         log::debug!("Performing handshake");
-        Self::perform_handshake(&mut stream).await?;
+        let credentials = Self::perform_handshake(&mut stream).await?;
         log::debug!("Handshake complete");
 
+        // Like Tor's IsolateSOCKSAuth: clients presenting the same credentials share
+        // circuits; anything else gets a circuit of its own
+        let isolation_key = match credentials {
+            Some((username, password)) => IsolationKey::Credentials { username, password },
+            None => IsolationKey::ClientPort(client_port),
+        };
+
         // Parse SOCKS5 request
         log::debug!("Parsing request");
-        let request = Self::parse_request(&mut stream).await?;
+        let request = match Self::parse_request(&mut stream).await {
+            Ok(request) => request,
+            Err(e) => {
+                let status = match e {
+                    ProxyError::UnsupportedCommand(_) => Some(REPLY_COMMAND_NOT_SUPPORTED),
+                    ProxyError::UnsupportedAddressType(_) => Some(REPLY_ADDRESS_TYPE_NOT_SUPPORTED),
+                    ProxyError::Onion(_) => Some(REPLY_HOST_UNREACHABLE),
+                    _ => None,
+                };
+                if let Some(status) = status {
+                    Self::send_response(&mut stream, status).await?;
+                }
+                return Err(e);
+            }
+        };
         
-        log::info!("SOCKS5 request: {}:{}", request.host, request.port);
-
-        // Create a circuit
-        log::debug!("Creating circuit");
-        match circuit_manager.create_circuit(3, &directory_client).await {
-            Ok(circuit_id) => {
-                log::info!("Created circuit {}", circuit_id);
-                // Send success response
-                log::debug!("Sending success response");
-                Self::send_response(&mut stream, 0x00).await?;
-                log::debug!("Response sent");
-                
-                // TODO: Actually relay traffic through circuit
-                // For now, simulate a basic HTTP response for testing
-                log::info!("Attempting to connect to {}:{}", request.host, request.port);
-                
-                // Try to connect directly (just for testing purposes)
-                match tokio::time::timeout(
-                    std::time::Duration::from_secs(10),
-                    TcpStream::connect(format!("{}:{}", request.host, request.port))
-                ).await {
-                    Ok(Ok(target)) => {
-                        log::info!("Connected to target, relaying traffic");
-                        
-                        // Simple bidirectional relay (not through Tor circuit yet)
-                        let (mut client_read, mut client_write) = stream.into_split();
-                        let (mut target_read, mut target_write) = target.into_split();
-                        
-                        // Spawn task for client -> target
-                        let client_to_target = tokio::spawn(async move {
-                            let mut buf = [0u8; 8192];
-                            let mut total_bytes = 0;
-                            loop {
-                                match client_read.read(&mut buf).await {
-                                    Ok(0) => {
-                                        log::debug!("Client closed connection (sent {} bytes)", total_bytes);
-                                        break;
-                                    }
-                                    Ok(n) => {
-                                        total_bytes += n;
-                                        if let Err(e) = target_write.write_all(&buf[..n]).await {
-                                            log::error!("Error writing to target: {}", e);
-                                            break;
-                                        }
-                                    }
-                                    Err(e) => {
-                                        log::error!("Error reading from client: {}", e);
-                                        break;
-                                    }
-                                }
-                            }
-                        });
-                        
-                        // Spawn task for target -> client
-                        let target_to_client = tokio::spawn(async move {
-                            let mut buf = [0u8; 8192];
-                            let mut total_bytes = 0;
-                            loop {
-                                match target_read.read(&mut buf).await {
-                                    Ok(0) => {
-                                        log::debug!("Target closed connection (received {} bytes)", total_bytes);
-                                        break;
-                                    }
-                                    Ok(n) => {
-                                        total_bytes += n;
-                                        if let Err(e) = client_write.write_all(&buf[..n]).await {
-                                            log::error!("Error writing to client: {}", e);
-                                            break;
-                                        }
-                                    }
-                                    Err(e) => {
-                                        log::error!("Error reading from target: {}", e);
-                                        break;
-                                    }
-                                }
-                            }
-                        });
-                        
-                        // Wait for both tasks to complete
-                        let _ = tokio::join!(client_to_target, target_to_client);
-                        log::info!("Connection relay finished");
+        log::info!("SOCKS5 request: {}", request.target());
+
+        let hops = circuit_length.hops_for(&request.host);
+        if request.command == COMMAND_BIND {
+            return Self::handle_bind(stream, &request, direct_connect_insecure, metrics).await;
+        }
+        if request.command == COMMAND_RESOLVE {
+            return Self::handle_resolve(stream, &request, &isolation_key, hops, &circuit_manager, &directory_client, direct_connect_insecure).await;
+        }
+
+        Self::connect(stream, &request, &isolation_key, hops, &circuit_manager, &directory_client, direct_connect_insecure, metrics).await
+    }
+
+    /// Route a CONNECT, from either SOCKS version, to its target over a
+    /// circuit of `hops` hops
+    #[allow(clippy::too_many_arguments)]
+    async fn connect(
+        mut stream: TcpStream,
+        request: &Socks5Request,
+        isolation_key: &IsolationKey,
+        hops: usize,
+        circuit_manager: &CircuitManager,
+        directory_client: &crate::directory::DirectoryClient,
+        direct_connect_insecure: bool,
+        metrics: Arc<Metrics>,
+    ) -> Result<(), ProxyError> {
+        // Onion services are reached by rendezvous, never through an exit
+        // (and never directly, whatever direct_connect_insecure says)
+        if let Some(onion) = &request.onion {
+            return match hs::connect(onion, request.port).await {
+                Ok(circuit_stream) => {
+                    Self::send_status(&mut stream, request, REPLY_SUCCEEDED).await?;
+                    Self::relay_circuit_stream(stream, circuit_stream, metrics).await
+                }
+                Err(e) => {
+                    log::error!("Failed to reach {}: {}", request.host, e);
+                    Self::send_status(&mut stream, request, REPLY_HOST_UNREACHABLE).await
+                }
+            };
+        }
+
+        if direct_connect_insecure {
+            log::warn!(
+                "⚠ INSECURE: connecting directly to {} without Tor (direct_connect_insecure is set)",
+                request.target()
+            );
+            return Self::relay_direct(stream, request, metrics).await;
+        }
+
+        // Get a circuit for this client's isolation key
+        log::debug!("Getting circuit for {:?}", isolation_key);
+        let target = ExitTarget::for_host(&request.host, request.port);
+        let circuit_id = match circuit_manager.get_or_create_circuit(isolation_key, Some(target), hops, directory_client).await {
+            Ok(circuit_id) => circuit_id,
+            Err(e) => {
+                log::error!("Failed to create circuit: {:?}", e);
+                return Self::send_status(&mut stream, request, reply_for_circuit_error(&e)).await;
+            }
+        };
+        log::debug!("Beginning a stream to {} on circuit {}", request.target(), circuit_id);
+        match circuit_manager.begin_stream(circuit_id, &request.host, request.port).await {
+            Ok(circuit_stream) => {
+                log::info!("Stream {} on circuit {} connected to {}", circuit_stream.id(), circuit_id, request.target());
+                Self::send_status(&mut stream, request, REPLY_SUCCEEDED).await?;
+                Self::relay_circuit_stream(stream, circuit_stream, metrics).await
+            }
+            Err(e) => {
+                log::error!("Failed to reach {} on circuit {}: {:?}", request.target(), circuit_id, e);
+                Self::send_status(&mut stream, request, reply_for_circuit_error(&e)).await
+            }
+        }
+    }
+
+    /// Shuttle bytes between the client and a connected stream, one DATA
+    /// cell per read. Like Tor, the client closing its side ends the stream
+    /// (RELAY_END); the exit ending it closes the client's connection.
+    async fn relay_circuit_stream(stream: TcpStream, mut circuit_stream: CircuitStream, metrics: Arc<Metrics>) -> Result<(), ProxyError> {
+        let (mut client_read, mut client_write) = stream.into_split();
+        let sender = circuit_stream.sender();
+        let stream_id = circuit_stream.id();
+
+        let sent = metrics.clone();
+        let client_to_exit = async move {
+            let mut buf = [0u8; RELAY_PAYLOAD_LEN];
+            loop {
+                match client_read.read(&mut buf).await {
+                    Ok(0) => {
+                        log::debug!("Client closed stream {}", stream_id);
+                        break;
                     }
-                    Ok(Err(e)) => {
-                        log::error!("Failed to connect to target: {}", e);
-                        // Connection already established, can't send failure response
+                    Ok(n) => {
+                        sent.bytes_sent.fetch_add(n as u64, std::sync::atomic::Ordering::Relaxed);
+                        if let Err(e) = sender.send(RELAY_COMMAND_DATA, buf[..n].to_vec()).await {
+                            log::error!("Error sending on stream {}: {:?}", stream_id, e);
+                            return;
+                        }
                     }
-                    Err(_) => {
-                        log::error!("Timeout connecting to target");
+                    Err(e) => {
+                        log::error!("Error reading from client: {}", e);
+                        break;
                     }
                 }
             }
+            if let Err(e) = sender.send(RELAY_COMMAND_END, vec![END_REASON_DONE]).await {
+                log::debug!("Couldn't end stream {}: {:?}", stream_id, e);
+            }
+        };
+
+        let exit_to_client = async {
+            while let Some(cell) = circuit_stream.recv().await {
+                match cell.command {
+                    RELAY_COMMAND_DATA => {
+                        metrics.bytes_received.fetch_add(cell.data.len() as u64, std::sync::atomic::Ordering::Relaxed);
+                        if let Err(e) = client_write.write_all(&cell.data).await {
+                            log::error!("Error writing to client: {}", e);
+                            return;
+                        }
+                    }
+                    RELAY_COMMAND_END => {
+                        log::debug!("Exit ended stream {} (reason {:?})", stream_id, cell.data.first());
+                        return;
+                    }
+                    other => log::debug!("Ignoring relay command {} on stream {}", other, stream_id),
+                }
+            }
+            log::debug!("Circuit {} closed under stream {}", circuit_stream.circuit_id(), stream_id);
+        };
+
+        tokio::select! {
+            _ = client_to_exit => {}
+            _ = exit_to_client => {}
+        }
+        log::info!("Stream {} finished", stream_id);
+        Ok(())
+    }
+
+    /// Tor's RESOLVE extension: answer with the hostname's address, looked up by
+    /// the exit so the name never reaches the local resolver
+    async fn handle_resolve(
+        mut stream: TcpStream,
+        request: &Socks5Request,
+        isolation_key: &IsolationKey,
+        hops: usize,
+        circuit_manager: &CircuitManager,
+        directory_client: &crate::directory::DirectoryClient,
+        direct_connect_insecure: bool,
+    ) -> Result<(), ProxyError> {
+        if let Ok(ip) = request.host.parse::<IpAddr>() {
+            return Self::send_reply(&mut stream, REPLY_SUCCEEDED, ip).await;
+        }
+        // Onion addresses have no IP address to give
+        if request.onion.is_some() {
+            return Self::send_response(&mut stream, REPLY_HOST_UNREACHABLE).await;
+        }
+
+        if direct_connect_insecure {
+            log::warn!("⚠ INSECURE: resolving {} locally without Tor (direct_connect_insecure is set)", request.host);
+            return match tokio::net::lookup_host((request.host.as_str(), 0)).await {
+                Ok(mut addresses) => match addresses.next() {
+                    Some(address) => Self::send_reply(&mut stream, REPLY_SUCCEEDED, address.ip()).await,
+                    None => Self::send_response(&mut stream, REPLY_HOST_UNREACHABLE).await,
+                },
+                Err(e) => Self::send_response(&mut stream, reply_for_io_error(&e)).await,
+            };
+        }
+
+        let resolved = match circuit_manager.get_or_create_circuit(isolation_key, None, hops, directory_client).await {
+            Ok(circuit_id) => circuit_manager.resolve(circuit_id, &request.host).await,
+            Err(e) => Err(e),
+        };
+        match resolved {
+            Ok(addresses) => Self::send_reply(&mut stream, REPLY_SUCCEEDED, addresses[0]).await,
+            Err(e) => {
+                log::error!("Failed to resolve {}: {:?}", request.host, e);
+                Self::send_response(&mut stream, reply_for_circuit_error(&e)).await
+            }
+        }
+    }
+
+    /// Connect straight to the target and shuttle bytes, bypassing Tor entirely
+    async fn relay_direct(mut stream: TcpStream, request: &Socks5Request, metrics: Arc<Metrics>) -> Result<(), ProxyError> {
+        log::info!("Attempting to connect to {}", request.target());
+
+        let target = match tokio::time::timeout(
+            std::time::Duration::from_secs(10),
+            TcpStream::connect(request.target())
+        ).await {
+            Ok(Ok(target)) => target,
+            Ok(Err(e)) => {
+                log::error!("Failed to connect to target: {}", e);
+                Self::send_status(&mut stream, request, reply_for_io_error(&e)).await?;
+                return Ok(());
+            }
+            Err(_) => {
+                log::error!("Timeout connecting to target");
+                Self::send_status(&mut stream, request, REPLY_TTL_EXPIRED).await?;
+                return Ok(());
+            }
+        };
+
+        Self::send_status(&mut stream, request, REPLY_SUCCEEDED).await?;
+        log::info!("Connected to target, relaying traffic");
+        Self::relay_streams(stream, target, metrics).await
+    }
+
+    /// SOCKS5 BIND (RFC 1928 section 4), for protocols like active FTP where
+    /// the far end connects back. Only possible with direct_connect_insecure:
+    /// exits can't accept connections on a client's behalf, so over Tor it's
+    /// refused, as Tor refuses it. Listens on the address the client reached
+    /// us on, replies with it, then replies again with the peer that connects
+    /// (which must be DST.ADDR, if that's an address) and relays between them.
+    async fn handle_bind(
+        mut stream: TcpStream,
+        request: &Socks5Request,
+        direct_connect_insecure: bool,
+        metrics: Arc<Metrics>,
+    ) -> Result<(), ProxyError> {
+        if !direct_connect_insecure || request.onion.is_some() {
+            log::warn!("Refusing BIND for {}: incoming connections can't come over Tor", request.target());
+            return Self::send_response(&mut stream, REPLY_COMMAND_NOT_SUPPORTED).await;
+        }
+        log::warn!(
+            "⚠ INSECURE: accepting a direct connection for {} without Tor (direct_connect_insecure is set)",
+            request.target()
+        );
+
+        let listener = match TcpListener::bind((stream.local_addr()?.ip(), 0)).await {
+            Ok(listener) => listener,
             Err(e) => {
-                log::error!("Failed to create circuit: {:?}", e);
-                // Send failure response
-                Self::send_response(&mut stream, 0x01).await?;
+                log::error!("Failed to listen for BIND: {}", e);
+                return Self::send_response(&mut stream, REPLY_GENERAL_FAILURE).await;
+            }
+        };
+        Self::send_bound_reply(&mut stream, REPLY_SUCCEEDED, listener.local_addr()?).await?;
+
+        let (peer, peer_address) = match tokio::time::timeout(BIND_ACCEPT_TIMEOUT, listener.accept()).await {
+            Ok(Ok(accepted)) => accepted,
+            Ok(Err(e)) => {
+                log::error!("Failed to accept the BIND connection: {}", e);
+                return Self::send_response(&mut stream, reply_for_io_error(&e)).await;
+            }
+            Err(_) => {
+                log::error!("No connection for BIND within {:?}", BIND_ACCEPT_TIMEOUT);
+                return Self::send_response(&mut stream, REPLY_TTL_EXPIRED).await;
             }
+        };
+        drop(listener);
+        let expected = request.host.parse::<IpAddr>().ok().filter(|ip| !ip.is_unspecified());
+        if expected.is_some_and(|ip| ip != peer_address.ip()) {
+            log::warn!("BIND connection from {} instead of {}; refusing it", peer_address, request.host);
+            return Self::send_bound_reply(&mut stream, REPLY_NOT_ALLOWED, peer_address).await;
         }
 
+        Self::send_bound_reply(&mut stream, REPLY_SUCCEEDED, peer_address).await?;
+        log::info!("BIND connection from {}, relaying traffic", peer_address);
+        Self::relay_streams(stream, peer, metrics).await
+    }
+
+    /// Shuttle bytes between the client and `target` until both directions close
+    async fn relay_streams(stream: TcpStream, target: TcpStream, metrics: Arc<Metrics>) -> Result<(), ProxyError> {
+        let (mut client_read, mut client_write) = stream.into_split();
+        let (mut target_read, mut target_write) = target.into_split();
+
+        // Spawn task for client -> target
+        let sent = metrics.clone();
+        let client_to_target = tokio::spawn(async move {
+            let mut buf = [0u8; 8192];
+            let mut total_bytes = 0;
+            loop {
+                match client_read.read(&mut buf).await {
+                    Ok(0) => {
+                        log::debug!("Client closed connection (sent {} bytes)", total_bytes);
+                        break;
+                    }
+                    Ok(n) => {
+                        total_bytes += n;
+                        sent.bytes_sent.fetch_add(n as u64, std::sync::atomic::Ordering::Relaxed);
+                        if let Err(e) = target_write.write_all(&buf[..n]).await {
+                            log::error!("Error writing to target: {}", e);
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        log::error!("Error reading from client: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        // Spawn task for target -> client
+        let target_to_client = tokio::spawn(async move {
+            let mut buf = [0u8; 8192];
+            let mut total_bytes = 0;
+            loop {
+                match target_read.read(&mut buf).await {
+                    Ok(0) => {
+                        log::debug!("Target closed connection (received {} bytes)", total_bytes);
+                        break;
+                    }
+                    Ok(n) => {
+                        total_bytes += n;
+                        metrics.bytes_received.fetch_add(n as u64, std::sync::atomic::Ordering::Relaxed);
+                        if let Err(e) = client_write.write_all(&buf[..n]).await {
+                            log::error!("Error writing to client: {}", e);
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        log::error!("Error reading from target: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        // Wait for both tasks to complete
+        let _ = tokio::join!(client_to_target, target_to_client);
+        log::info!("Connection relay finished");
+
         Ok(())
     }
-    
-    async fn perform_handshake(stream: &mut TcpStream) -> Result<(), ProxyError> {
+
+    /// Negotiate the auth method. Returns the username/password if the client
+    /// chose RFC 1929 authentication; any credentials are accepted, they only
+    /// serve to isolate streams.
+    async fn perform_handshake(stream: &mut TcpStream) -> Result<Option<(Vec<u8>, Vec<u8>)>, ProxyError> {
         log::debug!("Reading SOCKS5 version and method count");
         
         // Read version and methods
@@ -210,13 +690,41 @@ impl Socks5Proxy {
         
         log::debug!("SOCKS5 handshake: version={}, methods={:?}", version, methods);
         
-        // Accept no authentication (0x00)
+        // Prefer username/password (0x02) so clients can ask for isolation,
+        // otherwise accept no authentication (0x00)
+        if methods.contains(&0x02) {
+            stream.write_all(&[0x05, 0x02]).await?;
+            stream.flush().await?;
+            return Ok(Some(Self::read_credentials(stream).await?));
+        }
+
         log::debug!("Sending handshake response");
         stream.write_all(&[0x05, 0x00]).await?;
         stream.flush().await?;
         log::debug!("Handshake response sent");
         
-        Ok(())
+        Ok(None)
+    }
+
+    /// RFC 1929: VER(1) | ULEN | UNAME | PLEN | PASSWD
+    async fn read_credentials(stream: &mut TcpStream) -> Result<(Vec<u8>, Vec<u8>), ProxyError> {
+        let mut header = [0u8; 2];
+        stream.read_exact(&mut header).await?;
+        if header[0] != 0x01 {
+            return Err(ProxyError::InvalidVersion(header[0]));
+        }
+        let mut username = vec![0u8; header[1] as usize];
+        stream.read_exact(&mut username).await?;
+
+        let mut plen = [0u8; 1];
+        stream.read_exact(&mut plen).await?;
+        let mut password = vec![0u8; plen[0] as usize];
+        stream.read_exact(&mut password).await?;
+
+        stream.write_all(&[0x01, 0x00]).await?;
+        stream.flush().await?;
+        log::debug!("SOCKS5 username/password received");
+        Ok((username, password))
     }
 
     async fn parse_request(stream: &mut TcpStream) -> Result<Socks5Request, ProxyError> {
@@ -237,8 +745,8 @@ impl Socks5Proxy {
             return Err(ProxyError::InvalidVersion(version));
         }
         
-        if cmd != 0x01 { // Only support CONNECT
-            return Err(ProxyError::Unsupported(format!("Command {} not supported", cmd)));
+        if ![COMMAND_CONNECT, COMMAND_BIND, COMMAND_RESOLVE].contains(&cmd) {
+            return Err(ProxyError::UnsupportedCommand(cmd));
         }
         
         // Read address
@@ -262,11 +770,9 @@ impl Socks5Proxy {
                 // IPv6
                 let mut addr = [0u8; 16];
                 stream.read_exact(&mut addr).await?;
-                format!("{:02x}{:02x}:{:02x}{:02x}:{:02x}{:02x}:{:02x}{:02x}:{:02x}{:02x}:{:02x}{:02x}:{:02x}{:02x}:{:02x}{:02x}",
-                    addr[0], addr[1], addr[2], addr[3], addr[4], addr[5], addr[6], addr[7],
-                    addr[8], addr[9], addr[10], addr[11], addr[12], addr[13], addr[14], addr[15])
+                std::net::Ipv6Addr::from(addr).to_string()
             }
-            _ => return Err(ProxyError::Unsupported(format!("Address type {} not supported", atyp))),
+            _ => return Err(ProxyError::UnsupportedAddressType(atyp)),
         };
         
         // Read port
@@ -274,24 +780,114 @@ impl Socks5Proxy {
         stream.read_exact(&mut port_buf).await?;
         let port = u16::from_be_bytes(port_buf);
         
+        let onion = Self::onion_address(&host)?;
         log::debug!("Parsed request: {}:{}", host, port);
         
-        Ok(Socks5Request { host, port })
+        Ok(Socks5Request { command: cmd, host, port, version: 0x05, onion })
+    }
+
+    /// The decoded address if `host` is a .onion name; an error if it's a
+    /// .onion name that doesn't decode, which must not go to a resolver either
+    fn onion_address(host: &str) -> Result<Option<OnionAddress>, ProxyError> {
+        if !hs::is_onion_host(host) {
+            return Ok(None);
+        }
+        OnionAddress::parse(host).map(Some).map_err(ProxyError::Onion)
+    }
+
+    /// SOCKS4: VN(1)=4 | CD(1) | DSTPORT(2) | DSTIP(4) | USERID | NUL, and for
+    /// SOCKS4a (DSTIP 0.0.0.x, x != 0) the hostname and a NUL after that.
+    /// Returns the request and the userid.
+    async fn parse_socks4_request(stream: &mut TcpStream) -> Result<(Socks5Request, Vec<u8>), ProxyError> {
+        let mut header = [0u8; 8];
+        stream.read_exact(&mut header).await?;
+        if header[0] != 0x04 {
+            return Err(ProxyError::InvalidVersion(header[0]));
+        }
+        let port = u16::from_be_bytes([header[2], header[3]]);
+        let ip = Ipv4Addr::new(header[4], header[5], header[6], header[7]);
+
+        // Read the userid (and hostname) even for a command we refuse, so the
+        // reply isn't written while the client is still sending
+        let userid = Self::read_nul_terminated(stream).await?;
+        let is_socks4a = header[4..7] == [0, 0, 0] && header[7] != 0;
+        let host = if is_socks4a {
+            String::from_utf8_lossy(&Self::read_nul_terminated(stream).await?).to_string()
+        } else {
+            ip.to_string()
+        };
+
+        if header[1] != COMMAND_CONNECT {
+            return Err(ProxyError::UnsupportedCommand(header[1]));
+        }
+        let onion = Self::onion_address(&host)?;
+        log::debug!("Parsed SOCKS4{} request: {}:{}", if is_socks4a { "a" } else { "" }, host, port);
+        Ok((Socks5Request { command: COMMAND_CONNECT, host, port, version: 0x04, onion }, userid))
+    }
+
+    /// Read up to (and drop) a NUL; SOCKS4 userids and hostnames are capped at 255 bytes
+    async fn read_nul_terminated(stream: &mut TcpStream) -> Result<Vec<u8>, ProxyError> {
+        let mut bytes = Vec::new();
+        loop {
+            let byte = stream.read_u8().await?;
+            if byte == 0 {
+                return Ok(bytes);
+            }
+            if bytes.len() == 255 {
+                return Err(ProxyError::Io(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "SOCKS4 field too long",
+                )));
+            }
+            bytes.push(byte);
+        }
+    }
+
+    /// Reply to `request` in its own protocol version; a SOCKS5 status maps to
+    /// SOCKS4's granted or rejected
+    async fn send_status(stream: &mut TcpStream, request: &Socks5Request, status: u8) -> Result<(), ProxyError> {
+        if request.version == 0x04 {
+            let status = if status == REPLY_SUCCEEDED { SOCKS4_REQUEST_GRANTED } else { SOCKS4_REQUEST_REJECTED };
+            return Self::send_socks4_reply(stream, status).await;
+        }
+        Self::send_response(stream, status).await
+    }
+
+    /// VN(1)=0 | CD(1) | DSTPORT(2) | DSTIP(4); clients ignore the address
+    async fn send_socks4_reply(stream: &mut TcpStream, status: u8) -> Result<(), ProxyError> {
+        stream.write_all(&[0x00, status, 0, 0, 0, 0, 0, 0]).await?;
+        stream.flush().await?;
+        Ok(())
     }
 
     async fn send_response(stream: &mut TcpStream, status: u8) -> Result<(), ProxyError> {
+        Self::send_reply(stream, status, IpAddr::V4(Ipv4Addr::UNSPECIFIED)).await
+    }
+
+    /// Reply with `address` in BND.ADDR (the answer to a RESOLVE)
+    async fn send_reply(stream: &mut TcpStream, status: u8, address: IpAddr) -> Result<(), ProxyError> {
+        Self::send_bound_reply(stream, status, std::net::SocketAddr::new(address, 0)).await
+    }
+
+    /// Reply with `address` in BND.ADDR and BND.PORT (a BIND's listening
+    /// address, then its peer's)
+    async fn send_bound_reply(stream: &mut TcpStream, status: u8, address: std::net::SocketAddr) -> Result<(), ProxyError> {
         log::debug!("Sending SOCKS5 response with status {}", status);
         
         // Send SOCKS5 response
         // VER | REP | RSV | ATYP | BND.ADDR | BND.PORT
-        let response = [
-            0x05,  // Version
-            status, // Status (0x00 = success)
-            0x00,  // Reserved
-            0x01,  // Address type (IPv4)
-            0, 0, 0, 0,  // Bind address (0.0.0.0)
-            0, 0,  // Bind port (0)
-        ];
+        let mut response = vec![0x05, status, 0x00];
+        match address.ip() {
+            IpAddr::V4(ip) => {
+                response.push(0x01);
+                response.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                response.push(0x04);
+                response.extend_from_slice(&ip.octets());
+            }
+        }
+        response.extend_from_slice(&address.port().to_be_bytes());
         stream.write_all(&response).await?;
         stream.flush().await?;
         log::debug!("SOCKS5 response sent");
@@ -302,6 +898,24 @@ impl Socks5Proxy {
 
 #[derive(Debug)]
 pub struct Socks5Request {
+    /// `COMMAND_CONNECT`, `COMMAND_BIND` or `COMMAND_RESOLVE`
+    pub command: u8,
     pub host: String,
     pub port: u16,
+    /// 0x05, or 0x04 for a SOCKS4/4a client, which gets SOCKS4 replies
+    pub version: u8,
+    /// Set when `host` is a v3 onion address
+    pub onion: Option<OnionAddress>,
+}
+
+impl Socks5Request {
+    /// "host:port", with an IPv6 host in brackets so the port can't be
+    /// mistaken for part of the address
+    pub fn target(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
 }
\ No newline at end of file
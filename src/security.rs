@@ -1,8 +1,10 @@
 
 // src/security.rs
 
-// Always use constant-time comparisons
-fn constant_time_compare(a: &[u8], b: &[u8]) -> bool {
+/// Compare secret-derived bytes (MACs, handshake AUTH) without an early exit
+/// that would leak how many leading bytes matched. Inputs of different
+/// lengths never compare equal.
+pub fn constant_time_compare(a: &[u8], b: &[u8]) -> bool {
     if a.len() != b.len() {
         return false;
     }
@@ -18,17 +20,36 @@ fn constant_time_compare(a: &[u8], b: &[u8]) -> bool {
 // Secure memory zeroization
 use zeroize::Zeroize;
 
-struct SecretData {
-    key: [u8; 32],
+/// Raw key bytes that are wiped when dropped. Keep keys in one of these
+/// rather than a bare array so they don't linger in freed memory.
+#[derive(Clone)]
+pub(crate) struct SecretData<const N: usize> {
+    key: [u8; N],
 }
 
-impl Zeroize for SecretData {
+impl<const N: usize> SecretData<N> {
+    pub(crate) fn new(key: [u8; N]) -> Self {
+        Self { key }
+    }
+
+    pub(crate) fn expose(&self) -> &[u8; N] {
+        &self.key
+    }
+}
+
+impl<const N: usize> std::fmt::Debug for SecretData<N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SecretData(..)")
+    }
+}
+
+impl<const N: usize> Zeroize for SecretData<N> {
     fn zeroize(&mut self) {
         self.key.zeroize();
     }
 }
 
-impl Drop for SecretData {
+impl<const N: usize> Drop for SecretData<N> {
     fn drop(&mut self) {
         self.zeroize();
     }
@@ -0,0 +1,51 @@
+// tests/common/mod.rs
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+use tor_client::directory::{NetworkConsensus, RelayDescriptor, RelayFlag};
+
+pub fn relay(nickname: &str, address: &str, flags: Vec<RelayFlag>, bandwidth: u32) -> RelayDescriptor {
+    RelayDescriptor {
+        id: format!("test-{}", nickname),
+        nickname: nickname.to_string(),
+        address: address.parse().unwrap(),
+        identity_key: vec![0u8; 20],
+        onion_key: vec![0u8; 32],
+        ed25519_identity: None,
+        bandwidth,
+        flags,
+        platform: None,
+        microdesc_digest: None,
+        exit_policy: None,
+        exit_policy_v6: None,
+        ipv6_address: None,
+        protocols: None,
+        family: Vec::new(),
+    }
+}
+
+pub fn consensus(relays: Vec<RelayDescriptor>) -> NetworkConsensus {
+    let now = SystemTime::now();
+    NetworkConsensus {
+        valid_after: now,
+        fresh_until: now + Duration::from_secs(3600),
+        valid_until: now + Duration::from_secs(3 * 3600),
+        relays: relays.into_iter().map(|r| (r.id.clone(), r)).collect::<HashMap<_, _>>(),
+        signatures: vec![],
+        flag_thresholds: None,
+        bandwidth_weights: HashMap::new(),
+    }
+}
+
+pub fn guard_flags() -> Vec<RelayFlag> {
+    vec![RelayFlag::Guard, RelayFlag::Fast, RelayFlag::Running, RelayFlag::Valid]
+}
+
+pub fn middle_flags() -> Vec<RelayFlag> {
+    vec![RelayFlag::Fast, RelayFlag::Stable, RelayFlag::Running, RelayFlag::Valid]
+}
+
+pub fn exit_flags() -> Vec<RelayFlag> {
+    vec![RelayFlag::Exit, RelayFlag::Fast, RelayFlag::Running, RelayFlag::Valid]
+}
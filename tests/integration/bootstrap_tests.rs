@@ -0,0 +1,79 @@
+// tests/integration/bootstrap_tests.rs
+#[path = "../common/mod.rs"]
+mod common;
+
+use common::{consensus, exit_flags, guard_flags, middle_flags};
+use std::sync::{Arc, Mutex};
+use tor_client::bootstrap::Bootstrap;
+use tor_client::network::mock_relay::MockRelay;
+use tor_client::{CircuitManager, DirectoryClient};
+
+/// Keeps every bootstrap line logged by this test binary
+struct BootstrapLog(Mutex<Vec<String>>);
+
+impl log::Log for BootstrapLog {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        let line = record.args().to_string();
+        if line.starts_with("NOTICE BOOTSTRAP") {
+            self.0.lock().unwrap().push(line);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOG: BootstrapLog = BootstrapLog(Mutex::new(Vec::new()));
+
+fn progress(line: &str) -> (u8, &str) {
+    let field = |name: &str| line.split(' ').find_map(|f| f.strip_prefix(name)).unwrap();
+    (field("PROGRESS=").parse().unwrap(), field("TAG="))
+}
+
+#[tokio::test]
+async fn test_bootstrap_progress_logged_in_order() {
+    log::set_logger(&LOG).unwrap();
+    log::set_max_level(log::LevelFilter::Info);
+
+    let guard = MockRelay::spawn().await.unwrap();
+    let middle = MockRelay::spawn().await.unwrap();
+    let exit = MockRelay::spawn().await.unwrap();
+    let bootstrap = Arc::new(Bootstrap::new());
+
+    // The directory phases come from fetching a consensus...
+    let fetcher = DirectoryClient::new_mock().with_bootstrap(bootstrap.clone());
+    fetcher.fetch_consensus().await.unwrap();
+    // ...and the last ones from building a circuit through relays we can reach
+    let directory = DirectoryClient::from_consensus(consensus(vec![
+        guard.descriptor("Guard", guard_flags(), 1000),
+        middle.descriptor("Middle", middle_flags(), 1000),
+        exit.descriptor("Exit", exit_flags(), 1000),
+    ]))
+    .with_bootstrap(bootstrap.clone());
+    let manager = CircuitManager::new().with_bootstrap(bootstrap.clone());
+    manager.create_circuit(3, false, &directory).await.unwrap();
+    // Later builds aren't progress
+    manager.create_circuit(3, false, &directory).await.unwrap();
+
+    let lines = LOG.0.lock().unwrap().clone();
+    let phases: Vec<(u8, &str)> = lines.iter().map(|line| progress(line)).collect();
+    assert_eq!(
+        phases,
+        vec![
+            (5, "conn"),
+            (10, "handshake"),
+            (15, "onehop_create"),
+            (25, "requesting_status"),
+            (40, "loading_status"),
+            (45, "loading_keys"),
+            (50, "requesting_descriptors"),
+            (90, "circuit_create"),
+            (100, "done"),
+        ]
+    );
+    assert!(lines[8].ends_with("SUMMARY=\"Done\""));
+    assert!(bootstrap.is_done());
+}
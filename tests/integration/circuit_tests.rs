@@ -0,0 +1,1180 @@
+// tests/integration/circuit_tests.rs
+#[path = "../common/mod.rs"]
+mod common;
+
+use common::{consensus, exit_flags, guard_flags, middle_flags, relay};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpListener;
+use tor_client::directory::bridge::parse_bridge_line;
+use tor_client::directory::policy::{ExitPolicySummary, ExitTarget};
+use tor_client::http::{response_body, HttpUrl};
+use tor_client::metrics::Metrics;
+use tor_client::network::mock_relay::MockRelay;
+use tor_client::circuit::MAX_RELAY_EARLY_CELLS;
+use tor_client::network::cells::{
+    RELAY_COMMAND_BEGIN, RELAY_COMMAND_CONNECTED, RELAY_COMMAND_DATA, RELAY_COMMAND_DROP, RELAY_COMMAND_EXTEND2,
+    RELAY_PAYLOAD_LEN,
+};
+use tor_client::network::{tls, Channel, ClientHello, LinkError, SniStrategy, TlsBackend};
+use tor_client::circuit::build_timeout::{BuildTimes, DEFAULT_BUILD_TIMEOUT, MIN_BUILD_SAMPLES};
+use tor_client::circuit::sendme::{CIRCUIT_WINDOW_INCREMENT, STREAM_WINDOW_START};
+use tor_client::circuit::CircuitState;
+use tor_client::{CircuitError, CircuitEvent, CircuitFailureKind, CircuitManager, DirectoryClient, HopState, IsolationKey};
+
+struct MockNetwork {
+    guard: MockRelay,
+    middle: MockRelay,
+    exit: MockRelay,
+    directory: DirectoryClient,
+}
+
+async fn mock_network() -> MockNetwork {
+    let guard = MockRelay::spawn().await.unwrap();
+    let middle = MockRelay::spawn().await.unwrap();
+    let exit = MockRelay::spawn().await.unwrap();
+    let directory = DirectoryClient::from_consensus(consensus(vec![
+        guard.descriptor("Guard", guard_flags(), 1000),
+        middle.descriptor("Middle", middle_flags(), 1000),
+        exit.descriptor("Exit", exit_flags(), 1000),
+    ]));
+    MockNetwork { guard, middle, exit, directory }
+}
+
+#[tokio::test]
+async fn test_circuit_handshakes_with_mock_relays() {
+    let net = mock_network().await;
+    let manager = CircuitManager::new();
+
+    manager.create_circuit(3, false, &net.directory).await.unwrap();
+
+    assert_eq!(net.guard.handshakes(), 1);
+    assert_eq!(net.middle.handshakes(), 1);
+    assert_eq!(net.exit.handshakes(), 1);
+}
+
+#[tokio::test]
+async fn test_circuit_ids_set_the_client_bit() {
+    let net = mock_network().await;
+    let manager = CircuitManager::new();
+
+    let first = manager.create_circuit(3, false, &net.directory).await.unwrap();
+    let second = manager.create_circuit(3, false, &net.directory).await.unwrap();
+    assert_eq!(first, 0x8000_0001);
+    assert_eq!(second, 0x8000_0002);
+    assert_eq!(net.exit.handshakes(), 2);
+}
+
+#[tokio::test]
+async fn test_metrics_count_ready_circuits() {
+    let net = mock_network().await;
+    let metrics = std::sync::Arc::new(Metrics::new());
+    let manager = CircuitManager::new().with_metrics(metrics.clone());
+
+    let first = manager.create_circuit(3, false, &net.directory).await.unwrap();
+    manager.create_circuit(3, false, &net.directory).await.unwrap();
+    assert_eq!(metrics.circuits_created.load(Ordering::Relaxed), 2);
+    assert_eq!(metrics.active_circuits.load(Ordering::Relaxed), 2);
+
+    assert!(manager.close_circuit(first).await);
+    assert!(!manager.close_circuit(first).await);
+    assert_eq!(metrics.circuits_created.load(Ordering::Relaxed), 2);
+    assert_eq!(metrics.active_circuits.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn test_hops_come_from_distinct_subnets() {
+    let guard = MockRelay::spawn_on("127.0.0.1").await.unwrap();
+    let neighbour = MockRelay::spawn_on("127.0.0.2").await.unwrap();
+    let middle = MockRelay::spawn_on("127.1.0.1").await.unwrap();
+    let exit = MockRelay::spawn_on("127.2.0.1").await.unwrap();
+    // The guard's /16 neighbour would win nearly every middle pick on bandwidth
+    let directory = DirectoryClient::from_consensus(consensus(vec![
+        guard.descriptor("Guard", guard_flags(), 1000),
+        neighbour.descriptor("Neighbour", middle_flags(), 1_000_000),
+        middle.descriptor("Middle", middle_flags(), 1),
+        exit.descriptor("Exit", exit_flags(), 1000),
+    ]))
+    .with_distinct_subnets(true);
+    let manager = CircuitManager::new();
+
+    for _ in 0..5 {
+        manager.create_circuit(3, false, &directory).await.unwrap();
+    }
+    assert_eq!(neighbour.handshakes(), 0);
+    assert_eq!(middle.handshakes(), 5);
+}
+
+#[tokio::test]
+async fn test_guard_connection_circuit_cap() {
+    let net = mock_network().await;
+    let manager = CircuitManager::new().with_max_circuits_per_guard(Some(2));
+
+    manager.create_circuit(3, false, &net.directory).await.unwrap();
+    manager.create_circuit(3, false, &net.directory).await.unwrap();
+    assert_eq!(net.guard.connections(), 1, "circuits under the cap share one guard connection");
+
+    manager.create_circuit(3, false, &net.directory).await.unwrap();
+    assert_eq!(net.guard.connections(), 2, "the third circuit must use a new guard connection");
+    // The cap only applies to guards
+    assert_eq!(net.middle.connections(), 1);
+}
+
+/// A "relay" that accepts one connection but never answers the CREATE2.
+/// The receiver fires once the client closes the connection.
+async fn silent_relay() -> (String, tokio::sync::oneshot::Receiver<bool>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let (closed_tx, closed_rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut sink = Vec::new();
+        let _ = closed_tx.send(stream.read_to_end(&mut sink).await.is_ok());
+    });
+    (address, closed_rx)
+}
+
+#[tokio::test]
+async fn test_dropped_build_releases_circuit_and_connection() {
+    let (address, closed_rx) = silent_relay().await;
+    let directory = DirectoryClient::from_consensus(consensus(vec![relay("Silent", &address, guard_flags(), 1000)]));
+    let manager = CircuitManager::new();
+
+    let build = tokio::time::timeout(Duration::from_millis(200), manager.create_circuit(1, false, &directory)).await;
+    assert!(build.is_err(), "the build should still be waiting on the handshake");
+
+    assert_eq!(manager.circuit_count().await, 0);
+    let closed = tokio::time::timeout(Duration::from_secs(5), closed_rx).await;
+    assert!(matches!(closed, Ok(Ok(true))), "the relay connection should be closed");
+}
+
+#[tokio::test]
+async fn test_slow_guard_connection_does_not_hold_up_other_builds() {
+    let net = mock_network().await;
+    let (address, _closed_rx) = silent_relay().await;
+    let silent = DirectoryClient::from_consensus(consensus(vec![relay("Silent", &address, guard_flags(), 1000)]));
+    let manager = CircuitManager::new().with_handshake_timeout(Duration::from_secs(30));
+
+    let stuck = manager.create_circuit(1, false, &silent);
+    tokio::pin!(stuck);
+    let quick = tokio::time::timeout(Duration::from_secs(5), manager.create_circuit(3, false, &net.directory));
+    tokio::select! {
+        biased;
+        _ = &mut stuck => panic!("the silent guard should never finish its handshake"),
+        built = quick => assert!(matches!(built, Ok(Ok(_))), "got {:?}", built),
+    }
+}
+
+#[tokio::test]
+async fn test_estimate_throughput_is_bottleneck_bandwidth() {
+    let guard = MockRelay::spawn().await.unwrap();
+    let middle = MockRelay::spawn().await.unwrap();
+    let exit = MockRelay::spawn().await.unwrap();
+    let directory = DirectoryClient::from_consensus(consensus(vec![
+        guard.descriptor("Guard", guard_flags(), 5000),
+        middle.descriptor("Middle", middle_flags(), 800),
+        exit.descriptor("Exit", exit_flags(), 3000),
+    ]));
+    let manager = CircuitManager::new();
+
+    let circuit_id = manager.create_circuit(3, false, &directory).await.unwrap();
+
+    assert_eq!(manager.estimate_throughput(circuit_id).await, Some(800));
+    assert_eq!(manager.estimate_throughput(circuit_id + 1).await, None);
+}
+
+#[tokio::test]
+async fn test_handshake_times_out_on_silent_relay() {
+    let (address, closed_rx) = silent_relay().await;
+    let directory = DirectoryClient::from_consensus(consensus(vec![relay("Silent", &address, guard_flags(), 1000)]));
+    let manager = CircuitManager::new().with_handshake_timeout(Duration::from_millis(200));
+
+    let result = tokio::time::timeout(Duration::from_secs(5), manager.create_circuit(1, false, &directory))
+        .await
+        .expect("the handshake timeout should end the build");
+
+    assert!(matches!(result, Err(CircuitError::HandshakeFailed(ref reason)) if reason == "timeout"));
+    assert_eq!(manager.circuit_count().await, 0);
+    let closed = tokio::time::timeout(Duration::from_secs(5), closed_rx).await;
+    assert!(matches!(closed, Ok(Ok(true))));
+}
+
+#[tokio::test]
+async fn test_build_failures_counted_by_kind() {
+    let net = mock_network().await;
+    net.middle.corrupt_auth();
+    let metrics = std::sync::Arc::new(Metrics::new());
+    let manager = CircuitManager::new().with_metrics(metrics.clone());
+
+    let result = manager.create_circuit(3, false, &net.directory).await;
+    assert!(matches!(result, Err(CircuitError::Crypto(_))), "got {:?}", result);
+    assert_eq!(metrics.circuit_failures(CircuitFailureKind::HandshakeAuth), 1);
+
+    // A relay that never finishes connecting
+    let (address, _closed_rx) = silent_relay().await;
+    let directory = DirectoryClient::from_consensus(consensus(vec![relay("Silent", &address, guard_flags(), 1000)]));
+    let manager = CircuitManager::new()
+        .with_metrics(metrics.clone())
+        .with_handshake_timeout(Duration::from_millis(200));
+    assert!(manager.create_circuit(1, false, &directory).await.is_err());
+    assert_eq!(metrics.circuit_failures(CircuitFailureKind::Connectivity), 1);
+
+    let empty = DirectoryClient::from_consensus(consensus(vec![]));
+    assert!(manager.create_circuit(1, false, &empty).await.is_err());
+    assert_eq!(metrics.circuit_failures(CircuitFailureKind::Directory), 1);
+    assert_eq!(metrics.circuit_failures(CircuitFailureKind::Handshake), 0);
+    assert_eq!(metrics.to_json()["circuit_failures"]["handshake_auth"], 1);
+}
+
+#[tokio::test]
+async fn test_failed_build_keeps_its_error_until_reaped() {
+    let net = mock_network().await;
+    net.middle.corrupt_auth();
+    let manager = CircuitManager::new();
+
+    assert!(manager.create_circuit(3, false, &net.directory).await.is_err());
+    let infos = manager.circuit_infos().await;
+    assert_eq!(infos.len(), 1);
+    let circuit_id = infos[0].id;
+    let info = manager.get_circuit_info(circuit_id).await.expect("the failed circuit should still be listed");
+    assert!(
+        matches!(info.state, CircuitState::Error(CircuitFailureKind::HandshakeAuth, _)),
+        "got {:?}",
+        info.state
+    );
+    assert_eq!(manager.circuit_count().await, 0, "a failed circuit is neither building nor built");
+    assert!(matches!(manager.open_stream(circuit_id).await, Err(CircuitError::NotReady(_))));
+
+    assert_eq!(manager.reap_expired(Duration::from_secs(600), Duration::from_secs(60)).await, 1);
+    assert!(manager.get_circuit_info(circuit_id).await.is_none());
+}
+
+#[tokio::test]
+async fn test_failed_hop_is_retried_with_another_relay() {
+    let net = mock_network().await;
+    net.middle.corrupt_auth();
+    let good = MockRelay::spawn().await.unwrap();
+    let with_good_middle = || {
+        DirectoryClient::from_consensus(consensus(vec![
+            net.guard.descriptor("Guard", guard_flags(), 1000),
+            // Nearly always picked first
+            net.middle.descriptor("BadMiddle", middle_flags(), 1_000_000),
+            good.descriptor("GoodMiddle", middle_flags(), 1),
+            net.exit.descriptor("Exit", exit_flags(), 1000),
+        ]))
+    };
+
+    let manager = CircuitManager::new().with_hop_retries(0);
+    let result = manager.create_circuit(3, false, &with_good_middle()).await;
+    assert!(matches!(result, Err(CircuitError::Crypto(_))), "got {:?}", result);
+    assert_eq!(good.handshakes(), 0);
+
+    let manager = CircuitManager::new().with_hop_retries(1);
+    manager.create_circuit(3, false, &with_good_middle()).await.unwrap();
+    assert_eq!(net.middle.handshakes(), 2);
+    assert_eq!(good.handshakes(), 1);
+}
+
+#[tokio::test]
+async fn test_circuit_reports_the_consensus_it_was_built_from() {
+    let net = mock_network().await;
+    let valid_after = net.directory.current_consensus().await.unwrap().valid_after;
+    let manager = CircuitManager::new();
+    let circuit_id = manager.create_circuit(3, false, &net.directory).await.unwrap();
+
+    let info = manager.get_circuit_info(circuit_id).await.unwrap();
+    assert_eq!(info.consensus_valid_after, valid_after);
+    assert_eq!(manager.circuit_infos().await[0].consensus_valid_after, valid_after);
+}
+
+#[tokio::test]
+async fn test_subscribers_see_a_circuit_extend_build_and_close() {
+    let net = mock_network().await;
+    let manager = CircuitManager::new();
+    let mut events = manager.subscribe();
+    let circuit_id = manager.create_circuit(3, false, &net.directory).await.unwrap();
+    manager.close_circuit(circuit_id).await;
+
+    let names = |relays: &[&str]| relays.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+    let expected = [
+        CircuitEvent::Extended { circuit_id, relays: names(&["Guard"]) },
+        CircuitEvent::Extended { circuit_id, relays: names(&["Guard", "Middle"]) },
+        CircuitEvent::Extended { circuit_id, relays: names(&["Guard", "Middle", "Exit"]) },
+        CircuitEvent::Built { circuit_id, relays: names(&["Guard", "Middle", "Exit"]) },
+        CircuitEvent::Closed { circuit_id, relays: names(&["Guard", "Middle", "Exit"]) },
+    ];
+    for event in expected {
+        assert_eq!(events.try_recv().unwrap(), event);
+    }
+    assert!(events.try_recv().is_err());
+
+    // A guard nobody is listening for
+    let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().to_string();
+    let dead = DirectoryClient::from_consensus(consensus(vec![relay("Dead", &closed, guard_flags(), 1000)]));
+    let manager = CircuitManager::new().with_hop_retries(0);
+    let mut events = manager.subscribe();
+    assert!(manager.create_circuit(1, false, &dead).await.is_err());
+    match events.try_recv().unwrap() {
+        CircuitEvent::Failed { relays, kind, .. } => {
+            assert_eq!(relays, ["Dead"]);
+            assert_eq!(kind, CircuitFailureKind::Connectivity);
+        }
+        other => panic!("expected Failed, got {:?}", other),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_concurrent_builds_get_unique_circuit_ids() {
+    let net = Arc::new(mock_network().await);
+    let manager = Arc::new(CircuitManager::new());
+    let builds: Vec<_> = (0..100)
+        .map(|_| {
+            let (manager, net) = (manager.clone(), net.clone());
+            tokio::spawn(async move { manager.create_circuit(3, false, &net.directory).await })
+        })
+        .collect();
+
+    let mut ids = std::collections::HashSet::new();
+    for build in builds {
+        let circuit_id = build.await.unwrap().unwrap();
+        assert!(circuit_id & 0x8000_0000 != 0, "{:#x} lacks the client bit", circuit_id);
+        assert!(ids.insert(circuit_id), "{:#x} handed out twice", circuit_id);
+    }
+    assert_eq!(ids.len(), 100);
+    assert_eq!(manager.circuit_count().await, 100);
+}
+
+#[tokio::test]
+async fn test_circuit_info_shows_where_the_build_is_stuck() {
+    let net = mock_network().await;
+    let (silent, _closed) = silent_relay().await;
+    let directory = DirectoryClient::from_consensus(consensus(vec![
+        net.guard.descriptor("Guard", guard_flags(), 1000),
+        net.middle.descriptor("Middle", middle_flags(), 1000),
+        relay("Silent", &silent, exit_flags(), 1000),
+    ]));
+    let manager = CircuitManager::new().with_hop_retries(0);
+    // The first id a new manager hands out
+    let circuit_id = 0x8000_0001;
+
+    let build = tokio::time::timeout(Duration::from_secs(2), manager.create_circuit(3, false, &directory));
+    let watch = async {
+        loop {
+            if let Some(info) = manager.get_circuit_info(circuit_id).await {
+                if info.hops[1].state == HopState::KeysEstablished {
+                    return info;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    let (_, info) = tokio::join!(build, watch);
+
+    assert!(matches!(info.state, CircuitState::Building));
+    let hops: Vec<_> = info.hops.iter().map(|hop| (hop.nickname.as_str(), hop.state)).collect();
+    assert_eq!(
+        hops,
+        [("Guard", HopState::KeysEstablished), ("Middle", HopState::KeysEstablished), ("Silent", HopState::Pending)]
+    );
+}
+
+#[tokio::test]
+async fn test_build_abandoned_after_build_timeout() {
+    let net = mock_network().await;
+    let (silent, _closed) = silent_relay().await;
+    let directory = DirectoryClient::from_consensus(consensus(vec![
+        net.guard.descriptor("Guard", guard_flags(), 1000),
+        net.middle.descriptor("Middle", middle_flags(), 1000),
+        relay("Silent", &silent, exit_flags(), 1000),
+    ]));
+    let metrics = Arc::new(Metrics::new());
+    let manager = CircuitManager::new()
+        .with_handshake_timeout(Duration::from_secs(30))
+        .with_build_timeout(Duration::from_millis(300))
+        .with_metrics(metrics.clone());
+    assert_eq!(metrics.to_json()["circuit_build_timeout_ms"], 300);
+
+    let build = tokio::time::timeout(Duration::from_secs(5), manager.create_circuit(3, false, &directory)).await;
+    let result = build.expect("the build timeout should end the build long before the handshake timeout");
+    assert!(matches!(result, Err(CircuitError::BuildTimeout(_))), "got {:?}", result);
+    assert_eq!(metrics.circuit_failures(CircuitFailureKind::Timeout), 1);
+    assert_eq!(manager.circuit_count().await, 0);
+}
+
+#[test]
+fn test_build_timeout_adapts_to_build_times() {
+    let times = BuildTimes::default();
+    for i in 0..MIN_BUILD_SAMPLES - 1 {
+        times.record_build(Duration::from_millis(2000 + 20 * i as u64));
+    }
+    assert_eq!(times.timeout(), DEFAULT_BUILD_TIMEOUT, "too few builds to adapt yet");
+
+    let adapted = times.record_build(Duration::from_millis(4000));
+    assert!(adapted > Duration::from_secs(3) && adapted < Duration::from_secs(6), "got {:?}", adapted);
+
+    // Abandoned builds took at least as long, so they push the timeout up
+    for _ in 0..20 {
+        times.record_timeout(adapted);
+    }
+    assert!(times.timeout() > adapted, "got {:?}", times.timeout());
+}
+
+#[tokio::test]
+async fn test_internal_circuit_may_end_on_a_non_exit() {
+    let net = mock_network().await;
+    let last = MockRelay::spawn().await.unwrap();
+    let directory = DirectoryClient::from_consensus(consensus(vec![
+        net.guard.descriptor("Guard", guard_flags(), 1000),
+        net.middle.descriptor("Middle", middle_flags(), 1000),
+        last.descriptor("Last", middle_flags(), 1000),
+    ]));
+    let manager = CircuitManager::new();
+
+    let result = manager.create_circuit(3, false, &directory).await;
+    assert!(matches!(result, Err(CircuitError::Directory(_))), "got {:?}", result);
+
+    let circuit_id = manager.create_circuit(3, true, &directory).await.unwrap();
+    let info = manager.get_circuit_info(circuit_id).await.unwrap();
+    let mut nicknames: Vec<_> = info.hops.iter().map(|hop| hop.nickname.as_str()).collect();
+    nicknames[1..].sort();
+    assert_eq!(nicknames, ["Guard", "Last", "Middle"]);
+    // Its last hop isn't an exit, so it's never handed out for exit traffic
+    assert_eq!(manager.circuit_for_target(3, None, &directory).await.ok(), None);
+}
+
+#[tokio::test]
+async fn test_isolation_key_reuses_circuits() {
+    let net = mock_network().await;
+    let manager = CircuitManager::new();
+    let alice = IsolationKey::Credentials { username: b"alice".to_vec(), password: b"x".to_vec() };
+    let bob = IsolationKey::Credentials { username: b"bob".to_vec(), password: b"x".to_vec() };
+
+    let first = manager.get_or_create_circuit(&alice, None, 3, &net.directory).await.unwrap();
+    let again = manager.get_or_create_circuit(&alice, None, 3, &net.directory).await.unwrap();
+    assert_eq!(first, again, "the same key should reuse its circuit");
+    assert_eq!(net.exit.handshakes(), 1);
+
+    let other = manager.get_or_create_circuit(&bob, None, 3, &net.directory).await.unwrap();
+    assert_ne!(first, other, "a different key must get its own circuit");
+    assert_eq!(net.exit.handshakes(), 2);
+}
+
+#[tokio::test]
+async fn test_new_identity_retires_circuits() {
+    let net = mock_network().await;
+    let manager = CircuitManager::new();
+    let key = IsolationKey::ClientPort(1234);
+    let busy = manager.get_or_create_circuit(&key, None, 3, &net.directory).await.unwrap();
+    let idle = manager.create_circuit(3, false, &net.directory).await.unwrap();
+    let stream = manager.open_stream(busy).await.unwrap();
+
+    manager.new_identity().await;
+    assert_eq!(manager.circuit_count().await, 1, "the idle circuit should close right away");
+    assert!(manager.get_circuit_info(idle).await.is_none());
+    assert!(matches!(manager.open_stream(busy).await, Err(CircuitError::NotReady(_))));
+    assert!(matches!(manager.resolve(busy, "example.test").await, Err(CircuitError::NotReady(_))));
+
+    let fresh = manager.get_or_create_circuit(&key, None, 3, &net.directory).await.unwrap();
+    assert_ne!(fresh, busy, "the isolation key should get a new circuit");
+
+    // The retired circuit lasts as long as its stream
+    assert_eq!(manager.reap_expired(Duration::from_secs(600), Duration::from_secs(60)).await, 0);
+    drop(stream);
+    assert_eq!(manager.reap_expired(Duration::from_secs(600), Duration::from_secs(60)).await, 1);
+    assert!(manager.get_circuit_info(busy).await.is_none());
+}
+
+#[tokio::test]
+async fn test_resolve_through_exit() {
+    let net = mock_network().await;
+    net.exit.add_host("example.test", "10.1.2.3".parse().unwrap());
+    let manager = CircuitManager::new();
+    let circuit_id = manager.create_circuit(3, false, &net.directory).await.unwrap();
+
+    let addresses = manager.resolve(circuit_id, "example.test").await.unwrap();
+    assert_eq!(addresses, vec!["10.1.2.3".parse::<std::net::IpAddr>().unwrap()]);
+    assert_eq!(manager.circuit_request_count(circuit_id).await, Some(1), "a lookup counts as a request");
+
+    let missing = manager.resolve(circuit_id, "missing.test").await;
+    assert!(matches!(missing, Err(CircuitError::ResolveFailed(_))));
+    assert!(matches!(manager.resolve(circuit_id + 1, "example.test").await, Err(CircuitError::NotReady(_))));
+}
+
+#[tokio::test]
+async fn test_close_circuit_stops_its_tasks() {
+    let net = mock_network().await;
+    let manager = CircuitManager::new().with_keepalive_interval(Duration::from_millis(50));
+    let circuit_id = manager.create_circuit(3, false, &net.directory).await.unwrap();
+
+    let tasks = manager.circuit_tasks(circuit_id).await.unwrap();
+    assert_eq!(tasks.len(), 2, "the demux and keepalive tasks should be running");
+    tokio::time::sleep(Duration::from_millis(120)).await;
+    assert_eq!(tasks.len(), 2, "keepalives must not end the circuit's tasks");
+
+    assert!(manager.close_circuit(circuit_id).await);
+    tokio::time::timeout(Duration::from_secs(1), tasks.wait())
+        .await
+        .expect("closing the circuit should stop its tasks");
+    assert_eq!(manager.circuit_count().await, 0);
+    assert!(!manager.close_circuit(circuit_id).await);
+}
+
+#[tokio::test]
+async fn test_bridge_mode_enters_through_bridge() {
+    let net = mock_network().await;
+    let bridge = MockRelay::spawn().await.unwrap();
+    let unlisted = bridge.descriptor("Unlisted", vec![], 0);
+    let base64 = |bytes: &[u8]| base64::Engine::encode(&base64::engine::general_purpose::STANDARD, bytes);
+    let line = format!(
+        "Bridge {} {} {} {}",
+        bridge.address(),
+        hex::encode(&unlisted.identity_key),
+        base64(&unlisted.onion_key),
+        base64(&bridge.ed25519_identity())
+    );
+    let directory = net.directory.with_bridges(vec![parse_bridge_line(&line).unwrap()]);
+    assert!(directory.is_bridge_mode());
+    let manager = CircuitManager::new();
+
+    for _ in 0..3 {
+        manager.create_circuit(3, false, &directory).await.unwrap();
+    }
+
+    assert_eq!(bridge.handshakes(), 3, "every circuit should start at the bridge");
+    assert_eq!(net.guard.handshakes(), 0, "consensus guards aren't used in bridge mode");
+    assert_eq!(net.exit.handshakes(), 3);
+    assert!(parse_bridge_line("Bridge 127.0.0.1:9001 ABCD").is_err());
+}
+
+#[tokio::test]
+async fn test_reaper_closes_old_and_stuck_circuits() {
+    let net = mock_network().await;
+    let manager = std::sync::Arc::new(CircuitManager::new());
+    manager.create_circuit(3, false, &net.directory).await.unwrap();
+
+    assert_eq!(manager.reap_expired(Duration::from_secs(600), Duration::from_secs(60)).await, 0);
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert_eq!(manager.reap_expired(Duration::from_millis(10), Duration::from_secs(60)).await, 1);
+    assert_eq!(manager.circuit_count().await, 0);
+
+    // A build stuck on a relay that never answers
+    let (address, _closed_rx) = silent_relay().await;
+    let silent = DirectoryClient::from_consensus(consensus(vec![relay("Silent", &address, guard_flags(), 1000)]));
+    let build = {
+        let manager = manager.clone();
+        tokio::spawn(async move { manager.create_circuit(1, false, &silent).await })
+    };
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(manager.circuit_count().await, 1);
+
+    let reaper = manager.spawn_reaper(Duration::from_millis(20), Duration::from_secs(600), Duration::from_millis(50));
+    let result = tokio::time::timeout(Duration::from_secs(5), build).await.unwrap().unwrap();
+    assert!(result.is_err(), "the stuck build should be abandoned");
+    assert_eq!(manager.circuit_count().await, 0);
+    reaper.abort();
+}
+
+#[tokio::test]
+async fn test_close_circuits_older_than_an_age() {
+    let net = mock_network().await;
+    let manager = CircuitManager::new();
+    let old = [
+        manager.create_circuit(3, false, &net.directory).await.unwrap(),
+        manager.create_circuit(3, false, &net.directory).await.unwrap(),
+    ];
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let young = manager.create_circuit(3, false, &net.directory).await.unwrap();
+
+    assert_eq!(manager.close_circuits_older_than(Duration::from_secs(600)).await, 0);
+    assert_eq!(manager.close_circuits_older_than(Duration::from_millis(150)).await, 2);
+    for circuit_id in old {
+        assert!(manager.get_circuit_info(circuit_id).await.is_none());
+    }
+    assert!(manager.get_circuit_info(young).await.is_some());
+    assert_eq!(manager.circuit_count().await, 1);
+}
+
+#[tokio::test]
+async fn test_circuit_pool_hands_out_prebuilt_circuits() {
+    let net = mock_network().await;
+    let directory = std::sync::Arc::new(net.directory);
+    let manager = std::sync::Arc::new(CircuitManager::new());
+    let pool = manager.spawn_circuit_pool(directory.clone(), 2, 3);
+
+    let filled = async {
+        while manager.pooled_circuits().await < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), filled).await.expect("the pool should fill up");
+    assert_eq!(net.exit.handshakes(), 2);
+
+    let key = IsolationKey::ClientPort(4242);
+    let circuit_id = manager.get_or_create_circuit(&key, None, 3, &directory).await.unwrap();
+    assert!(circuit_id & 0x7fff_ffff <= 2, "the circuit should come from the pool");
+
+    let replenished = async {
+        while net.exit.handshakes() < 3 || manager.pooled_circuits().await < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), replenished).await.expect("the pool should be replenished");
+    assert_eq!(manager.get_or_create_circuit(&key, None, 3, &directory).await.unwrap(), circuit_id);
+    pool.abort();
+}
+
+#[tokio::test]
+async fn test_circuit_ready_hook_fires_once_per_build() {
+    let net = mock_network().await;
+    let manager = CircuitManager::new();
+    let ready = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    {
+        let ready = ready.clone();
+        manager.on_circuit_ready(Box::new(move |circuit_id| ready.lock().unwrap().push(circuit_id)));
+    }
+
+    let first = manager.create_circuit(3, false, &net.directory).await.unwrap();
+    let second = manager.create_circuit(3, false, &net.directory).await.unwrap();
+    assert_eq!(*ready.lock().unwrap(), vec![first, second]);
+
+    // A failed build doesn't count
+    let (address, _closed_rx) = silent_relay().await;
+    let silent = DirectoryClient::from_consensus(consensus(vec![relay("Silent", &address, guard_flags(), 1000)]));
+    let manager_with_timeout = CircuitManager::new().with_handshake_timeout(Duration::from_millis(50));
+    let fired = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    {
+        let fired = fired.clone();
+        manager_with_timeout.on_circuit_ready(Box::new(move |_| {
+            fired.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        }));
+    }
+    assert!(manager_with_timeout.create_circuit(1, false, &silent).await.is_err());
+    assert_eq!(fired.load(std::sync::atomic::Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn test_exit_chosen_by_destination_port() {
+    let guard = MockRelay::spawn().await.unwrap();
+    let middle = MockRelay::spawn().await.unwrap();
+    let web_exit = MockRelay::spawn().await.unwrap();
+    let mail_exit = MockRelay::spawn().await.unwrap();
+    let with_policy = |relay: &MockRelay, nickname: &str, policy: &str| {
+        let mut descriptor = relay.descriptor(nickname, exit_flags(), 1000);
+        descriptor.exit_policy = ExitPolicySummary::parse(policy);
+        descriptor
+    };
+    let directory = DirectoryClient::from_consensus(consensus(vec![
+        guard.descriptor("Guard", guard_flags(), 1000),
+        middle.descriptor("Middle", middle_flags(), 1000),
+        with_policy(&web_exit, "WebExit", "accept 80,443"),
+        with_policy(&mail_exit, "MailExit", "accept 25"),
+    ]));
+    let manager = CircuitManager::new();
+    let key = IsolationKey::ClientPort(5555);
+
+    let smtp = manager.get_or_create_circuit(&key, Some(ExitTarget::ipv4(25)), 3, &directory).await.unwrap();
+    assert_eq!((mail_exit.handshakes(), web_exit.handshakes()), (1, 0));
+    assert_eq!(manager.get_or_create_circuit(&key, Some(ExitTarget::ipv4(25)), 3, &directory).await.unwrap(), smtp);
+
+    let https = manager.get_or_create_circuit(&key, Some(ExitTarget::ipv4(443)), 3, &directory).await.unwrap();
+    assert_ne!(https, smtp, "the SMTP exit can't carry port 443");
+    assert_eq!((mail_exit.handshakes(), web_exit.handshakes()), (1, 1));
+}
+
+#[tokio::test]
+async fn test_least_loaded_circuit_preferred() {
+    let net = mock_network().await;
+    let manager = CircuitManager::new();
+    let busy = manager.create_circuit(3, false, &net.directory).await.unwrap();
+    let idle = manager.create_circuit(3, false, &net.directory).await.unwrap();
+
+    let streams = vec![
+        manager.open_stream(busy).await.unwrap(),
+        manager.open_stream(busy).await.unwrap(),
+        manager.open_stream(idle).await.unwrap(),
+    ];
+    assert_eq!(manager.circuit_load(busy).await.unwrap().active_streams, 2);
+    assert_eq!(manager.least_loaded_ready_circuit().await, Some(idle));
+
+    // Closed streams stop counting
+    drop(streams);
+    let _more = (
+        manager.open_stream(idle).await.unwrap(),
+        manager.open_stream(idle).await.unwrap(),
+    );
+    assert_eq!(manager.least_loaded_ready_circuit().await, Some(busy));
+}
+
+#[tokio::test]
+async fn test_link_handshake_negotiates_version() {
+    let relay = MockRelay::spawn().await.unwrap();
+    let channel = Channel::connect(
+        &relay.id(),
+        &relay.relay_identity(),
+        relay.address(),
+        TlsBackend::NativeTls,
+        &ClientHello::default(),
+        Duration::from_secs(5),
+        Duration::from_secs(5),
+    )
+    .await
+    .unwrap();
+
+    let link = channel.link_info();
+    assert_eq!(link.version, 5);
+    assert_eq!(link.ed25519_identity, relay.ed25519_identity());
+    assert_eq!(link.our_address, Some("127.0.0.1".parse().unwrap()));
+    assert_eq!(link.relay_addresses, vec![relay.address().ip()]);
+}
+
+#[tokio::test]
+async fn test_relay_must_prove_the_identity_it_is_listed_under() {
+    let listed = MockRelay::spawn().await.unwrap();
+    let impostor = MockRelay::spawn().await.unwrap();
+    let timeout = Duration::from_secs(5);
+    let hello = ClientHello::default();
+
+    // The impostor answers at the listed relay's address with its own keys
+    let connect = Channel::connect(
+        &listed.id(),
+        &listed.relay_identity(),
+        impostor.address(),
+        TlsBackend::NativeTls,
+        &hello,
+        timeout,
+        timeout,
+    )
+    .await;
+    assert!(matches!(connect, Err(LinkError::Protocol(ref e)) if e.contains("Ed25519 identity")), "got {:?}", connect);
+
+    let mut hijacked = listed.descriptor("Guard", guard_flags(), 1000);
+    hijacked.address = impostor.address();
+    let directory = DirectoryClient::from_consensus(consensus(vec![hijacked]));
+    let manager = CircuitManager::new().with_hop_retries(0);
+    assert!(manager.create_circuit(1, false, &directory).await.is_err());
+    assert_eq!(impostor.handshakes(), 0, "no CREATE2 goes to a relay that isn't the one we chose");
+}
+
+#[tokio::test]
+async fn test_cleartext_links_only_reach_cleartext_relays() {
+    let timeout = Duration::from_secs(2);
+    let tls_relay = MockRelay::spawn().await.unwrap();
+    let hello = ClientHello::default();
+    let identity = tls_relay.relay_identity();
+    assert!(Channel::connect(&tls_relay.id(), &identity, tls_relay.address(), TlsBackend::None, &hello, timeout, timeout)
+        .await
+        .is_err());
+
+    let guard = MockRelay::spawn_cleartext().await.unwrap();
+    let identity = guard.relay_identity();
+    let channel = Channel::connect(&guard.id(), &identity, guard.address(), TlsBackend::None, &hello, timeout, timeout)
+        .await
+        .unwrap();
+    assert_eq!(channel.link_info().ed25519_identity, guard.ed25519_identity());
+
+    let middle = MockRelay::spawn_cleartext().await.unwrap();
+    let exit = MockRelay::spawn_cleartext().await.unwrap();
+    let directory = DirectoryClient::from_consensus(consensus(vec![
+        guard.descriptor("Guard", guard_flags(), 1000),
+        middle.descriptor("Middle", middle_flags(), 1000),
+        exit.descriptor("Exit", exit_flags(), 1000),
+    ]));
+    let manager = CircuitManager::new().with_tls_backend(TlsBackend::None);
+    manager.create_circuit(3, false, &directory).await.unwrap();
+}
+
+/// The server_name and ALPN protocols in a ClientHello record
+fn client_hello_extensions(record: &[u8]) -> (Option<String>, Vec<String>) {
+    let u16_at = |at: usize| u16::from_be_bytes([record[at], record[at + 1]]) as usize;
+    // Record header, handshake header, version and random
+    let mut at = 5 + 4 + 2 + 32;
+    at += 1 + record[at] as usize; // session id
+    at += 2 + u16_at(at); // cipher suites
+    at += 1 + record[at] as usize; // compression methods
+    let end = at + 2 + u16_at(at);
+    at += 2;
+    let (mut sni, mut alpn) = (None, vec![]);
+    while at < end {
+        let (kind, len) = (u16_at(at), u16_at(at + 2));
+        let body = &record[at + 4..at + 4 + len];
+        match kind {
+            0 => sni = Some(String::from_utf8(body[5..].to_vec()).unwrap()),
+            16 => {
+                let mut i = 2;
+                while i < body.len() {
+                    let n = body[i] as usize;
+                    alpn.push(String::from_utf8(body[i + 1..i + 1 + n].to_vec()).unwrap());
+                    i += 1 + n;
+                }
+            }
+            _ => {}
+        }
+        at += 4 + len;
+    }
+    (sni, alpn)
+}
+
+#[tokio::test]
+async fn test_client_hello_uses_the_configured_sni_and_alpn() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let mut names = vec![];
+    for hello in [
+        ClientHello { sni: SniStrategy::Random, alpn: vec!["h2".to_string(), "http/1.1".to_string()] },
+        ClientHello { sni: SniStrategy::Random, alpn: vec![] },
+        ClientHello { sni: SniStrategy::None, alpn: vec![] },
+    ] {
+        // No ServerHello ever comes back; the ClientHello is all that's needed
+        let client = tokio::spawn(async move {
+            let stream = tokio::net::TcpStream::connect(address).await.unwrap();
+            let _ = tls::connect(stream, TlsBackend::NativeTls, &hello).await;
+        });
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut header = [0u8; 5];
+        server.read_exact(&mut header).await.unwrap();
+        let mut record = header.to_vec();
+        record.resize(5 + u16::from_be_bytes([header[3], header[4]]) as usize, 0);
+        server.read_exact(&mut record[5..]).await.unwrap();
+        drop(server);
+        client.await.unwrap();
+        names.push(client_hello_extensions(&record));
+    }
+
+    let (first, alpn) = &names[0];
+    let first = first.as_deref().expect("random SNI should send a server name");
+    let label = first.strip_prefix("www.").and_then(|rest| rest.strip_suffix(".com")).unwrap();
+    assert!((8..=20).contains(&label.len()), "{}", first);
+    assert!(label.bytes().all(|b| b.is_ascii_lowercase() || (b'2'..=b'7').contains(&b)), "{}", first);
+    assert_eq!(alpn, &["h2", "http/1.1"]);
+    // A new name for every connection, and no ALPN unless asked for
+    assert_ne!(names[1].0.as_deref(), Some(first));
+    assert!(names[1].1.is_empty());
+    assert_eq!(names[2], (None, vec![]));
+}
+
+#[tokio::test]
+async fn test_streams_refused_until_ready() {
+    let (address, _closed_rx) = silent_relay().await;
+    let directory = DirectoryClient::from_consensus(consensus(vec![relay("Silent", &address, guard_flags(), 1000)]));
+    let manager = std::sync::Arc::new(CircuitManager::new());
+
+    let build = {
+        let manager = manager.clone();
+        tokio::spawn(async move { manager.create_circuit(1, false, &directory).await })
+    };
+    while manager.circuit_count().await == 0 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // Circuit 1 is still Building: its relay never answers
+    assert!(matches!(manager.open_stream(1).await, Err(CircuitError::NotReady(1))));
+    assert!(matches!(manager.resolve(1, "example.com").await, Err(CircuitError::NotReady(1))));
+    build.abort();
+}
+
+#[tokio::test]
+async fn test_stream_send_fails_once_circuit_closed() {
+    let net = mock_network().await;
+    let manager = CircuitManager::new();
+    let circuit_id = manager.create_circuit(3, false, &net.directory).await.unwrap();
+    let stream = manager.open_stream(circuit_id).await.unwrap();
+
+    manager.close_circuit(circuit_id).await;
+    assert!(matches!(stream.send(RELAY_COMMAND_DROP, Vec::new()).await, Err(CircuitError::NotReady(id)) if id == circuit_id));
+}
+
+#[tokio::test]
+async fn test_open_stream_fails_once_every_stream_id_is_taken() {
+    let net = mock_network().await;
+    let manager = CircuitManager::new();
+    let circuit_id = manager.create_circuit(3, false, &net.directory).await.unwrap();
+
+    let mut streams = Vec::new();
+    for _ in 1..=u16::MAX {
+        streams.push(manager.open_stream(circuit_id).await.unwrap());
+    }
+    assert!(matches!(
+        manager.open_stream(circuit_id).await,
+        Err(CircuitError::StreamIdsExhausted(id)) if id == circuit_id
+    ));
+    // Closing a stream frees its ID for the next one
+    let freed = streams.swap_remove(1234).id();
+    assert_eq!(manager.open_stream(circuit_id).await.unwrap().id(), freed);
+}
+
+#[tokio::test]
+async fn test_stream_refuses_data_larger_than_one_cell() {
+    let net = mock_network().await;
+    let manager = CircuitManager::new();
+    let circuit_id = manager.create_circuit(3, false, &net.directory).await.unwrap();
+    let stream = manager.open_stream(circuit_id).await.unwrap();
+
+    let result = stream.send(RELAY_COMMAND_DATA, vec![b'x'; RELAY_PAYLOAD_LEN + 1]).await;
+    assert!(matches!(result, Err(CircuitError::HandshakeFailed(_))), "got {:?}", result);
+    // Nothing was sent, so nothing came out of the windows
+    assert_eq!(stream.package_window(), STREAM_WINDOW_START);
+    stream.send(RELAY_COMMAND_DATA, vec![b'x'; RELAY_PAYLOAD_LEN]).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_data_stops_when_package_window_is_empty() {
+    let net = mock_network().await;
+    net.exit.withhold_sendmes();
+    let manager = CircuitManager::new();
+    let circuit_id = manager.create_circuit(3, false, &net.directory).await.unwrap();
+    let stream = manager.open_stream(circuit_id).await.unwrap();
+
+    for _ in 0..500 {
+        stream.send(RELAY_COMMAND_DATA, b"x".to_vec()).await.unwrap();
+    }
+    assert_eq!(stream.package_window(), 0);
+    // The stream window is spent and the exit never acknowledges anything
+    let blocked = tokio::time::timeout(Duration::from_millis(200), stream.send(RELAY_COMMAND_DATA, b"x".to_vec())).await;
+    assert!(blocked.is_err());
+}
+
+#[tokio::test]
+async fn test_sendmes_keep_data_flowing() {
+    let net = mock_network().await;
+    let manager = CircuitManager::new();
+    let circuit_id = manager.create_circuit(3, false, &net.directory).await.unwrap();
+    let mut stream = manager.open_stream(circuit_id).await.unwrap();
+    stream.send(RELAY_COMMAND_BEGIN, b"example.com:80\0".to_vec()).await.unwrap();
+    assert_eq!(stream.recv().await.unwrap().command, RELAY_COMMAND_CONNECTED);
+
+    // Twice the stream window: only possible if the exit's SENDMEs reopen it
+    let send = async {
+        for _ in 0..1000 {
+            stream.send(RELAY_COMMAND_DATA, b"x".to_vec()).await.unwrap();
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), send).await.unwrap();
+
+    // The exit echoes every cell back; reading them is what makes us acknowledge them
+    let mut echoed = 0;
+    while echoed < 1000 {
+        let cell = tokio::time::timeout(Duration::from_secs(10), stream.recv()).await.unwrap().unwrap();
+        if cell.command == RELAY_COMMAND_DATA {
+            echoed += 1;
+        }
+    }
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while net.exit.stream_sendmes() < 20 || net.exit.circuit_sendmes() < 10 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(net.exit.stream_sendmes(), 20);
+    // Each circuit SENDME carried the digest of the cell it acknowledged
+    assert_eq!(net.exit.circuit_sendmes(), 10);
+}
+
+#[tokio::test]
+async fn test_circuit_closed_when_exit_over_credits_a_stream() {
+    let net = mock_network().await;
+    net.exit.over_credit();
+    let manager = CircuitManager::new();
+    let circuit_id = manager.create_circuit(3, false, &net.directory).await.unwrap();
+    let mut stream = manager.open_stream(circuit_id).await.unwrap();
+    stream.send(RELAY_COMMAND_BEGIN, b"example.com:80\0".to_vec()).await.unwrap();
+    assert_eq!(stream.recv().await.unwrap().command, RELAY_COMMAND_CONNECTED);
+
+    // The SENDME after CONNECTED would take the stream's window past 500
+    let ended = tokio::time::timeout(Duration::from_secs(5), stream.recv()).await.unwrap();
+    assert!(ended.is_none(), "got {:?}", ended);
+    assert_eq!(stream.package_window(), STREAM_WINDOW_START);
+    assert!(matches!(stream.send(RELAY_COMMAND_DATA, b"x".to_vec()).await, Err(CircuitError::NotReady(_))));
+}
+
+#[tokio::test]
+async fn test_circuit_closed_on_sendme_with_the_wrong_digest() {
+    let net = mock_network().await;
+    net.exit.forge_sendme_digests();
+    let manager = CircuitManager::new();
+    let circuit_id = manager.create_circuit(3, false, &net.directory).await.unwrap();
+    let mut stream = manager.open_stream(circuit_id).await.unwrap();
+
+    // The 100th DATA cell is acknowledged with a digest it never had
+    for _ in 0..CIRCUIT_WINDOW_INCREMENT {
+        stream.send(RELAY_COMMAND_DATA, b"x".to_vec()).await.unwrap();
+    }
+    let ended = tokio::time::timeout(Duration::from_secs(5), async {
+        while stream.recv().await.is_some() {}
+    })
+    .await;
+    assert!(ended.is_ok(), "the circuit should have been closed");
+    assert!(matches!(stream.send(RELAY_COMMAND_DATA, b"x".to_vec()).await, Err(CircuitError::NotReady(_))));
+}
+
+#[tokio::test]
+async fn test_circuit_extends_through_the_guard_with_relay_early() {
+    let net = mock_network().await;
+    let manager = CircuitManager::new();
+    let circuit_id = manager.create_circuit(3, false, &net.directory).await.unwrap();
+
+    // Only the guard gets a CREATE2 from us; the middle and the exit are
+    // reached by one EXTEND2 each, both arriving at the guard as RELAY_EARLY
+    assert_eq!(net.guard.relay_early_cells(), 2);
+    // The one for the exit went on to the middle, still RELAY_EARLY
+    assert_eq!(net.guard.forwarded_cells(), 1);
+    assert_eq!(net.middle.relay_early_cells(), 1);
+    assert_eq!(net.exit.relay_early_cells(), 0);
+    assert_eq!(net.middle.connections(), 1, "only the guard connects to the middle");
+    assert_eq!(net.exit.connections(), 1, "only the middle connects to the exit");
+
+    // The extensions came out of the circuit's RELAY_EARLY budget
+    let mut stream = manager.open_stream(circuit_id).await.unwrap();
+    for _ in 2..MAX_RELAY_EARLY_CELLS {
+        stream.send(RELAY_COMMAND_EXTEND2, Vec::new()).await.unwrap();
+    }
+    assert!(matches!(
+        stream.send(RELAY_COMMAND_EXTEND2, Vec::new()).await,
+        Err(CircuitError::RelayEarlyExhausted(id)) if id == circuit_id
+    ));
+    stream.send(RELAY_COMMAND_BEGIN, b"example.com:80\0".to_vec()).await.unwrap();
+    while stream.recv().await.unwrap().command != RELAY_COMMAND_CONNECTED {}
+    assert_eq!(net.exit.relay_early_cells(), MAX_RELAY_EARLY_CELLS as usize - 2);
+}
+
+#[tokio::test]
+async fn test_echo_through_three_hop_circuit() {
+    let net = mock_network().await;
+    let manager = CircuitManager::new();
+    let circuit_id = manager.create_circuit(3, false, &net.directory).await.unwrap();
+
+    // Spans many cells, with a partial one at the end
+    let payload: Vec<u8> = (0..20_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let cells = payload.len().div_ceil(RELAY_PAYLOAD_LEN);
+    let (guard_forwarded, middle_forwarded) = (net.guard.forwarded_cells(), net.middle.forwarded_cells());
+    let echoed = manager.echo_test(circuit_id, &payload).await.unwrap();
+    assert_eq!(echoed, payload);
+
+    // Each DATA cell went guard, middle, exit: the guard and the middle each
+    // peeled a layer and passed it on without recognizing it, and the exit
+    // recognized it under the last layer and echoed it
+    assert_eq!(net.guard.forwarded_cells() - guard_forwarded, cells);
+    assert_eq!(net.middle.forwarded_cells() - middle_forwarded, cells);
+    assert_eq!(net.exit.forwarded_cells(), 0);
+    assert_eq!(net.exit.data_cells(), cells);
+    assert_eq!(net.guard.data_cells() + net.middle.data_cells(), 0);
+
+    manager.close_circuit(circuit_id).await;
+    assert!(matches!(manager.echo_test(circuit_id, b"gone").await, Err(CircuitError::NotReady(_))));
+}
+
+#[tokio::test]
+async fn test_truncated_circuit_relays_through_new_last_hop() {
+    let net = mock_network().await;
+    let manager = CircuitManager::new();
+    let circuit_id = manager.create_circuit(3, false, &net.directory).await.unwrap();
+    assert_eq!(manager.echo_test(circuit_id, b"to the exit").await.unwrap(), b"to the exit");
+    let mut old_stream = manager.open_stream(circuit_id).await.unwrap();
+
+    assert!(matches!(manager.truncate_circuit(circuit_id, 3).await, Err(CircuitError::InvalidHopCount(3))));
+    let (guard_forwarded, middle_forwarded) = (net.guard.forwarded_cells(), net.middle.forwarded_cells());
+    manager.truncate_circuit(circuit_id, 2).await.unwrap();
+    // The TRUNCATE went to the middle through the guard, which peeled its layer and passed it on
+    assert_eq!(net.guard.forwarded_cells(), guard_forwarded + 1);
+    assert_eq!(net.middle.forwarded_cells(), middle_forwarded);
+
+    // Streams on the old path end, and new cells are keyed for the middle,
+    // still under the guard's layer
+    assert!(old_stream.recv().await.is_none());
+    assert_eq!(manager.echo_test(circuit_id, b"to the middle").await.unwrap(), b"to the middle");
+    assert_eq!(net.guard.forwarded_cells(), guard_forwarded + 2);
+    assert_eq!(net.middle.forwarded_cells(), middle_forwarded);
+    assert_eq!(net.exit.data_cells(), 1);
+    assert_eq!(net.middle.data_cells(), 1);
+    assert_eq!(net.guard.data_cells(), 0);
+
+    // Down to the guard's layer alone
+    manager.truncate_circuit(circuit_id, 1).await.unwrap();
+    assert_eq!(manager.echo_test(circuit_id, b"to the guard").await.unwrap(), b"to the guard");
+    assert_eq!(net.guard.forwarded_cells(), guard_forwarded + 2);
+    assert_eq!(net.guard.data_cells(), 1);
+    assert_eq!(manager.get_circuit_info(circuit_id).await.unwrap().hops.len(), 1);
+}
+
+#[tokio::test]
+async fn test_circuit_age_and_request_count() {
+    let net = mock_network().await;
+    let manager = CircuitManager::new();
+    let circuit_id = manager.create_circuit(3, false, &net.directory).await.unwrap();
+    assert_eq!(manager.circuit_request_count(circuit_id).await, Some(0));
+
+    let age = manager.circuit_age(circuit_id).await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    assert!(manager.circuit_age(circuit_id).await.unwrap() >= age + std::time::Duration::from_millis(20));
+
+    let _first = manager.open_stream(circuit_id).await.unwrap();
+    manager.echo_test(circuit_id, b"ping").await.unwrap();
+    assert_eq!(manager.circuit_request_count(circuit_id).await, Some(2));
+
+    manager.close_circuit(circuit_id).await;
+    assert_eq!(manager.circuit_age(circuit_id).await, None);
+    assert_eq!(manager.circuit_request_count(circuit_id).await, None);
+}
+
+#[tokio::test]
+async fn test_each_hop_gets_a_fresh_client_key() {
+    let net = mock_network().await;
+    let manager = CircuitManager::new();
+    manager.create_circuit(3, false, &net.directory).await.unwrap();
+    manager.create_circuit(3, false, &net.directory).await.unwrap();
+
+    let keys: Vec<[u8; 32]> = [&net.guard, &net.middle, &net.exit]
+        .iter()
+        .flat_map(|relay| relay.client_keys())
+        .collect();
+    assert_eq!(keys.len(), 6);
+    // Distinct across the hops of a circuit and across circuits
+    let distinct: std::collections::HashSet<_> = keys.iter().collect();
+    assert_eq!(distinct.len(), 6);
+}
+
+#[tokio::test]
+async fn test_max_concurrent_builds_serializes_handshakes() {
+    let net = mock_network().await;
+    let manager = CircuitManager::new().with_max_concurrent_builds(Some(1));
+
+    let (first, second) = tokio::join!(
+        manager.create_circuit(3, false, &net.directory),
+        manager.create_circuit(3, false, &net.directory),
+    );
+    first.unwrap();
+    second.unwrap();
+
+    // One circuit's three handshakes all finish before the other's start
+    let relays = [(&net.guard, "guard"), (&net.middle, "middle"), (&net.exit, "exit")];
+    let mut handshakes: Vec<(std::time::Instant, &str)> = relays
+        .iter()
+        .flat_map(|(relay, name)| relay.handshake_times().into_iter().map(move |at| (at, *name)))
+        .collect();
+    handshakes.sort();
+    let order: Vec<&str> = handshakes.iter().map(|(_, name)| *name).collect();
+    assert_eq!(order, ["guard", "middle", "exit", "guard", "middle", "exit"]);
+}
+
+#[tokio::test]
+async fn test_http_get_over_begun_stream() {
+    let net = mock_network().await;
+    // Large enough to span several DATA cells, and chunked like many servers send it
+    let page = "<html>".repeat(300);
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n{}\r\n0\r\n\r\n",
+        page.len(),
+        page
+    );
+    net.exit.add_site("example.com", 80, response.as_bytes());
+    let manager = CircuitManager::new();
+    let circuit_id = manager.create_circuit(3, false, &net.directory).await.unwrap();
+
+    let url = HttpUrl::parse("http://example.com/index.html").unwrap();
+    let mut stream = manager.begin_stream(circuit_id, &url.host, url.port).await.unwrap();
+    stream.send(RELAY_COMMAND_DATA, url.get_request()).await.unwrap();
+    let received = stream.read_to_end(Duration::from_secs(10)).await.unwrap();
+    assert_eq!(response_body(&received).unwrap(), page);
+}
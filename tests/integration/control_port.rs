@@ -0,0 +1,92 @@
+// tests/integration/control_port.rs
+#[path = "../common/mod.rs"]
+mod common;
+
+use common::{consensus, exit_flags, guard_flags, middle_flags};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tor_client::control::ControlPort;
+use tor_client::network::mock_relay::MockRelay;
+use tor_client::{CircuitManager, DirectoryClient};
+
+/// Start a control port for `manager`, returning its port. The listener is
+/// bound before this returns, so controllers can connect straight away.
+async fn spawn_control_port(manager: Arc<CircuitManager>) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let control = ControlPort::new("127.0.0.1:0".to_string(), manager);
+    tokio::spawn(async move {
+        let _ = control.serve(listener).await;
+    });
+    port
+}
+
+/// Read one reply: every line up to the final "NNN " one
+async fn read_reply(reader: &mut BufReader<OwnedReadHalf>) -> Vec<String> {
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await.unwrap() == 0 {
+            return lines;
+        }
+        let line = line.trim_end().to_string();
+        let last = line.len() >= 4 && line.as_bytes()[3] == b' ' && line[..3].bytes().all(|b| b.is_ascii_digit());
+        lines.push(line);
+        if last {
+            return lines;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_control_port_reports_circuits_and_handles_newnym() {
+    let guard = MockRelay::spawn().await.unwrap();
+    let middle = MockRelay::spawn().await.unwrap();
+    let exit = MockRelay::spawn().await.unwrap();
+    let directory = DirectoryClient::from_consensus(consensus(vec![
+        guard.descriptor("Guard", guard_flags(), 1000),
+        middle.descriptor("Middle", middle_flags(), 1000),
+        exit.descriptor("Exit", exit_flags(), 1000),
+    ]));
+    let manager = Arc::new(CircuitManager::new());
+    let circuit_id = manager.create_circuit(3, false, &directory).await.unwrap();
+    let port = spawn_control_port(manager.clone()).await;
+
+    let (reader, mut writer) = TcpStream::connect(("127.0.0.1", port)).await.unwrap().into_split();
+    let mut reader = BufReader::new(reader);
+
+    writer.write_all(b"PROTOCOLINFO 1\r\n").await.unwrap();
+    let reply = read_reply(&mut reader).await;
+    assert!(reply.contains(&"250-AUTH METHODS=NULL".to_string()), "got {:?}", reply);
+    writer.write_all(b"AUTHENTICATE\r\n").await.unwrap();
+    assert_eq!(read_reply(&mut reader).await, ["250 OK"]);
+
+    writer.write_all(b"GETINFO version circuit-status\r\n").await.unwrap();
+    let reply = read_reply(&mut reader).await;
+    assert_eq!(reply[0], format!("250-version={}", env!("CARGO_PKG_VERSION")));
+    assert_eq!(reply[1], "250+circuit-status=");
+    assert!(reply[2].starts_with(&format!("{} BUILT ", circuit_id)), "got {:?}", reply);
+    assert!(reply[2].ends_with("~Exit"), "got {:?}", reply);
+    assert_eq!(reply[3..], [".", "250 OK"]);
+
+    writer.write_all(b"GETINFO no-such-key\r\n").await.unwrap();
+    assert_eq!(read_reply(&mut reader).await, ["552 Unrecognized key \"no-such-key\""]);
+
+    writer.write_all(b"SIGNAL NEWNYM\r\n").await.unwrap();
+    assert_eq!(read_reply(&mut reader).await, ["250 OK"]);
+    assert_eq!(manager.circuit_count().await, 0);
+}
+
+#[tokio::test]
+async fn test_control_port_requires_authentication() {
+    let port = spawn_control_port(Arc::new(CircuitManager::new())).await;
+    let (reader, mut writer) = TcpStream::connect(("127.0.0.1", port)).await.unwrap().into_split();
+    let mut reader = BufReader::new(reader);
+
+    writer.write_all(b"GETINFO version\r\n").await.unwrap();
+    assert_eq!(read_reply(&mut reader).await, ["514 Authentication required."]);
+    // And the connection is closed
+    assert!(read_reply(&mut reader).await.is_empty());
+}
@@ -1,11 +1,19 @@
 
 // tests/integration/full_circuit.rs
-use tor_client::{TorClient, TorConfig};
+#[path = "../common/mod.rs"]
+mod common;
+
+use common::{consensus, exit_flags, guard_flags, middle_flags};
+use std::time::{Duration, Instant};
+use tor_client::directory::guards::GuardSet;
+use tor_client::directory::NetworkConsensus;
+use tor_client::network::mock_relay::MockRelay;
+use tor_client::{DirectoryClient, TorClient, TorConfig, TorError};
 
 #[tokio::test]
 async fn test_complete_tor_flow() {
     let config = TorConfig::test_config();
-    let client = TorClient::start(config).await.unwrap();
+    let _client = TorClient::start(config).await.unwrap();
     
     // Test HTTP request through Tor
     // let response = client.http_get("http://example.com").await;
@@ -13,3 +21,63 @@ async fn test_complete_tor_flow() {
     
     // client.shutdown().await;
 }
+
+#[tokio::test]
+async fn test_shutdown_persists_guards_and_consensus() {
+    let data_dir = std::env::temp_dir().join(format!("tor-client-test-{}", rand::random::<u64>()));
+    let config = TorConfig {
+        data_directory: data_dir.to_str().unwrap().to_string(),
+        circuit_pool_size: 0,
+        ..TorConfig::test_config()
+    };
+    let client = TorClient::start(config).await.unwrap();
+    let guard = client.directory().select_relay(0).await.unwrap();
+    client.shutdown().await;
+
+    let consensus: NetworkConsensus =
+        serde_json::from_slice(&std::fs::read(data_dir.join("cached-consensus.json")).unwrap()).unwrap();
+    assert!(consensus.relays.contains_key(&guard.id));
+    let guards = GuardSet::load(&data_dir.join("guards.json")).expect("guard file should be written");
+    assert!(guards.contains(&guard.id));
+    assert!(data_dir.join("metrics.json").exists());
+
+    // The next start picks the saved guards back up
+    let restarted = DirectoryClient::new_mock().with_data_directory(data_dir.to_str().unwrap());
+    assert_eq!(restarted.guards().await, guards);
+
+    std::fs::remove_dir_all(&data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_http_get_times_out_on_a_stalled_exit() {
+    // The exit has no site for the host, so it echoes the request and then goes quiet
+    let guard = MockRelay::spawn().await.unwrap();
+    let middle = MockRelay::spawn().await.unwrap();
+    let exit = MockRelay::spawn().await.unwrap();
+    let data_dir = std::env::temp_dir().join(format!("tor-client-test-{}", rand::random::<u64>()));
+    std::fs::create_dir_all(&data_dir).unwrap();
+    let network = consensus(vec![
+        guard.descriptor("Guard", guard_flags(), 1000),
+        middle.descriptor("Middle", middle_flags(), 1000),
+        exit.descriptor("Exit", exit_flags(), 1000),
+    ]);
+    std::fs::write(data_dir.join("cached-consensus.json"), serde_json::to_vec(&network).unwrap()).unwrap();
+
+    let config = TorConfig {
+        data_directory: data_dir.to_str().unwrap().to_string(),
+        directory_authorities: vec![data_dir.to_str().unwrap().to_string()],
+        circuit_pool_size: 0,
+        http_timeout: Duration::from_secs(1),
+        ..TorConfig::test_config()
+    };
+    let client = TorClient::start(config).await.unwrap();
+
+    let started = Instant::now();
+    let result = client.http_get("http://stalled.example/").await;
+    assert!(matches!(result, Err(TorError::Timeout(limit)) if limit == Duration::from_secs(1)), "{:?}", result);
+    assert!(started.elapsed() < Duration::from_secs(3));
+    assert_eq!(exit.handshakes(), 1);
+
+    client.shutdown().await;
+    std::fs::remove_dir_all(&data_dir).unwrap();
+}
@@ -91,8 +91,9 @@ async fn test_circuit_with_reachability() {
     println!("\n=== Testing Full Circuit Creation with Reachability ===\n");
 
     let config = TorConfig {
+        data_directory: std::env::temp_dir().join("tor-client-reachability").to_string_lossy().into_owned(),
         directory_authorities: vec!["tor-collector".to_string()],
-        socks_port: 9051, // Different port to avoid conflicts
+        socks_port: 9053, // Different port to avoid conflicts (9051 is the control port)
         ..Default::default()
     };
 
@@ -118,6 +119,7 @@ async fn test_multiple_circuits_reachability() {
     println!("\n=== Testing Multiple Circuits for Reachability ===\n");
 
     let config = TorConfig {
+        data_directory: std::env::temp_dir().join("tor-client-reachability").to_string_lossy().into_owned(),
         directory_authorities: vec!["tor-collector".to_string()],
         socks_port: 9052, // Different port
         ..Default::default()
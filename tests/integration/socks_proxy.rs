@@ -0,0 +1,528 @@
+// tests/integration/socks_proxy.rs
+#[path = "../common/mod.rs"]
+mod common;
+
+use common::{consensus, exit_flags, guard_flags, middle_flags, relay};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tor_client::hs::OnionAddress;
+use tor_client::metrics::Metrics;
+use tor_client::network::cells::{
+    END_REASON_CONNECTREFUSED, END_REASON_RESOLVEFAILED, END_REASON_TIMEOUT, RELAY_PAYLOAD_LEN,
+};
+use tor_client::network::mock_relay::MockRelay;
+use tor_client::proxy::socks5::{
+    reply_for_end_reason, CircuitLength, ProxyError, Socks5Proxy, Socks5Request, COMMAND_BIND, COMMAND_CONNECT,
+    COMMAND_RESOLVE, REPLY_COMMAND_NOT_SUPPORTED, REPLY_CONNECTION_REFUSED, REPLY_HOST_UNREACHABLE,
+    REPLY_NETWORK_UNREACHABLE, REPLY_SUCCEEDED, REPLY_TTL_EXPIRED, SOCKS4_REQUEST_GRANTED, SOCKS4_REQUEST_REJECTED,
+};
+use tor_client::{CircuitManager, DirectoryClient};
+
+async fn free_port() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+/// Perform a no-auth SOCKS5 CONNECT to 127.0.0.1:`port` and return the reply status
+async fn socks5_connect(proxy_port: u16, port: u16) -> u8 {
+    let mut stream = TcpStream::connect(("127.0.0.1", proxy_port)).await.unwrap();
+
+    stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).await.unwrap();
+    assert_eq!(method, [0x05, 0x00]);
+
+    let mut request = vec![0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1];
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await.unwrap();
+
+    let mut reply = [0u8; 10];
+    stream.read_exact(&mut reply).await.unwrap();
+    reply[1]
+}
+
+/// Run `proxy` on a listener that is bound before this returns its port,
+/// so clients can connect straight away
+async fn serve(proxy: Socks5Proxy) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let socks_port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let _ = proxy.serve(listener).await;
+    });
+    socks_port
+}
+
+/// Start a proxy whose circuits can never be built, returning its port
+async fn spawn_proxy(direct_connect_insecure: bool) -> u16 {
+    serve(Socks5Proxy::new(
+        "127.0.0.1:0".to_string(),
+        Arc::new(CircuitManager::new()),
+        Arc::new(DirectoryClient::from_consensus(consensus(vec![]))),
+        direct_connect_insecure,
+    ))
+    .await
+}
+
+#[tokio::test]
+async fn test_second_proxy_on_a_port_reports_bind_failure() {
+    let address = format!("127.0.0.1:{}", spawn_proxy(false).await);
+    let proxy = Socks5Proxy::new(
+        address.clone(),
+        Arc::new(CircuitManager::new()),
+        Arc::new(DirectoryClient::from_consensus(consensus(vec![]))),
+        false,
+    );
+
+    let result = proxy.run().await;
+    match result {
+        Err(ProxyError::BindFailed { address: failed, source }) => {
+            assert_eq!(failed, address);
+            assert_eq!(source.kind(), std::io::ErrorKind::AddrInUse);
+        }
+        other => panic!("expected BindFailed, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_shutdown_drains_clients_and_closes_circuits() {
+    let guard = MockRelay::spawn().await.unwrap();
+    let middle = MockRelay::spawn().await.unwrap();
+    let exit = MockRelay::spawn().await.unwrap();
+    let directory = Arc::new(DirectoryClient::from_consensus(consensus(vec![
+        guard.descriptor("Guard", guard_flags(), 1000),
+        middle.descriptor("Middle", middle_flags(), 1000),
+        exit.descriptor("Exit", exit_flags(), 1000),
+    ])));
+    let manager = Arc::new(CircuitManager::new());
+    manager.create_circuit(3, false, &directory).await.unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let socks_port = listener.local_addr().unwrap().port();
+    let proxy = Arc::new(
+        Socks5Proxy::new("127.0.0.1:0".to_string(), manager.clone(), directory, false)
+            .with_drain_timeout(Duration::from_millis(300)),
+    );
+    let run = tokio::spawn({
+        let proxy = proxy.clone();
+        async move { proxy.serve(listener).await }
+    });
+    // A client that never sends a request keeps the drain waiting; the
+    // method reply shows the proxy is serving it
+    let mut idle = TcpStream::connect(("127.0.0.1", socks_port)).await.unwrap();
+    idle.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    let mut method = [0u8; 2];
+    idle.read_exact(&mut method).await.unwrap();
+
+    let started = std::time::Instant::now();
+    proxy.shutdown();
+    let result = tokio::time::timeout(Duration::from_secs(5), run).await.unwrap().unwrap();
+    assert!(result.is_ok(), "{:?}", result);
+    assert!(started.elapsed() >= Duration::from_millis(300), "should wait for the open client");
+    assert_eq!(manager.circuit_count().await, 0);
+    assert!(TcpStream::connect(("127.0.0.1", socks_port)).await.is_err(), "should stop accepting");
+}
+
+/// Send a no-auth SOCKS5 BIND for 127.0.0.1:`port`, returning the stream
+/// and the first reply
+async fn socks5_bind(proxy_port: u16, port: u16) -> (TcpStream, [u8; 10]) {
+    let mut stream = TcpStream::connect(("127.0.0.1", proxy_port)).await.unwrap();
+    stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).await.unwrap();
+
+    let mut request = vec![0x05, COMMAND_BIND, 0x00, 0x01, 127, 0, 0, 1];
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await.unwrap();
+    let mut reply = [0u8; 10];
+    stream.read_exact(&mut reply).await.unwrap();
+    (stream, reply)
+}
+
+#[tokio::test]
+async fn test_bind_accepts_a_connection_back_when_connecting_directly() {
+    let (_, refused) = socks5_bind(spawn_proxy(false).await, 21).await;
+    assert_eq!(refused[1], REPLY_COMMAND_NOT_SUPPORTED, "BIND can't work over Tor");
+
+    let (mut client, first) = socks5_bind(spawn_proxy(true).await, 21).await;
+    assert_eq!(first[1], REPLY_SUCCEEDED);
+    assert_eq!(first[3..8], [0x01, 127, 0, 0, 1]);
+    let bound_port = u16::from_be_bytes([first[8], first[9]]);
+    assert_ne!(bound_port, 0);
+
+    let mut peer = TcpStream::connect(("127.0.0.1", bound_port)).await.unwrap();
+    let mut second = [0u8; 10];
+    client.read_exact(&mut second).await.unwrap();
+    assert_eq!(second[1], REPLY_SUCCEEDED);
+    assert_eq!(u16::from_be_bytes([second[8], second[9]]), peer.local_addr().unwrap().port());
+
+    peer.write_all(b"220 ready\r\n").await.unwrap();
+    let mut greeting = [0u8; 11];
+    client.read_exact(&mut greeting).await.unwrap();
+    assert_eq!(&greeting, b"220 ready\r\n");
+    client.write_all(b"QUIT\r\n").await.unwrap();
+    let mut quit = [0u8; 6];
+    peer.read_exact(&mut quit).await.unwrap();
+    assert_eq!(&quit, b"QUIT\r\n");
+}
+
+#[tokio::test]
+async fn test_ipv6_targets_keep_their_compressed_form() {
+    let request = Socks5Request {
+        command: COMMAND_CONNECT,
+        host: "2001:db8::1".to_string(),
+        port: 443,
+        version: 0x05,
+        onion: None,
+    };
+    assert_eq!(request.target(), "[2001:db8::1]:443");
+
+    // ::1 on the wire is sixteen bytes; the proxy has to reach it as [::1]:port
+    let target = TcpListener::bind("[::1]:0").await.unwrap();
+    let target_port = target.local_addr().unwrap().port();
+    let mut stream = TcpStream::connect(("127.0.0.1", spawn_proxy(true).await)).await.unwrap();
+    stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).await.unwrap();
+    let mut connect = vec![0x05, COMMAND_CONNECT, 0x00, 0x04];
+    connect.extend_from_slice(&std::net::Ipv6Addr::LOCALHOST.octets());
+    connect.extend_from_slice(&target_port.to_be_bytes());
+    stream.write_all(&connect).await.unwrap();
+
+    let (mut accepted, _) = tokio::time::timeout(Duration::from_secs(5), target.accept()).await.unwrap().unwrap();
+    let mut reply = [0u8; 10];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[1], REPLY_SUCCEEDED);
+    stream.write_all(b"ping").await.unwrap();
+    let mut ping = [0u8; 4];
+    accepted.read_exact(&mut ping).await.unwrap();
+    assert_eq!(&ping, b"ping");
+}
+
+#[tokio::test]
+async fn test_no_direct_connection_without_opt_in() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_port = target.local_addr().unwrap().port();
+
+    // Relays on a closed local port, so circuit building fails fast
+    let closed_port = free_port().await;
+    let directory = DirectoryClient::from_consensus(consensus(vec![
+        relay("Guard", &format!("127.0.0.1:{}", closed_port), guard_flags(), 1000),
+        relay("Middle", &format!("127.0.0.1:{}", closed_port), middle_flags(), 1000),
+        relay("Exit", &format!("127.0.0.1:{}", closed_port), exit_flags(), 1000),
+    ]));
+
+    let proxy = Socks5Proxy::new(
+        "127.0.0.1:0".to_string(),
+        Arc::new(CircuitManager::new()),
+        Arc::new(directory),
+        false,
+    );
+    let socks_port = serve(proxy).await;
+
+    let status = socks5_connect(socks_port, target_port).await;
+    assert_ne!(status, 0x00, "proxy must not report success without a circuit stream");
+    assert_eq!(status, REPLY_NETWORK_UNREACHABLE);
+
+    let accepted = tokio::time::timeout(Duration::from_millis(500), target.accept()).await;
+    assert!(accepted.is_err(), "target must not receive a direct connection");
+}
+
+#[tokio::test]
+async fn test_refused_direct_connection_reply() {
+    let socks_port = spawn_proxy(true).await;
+    let closed_port = free_port().await;
+
+    assert_eq!(socks5_connect(socks_port, closed_port).await, REPLY_CONNECTION_REFUSED);
+}
+
+#[tokio::test]
+async fn test_direct_relay_counts_bytes() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_port = target.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (mut stream, _) = target.accept().await.unwrap();
+        let mut request = [0u8; 5];
+        stream.read_exact(&mut request).await.unwrap();
+        stream.write_all(b"pong!pong!").await.unwrap();
+    });
+
+    let metrics = Arc::new(Metrics::new());
+    let proxy = Socks5Proxy::new(
+        "127.0.0.1:0".to_string(),
+        Arc::new(CircuitManager::new()),
+        Arc::new(DirectoryClient::from_consensus(consensus(vec![]))),
+        true,
+    )
+    .with_metrics(metrics.clone());
+    let socks_port = serve(proxy).await;
+
+    let mut stream = TcpStream::connect(("127.0.0.1", socks_port)).await.unwrap();
+    stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).await.unwrap();
+    let mut request = vec![0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1];
+    request.extend_from_slice(&target_port.to_be_bytes());
+    stream.write_all(&request).await.unwrap();
+    let mut reply = [0u8; 10];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[1], REPLY_SUCCEEDED);
+
+    stream.write_all(b"ping!").await.unwrap();
+    let mut answer = [0u8; 10];
+    stream.read_exact(&mut answer).await.unwrap();
+    assert_eq!(metrics.bytes_sent.load(Ordering::Relaxed), 5);
+    assert_eq!(metrics.bytes_received.load(Ordering::Relaxed), 10);
+}
+
+#[tokio::test]
+async fn test_unsupported_command_reply() {
+    let socks_port = spawn_proxy(false).await;
+    let mut stream = TcpStream::connect(("127.0.0.1", socks_port)).await.unwrap();
+
+    stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).await.unwrap();
+
+    // UDP ASSOCIATE
+    stream.write_all(&[0x05, 0x03, 0x00, 0x01, 127, 0, 0, 1, 0, 80]).await.unwrap();
+    let mut reply = [0u8; 10];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[1], REPLY_COMMAND_NOT_SUPPORTED);
+}
+
+#[test]
+fn test_end_reason_reply_codes() {
+    assert_eq!(reply_for_end_reason(END_REASON_CONNECTREFUSED), REPLY_CONNECTION_REFUSED);
+    assert_eq!(reply_for_end_reason(END_REASON_RESOLVEFAILED), REPLY_HOST_UNREACHABLE);
+    assert_eq!(reply_for_end_reason(END_REASON_TIMEOUT), REPLY_TTL_EXPIRED);
+}
+
+/// SOCKS5 RESOLVE (0xF0) for `hostname`; returns the reply status and BND.ADDR bytes
+async fn socks5_resolve(proxy_port: u16, hostname: &str) -> (u8, Vec<u8>) {
+    let mut stream = TcpStream::connect(("127.0.0.1", proxy_port)).await.unwrap();
+
+    stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).await.unwrap();
+
+    let mut request = vec![0x05, COMMAND_RESOLVE, 0x00, 0x03, hostname.len() as u8];
+    request.extend_from_slice(hostname.as_bytes());
+    request.extend_from_slice(&[0, 0]);
+    stream.write_all(&request).await.unwrap();
+
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await.unwrap();
+    let mut address = vec![0u8; if header[3] == 0x04 { 16 } else { 4 }];
+    stream.read_exact(&mut address).await.unwrap();
+    let mut port = [0u8; 2];
+    stream.read_exact(&mut port).await.unwrap();
+    (header[1], address)
+}
+
+#[tokio::test]
+async fn test_resolve_command_uses_exit() {
+    let guard = MockRelay::spawn().await.unwrap();
+    let middle = MockRelay::spawn().await.unwrap();
+    let exit = MockRelay::spawn().await.unwrap();
+    exit.add_host("hidden.test", "10.9.8.7".parse().unwrap());
+    let directory = DirectoryClient::from_consensus(consensus(vec![
+        guard.descriptor("Guard", guard_flags(), 1000),
+        middle.descriptor("Middle", middle_flags(), 1000),
+        exit.descriptor("Exit", exit_flags(), 1000),
+    ]));
+
+    let proxy = Socks5Proxy::new(
+        "127.0.0.1:0".to_string(),
+        Arc::new(CircuitManager::new()),
+        Arc::new(directory),
+        false,
+    );
+    let socks_port = serve(proxy).await;
+
+    assert_eq!(socks5_resolve(socks_port, "hidden.test").await, (REPLY_SUCCEEDED, vec![10, 9, 8, 7]));
+    assert_eq!(socks5_resolve(socks_port, "unknown.test").await.0, REPLY_HOST_UNREACHABLE);
+}
+
+/// Send a raw SOCKS4 request and return the reply's status byte and the stream
+async fn socks4_request(proxy_port: u16, request: &[u8]) -> (u8, TcpStream) {
+    let mut stream = TcpStream::connect(("127.0.0.1", proxy_port)).await.unwrap();
+    stream.write_all(request).await.unwrap();
+    let mut reply = [0u8; 8];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[0], 0x00);
+    (reply[1], stream)
+}
+
+#[tokio::test]
+async fn test_socks4_and_socks4a_connect() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_port = target.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = target.accept().await {
+            let mut request = [0u8; 4];
+            stream.read_exact(&mut request).await.unwrap();
+            stream.write_all(&request).await.unwrap();
+        }
+    });
+    let socks_port = spawn_proxy(true).await;
+
+    // SOCKS4: destination IP, userid "alice"
+    let mut request = vec![0x04, 0x01];
+    request.extend_from_slice(&target_port.to_be_bytes());
+    request.extend_from_slice(&[127, 0, 0, 1]);
+    request.extend_from_slice(b"alice\0");
+    let (status, mut stream) = socks4_request(socks_port, &request).await;
+    assert_eq!(status, SOCKS4_REQUEST_GRANTED);
+    stream.write_all(b"ping").await.unwrap();
+    let mut echoed = [0u8; 4];
+    stream.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"ping");
+
+    // SOCKS4a: IP 0.0.0.1, empty userid, then the hostname
+    let mut request = vec![0x04, 0x01];
+    request.extend_from_slice(&target_port.to_be_bytes());
+    request.extend_from_slice(&[0, 0, 0, 1, 0]);
+    request.extend_from_slice(b"127.0.0.1\0");
+    assert_eq!(socks4_request(socks_port, &request).await.0, SOCKS4_REQUEST_GRANTED);
+
+    // A refused connection and BIND (CD=2) are both plain rejections
+    let closed_port = free_port().await;
+    let mut request = vec![0x04, 0x01];
+    request.extend_from_slice(&closed_port.to_be_bytes());
+    request.extend_from_slice(&[127, 0, 0, 1, 0]);
+    assert_eq!(socks4_request(socks_port, &request).await.0, SOCKS4_REQUEST_REJECTED);
+    let mut request = vec![0x04, 0x02];
+    request.extend_from_slice(&target_port.to_be_bytes());
+    request.extend_from_slice(&[127, 0, 0, 1, 0]);
+    assert_eq!(socks4_request(socks_port, &request).await.0, SOCKS4_REQUEST_REJECTED);
+}
+
+/// SOCKS5 CONNECT to `hostname`:`port` by name; returns the reply status
+/// and the stream
+async fn socks5_open(proxy_port: u16, hostname: &str, port: u16) -> (u8, TcpStream) {
+    let mut stream = TcpStream::connect(("127.0.0.1", proxy_port)).await.unwrap();
+    stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).await.unwrap();
+
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, hostname.len() as u8];
+    request.extend_from_slice(hostname.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await.unwrap();
+    let mut reply = [0u8; 10];
+    stream.read_exact(&mut reply).await.unwrap();
+    (reply[1], stream)
+}
+
+/// SOCKS5 CONNECT to `hostname`:80 by name; returns the reply status
+async fn socks5_connect_host(proxy_port: u16, hostname: &str) -> u8 {
+    socks5_open(proxy_port, hostname, 80).await.0
+}
+
+#[tokio::test]
+async fn test_connect_relays_through_an_exit_stream() {
+    let guard = MockRelay::spawn().await.unwrap();
+    let middle = MockRelay::spawn().await.unwrap();
+    let exit = MockRelay::spawn().await.unwrap();
+    // More than one DATA cell's worth each way
+    let page: Vec<u8> = (0..1500u32).map(|i| b'a' + (i % 26) as u8).collect();
+    exit.add_site("www.example.test", 80, &page);
+    let directory = DirectoryClient::from_consensus(consensus(vec![
+        guard.descriptor("Guard", guard_flags(), 1000),
+        middle.descriptor("Middle", middle_flags(), 1000),
+        exit.descriptor("Exit", exit_flags(), 1000),
+    ]));
+    let metrics = Arc::new(Metrics::new());
+    let proxy = Socks5Proxy::new(
+        "127.0.0.1:0".to_string(),
+        Arc::new(CircuitManager::new()),
+        Arc::new(directory),
+        false,
+    )
+    .with_metrics(metrics.clone());
+    let socks_port = serve(proxy).await;
+
+    // The site answers the first DATA cell and ends the stream
+    let (status, mut stream) = socks5_open(socks_port, "www.example.test", 80).await;
+    assert_eq!(status, REPLY_SUCCEEDED);
+    stream.write_all(b"GET / HTTP/1.0\r\n\r\n").await.unwrap();
+    let mut received = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut received)).await.unwrap().unwrap();
+    assert_eq!(received, page);
+
+    // Anywhere else the mock exit echoes, in cells of at most RELAY_PAYLOAD_LEN
+    let (status, mut stream) = socks5_open(socks_port, "echo.test", 7).await;
+    assert_eq!(status, REPLY_SUCCEEDED);
+    let data_cells = exit.data_cells();
+    stream.write_all(&page).await.unwrap();
+    let mut echoed = vec![0u8; page.len()];
+    tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut echoed)).await.unwrap().unwrap();
+    assert_eq!(echoed, page);
+    assert!(exit.data_cells() - data_cells >= page.len().div_ceil(RELAY_PAYLOAD_LEN));
+    assert!(metrics.bytes_received.load(Ordering::Relaxed) >= 2 * page.len() as u64);
+}
+
+#[tokio::test]
+async fn test_sensitive_hosts_get_longer_circuits() {
+    let relays = [
+        MockRelay::spawn().await.unwrap(),
+        MockRelay::spawn().await.unwrap(),
+        MockRelay::spawn().await.unwrap(),
+        MockRelay::spawn().await.unwrap(),
+    ];
+    let directory = DirectoryClient::from_consensus(consensus(vec![
+        relays[0].descriptor("Guard", guard_flags(), 1000),
+        relays[1].descriptor("MiddleA", middle_flags(), 1000),
+        relays[2].descriptor("MiddleB", middle_flags(), 1000),
+        relays[3].descriptor("Exit", exit_flags(), 1000),
+    ]));
+    let handshakes = || relays.iter().map(|relay| relay.handshakes()).sum::<usize>();
+
+    let proxy = Socks5Proxy::new(
+        "127.0.0.1:0".to_string(),
+        Arc::new(CircuitManager::new()),
+        Arc::new(directory),
+        false,
+    )
+    .with_circuit_length(CircuitLength::new(vec![".secret.test".to_string()], 4));
+    let socks_port = serve(proxy).await;
+
+    assert_eq!(socks5_connect_host(socks_port, "www.secret.test").await, REPLY_SUCCEEDED);
+    assert_eq!(handshakes(), 4);
+    assert_eq!(socks5_connect_host(socks_port, "example.com").await, REPLY_SUCCEEDED);
+    assert_eq!(handshakes(), 4 + 3);
+}
+
+#[tokio::test]
+async fn test_onion_addresses_never_reach_an_exit() {
+    let address = "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+    let onion = OnionAddress::parse(&format!("www.{}", address.to_uppercase())).unwrap();
+    assert_eq!(onion.to_string(), address);
+    // One flipped character breaks the checksum; v2 addresses are refused
+    assert!(OnionAddress::parse("duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczae.onion").is_err());
+    assert!(OnionAddress::parse("3g2upl4pq6kufc4m.onion").is_err());
+
+    let guard = MockRelay::spawn().await.unwrap();
+    let middle = MockRelay::spawn().await.unwrap();
+    let exit = MockRelay::spawn().await.unwrap();
+    let directory = DirectoryClient::from_consensus(consensus(vec![
+        guard.descriptor("Guard", guard_flags(), 1000),
+        middle.descriptor("Middle", middle_flags(), 1000),
+        exit.descriptor("Exit", exit_flags(), 1000),
+    ]));
+    let proxy = Socks5Proxy::new(
+        "127.0.0.1:0".to_string(),
+        Arc::new(CircuitManager::new()),
+        Arc::new(directory),
+        false,
+    );
+    let socks_port = serve(proxy).await;
+
+    assert_eq!(socks5_connect_host(socks_port, address).await, REPLY_HOST_UNREACHABLE);
+    assert_eq!(socks5_connect_host(socks_port, "not-a-real-address.onion").await, REPLY_HOST_UNREACHABLE);
+    assert_eq!(socks5_resolve(socks_port, address).await.0, REPLY_HOST_UNREACHABLE);
+    assert_eq!(exit.connections(), 0);
+}
@@ -0,0 +1,339 @@
+// tests/unit/cells_tests.rs
+use ed25519_dalek::SigningKey;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+use tor_client::circuit::sendme::{encode_sendme_v1, parse_sendme_digest, SENDME_VERSION_AUTHENTICATED};
+use tor_client::crypto::tor_cert::{
+    Ed25519Cert, CERT_KEY_TYPE_ED25519, CERT_KEY_TYPE_SHA256_OF_X509, CERT_TYPE_IDENTITY_V_SIGNING,
+    CERT_TYPE_SIGNING_V_TLS_CERT,
+};
+use tor_client::network::cells::{
+    Cell, CellCommand, CellError, Create2Cell, Created2Cell, Extend2Cell, Extended2Cell, LinkSpecifier, RelayCell,
+    CELL_LEN, CELL_PAYLOAD_LEN, HANDSHAKE_TYPE_NTOR, HANDSHAKE_TYPE_NTOR_V3, RELAY_COMMAND_DATA, RELAY_PAYLOAD_LEN,
+};
+use tor_client::crypto::rsa::pem_decode;
+use tor_client::crypto::tor_cert::{CERT_TYPE_RSA_ED25519_CROSSCERT, CERT_TYPE_RSA_IDENTITY};
+use tor_client::network::link::{
+    circ_id_len, encode_netinfo, parse_certs, parse_netinfo, verify_certs, CellCodec, RelayIdentity,
+};
+
+#[test]
+fn test_relay_cell_rejects_data_larger_than_one_cell() {
+    let fits = RelayCell::new(RELAY_COMMAND_DATA, 1, vec![7; RELAY_PAYLOAD_LEN]);
+    let body = fits.to_bytes().unwrap();
+    assert_eq!(RelayCell::from_bytes(&body).unwrap(), fits);
+
+    let oversized = RelayCell::new(RELAY_COMMAND_DATA, 1, vec![7; RELAY_PAYLOAD_LEN + 1]);
+    match oversized.to_bytes() {
+        Err(CellError::TooLarge { max, actual }) => {
+            assert_eq!(max, RELAY_PAYLOAD_LEN);
+            assert_eq!(actual, RELAY_PAYLOAD_LEN + 1);
+        }
+        other => panic!("expected TooLarge, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_create2_rejects_oversized_handshake_data() {
+    let fits = Create2Cell::new(HANDSHAKE_TYPE_NTOR, vec![7; CELL_PAYLOAD_LEN - 4]);
+    assert_eq!(fits.to_bytes().unwrap().len(), CELL_PAYLOAD_LEN);
+
+    let oversized = Create2Cell::new(HANDSHAKE_TYPE_NTOR, vec![7; CELL_PAYLOAD_LEN]);
+    match oversized.to_bytes() {
+        Err(CellError::TooLarge { max, actual }) => {
+            assert_eq!(max, CELL_PAYLOAD_LEN - 4);
+            assert_eq!(actual, CELL_PAYLOAD_LEN);
+        }
+        other => panic!("expected TooLarge, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_create2_carries_handshake_type() {
+    let create2 = Create2Cell::new(HANDSHAKE_TYPE_NTOR_V3, vec![1, 2, 3]);
+    let bytes = create2.to_bytes().unwrap();
+    assert_eq!(&bytes[..4], &[0, 3, 0, 3]);
+    assert_eq!(&bytes[4..], &[1, 2, 3]);
+
+    let parsed = Create2Cell::from_bytes(&bytes).unwrap();
+    assert_eq!(parsed.handshake_type, HANDSHAKE_TYPE_NTOR_V3);
+    assert_eq!(parsed.handshake_data, vec![1, 2, 3]);
+}
+
+#[test]
+fn test_created2_rejects_unsupported_handshake_type() {
+    let reply = [&64u16.to_be_bytes()[..], &[0u8; 64]].concat();
+    assert!(Created2Cell::from_bytes(HANDSHAKE_TYPE_NTOR, &reply).is_ok());
+    assert!(matches!(
+        Created2Cell::from_bytes(HANDSHAKE_TYPE_NTOR_V3, &reply),
+        Err(CellError::InvalidHandshake(_))
+    ));
+}
+
+#[test]
+fn test_extend2_link_specifiers_roundtrip() {
+    let specs = vec![
+        LinkSpecifier::Ipv4("198.51.100.7:9001".parse().unwrap()),
+        LinkSpecifier::Ipv6("[2001:db8::7]:443".parse().unwrap()),
+        LinkSpecifier::RsaId([0x11; 20]),
+        LinkSpecifier::Ed25519Id([0x22; 32]),
+    ];
+    let extend2 = Extend2Cell::new(specs.clone(), Create2Cell::new(HANDSHAKE_TYPE_NTOR, vec![5; 84]));
+    let bytes = extend2.to_bytes().unwrap();
+    assert_eq!(bytes[0], 4);
+    assert_eq!(&bytes[1..9], &[0, 6, 198, 51, 100, 7, 0x23, 0x29]);
+    assert_eq!(bytes.len(), 1 + 8 + 20 + 22 + 34 + 4 + 84);
+
+    let parsed = Extend2Cell::from_bytes(&bytes).unwrap();
+    assert_eq!(parsed.link_specifiers, specs);
+    assert_eq!(parsed.create2.handshake_type, HANDSHAKE_TYPE_NTOR);
+    assert_eq!(parsed.create2.handshake_data, vec![5; 84]);
+
+    // Unknown specifier types pass through; known ones must have the right length
+    let unknown = [1, 9, 2, 0xab, 0xcd, 0, 2, 0, 0];
+    assert_eq!(
+        Extend2Cell::from_bytes(&unknown).unwrap().link_specifiers,
+        vec![LinkSpecifier::Unrecognized { ls_type: 9, data: vec![0xab, 0xcd] }]
+    );
+    assert!(matches!(Extend2Cell::from_bytes(&[1, 0, 4, 1, 2, 3, 4, 0, 2, 0, 0]), Err(CellError::InvalidHandshake(_))));
+    assert!(matches!(Extend2Cell::from_bytes(&bytes[..40]), Err(CellError::Truncated { .. })));
+}
+
+#[test]
+fn test_extended2_roundtrip() {
+    let extended2 = Extended2Cell::new(vec![3; 64]);
+    let bytes = extended2.to_bytes().unwrap();
+    assert_eq!(&bytes[..2], &[0, 64]);
+    assert_eq!(Extended2Cell::from_bytes(&bytes).unwrap(), extended2);
+    assert_eq!(extended2.created2(HANDSHAKE_TYPE_NTOR).unwrap().auth, [3; 32]);
+    assert!(Extended2Cell::from_bytes(&bytes[..30]).is_err());
+}
+
+#[test]
+fn test_netinfo_roundtrip() {
+    let other: IpAddr = "203.0.113.5".parse().unwrap();
+    let mine: Vec<IpAddr> = vec!["198.51.100.7".parse().unwrap(), "2001:db8::7".parse().unwrap()];
+    let parsed = parse_netinfo(&encode_netinfo(1_700_000_000, other, &mine)).unwrap();
+    assert_eq!(parsed.timestamp, 1_700_000_000);
+    assert_eq!(parsed.other_address, Some(other));
+    assert_eq!(parsed.my_addresses, mine);
+
+    assert!(parse_netinfo(&[0, 0, 0, 0, 4, 4, 127]).is_err());
+}
+
+#[test]
+fn test_certs_cell_types() {
+    // Two certificates: type 1 (3 bytes), type 7 (empty)
+    let payload = [2, 1, 0, 3, 0xaa, 0xbb, 0xcc, 7, 0, 0];
+    assert_eq!(parse_certs(&payload).unwrap(), vec![(1, vec![0xaa, 0xbb, 0xcc]), (7, vec![])]);
+    assert!(parse_certs(&payload[..5]).is_err());
+}
+
+/// A relay's identity->signing and signing->TLS certificates, both valid until `expires`
+fn link_certs(identity: &SigningKey, tls_cert: &[u8], expires: SystemTime) -> Vec<(u8, Vec<u8>)> {
+    let signing_key = SigningKey::from_bytes(&[9; 32]);
+    let mut tls_digest = [0u8; 32];
+    tls_digest.copy_from_slice(ring::digest::digest(&ring::digest::SHA256, tls_cert).as_ref());
+    vec![
+        (
+            CERT_TYPE_IDENTITY_V_SIGNING,
+            Ed25519Cert::sign(
+                CERT_TYPE_IDENTITY_V_SIGNING,
+                CERT_KEY_TYPE_ED25519,
+                signing_key.verifying_key().as_bytes(),
+                expires,
+                identity,
+            ),
+        ),
+        (
+            CERT_TYPE_SIGNING_V_TLS_CERT,
+            Ed25519Cert::sign(CERT_TYPE_SIGNING_V_TLS_CERT, CERT_KEY_TYPE_SHA256_OF_X509, &tls_digest, expires, &signing_key),
+        ),
+    ]
+}
+
+#[test]
+fn test_certs_chain_to_ed25519_identity() {
+    let identity = SigningKey::from_bytes(&[1; 32]);
+    let now = SystemTime::now();
+    let certs = link_certs(&identity, b"tls certificate", now + Duration::from_secs(86400));
+    let expected = RelayIdentity { rsa: [0; 20], ed25519: Some(identity.verifying_key().to_bytes()) };
+
+    let verified = verify_certs(&certs, Some(b"tls certificate"), &expected, now).unwrap();
+    assert_eq!(verified, identity.verifying_key().to_bytes());
+    // Without TLS there's no certificate to compare against
+    assert!(verify_certs(&certs, None, &expected, now).is_ok());
+
+    assert!(verify_certs(&certs, Some(b"another certificate"), &expected, now).is_err());
+    assert!(verify_certs(&certs, Some(b"tls certificate"), &expected, now + Duration::from_secs(2 * 86400)).is_err());
+    assert!(verify_certs(&certs[..1], Some(b"tls certificate"), &expected, now).is_err());
+
+    // Swapping in another signing key breaks the identity's signature
+    let mut forged = certs.clone();
+    forged[0].1[10] ^= 1;
+    assert!(verify_certs(&forged, Some(b"tls certificate"), &expected, now).is_err());
+
+    // A sound chain for some other relay's identity
+    let someone_else = RelayIdentity { rsa: [0; 20], ed25519: Some([5; 32]) };
+    assert!(verify_certs(&certs, Some(b"tls certificate"), &someone_else, now).is_err());
+    // Nothing to check the identity against
+    let unknown = RelayIdentity { rsa: [0; 20], ed25519: None };
+    assert!(verify_certs(&certs, Some(b"tls certificate"), &unknown, now).is_err());
+}
+
+#[test]
+fn test_rsa_identity_cross_certifies_ed25519_identity() {
+    // An RSA identity certificate and cross-certificate made with openssl for
+    // the Ed25519 identity below, valid until 2099
+    let fixture = include_str!("../fixtures/relay-rsa-identity");
+    let rsa_cert = pem_decode(fixture, "CERTIFICATE").unwrap();
+    let crosscert = pem_decode(fixture, "RSA ED25519 CROSS CERTIFICATE").unwrap();
+    let rsa_id: [u8; 20] = hex::decode("F1F4CAA2C4358827013C5C195BAF36825482BFF5").unwrap().try_into().unwrap();
+    let identity = SigningKey::from_bytes(&[7; 32]);
+
+    let now = SystemTime::now();
+    let mut certs = link_certs(&identity, b"tls certificate", now + Duration::from_secs(86400));
+    certs.push((CERT_TYPE_RSA_IDENTITY, rsa_cert));
+    certs.push((CERT_TYPE_RSA_ED25519_CROSSCERT, crosscert.clone()));
+
+    // A bridge, say, whose Ed25519 identity we don't know
+    let by_fingerprint = RelayIdentity { rsa: rsa_id, ed25519: None };
+    let verified = verify_certs(&certs, Some(b"tls certificate"), &by_fingerprint, now).unwrap();
+    assert_eq!(verified, identity.verifying_key().to_bytes());
+    let both = RelayIdentity { rsa: rsa_id, ed25519: Some(verified) };
+    assert!(verify_certs(&certs, Some(b"tls certificate"), &both, now).is_ok());
+
+    let other_fingerprint = RelayIdentity { rsa: [1; 20], ed25519: None };
+    assert!(verify_certs(&certs, Some(b"tls certificate"), &other_fingerprint, now).is_err());
+    let mut tampered = certs.clone();
+    let last = tampered.last_mut().unwrap();
+    let end = last.1.len() - 1;
+    last.1[end] ^= 1;
+    assert!(verify_certs(&tampered, Some(b"tls certificate"), &by_fingerprint, now).is_err());
+
+    // The cross-certificate vouches for a different Ed25519 identity
+    let impostor = SigningKey::from_bytes(&[8; 32]);
+    let mut certs = link_certs(&impostor, b"tls certificate", now + Duration::from_secs(86400));
+    certs.push((CERT_TYPE_RSA_IDENTITY, pem_decode(fixture, "CERTIFICATE").unwrap()));
+    certs.push((CERT_TYPE_RSA_ED25519_CROSSCERT, crosscert));
+    assert!(verify_certs(&certs, Some(b"tls certificate"), &by_fingerprint, now).is_err());
+}
+
+#[test]
+fn test_sendme_v1_body_round_trips() {
+    let digest = [7u8; 20];
+    let body = encode_sendme_v1(&digest);
+    assert_eq!(body.len(), 23);
+    assert_eq!(body[0], SENDME_VERSION_AUTHENTICATED);
+    assert_eq!(parse_sendme_digest(&body), Some(digest));
+    // Version 0 SENDMEs have no digest
+    assert_eq!(parse_sendme_digest(&[]), None);
+    assert_eq!(parse_sendme_digest(&body[..10]), None);
+}
+
+#[test]
+fn test_cell_commands_round_trip() {
+    let known: Vec<u8> = (0..=12).chain(128..=132).collect();
+    for byte in 0..=u8::MAX {
+        match CellCommand::from_u8(byte) {
+            Some(command) => {
+                assert!(known.contains(&byte), "{} isn't a cell command", byte);
+                assert_eq!(command.as_u8(), byte);
+                assert_eq!(command.is_variable_length(), byte == 7 || byte >= 128);
+            }
+            None => assert!(!known.contains(&byte), "{} should be a cell command", byte),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_codec_skips_cells_with_unknown_commands() {
+    let codec = CellCodec::for_version(5);
+    let cell = Cell::new(9, CellCommand::Create2, vec![1, 2, 3]);
+    let mut unknown = codec.encode(&cell).unwrap();
+    assert_eq!(unknown.len(), CELL_LEN);
+    unknown[4] = 100;
+    let stream = [unknown, codec.encode(&cell).unwrap()].concat();
+
+    let parsed = codec.read_cell(&mut stream.as_slice()).await.unwrap();
+    assert_eq!((parsed.circ_id, parsed.command), (9, CellCommand::Create2));
+    assert_eq!(&parsed.payload[..3], [1, 2, 3]);
+}
+
+#[tokio::test]
+async fn test_variable_length_cells_are_not_padded() {
+    let codec = CellCodec::for_version(5);
+    let versions = Cell::new(0, CellCommand::Versions, vec![0, 4, 0, 5]);
+    let bytes = codec.encode(&versions).unwrap();
+    assert_eq!(bytes, vec![0, 0, 0, 0, 7, 0, 4, 0, 4, 0, 5]);
+
+    // A CERTS cell longer than a fixed-length payload, followed by a fixed-length cell
+    let certs = Cell::new(0, CellCommand::Certs, vec![0xcc; 700]);
+    let padding = Cell::new(0, CellCommand::Padding, Vec::new());
+    let stream = [codec.encode(&certs).unwrap(), codec.encode(&padding).unwrap()].concat();
+    assert_eq!(stream.len(), 7 + 700 + CELL_LEN);
+
+    let mut reader = stream.as_slice();
+    let parsed = codec.read_cell(&mut reader).await.unwrap();
+    assert_eq!(parsed.command, CellCommand::Certs);
+    assert_eq!(parsed.payload, vec![0xcc; 700]);
+    let next = codec.read_cell(&mut reader).await.unwrap();
+    assert_eq!(next.command, CellCommand::Padding);
+    assert_eq!(next.payload.len(), CELL_PAYLOAD_LEN);
+
+    assert!(codec.read_cell(&mut &bytes[..9]).await.is_err(), "a truncated cell");
+    let too_long = Cell::new(0, CellCommand::VPadding, vec![0; 70_000]);
+    assert!(codec.encode(&too_long).is_err());
+}
+
+#[tokio::test]
+async fn test_codec_frames_with_the_link_version_circ_id_width() {
+    let cell = Cell::new(0x1234, CellCommand::Create2, vec![7]);
+    let v3 = CellCodec::for_version(3);
+    let bytes = v3.encode(&cell).unwrap();
+    assert_eq!(bytes.len(), 2 + 1 + CELL_PAYLOAD_LEN);
+    assert_eq!(bytes[..3], [0x12, 0x34, 10]);
+    let parsed = v3.read_cell(&mut bytes.as_slice()).await.unwrap();
+    assert_eq!((parsed.circ_id, parsed.command), (0x1234, CellCommand::Create2));
+
+    assert_eq!(CellCodec::for_version(4).encode(&cell).unwrap()[..5], [0, 0, 0x12, 0x34, 10]);
+    assert!(v3.encode(&Cell::new(0x8000_0001, CellCommand::Create2, vec![])).is_err());
+}
+
+#[test]
+fn test_circ_id_width_follows_link_version() {
+    assert_eq!(circ_id_len(1), 2);
+    assert_eq!(circ_id_len(3), 2);
+    assert_eq!(circ_id_len(4), 4);
+    assert_eq!(circ_id_len(5), 4);
+}
+
+#[tokio::test]
+async fn test_cell_codec_reads_whole_cells_from_short_reads() {
+    let codec = CellCodec::for_version(5);
+    let fixed = Cell::new(0x8000_0001, CellCommand::Relay, vec![9; CELL_PAYLOAD_LEN]);
+    let var = Cell::new(0, CellCommand::VPadding, vec![1, 2, 3]);
+    let fixed_bytes = codec.encode(&fixed).unwrap();
+    let var_bytes = codec.encode(&var).unwrap();
+    assert_eq!(fixed_bytes.len(), CELL_LEN);
+    assert_eq!(var_bytes.len(), 4 + 1 + 2 + 3);
+
+    // The fixed-length cell dribbles in; the variable-length one arrives
+    // in the same read as the fixed-length cell's tail
+    let mut tail = fixed_bytes[300..].to_vec();
+    tail.extend_from_slice(&var_bytes);
+    let mut reader = tokio_test::io::Builder::new()
+        .read(&fixed_bytes[..3])
+        .read(&fixed_bytes[3..300])
+        .read(&tail)
+        .build();
+
+    let cell = codec.read_cell(&mut reader).await.unwrap();
+    assert_eq!((cell.circ_id, cell.command, cell.payload), (fixed.circ_id, fixed.command, fixed.payload));
+    let cell = codec.read_cell(&mut reader).await.unwrap();
+    assert_eq!((cell.circ_id, cell.command, cell.payload), (var.circ_id, var.command, var.payload));
+    assert!(codec.read_cell(&mut reader).await.is_err(), "no third cell");
+
+    // VERSIONS goes out with 2-byte circuit IDs, so wider ones don't fit
+    assert!(CellCodec::versions().encode(&Cell::new(0x1_0000, CellCommand::Versions, vec![])).is_err());
+}
@@ -0,0 +1,129 @@
+// tests/unit/config_tests.rs
+use tor_client::config::{ConfigError, SOCKS_PORT_ENV};
+use std::time::Duration;
+use tor_client::{TlsBackend, TorConfig};
+
+fn temp_path(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("tor-client-test-{}-{}", rand::random::<u64>(), name))
+}
+
+#[test]
+fn test_config_file_then_environment_override_defaults() {
+    let data_dir = temp_path("data");
+    let path = temp_path("torrc.toml");
+    std::fs::write(
+        &path,
+        format!(
+            "data_directory = {:?}\nsocks_port = 9150\ncontrol_port = 9151\nentry_guards = [\"$0102030405060708090A0B0C0D0E0F1011121314\"]\n",
+            data_dir.to_string_lossy()
+        ),
+    )
+    .unwrap();
+
+    let config = TorConfig::from_toml_file(&path).unwrap();
+    assert_eq!(config.socks_port, 9150);
+    assert_eq!(config.control_port, 9151);
+    assert_eq!(config.entry_guards, ["$0102030405060708090A0B0C0D0E0F1011121314"]);
+    assert_eq!(config.directory_authorities, TorConfig::default().directory_authorities);
+    assert!(data_dir.is_dir(), "the data directory should have been created");
+
+    // A directory can't be made under a regular file
+    let blocked = temp_path("blocked.toml");
+    std::fs::write(&blocked, format!("data_directory = {:?}\n", path.join("data").to_string_lossy())).unwrap();
+    let result = TorConfig::from_toml_file(&blocked);
+    assert!(matches!(result, Err(ConfigError::DataDirectoryNotWritable(..))), "got {:?}", result);
+
+    // The only test in this binary touching the environment; the others fail
+    // before it's read
+    std::env::set_var(SOCKS_PORT_ENV, "9250");
+    let from_file = TorConfig::from_toml_file(&path).unwrap();
+    let from_env = TorConfig::from_env().unwrap();
+    std::env::set_var(SOCKS_PORT_ENV, "70000");
+    let out_of_range = TorConfig::from_env();
+    std::env::remove_var(SOCKS_PORT_ENV);
+
+    assert_eq!(from_file.socks_port, 9250);
+    assert_eq!(from_file.control_port, 9151);
+    assert_eq!(from_env.socks_port, 9250);
+    assert_eq!(from_env.control_port, 9051);
+    assert!(matches!(out_of_range, Err(ConfigError::InvalidPort("socks_port", _))), "got {:?}", out_of_range);
+}
+
+#[test]
+fn test_config_file_is_validated() {
+    let path = temp_path("torrc.toml");
+
+    std::fs::write(&path, "control_port = 0\n").unwrap();
+    let result = TorConfig::from_toml_file(&path);
+    assert!(matches!(result, Err(ConfigError::InvalidPort("control_port", _))), "got {:?}", result);
+
+    std::fs::write(&path, "sox_port = 9050\n").unwrap();
+    assert!(matches!(TorConfig::from_toml_file(&path), Err(ConfigError::Parse(_))));
+
+    assert!(matches!(TorConfig::from_toml_file(temp_path("missing.toml")), Err(ConfigError::Io(..))));
+}
+
+#[test]
+fn test_toml_config_deserializes_with_defaults_for_missing_keys() {
+    let config = TorConfig::from_toml_str(
+        r#"
+        socks_port = 19050
+        socks_bind_address = "0.0.0.0"
+        control_port = 19051
+        directory_authorities = ["tor-collector", "http://128.31.0.34:9131"]
+        tls_backend = "native-tls"
+        handshake_read_timeout = "30s"
+        max_circuit_dirtiness = 1200
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(config.socks_port, 19050);
+    assert_eq!(config.socks_bind_address, std::net::IpAddr::from([0, 0, 0, 0]));
+    assert_eq!(config.control_port, 19051);
+    assert_eq!(config.directory_authorities, ["tor-collector", "http://128.31.0.34:9131"]);
+    assert_eq!(config.tls_backend, TlsBackend::NativeTls);
+    assert_eq!(config.handshake_read_timeout, Duration::from_secs(30));
+    assert_eq!(config.max_circuit_dirtiness, Duration::from_secs(1200));
+    assert_eq!(config.hop_retries, TorConfig::default().hop_retries);
+}
+
+#[test]
+fn test_config_cannot_turn_off_link_tls() {
+    // Without TLS nothing ties the link to the relay's identity key
+    assert!(TorConfig::from_toml_str("tls_backend = \"none\"\n").is_err());
+    assert!("none".parse::<TlsBackend>().is_err());
+    assert_eq!("native-tls".parse::<TlsBackend>(), Ok(TlsBackend::NativeTls));
+}
+
+#[tokio::test]
+async fn test_start_uses_max_circuit_build_time_as_the_build_timeout() {
+    let config = TorConfig { max_circuit_build_time: Duration::from_secs(45), ..TorConfig::test_config() };
+    let client = tor_client::TorClient::start(config).await.unwrap();
+    assert_eq!(client.metrics().to_json()["circuit_build_timeout_ms"], 45_000);
+}
+
+#[tokio::test]
+async fn test_start_rejects_an_invalid_config_and_binds_socks_to_loopback() {
+    let result = tor_client::TorClient::start(TorConfig::default()).await;
+    assert!(matches!(result, Err(tor_client::TorError::Config(_))), "expected a config error");
+
+    let valid = TorConfig::test_config();
+    assert!(valid.validate().is_ok());
+    let client = tor_client::TorClient::start(valid.clone()).await.unwrap();
+    assert_eq!(client.socks5_proxy.bind_address(), format!("127.0.0.1:{}", valid.socks_port));
+
+    let colliding = TorConfig { control_port: valid.socks_port, ..valid.clone() };
+    assert!(matches!(colliding.validate(), Err(ConfigError::PortCollision(port)) if port == valid.socks_port));
+
+    let bad_source = TorConfig {
+        directory_authorities: vec!["tor-collector".to_string(), "authority1.torproject.org:80".to_string()],
+        ..valid.clone()
+    };
+    let result = bad_source.validate();
+    assert!(
+        matches!(&result, Err(ConfigError::InvalidDirectorySource(source)) if source == "authority1.torproject.org:80"),
+        "got {:?}",
+        result
+    );
+}
@@ -1,26 +1,146 @@
-
 // tests/unit/crypto_tests.rs
-#[cfg(test)]
-mod tests {
-    use super::*;
-    
-    #[test]
-    fn test_onion_encryption_roundtrip() {
-        let mut crypto = OnionCrypto::new().unwrap();
-        let plaintext = b"test message";
-        
-        let encrypted = crypto.encrypt_forward(plaintext).unwrap();
-        let decrypted = crypto.decrypt_forward(&encrypted).unwrap();
-        
-        assert_eq!(plaintext, decrypted.as_slice());
+use tor_client::crypto::rsa::RsaPublicKey;
+use tor_client::crypto::{ntor_handshake, ntor_server_handshake, CryptoError, OnionCrypto};
+use tor_client::security::constant_time_compare;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroize;
+
+#[test]
+fn test_ntor_rejects_short_onion_key() {
+    let client_secret = StaticSecret::random_from_rng(rand::rngs::OsRng);
+    let server_public = PublicKey::from(&StaticSecret::random_from_rng(rand::rngs::OsRng));
+
+    let result = ntor_handshake(&client_secret, &server_public, &[0u8; 32], &[0u8; 20], &[0u8; 20]);
+
+    match result {
+        Err(CryptoError::NtorError(message)) => assert_eq!(message, "onion key must be 32 bytes"),
+        other => panic!("expected an onion key error, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_ntor_rejects_low_order_relay_keys() {
+    let client_secret = StaticSecret::random_from_rng(rand::rngs::OsRng);
+    let onion_key = PublicKey::from(&StaticSecret::random_from_rng(rand::rngs::OsRng));
+    let honest = PublicKey::from(&StaticSecret::random_from_rng(rand::rngs::OsRng));
+
+    // The all-zero point, and u = 1, a point of order 4
+    let mut order_four = [0u8; 32];
+    order_four[0] = 1;
+    for low_order in [[0u8; 32], order_four] {
+        let bad = PublicKey::from(low_order);
+        let as_y = ntor_handshake(&client_secret, &bad, &[0u8; 32], &[0u8; 20], onion_key.as_bytes());
+        assert!(matches!(as_y, Err(CryptoError::NtorError(_))));
+        let as_b = ntor_handshake(&client_secret, &honest, &[0u8; 32], &[0u8; 20], &low_order);
+        match as_b {
+            Err(CryptoError::NtorError(message)) => assert!(message.contains("all-zero shared secret")),
+            other => panic!("expected a low-order key error, got {:?}", other.map(|_| ())),
+        }
+    }
+}
+
+#[test]
+fn test_onion_crypto_round_trips_both_directions() {
+    let mut client = OnionCrypto::new().unwrap();
+    // The relay's side of the circuit holds the same keys
+    let mut relay = client.clone();
+
+    for message in [&b"GET / HTTP/1.0"[..], b"", b"second cell"] {
+        let sealed = client.encrypt_forward(message).unwrap();
+        assert_eq!(sealed.len(), message.len() + 16);
+        assert!(message.is_empty() || &sealed[..message.len()] != message);
+        assert_eq!(relay.decrypt_forward(&sealed).unwrap(), message);
+    }
+
+    let reply = relay.encrypt_backward(b"HTTP/1.0 200 OK").unwrap();
+    assert_eq!(client.decrypt_backward(&reply).unwrap(), b"HTTP/1.0 200 OK");
+
+    // Each direction has its own key: a backward cell doesn't open as forward
+    let reply = relay.encrypt_backward(b"more").unwrap();
+    assert!(client.clone().decrypt_forward(&reply).is_err());
+    assert_eq!(client.decrypt_backward(&reply).unwrap(), b"more");
+}
+
+#[test]
+fn test_ntor_keys_zeroize() {
+    let onion_secret = StaticSecret::random_from_rng(rand::rngs::OsRng);
+    let server_secret = StaticSecret::random_from_rng(rand::rngs::OsRng);
+    let client_public = PublicKey::from(&StaticSecret::random_from_rng(rand::rngs::OsRng));
+
+    let (_, _, mut keys) = ntor_server_handshake(&server_secret, &onion_secret, &[7u8; 20], &client_public).unwrap();
+    assert_ne!(keys.forward_key, [0u8; 16]);
+
+    let printed = format!("{:?}", keys);
+    assert!(!printed.contains(&format!("{:?}", keys.forward_key)), "{} shows Kf", printed);
+
+    keys.zeroize();
+    assert_eq!(keys.forward_digest, [0u8; 20]);
+    assert_eq!(keys.backward_digest, [0u8; 20]);
+    assert_eq!(keys.forward_key, [0u8; 16]);
+    assert_eq!(keys.backward_key, [0u8; 16]);
+    assert_eq!(keys.kh, [0u8; 20]);
+}
+
+#[test]
+fn test_constant_time_compare() {
+    assert!(constant_time_compare(b"same bytes", b"same bytes"));
+    assert!(constant_time_compare(b"", b""));
+    assert!(!constant_time_compare(b"same bytes", b"same bytez"));
+    assert!(!constant_time_compare(b"prefix", b"prefix and more"));
+    assert!(!constant_time_compare(&[0u8; 32], &[0u8; 31]));
+}
+
+#[test]
+fn test_ntor_auth_is_checked_in_full() {
+    let onion_secret = StaticSecret::random_from_rng(rand::rngs::OsRng);
+    let server_secret = StaticSecret::random_from_rng(rand::rngs::OsRng);
+    let client_secret = StaticSecret::random_from_rng(rand::rngs::OsRng);
+    let identity = [7u8; 20];
+
+    let (server_public, auth, keys) =
+        ntor_server_handshake(&server_secret, &onion_secret, &identity, &PublicKey::from(&client_secret)).unwrap();
+    let onion_key = PublicKey::from(&onion_secret);
+
+    let client_keys = ntor_handshake(&client_secret, &server_public, &auth, &identity, onion_key.as_bytes()).unwrap();
+    assert_eq!(client_keys.forward_key, keys.forward_key);
+
+    // A truncated AUTH that matches as far as it goes is still rejected
+    let truncated = ntor_handshake(&client_secret, &server_public, &auth[..31], &identity, onion_key.as_bytes());
+    match truncated {
+        Err(CryptoError::NtorError(message)) => assert_eq!(message, "relay AUTH does not match"),
+        other => panic!("expected an AUTH mismatch, got {:?}", other.map(|_| ())),
     }
-    
-    #[tokio::test]
-    async fn test_circuit_creation() {
-        let manager = CircuitManager::new();
-        let directory = MockDirectory::new();
-        
-        let circuit_id = manager.create_circuit(3, &directory).await.unwrap();
-        assert!(circuit_id > 0);
+}
+
+/// DER tag, length and contents
+fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    match contents.len() {
+        len if len < 0x80 => out.push(len as u8),
+        len => out.extend_from_slice(&[0x82, (len >> 8) as u8, len as u8]),
     }
+    out.extend_from_slice(contents);
+    out
+}
+
+/// A PKCS#1 RSAPublicKey with a `bits`-bit modulus and public exponent `exponent`
+fn rsa_public_key_der(bits: usize, exponent: &[u8]) -> Vec<u8> {
+    let mut modulus = vec![0xc3; bits / 8];
+    modulus.insert(0, 0); // keep the INTEGER positive
+    let mut sequence = der(0x02, &modulus);
+    sequence.extend(der(0x02, exponent));
+    der(0x30, &sequence)
+}
+
+#[test]
+fn test_rsa_keys_limited_to_4096_bits_and_exponent_65537() {
+    assert!(RsaPublicKey::from_der(&rsa_public_key_der(1024, &[0x01, 0x00, 0x01])).is_ok());
+    assert!(RsaPublicKey::from_der(&rsa_public_key_der(4096, &[0x01, 0x00, 0x01])).is_ok());
+
+    // Either would let a hostile certificate make verification slow or weak
+    assert!(matches!(
+        RsaPublicKey::from_der(&rsa_public_key_der(8192, &[0x01, 0x00, 0x01])),
+        Err(CryptoError::RsaError(_))
+    ));
+    assert!(matches!(RsaPublicKey::from_der(&rsa_public_key_der(1024, &[0x03])), Err(CryptoError::RsaError(_))));
 }
@@ -0,0 +1,758 @@
+// tests/unit/directory_tests.rs
+#[path = "../common/mod.rs"]
+mod common;
+
+use common::{consensus, exit_flags, guard_flags, middle_flags, relay};
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tor_client::directory::fingerprint::{base64_to_hex, hex_to_base64};
+use tor_client::directory::policy::{ExitPolicySummary, ExitTarget};
+use tor_client::directory::probe::HopReachability;
+use tor_client::directory::source::{collector_consensus_base, ConsensusSource, TOR_COLLECTOR_BASE};
+use tor_client::directory::{parse_flag_thresholds, parse_microdescriptors, FlagThresholds, RelayFlag};
+use tor_client::DirectoryClient;
+
+#[tokio::test]
+async fn test_min_relay_version_excludes_old_relays() {
+    let mut old = relay("OldGuard", "10.0.0.1:9001", guard_flags(), 1000);
+    old.platform = Some("Tor 0.3.5.0".to_string());
+    let mut new = relay("NewGuard", "10.1.0.1:9001", guard_flags(), 1000);
+    new.platform = Some("Tor 0.4.8.10".to_string());
+
+    let directory = DirectoryClient::from_consensus(consensus(vec![old, new]))
+        .with_min_relay_version(Some("0.4.7.0"))
+        .unwrap();
+
+    for _ in 0..50 {
+        let selected = directory.select_relay(0).await.unwrap();
+        assert_eq!(selected.nickname, "NewGuard");
+    }
+}
+
+#[tokio::test]
+async fn test_parse_consensus_validity_header() {
+    let text = "\
+network-status-version 3
+valid-after 2025-10-13 20:00:00
+fresh-until 2025-10-13 21:00:00
+valid-until 2025-10-13 23:00:00
+r ChaseTGL AAgYiZwp6HDQSMHR8lyrau/kF10 uG5kFE7Wv6qcthJaqzisLqIQ8PE 2025-10-13 12:03:04 23.169.120.125 4187 0
+s Fast Guard HSDir Running Stable V2Dir Valid
+w Bandwidth=3300
+";
+
+    let parsed = DirectoryClient::new_mock().parse_consensus(text).await.unwrap();
+    let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+    assert_eq!(parsed.valid_after, at(1_760_385_600));
+    assert_eq!(parsed.fresh_until, at(1_760_389_200));
+    assert_eq!(parsed.valid_until, at(1_760_396_400));
+
+    let missing = text.replace("valid-until 2025-10-13 23:00:00\n", "");
+    assert!(DirectoryClient::new_mock().parse_consensus(&missing).await.is_err());
+}
+
+#[test]
+fn test_parse_flag_thresholds() {
+    let thresholds = parse_flag_thresholds(
+        "flag-thresholds stable-uptime=1987911 stable-mtbf=3379516 enough-mtbf=1 fast-speed=102000 \
+         guard-wfu=98.000% guard-tk=691200 guard-bw-inc-exits=31000000 guard-bw-exc-exits=27600000 \
+         ignoring-advertised-bws=1 unknown-key=7",
+    );
+
+    assert_eq!(
+        thresholds,
+        FlagThresholds {
+            stable_uptime: Some(1_987_911),
+            stable_mtbf: Some(3_379_516),
+            enough_mtbf: Some(true),
+            fast_speed: Some(102_000),
+            guard_wfu: Some(98.0),
+            guard_tk: Some(691_200),
+            guard_bw_inc_exits: Some(31_000_000),
+            guard_bw_exc_exits: Some(27_600_000),
+            ignoring_advertised_bws: Some(true),
+        }
+    );
+}
+
+const TEST_AUTHORITY: &str = "AD321FF1F63E7FC08C0B51C93052FB5AB8E3E6FF";
+
+#[tokio::test]
+async fn test_verify_consensus_signatures() {
+    let document = include_str!("../fixtures/signed-consensus");
+    let directory = DirectoryClient::new_mock().with_trusted_authorities(vec![TEST_AUTHORITY.to_string()]);
+
+    // Without the authority's key certificate nothing can be verified
+    assert!(directory.verify_signatures(document).await.is_err());
+
+    assert_eq!(directory.load_authority_certificates(include_str!("../fixtures/authority-cert")).await, 1);
+    directory.verify_signatures(document).await.unwrap();
+
+    let forged = document.replace("Bandwidth=3300", "Bandwidth=9999999");
+    assert!(directory.verify_signatures(&forged).await.is_err());
+
+    // The test authority isn't one of the real ones, so its certificate
+    // isn't even parsed, and one signature couldn't be a majority anyway
+    let directory = DirectoryClient::new_mock();
+    assert_eq!(directory.load_authority_certificates(include_str!("../fixtures/authority-cert")).await, 0);
+    assert!(directory.verify_signatures(document).await.is_err());
+}
+
+#[tokio::test]
+async fn test_failed_relay_skipped_during_cooldown() {
+    let directory = DirectoryClient::from_consensus(consensus(vec![
+        relay("DeadGuard", "10.0.0.1:9001", guard_flags(), 1000),
+        relay("LiveGuard", "10.1.0.1:9001", guard_flags(), 1000),
+    ]))
+    .with_relay_failure_cooldown(Duration::from_millis(300));
+
+    directory.mark_relay_failed("test-DeadGuard").await;
+    for _ in 0..50 {
+        assert_eq!(directory.select_relay(0).await.unwrap().nickname, "LiveGuard");
+    }
+
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    let mut picked_dead = false;
+    for _ in 0..200 {
+        picked_dead |= directory.select_relay(0).await.unwrap().nickname == "DeadGuard";
+    }
+    assert!(picked_dead, "the relay should be eligible again once its cooldown expires");
+}
+
+#[tokio::test]
+async fn test_cached_consensus_loaded_from_data_directory() {
+    let data_dir = std::env::temp_dir().join(format!("tor-client-test-{}", rand::random::<u64>()));
+    std::fs::create_dir_all(&data_dir).unwrap();
+    let cache = data_dir.join("cached-consensus.json");
+    let data_dir = data_dir.to_str().unwrap();
+
+    let cached = consensus(vec![relay("CachedGuard", "10.0.0.1:9001", guard_flags(), 1000)]);
+    std::fs::write(&cache, serde_json::to_vec(&cached).unwrap()).unwrap();
+
+    let directory = DirectoryClient::new(vec![]).with_data_directory(data_dir);
+    let loaded = directory.current_consensus().await.expect("cached consensus should be loaded");
+    assert!(loaded.relays.contains_key("test-CachedGuard"));
+    // Fresh enough to be used without touching the network
+    assert_eq!(directory.select_relay(0).await.unwrap().nickname, "CachedGuard");
+
+    let mut expired = cached;
+    expired.valid_until = SystemTime::now() - Duration::from_secs(60);
+    std::fs::write(&cache, serde_json::to_vec(&expired).unwrap()).unwrap();
+    let directory = DirectoryClient::new(vec![]).with_data_directory(data_dir);
+    assert!(directory.current_consensus().await.is_none());
+
+    std::fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_consensus_sources_tried_in_order() {
+    let directory = DirectoryClient::new(vec![
+        "https://mirror.example/consensus-microdesc/".to_string(),
+        "http://198.51.100.7:80".to_string(),
+        "/var/lib/tor-consensus".to_string(),
+        "tor-collector".to_string(),
+    ]);
+    assert_eq!(
+        directory.consensus_sources(),
+        &[
+            ConsensusSource::Archive("https://mirror.example/consensus-microdesc".to_string()),
+            ConsensusSource::Mirror("http://198.51.100.7:80".to_string()),
+            ConsensusSource::Directory("/var/lib/tor-consensus".into()),
+            ConsensusSource::Archive(TOR_COLLECTOR_BASE.to_string()),
+        ]
+    );
+    assert_eq!(
+        DirectoryClient::new(vec![]).consensus_sources(),
+        &[ConsensusSource::Archive(TOR_COLLECTOR_BASE.to_string())]
+    );
+
+    // Mirrors only have the current consensus
+    let name = "2026-01-02-03-00-00-consensus-microdesc";
+    let mirror = &directory.consensus_sources()[1];
+    assert_eq!(
+        mirror.location(name, true).as_deref(),
+        Some("http://198.51.100.7:80/tor/status-vote/current/consensus-microdesc")
+    );
+    assert_eq!(mirror.location(name, false), None);
+    assert_eq!(
+        directory.consensus_sources()[0].location(name, false).as_deref(),
+        Some("https://mirror.example/consensus-microdesc/2026-01-02-03-00-00-consensus-microdesc")
+    );
+
+    // A local directory without any consensus fails without touching the network
+    let empty = std::env::temp_dir().join(format!("tor-client-test-{}", rand::random::<u64>()));
+    let directory = DirectoryClient::new(vec![empty.to_str().unwrap().to_string()]);
+    assert!(directory.fetch_consensus().await.is_err());
+}
+
+#[tokio::test]
+async fn test_collector_override_changes_fetched_urls() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // A Collector stand-in that records each request path and has nothing
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let collector = format!("http://{}", listener.local_addr().unwrap());
+    let paths = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let seen = paths.clone();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut request = vec![0u8; 4096];
+            let n = stream.read(&mut request).await.unwrap_or(0);
+            let request = String::from_utf8_lossy(&request[..n]).to_string();
+            seen.lock().unwrap().push(request.split_whitespace().nth(1).unwrap_or_default().to_string());
+            let _ = stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await;
+        }
+    });
+
+    let directory = DirectoryClient::new(vec!["tor-collector".to_string()]).with_collector(Some(&collector));
+    assert_eq!(
+        directory.consensus_sources(),
+        &[ConsensusSource::Archive(collector_consensus_base(&collector))]
+    );
+    assert!(directory.fetch_consensus().await.is_err());
+
+    let name = chrono::Utc::now().format("%Y-%m-%d-%H-00-00-consensus-microdesc").to_string();
+    let paths = paths.lock().unwrap();
+    assert_eq!(paths.len(), 48, "one request per hour looked back");
+    assert_eq!(paths[0], format!("/recent/relay-descriptors/microdescs/consensus-microdesc/{}", name));
+}
+
+#[tokio::test]
+async fn test_relay_buckets_hold_eligible_relays() {
+    let mut not_running = guard_flags();
+    not_running.retain(|f| *f != RelayFlag::Running);
+    let mut relays: Vec<_> = (0..3)
+        .map(|i| relay(&format!("Guard{}", i), &format!("10.0.0.{}:9001", i + 1), guard_flags(), 1000))
+        .collect();
+    relays.push(relay("DownGuard", "10.0.1.1:9001", not_running, 1000));
+    relays.push(relay("Middle", "10.1.0.1:9001", middle_flags(), 1000));
+    relays.push(relay("Exit", "10.2.0.1:9001", exit_flags(), 1000));
+    let directory = DirectoryClient::from_consensus(consensus(relays)).with_relay_buckets(true);
+
+    let buckets = directory.relay_buckets().await.expect("a consensus is loaded");
+    let guards: HashSet<&str> = buckets.guards.iter().map(String::as_str).collect();
+    assert_eq!(guards, HashSet::from(["test-Guard0", "test-Guard1", "test-Guard2"]));
+    assert_eq!(buckets.middles, vec!["test-Middle".to_string()]);
+    assert_eq!(buckets.exits, vec!["test-Exit".to_string()]);
+
+    for _ in 0..20 {
+        assert!(guards.contains(directory.select_relay(0).await.unwrap().id.as_str()));
+    }
+    assert_eq!(directory.select_relay(1).await.unwrap().nickname, "Middle");
+    assert_eq!(directory.select_relay(2).await.unwrap().nickname, "Exit");
+
+    assert!(DirectoryClient::from_consensus(consensus(vec![])).relay_buckets().await.is_none());
+}
+
+#[test]
+fn test_microdescriptor_carries_ed25519_identity() {
+    let microdescs = parse_microdescriptors(
+        "onion-key\n\
+         ntor-onion-key AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA\n\
+         id ed25519 BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc\n\
+         onion-key\n\
+         ntor-onion-key AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE\n",
+    );
+    let mut identities: Vec<_> = microdescs.into_values().map(|md| md.ed25519_identity).collect();
+    identities.sort();
+    assert_eq!(identities, vec![None, Some([7; 32])]);
+}
+
+#[tokio::test]
+async fn test_path_skips_family_members_and_shared_subnets() {
+    // Family lines name relays by hex fingerprint, optionally with "~nickname"
+    let microdescs = parse_microdescriptors(
+        "onion-key\n\
+         ntor-onion-key AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA\n\
+         family $0102030405060708090A0B0C0D0E0F1011121314~Sibling Other\n",
+    );
+    let family = microdescs.into_values().next().unwrap().family;
+    assert_eq!(family, vec!["$0102030405060708090A0B0C0D0E0F1011121314~Sibling", "Other"]);
+
+    let mut guard = relay("Guard", "10.0.0.1:9001", guard_flags(), 1000);
+    guard.family = family;
+    let mut sibling = relay("Sibling", "10.1.0.1:9001", middle_flags(), 1_000_000);
+    sibling.id = hex_to_base64("0102030405060708090A0B0C0D0E0F1011121314").unwrap();
+    let neighbour = relay("Neighbour", "10.0.5.1:9001", middle_flags(), 1_000_000);
+    let unrelated = relay("Unrelated", "10.2.0.1:9001", middle_flags(), 1);
+    let directory = DirectoryClient::from_consensus(consensus(vec![guard.clone(), sibling, neighbour, unrelated]))
+        .with_distinct_subnets(true);
+
+    let path = [guard];
+    for _ in 0..50 {
+        assert_eq!(directory.select_relay_for_path(1, &path).await.unwrap().nickname, "Unrelated");
+    }
+}
+
+#[tokio::test]
+async fn test_forced_selection_still_keeps_hops_in_distinct_subnets() {
+    let relays = vec![
+        relay("Guard", "10.0.0.1:9001", guard_flags(), 1000),
+        relay("Near", "10.0.7.1:9001", middle_flags(), 1000),
+        relay("Light", "10.5.0.1:9001", middle_flags(), 1),
+        relay("Heavy", "10.6.0.1:9001", middle_flags(), 1_000_000),
+    ];
+    let directory = DirectoryClient::from_consensus(consensus(relays.clone())).with_distinct_subnets(true);
+
+    // Forcing bypasses weighting...
+    directory.force_next_selection(vec!["Guard".to_string(), "Light".to_string()]).await;
+    let path = directory.select_path(2, None, false).await.unwrap();
+    assert_eq!(path.iter().map(|r| r.nickname.as_str()).collect::<Vec<_>>(), ["Guard", "Light"]);
+
+    // ...but not the subnet rule: Near shares the guard's /16 and is passed over
+    directory.force_next_selection(vec!["Guard".to_string(), "Near".to_string()]).await;
+    let path = directory.select_path(2, None, false).await.unwrap();
+    assert_eq!(path[0].nickname, "Guard");
+    assert_ne!(path[1].nickname, "Near");
+
+    let same_host = DirectoryClient::from_consensus(consensus(relays)).with_distinct_subnets(false);
+    same_host.force_next_selection(vec!["Guard".to_string(), "Near".to_string()]).await;
+    assert_eq!(same_host.select_path(2, None, false).await.unwrap()[1].nickname, "Near");
+}
+
+#[tokio::test]
+async fn test_select_path_picks_distinct_relays() {
+    let directory = DirectoryClient::from_consensus(consensus(vec![
+        relay("Guard", "10.0.0.1:9001", guard_flags(), 1000),
+        relay("MiddleA", "10.1.0.1:9001", middle_flags(), 1000),
+        relay("MiddleB", "10.2.0.1:9001", middle_flags(), 1000),
+        relay("Exit", "10.3.0.1:9001", exit_flags(), 1000),
+    ]));
+
+    for num_hops in [3, 4] {
+        for _ in 0..20 {
+            let path = directory.select_path(num_hops, None, false).await.unwrap();
+            let ids: HashSet<_> = path.iter().map(|r| r.id.clone()).collect();
+            assert_eq!(ids.len(), num_hops, "{:?}", path.iter().map(|r| &r.nickname).collect::<Vec<_>>());
+            assert_eq!(path[0].nickname, "Guard");
+            assert_eq!(path[num_hops - 1].nickname, "Exit");
+        }
+    }
+    // Not enough relays for five distinct hops
+    assert!(directory.select_path(5, None, false).await.is_err());
+}
+
+#[tokio::test]
+async fn test_relaxed_middle_admits_fast_only_relays() {
+    let fast_only = vec![RelayFlag::Fast, RelayFlag::Running, RelayFlag::Valid];
+    let relays = || {
+        consensus(vec![
+            relay("StableMiddle", "10.0.0.1:9001", middle_flags(), 1000),
+            relay("FastMiddle", "10.1.0.1:9001", fast_only.clone(), 1000),
+        ])
+    };
+
+    let strict = DirectoryClient::from_consensus(relays());
+    for _ in 0..50 {
+        assert_eq!(strict.select_relay(1).await.unwrap().nickname, "StableMiddle");
+    }
+
+    let relaxed = DirectoryClient::from_consensus(relays()).with_require_stable_middle(false);
+    let mut picked_fast = false;
+    for _ in 0..200 {
+        picked_fast |= relaxed.select_relay(1).await.unwrap().nickname == "FastMiddle";
+    }
+    assert!(picked_fast, "a Fast-only relay should be eligible as middle when Stable isn't required");
+}
+
+#[tokio::test]
+async fn test_exit_selection_honors_policy_and_position_weights() {
+    let with_policy = |nickname: &str, address: &str, flags: Vec<RelayFlag>, policy: &str| {
+        let mut exit = relay(nickname, address, flags, 1000);
+        exit.exit_policy = ExitPolicySummary::parse(policy);
+        exit
+    };
+    let mut guard_exit_flags = exit_flags();
+    guard_exit_flags.push(RelayFlag::Guard);
+
+    let mut relays = consensus(vec![
+        with_policy("WebExit", "10.0.0.1:9001", exit_flags(), "accept 80,443"),
+        with_policy("NoHttps", "10.1.0.1:9001", exit_flags(), "reject 443"),
+        with_policy("GuardExit", "10.2.0.1:9001", guard_exit_flags, "accept 1-65535"),
+    ]);
+    relays.bandwidth_weights.insert("Wee".to_string(), 10000);
+    relays.bandwidth_weights.insert("Wed".to_string(), 0);
+    let directory = DirectoryClient::from_consensus(relays);
+
+    for _ in 0..100 {
+        assert_eq!(directory.select_exit_relay(Some(443)).await.unwrap().nickname, "WebExit");
+    }
+    let mut picked_no_https = false;
+    for _ in 0..200 {
+        let exit = directory.select_exit_relay(Some(22)).await.unwrap();
+        assert_ne!(exit.nickname, "GuardExit", "Wed=0 gives Guard+Exit relays no exit weight");
+        picked_no_https |= exit.nickname == "NoHttps";
+    }
+    assert!(picked_no_https);
+}
+
+#[tokio::test]
+async fn test_ipv6_exit_policy_applies_to_ipv6_targets() {
+    let text = "\
+network-status-version 3
+valid-after 2025-10-13 20:00:00
+fresh-until 2025-10-13 21:00:00
+valid-until 2025-10-13 23:00:00
+r V4Only AAgYiZwp6HDQSMHR8lyrau/kF10 uG5kFE7Wv6qcthJaqzisLqIQ8PE 2025-10-13 12:03:04 23.169.120.125 4187 0
+s Exit Fast Running Valid
+w Bandwidth=3300
+p accept 443
+p6 reject 443
+";
+    let parsed = DirectoryClient::new_mock().parse_consensus(text).await.unwrap();
+    let mut v4_only = parsed.relays.values().next().unwrap().clone();
+    assert_eq!(v4_only.exit_policy_v6, ExitPolicySummary::parse("reject 443"));
+    assert!(v4_only.allows_exit_to(ExitTarget::ipv4(443)));
+    assert!(!v4_only.allows_exit_to(ExitTarget::ipv6(443)));
+
+    // The onion key comes from the microdescriptor, which this consensus has none of
+    v4_only.onion_key = vec![0u8; 32];
+
+    let directory = DirectoryClient::from_consensus(consensus(vec![v4_only.clone()]));
+    assert_eq!(directory.select_exit_for_target(ExitTarget::ipv4(443)).await.unwrap().nickname, "V4Only");
+    assert!(directory.select_exit_for_target(ExitTarget::ipv6(443)).await.is_err());
+
+    let mut dual_stack = relay("DualStack", "10.1.0.1:9001", exit_flags(), 1000);
+    dual_stack.exit_policy = ExitPolicySummary::parse("accept 443");
+    dual_stack.exit_policy_v6 = ExitPolicySummary::parse("accept 443");
+    let directory = DirectoryClient::from_consensus(consensus(vec![v4_only, dual_stack]));
+    for _ in 0..100 {
+        let exit = directory.select_exit_for_target(ExitTarget::for_host("2001:db8::1", 443)).await.unwrap();
+        assert_eq!(exit.nickname, "DualStack");
+    }
+}
+
+#[tokio::test]
+async fn test_consensus_stale_after_max_age() {
+    let directory = DirectoryClient::from_consensus(consensus(vec![]));
+    assert!(directory.is_consensus_fresh().await);
+
+    let directory = DirectoryClient::from_consensus(consensus(vec![]))
+        .with_max_consensus_age(Duration::from_millis(20));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let valid_until = directory.current_consensus().await.unwrap().valid_until;
+    assert!(valid_until > SystemTime::now(), "the consensus itself is still valid");
+    assert!(!directory.is_consensus_fresh().await, "but it has been held longer than the max age");
+}
+
+#[tokio::test]
+async fn test_forced_refreshes_are_rate_limited() {
+    let directory = DirectoryClient::new_mock().with_fetch_rate_limit(1, Duration::from_millis(300));
+
+    let start = std::time::Instant::now();
+    directory.refresh_consensus(true).await.unwrap();
+    assert!(start.elapsed() < Duration::from_millis(250), "the first fetch has a token waiting");
+
+    let start = std::time::Instant::now();
+    directory.refresh_consensus(true).await.unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(250), "the second fetch waits for the bucket to refill");
+
+    // Fresh consensuses come from memory and don't use up the bucket
+    let start = std::time::Instant::now();
+    directory.refresh_consensus(false).await.unwrap();
+    assert!(start.elapsed() < Duration::from_millis(250));
+}
+
+#[tokio::test]
+async fn test_relays_with_or_port_zero_are_skipped() {
+    let text = "\
+network-status-version 3
+valid-after 2025-10-13 20:00:00
+fresh-until 2025-10-13 21:00:00
+valid-until 2025-10-13 23:00:00
+r ChaseTGL AAgYiZwp6HDQSMHR8lyrau/kF10 uG5kFE7Wv6qcthJaqzisLqIQ8PE 2025-10-13 12:03:04 23.169.120.125 4187 0
+s Fast Guard HSDir Running Stable V2Dir Valid
+w Bandwidth=3300
+r NoPort AAgYiZwp6HDQSMHR8lyrau/kF18 uG5kFE7Wv6qcthJaqzisLqIQ8PE 2025-10-13 12:03:04 23.169.120.126 0 80
+s Fast Guard Running Stable Valid
+w Bandwidth=9000
+";
+
+    let parsed = DirectoryClient::new_mock().parse_consensus(text).await.unwrap();
+    let names: Vec<_> = parsed.relays.values().map(|r| r.nickname.as_str()).collect();
+    assert_eq!(names, ["ChaseTGL"]);
+}
+
+#[tokio::test]
+async fn test_parse_ipv6_or_address() {
+    let text = "\
+network-status-version 3
+valid-after 2025-10-13 20:00:00
+fresh-until 2025-10-13 21:00:00
+valid-until 2025-10-13 23:00:00
+r ChaseTGL AAgYiZwp6HDQSMHR8lyrau/kF10 uG5kFE7Wv6qcthJaqzisLqIQ8PE 2025-10-13 12:03:04 23.169.120.125 4187 0
+a [2001:db8::1]:9001
+s Fast Guard HSDir Running Stable V2Dir Valid
+w Bandwidth=3300
+r NoSix AAgYiZwp6HDQSMHR8lyrau/kF18 uG5kFE7Wv6qcthJaqzisLqIQ8PE 2025-10-13 12:03:04 23.169.120.126 443 0
+s Fast Running Valid
+w Bandwidth=1000
+";
+
+    let parsed = DirectoryClient::new_mock().parse_consensus(text).await.unwrap();
+    let by_name = |name: &str| parsed.relays.values().find(|r| r.nickname == name).unwrap().clone();
+
+    let dual = by_name("ChaseTGL");
+    assert_eq!(dual.ipv6_address, Some("[2001:db8::1]:9001".parse().unwrap()));
+    assert_eq!(dual.or_address(true), "[2001:db8::1]:9001".parse().unwrap());
+    assert_eq!(dual.or_address(false), "23.169.120.125:4187".parse().unwrap());
+
+    let ipv4_only = by_name("NoSix");
+    assert_eq!(ipv4_only.ipv6_address, None);
+    assert_eq!(ipv4_only.or_address(true), "23.169.120.126:443".parse().unwrap());
+}
+
+#[tokio::test]
+async fn test_middle_selection_uses_position_weights() {
+    let mut guard_middle_flags = guard_flags();
+    guard_middle_flags.push(RelayFlag::Middle);
+    let mut plain_middle_flags = middle_flags();
+    plain_middle_flags.push(RelayFlag::Middle);
+
+    let mut relays = consensus(vec![
+        relay("GuardMiddle", "10.0.0.1:9001", guard_middle_flags, 1_000_000),
+        relay("PlainMiddle", "10.1.0.1:9001", plain_middle_flags, 1000),
+    ]);
+    relays.bandwidth_weights.insert("Wmg".to_string(), 0);
+    relays.bandwidth_weights.insert("Wmm".to_string(), 10000);
+    let directory = DirectoryClient::from_consensus(relays);
+
+    for _ in 0..100 {
+        let middle = directory.select_relay(1).await.unwrap();
+        assert_eq!(middle.nickname, "PlainMiddle", "Wmg=0 keeps guards out of the middle position");
+    }
+}
+
+#[tokio::test]
+async fn test_guard_set_is_pinned_and_persisted() {
+    let data_dir = std::env::temp_dir().join(format!("tor-client-test-{}", rand::random::<u64>()));
+    let guards = || {
+        let relays = (0..10)
+            .map(|i| relay(&format!("Guard{}", i), &format!("10.{}.0.1:9001", i), guard_flags(), 1000))
+            .collect();
+        DirectoryClient::from_consensus(consensus(relays))
+            .with_guard_params(2, Duration::from_secs(3600))
+            .with_relay_failure_cooldown(Duration::from_secs(60))
+            .with_data_directory(data_dir.to_str().unwrap())
+    };
+
+    let directory = guards();
+    let mut used = HashSet::new();
+    for _ in 0..100 {
+        used.insert(directory.select_relay(0).await.unwrap().id);
+    }
+    let chosen = directory.guards().await;
+    assert_eq!(chosen.guards.len(), 2);
+    assert!(used.iter().all(|id| chosen.contains(id)), "hop 0 only uses our guards");
+
+    // A restart keeps the same guards
+    let restarted = guards();
+    let first = restarted.select_relay(0).await.unwrap();
+    assert!(chosen.contains(&first.id));
+    assert_eq!(restarted.guards().await, chosen);
+
+    // Only once every guard is down is another one taken on
+    for guard in &chosen.guards {
+        restarted.mark_relay_failed(&guard.relay_id).await;
+    }
+    let replacement = restarted.select_relay(0).await.unwrap();
+    assert!(!chosen.contains(&replacement.id));
+    assert!(restarted.guards().await.contains(&replacement.id));
+
+    std::fs::remove_dir_all(&data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_guards_rotate_after_lifetime() {
+    let relays = (0..10)
+        .map(|i| relay(&format!("Guard{}", i), &format!("10.{}.0.1:9001", i), guard_flags(), 1000))
+        .collect();
+    let directory = DirectoryClient::from_consensus(consensus(relays))
+        .with_guard_params(1, Duration::from_millis(50));
+
+    directory.select_relay(0).await.unwrap();
+    let first = directory.guards().await.guards[0].clone();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    directory.select_relay(0).await.unwrap();
+    let second = directory.guards().await.guards[0].clone();
+    assert!(second.added_at > first.added_at, "an expired guard is replaced by a fresh sample");
+}
+
+#[tokio::test]
+async fn test_reconcile_replaces_guards_missing_from_consensus() {
+    let relays: Vec<_> = (0..10)
+        .map(|i| relay(&format!("Guard{}", i), &format!("10.{}.0.1:9001", i), guard_flags(), 1000))
+        .collect();
+    let directory = DirectoryClient::from_consensus(consensus(relays.clone()))
+        .with_guard_params(3, Duration::from_secs(3600));
+    directory.select_relay(0).await.unwrap();
+    let before = directory.guards().await;
+    assert_eq!(before.guards.len(), 3);
+
+    // The refreshed consensus drops one guard and lists another as not Running
+    let gone = before.guards[0].relay_id.clone();
+    let stopped = before.guards[1].relay_id.clone();
+    let kept = before.guards[2].clone();
+    let refreshed: Vec<_> = relays
+        .into_iter()
+        .filter(|r| r.id != gone)
+        .map(|mut r| {
+            if r.id == stopped {
+                r.flags.retain(|f| *f != RelayFlag::Running);
+            }
+            r
+        })
+        .collect();
+
+    let down = directory.reconcile_guards(&consensus(refreshed)).await;
+    let down: HashSet<String> = down.into_iter().map(|guard| guard.relay_id).collect();
+    assert_eq!(down, HashSet::from([gone.clone(), stopped.clone()]));
+
+    let after = directory.guards().await;
+    assert_eq!(after.guards.len(), 3);
+    assert_eq!(after.guards[0], kept, "the remaining guard keeps its place");
+    assert!(!after.contains(&gone) && !after.contains(&stopped));
+}
+
+#[tokio::test]
+async fn test_configured_entry_guards_are_used() {
+    let directory = DirectoryClient::from_consensus(consensus(vec![
+        relay("Pinned", "10.0.0.1:9001", guard_flags(), 10),
+        relay("Other", "10.1.0.1:9001", guard_flags(), 100_000),
+    ]))
+    .with_entry_guards(vec!["Pinned".to_string()]);
+
+    for _ in 0..50 {
+        assert_eq!(directory.select_relay(0).await.unwrap().nickname, "Pinned");
+    }
+}
+
+/// An address whose connections hang: the listener's accept queue is full
+/// and it never accepts, so new SYNs are dropped
+async fn black_hole() -> (std::net::SocketAddr, tokio::net::TcpListener, tokio::net::TcpStream) {
+    let socket = tokio::net::TcpSocket::new_v4().unwrap();
+    socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let address = socket.local_addr().unwrap();
+    let listener = socket.listen(0).unwrap();
+    let filler = tokio::net::TcpStream::connect(address).await.unwrap();
+    (address, listener, filler)
+}
+
+#[tokio::test]
+async fn test_concurrent_hop_probing_is_faster_and_counts_reachable_relays() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let open = listener.local_addr().unwrap().to_string();
+    let (hole, _hole_listener, _filler) = black_hole().await;
+    let hole = hole.to_string();
+
+    let mut relays = Vec::new();
+    for (name, flags) in [("Guard", guard_flags()), ("Middle", middle_flags()), ("Exit", exit_flags())] {
+        for i in 0..2 {
+            relays.push(relay(&format!("{}Open{}", name, i), &open, flags.clone(), 1000));
+            relays.push(relay(&format!("{}Hole{}", name, i), &hole, flags.clone(), 1000));
+        }
+    }
+    let directory = DirectoryClient::from_consensus(consensus(relays));
+    let timeout = Duration::from_millis(300);
+
+    let started = tokio::time::Instant::now();
+    let sequential = directory.probe_all_hops_concurrent(4, 1, timeout).await.unwrap();
+    let sequential_time = started.elapsed();
+    let started = tokio::time::Instant::now();
+    let concurrent = directory.probe_all_hops_concurrent(4, 12, timeout).await.unwrap();
+    let concurrent_time = started.elapsed();
+
+    let expected: Vec<HopReachability> =
+        (0..3).map(|hop| HopReachability { hop, probed: 4, reachable: 2 }).collect();
+    assert_eq!(sequential, expected);
+    assert_eq!(concurrent, expected);
+    // Six probes time out one after another, versus all at once
+    assert!(sequential_time >= timeout * 6);
+    assert!(concurrent_time < sequential_time / 2);
+}
+
+#[tokio::test]
+async fn test_describe_relay() {
+    let mut exit = relay("NiceExit", "10.2.0.1:9001", exit_flags(), 4200);
+    exit.exit_policy = ExitPolicySummary::parse("accept 80,443,1000-2000");
+    exit.platform = Some("Tor 0.4.8.10".to_string());
+    let directory = DirectoryClient::from_consensus(consensus(vec![exit]));
+
+    let description = directory.describe_relay("test-NiceExit").await.unwrap();
+    assert!(description.contains("Nickname: NiceExit"));
+    assert!(description.contains("Address: 10.2.0.1:9001"));
+    assert!(description.contains("Flags: Exit Fast Running Valid"));
+    assert!(description.contains("Bandwidth: 4200 KB/s"));
+    assert!(description.contains("Exit policy: accept 80,443,1000-2000"));
+
+    assert!(directory.describe_relay("test-Nobody").await.is_none());
+}
+
+#[tokio::test]
+async fn test_fingerprint_encodings_round_trip() {
+    let id = "AAgYiZwp6HDQSMHR8lyrau/kF10";
+    let hex = "000818899C29E870D048C1D1F25CAB6AEFE4175D";
+    assert_eq!(base64_to_hex(id).as_deref(), Some(hex));
+    assert_eq!(hex_to_base64(hex).as_deref(), Some(id));
+    assert_eq!(hex_to_base64(&format!("${}", hex.to_lowercase())).as_deref(), Some(id));
+    assert_eq!(hex_to_base64("0008 1889 9C29 E870 D048 C1D1 F25C AB6A EFE4 175D").as_deref(), Some(id));
+    assert!(hex_to_base64("0008").is_none());
+    assert!(base64_to_hex("not base64!").is_none());
+
+    // Entry guards configured by hex fingerprint match the consensus' base64 ids
+    let mut pinned = relay("Pinned", "10.0.0.1:9001", guard_flags(), 10);
+    pinned.id = id.to_string();
+    let directory = DirectoryClient::from_consensus(consensus(vec![
+        pinned,
+        relay("Other", "10.1.0.1:9001", guard_flags(), 100_000),
+    ]))
+    .with_entry_guards(vec![format!("${}", hex)]);
+    for _ in 0..20 {
+        assert_eq!(directory.select_relay(0).await.unwrap().nickname, "Pinned");
+    }
+}
+
+#[tokio::test]
+async fn test_fetch_retries_ride_out_simulated_directory_failures() {
+    // Half the tries fail; thirty tries all failing is a one in a billion event
+    for _ in 0..10 {
+        let directory = DirectoryClient::new_mock()
+            .with_fetch_failure_rate(0.5)
+            .with_fetch_retries(30, Duration::from_millis(1));
+        let consensus = directory.fetch_consensus().await.unwrap();
+        assert!(!consensus.relays.is_empty());
+    }
+
+    // Past the budget the failure gets through, after backing off 1ms then 2ms
+    let directory = DirectoryClient::new_mock()
+        .with_fetch_failure_rate(1.0)
+        .with_fetch_retries(3, Duration::from_millis(1));
+    let started = std::time::Instant::now();
+    assert!(directory.fetch_consensus().await.is_err());
+    assert!(started.elapsed() >= Duration::from_millis(3));
+    assert!(directory.current_consensus().await.is_none());
+}
+
+#[tokio::test]
+async fn test_relays_at_local_addresses_are_never_selected() {
+    let mut flags = guard_flags();
+    flags.extend([RelayFlag::Exit, RelayFlag::Stable]);
+    let mut home = relay("Home", "10.0.0.1:9001", flags, 10_000_000);
+    home.ipv6_address = Some("[2001:db8::1]:9001".parse().unwrap());
+    let relays = vec![
+        home,
+        relay("Guard", "10.1.0.1:9001", guard_flags(), 1000),
+        relay("Middle", "10.2.0.1:9001", middle_flags(), 1000),
+        relay("Exit", "10.3.0.1:9001", exit_flags(), 1000),
+    ];
+
+    // Matched by either of its addresses
+    for local in ["10.0.0.1", "2001:db8::1"] {
+        let directory = DirectoryClient::from_consensus(consensus(relays.clone()))
+            .with_local_addresses(vec![local.parse().unwrap()]);
+        for _ in 0..50 {
+            let path = directory.select_path(3, None, false).await.unwrap();
+            assert!(path.iter().all(|r| r.nickname != "Home"), "{} selected {:?}", local, path);
+        }
+    }
+}
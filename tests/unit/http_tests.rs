@@ -0,0 +1,29 @@
+// tests/unit/http_tests.rs
+use tor_client::http::{response_body, HttpUrl};
+
+#[test]
+fn test_parse_http_url() {
+    let url = HttpUrl::parse("http://example.com").unwrap();
+    assert_eq!((url.host.as_str(), url.port, url.path.as_str()), ("example.com", 80, "/"));
+
+    let url = HttpUrl::parse("http://example.com:8080/a/b?q=1#frag").unwrap();
+    assert_eq!((url.host.as_str(), url.port, url.path.as_str()), ("example.com", 8080, "/a/b?q=1"));
+
+    let url = HttpUrl::parse("http://[2001:db8::1]:81/").unwrap();
+    assert_eq!((url.host.as_str(), url.port), ("2001:db8::1", 81));
+    assert!(String::from_utf8(url.get_request()).unwrap().contains("\r\nHost: [2001:db8::1]:81\r\n"));
+
+    assert!(HttpUrl::parse("https://example.com/").is_none());
+    assert!(HttpUrl::parse("http://:80/").is_none());
+}
+
+#[test]
+fn test_response_body() {
+    assert_eq!(response_body(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello").unwrap(), "hello");
+    assert_eq!(
+        response_body(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nhel\r\n2;x=y\r\nlo\r\n0\r\n\r\n").unwrap(),
+        "hello"
+    );
+    assert!(response_body(b"HTTP/1.1 404 Not Found\r\n\r\nnope").is_err());
+    assert!(response_body(b"GET / HTTP/1.1\r\n\r\n").is_err());
+}
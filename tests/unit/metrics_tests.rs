@@ -0,0 +1,39 @@
+// tests/unit/metrics_tests.rs
+use std::sync::atomic::Ordering;
+use tor_client::circuit::CircuitFailureKind;
+use tor_client::metrics::{Metrics, BUILD_OUTCOME_WINDOW};
+
+#[test]
+fn test_metrics_to_json() {
+    let metrics = Metrics::new();
+    metrics.circuits_created.fetch_add(3, Ordering::Relaxed);
+    metrics.bytes_sent.fetch_add(1024, Ordering::Relaxed);
+    metrics.bytes_received.fetch_add(2048, Ordering::Relaxed);
+    metrics.active_circuits.fetch_add(2, Ordering::Relaxed);
+
+    let json = metrics.to_json();
+    assert_eq!(json["circuits_created"], 3);
+    assert_eq!(json["bytes_sent"], 1024);
+    assert_eq!(json["bytes_received"], 2048);
+    assert_eq!(json["active_circuits"], 2);
+}
+
+#[test]
+fn test_build_success_rate_covers_recent_builds() {
+    let metrics = Metrics::new();
+    assert_eq!(metrics.build_success_rate(), 1.0);
+
+    for _ in 0..3 {
+        metrics.record_circuit_built();
+    }
+    metrics.record_circuit_failure(CircuitFailureKind::Handshake);
+    assert_eq!(metrics.build_success_rate(), 0.75);
+    assert_eq!(metrics.to_json()["build_success_rate"], 0.75);
+
+    // Once the window is full of failures, the early successes no longer count
+    for _ in 0..BUILD_OUTCOME_WINDOW {
+        metrics.record_circuit_failure(CircuitFailureKind::Connectivity);
+    }
+    assert_eq!(metrics.build_success_rate(), 0.0);
+    assert_eq!(metrics.circuits_created.load(Ordering::Relaxed), 3);
+}
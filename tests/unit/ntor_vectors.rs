@@ -0,0 +1,77 @@
+// tests/unit/ntor_vectors.rs
+//! ntor handshake against fixed test vectors. Tor doesn't publish ntor vectors
+//! in the spec, so these were computed from fixed x, y, b and ID with an
+//! independent implementation of tor-spec 5.1.4 and 5.2.2 (X25519,
+//! HMAC-SHA256 and HKDF-SHA256 from Python's `cryptography`, in the manner of
+//! tor's src/test/ntor_ref.py).
+use tor_client::crypto::{ntor_handshake, ntor_server_handshake, NtorKeys};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const CLIENT_SECRET_X: &[u8; 32] = b"client ephemeral secret x ......";
+const RELAY_SECRET_Y: &[u8; 32] = b"relay ephemeral secret y .......";
+const ONION_SECRET_B: &[u8; 32] = b"relay onion secret b ...........";
+const RELAY_ID: &[u8; 20] = b"relay identity ID...";
+
+const EXPECTED_X: &str = "b1a752ef0084c94a0d85401e6b63b9c4a24459a41aea71736eaef0de9550930e";
+const EXPECTED_Y: &str = "6f2d0bc3d57bc8e41c6c15f91cb3c7e43fd43d11b2dc2b3a937139f70dd12b07";
+const EXPECTED_B: &str = "9aa01bf69687f4fe84d6938a3705dca9ceb09ee14f98e221cc262cdae0a82051";
+const EXPECTED_AUTH: &str = "1fe54140eae532e95a606f75741e6f9c9ba81188c48ad44d2fb72e3ab2f90658";
+const EXPECTED_DF: &str = "9de41a16d25fc2bcb3e1610ef214a598b9b5fc39";
+const EXPECTED_DB: &str = "0b2e15f7780a4a19a8809fc99c3ceeacb3518e98";
+const EXPECTED_KF: &str = "e568ac666749ed51db7e0f4c9c4f0f7e";
+const EXPECTED_KB: &str = "91e63582bb04c0626301f88047c21d9c";
+const EXPECTED_KH: &str = "c2570e2078b3413708fd8e545b7a7fbfd7c60fea";
+
+/// Compare one output, naming it when it diverges
+fn check(output: &str, actual: &[u8], expected: &str) {
+    assert_eq!(hex::encode(actual), expected, "ntor output {} diverges from the test vector", output);
+}
+
+fn check_keys(side: &str, keys: &NtorKeys) {
+    check(&format!("{} Df", side), &keys.forward_digest, EXPECTED_DF);
+    check(&format!("{} Db", side), &keys.backward_digest, EXPECTED_DB);
+    check(&format!("{} Kf", side), &keys.forward_key, EXPECTED_KF);
+    check(&format!("{} Kb", side), &keys.backward_key, EXPECTED_KB);
+    check(&format!("{} KH", side), &keys.kh, EXPECTED_KH);
+}
+
+#[test]
+fn test_ntor_public_keys_match_vectors() {
+    check("X", PublicKey::from(&StaticSecret::from(*CLIENT_SECRET_X)).as_bytes(), EXPECTED_X);
+    check("Y", PublicKey::from(&StaticSecret::from(*RELAY_SECRET_Y)).as_bytes(), EXPECTED_Y);
+    check("B", PublicKey::from(&StaticSecret::from(*ONION_SECRET_B)).as_bytes(), EXPECTED_B);
+}
+
+#[test]
+fn test_ntor_server_handshake_matches_vectors() {
+    let client_public = PublicKey::from(&StaticSecret::from(*CLIENT_SECRET_X));
+    let (relay_public, auth, keys) = ntor_server_handshake(
+        &StaticSecret::from(*RELAY_SECRET_Y),
+        &StaticSecret::from(*ONION_SECRET_B),
+        RELAY_ID,
+        &client_public,
+    )
+    .unwrap();
+
+    check("Y", relay_public.as_bytes(), EXPECTED_Y);
+    check("AUTH", &auth, EXPECTED_AUTH);
+    check_keys("relay", &keys);
+}
+
+#[test]
+fn test_ntor_client_handshake_matches_vectors() {
+    let relay_public: [u8; 32] = hex::decode(EXPECTED_Y).unwrap().try_into().unwrap();
+    let onion_key = hex::decode(EXPECTED_B).unwrap();
+    let auth = hex::decode(EXPECTED_AUTH).unwrap();
+
+    let keys = ntor_handshake(
+        &StaticSecret::from(*CLIENT_SECRET_X),
+        &PublicKey::from(relay_public),
+        &auth,
+        RELAY_ID,
+        &onion_key,
+    )
+    .expect("the vector's AUTH should verify");
+
+    check_keys("client", &keys);
+}